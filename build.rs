@@ -1,6 +1,54 @@
+use std::path::PathBuf;
+
+/// Directory of pre-generated stubs to use instead of running protoc.
+const STUBS_ENV: &str = "CHISELSTORE_PROTO_STUBS";
+/// Attribute added to every generated message and enum, e.g. a derive.
+const TYPE_ATTRIBUTE_ENV: &str = "CHISELSTORE_PROTO_TYPE_ATTRIBUTE";
+
 fn main() -> std::io::Result<()> {
     let proto = "proto/proto.proto";
-    tonic_build::compile_protos(proto)?;
     println!("cargo:rerun-if-changed={}", proto);
+    println!("cargo:rerun-if-env-changed={}", STUBS_ENV);
+    println!("cargo:rerun-if-env-changed={}", TYPE_ATTRIBUTE_ENV);
+
+    // Downstream build scripts find the proto files in
+    // `DEP_CHISELSTORE_PROTO_DIR`, to generate their own code from them.
+    let proto_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("proto");
+    println!("cargo:proto_dir={}", proto_dir.display());
+
+    if let Ok(stubs) = std::env::var(STUBS_ENV) {
+        let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+        let stubs = PathBuf::from(stubs).join("proto.rs");
+        println!("cargo:rerun-if-changed={}", stubs.display());
+        std::fs::copy(&stubs, out_dir.join("proto.rs"))?;
+        return Ok(());
+    }
+
+    use_vendored_protoc()?;
+
+    // Only the client is generated for wasm32, where the replica isn't built.
+    let wasm = std::env::var("CARGO_CFG_TARGET_ARCH")
+        .map(|arch| arch == "wasm32")
+        .unwrap_or(false);
+    let mut builder = tonic_build::configure().build_server(!wasm);
+    if let Ok(attribute) = std::env::var(TYPE_ATTRIBUTE_ENV) {
+        builder = builder.type_attribute(".", attribute);
+    }
+    builder.compile(&[proto], &["proto"])
+}
+
+/// Points prost at the bundled protoc unless `PROTOC` is set.
+#[cfg(feature = "vendored-protoc")]
+fn use_vendored_protoc() -> std::io::Result<()> {
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e.to_string()))?;
+        std::env::set_var("PROTOC", protoc);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "vendored-protoc"))]
+fn use_vendored_protoc() -> std::io::Result<()> {
     Ok(())
 }
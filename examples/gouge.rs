@@ -5,7 +5,7 @@ pub mod proto {
     tonic::include_proto!("proto");
 }
 
-use proto::rpc_client::RpcClient;
+use proto::rpc_v2_client::RpcV2Client;
 use proto::{Consistency, Query};
 
 #[tokio::main]
@@ -17,10 +17,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     std::io::stdout().flush().unwrap();
     while let Some(line) = lines.next_line().await? {
         let addr = "http://127.0.0.1:50001";
-        let mut client = RpcClient::connect(addr).await?;
+        let mut client = RpcV2Client::connect(addr).await?;
         let query = tonic::Request::new(Query {
             sql: line.to_string(),
             consistency: Consistency::Strong as i32,
+            ..Default::default()
         });
         let response = client.execute(query).await?;
         let response = response.into_inner();
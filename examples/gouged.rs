@@ -1,10 +1,15 @@
 use anyhow::Result;
+use chiselstore::advertise::{DnsZoneRegistry, LeaderRegistry};
+use chiselstore::rpc::proto::ble_server::BleServer;
 use chiselstore::rpc::proto::rpc_server::RpcServer;
+use chiselstore::rpc::proto::rpc_v2_server::RpcV2Server;
 use chiselstore::{
-    rpc::{RpcService, RpcTransport},
-    StoreServer,
+    rpc::{BleService, RpcService, RpcTransport, RpcV2Service},
+    ServerConfig, StoreConfig, StoreServer,
 };
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 use tonic::transport::Server;
 
@@ -17,8 +22,29 @@ struct Opt {
     /// The IDs of peers.
     #[structopt(short, long, required = false)]
     peers: Vec<usize>,
+    /// Start even if the peers differ from the membership recorded on first
+    /// boot, replacing the record.
+    #[structopt(long)]
+    override_membership: bool,
+    /// Number of acceptor sockets sharing the listen port (SO_REUSEPORT).
+    #[structopt(long, default_value = "1")]
+    acceptors: usize,
+    /// Zone file to publish this node to as a DNS SRV record whenever it
+    /// becomes the leader.
+    #[structopt(long)]
+    leader_zone_file: Option<PathBuf>,
+    /// Service name of the leader's DNS records.
+    #[structopt(long, default_value = "_chiselstore-leader._tcp.local.")]
+    leader_service: String,
+    /// Origins allowed to make gRPC-Web requests (none if none are given).
+    #[cfg(feature = "grpc-web")]
+    #[structopt(long, required = false)]
+    cors_origin: Vec<String>,
 }
 
+/// How long to wait for in-flight requests when shutting down.
+const DRAIN_DEADLINE: Duration = Duration::from_secs(10);
+
 /// Node authority (host and port) in the cluster.
 fn node_authority(id: usize) -> (&'static str, u16) {
     let host = "127.0.0.1";
@@ -43,8 +69,23 @@ async fn main() -> Result<()> {
         .collect();
     let (host, port) = node_authority(opt.id);
     let rpc_listen_addr = format!("{}:{}", host, port).parse().unwrap();
+    let server_config = ServerConfig {
+        acceptors: opt.acceptors,
+        reuse_port: opt.acceptors > 1,
+        ..Default::default()
+    };
+    let incoming = server_config.incoming(rpc_listen_addr)?;
     let transport = RpcTransport::new(Box::new(node_rpc_addr));
-    let server = StoreServer::start(opt.id as u64, peers, transport).unwrap();
+    let leader_registry = opt.leader_zone_file.clone().map(|path| {
+        let registry = DnsZoneRegistry::new(path, &opt.leader_service, host, port);
+        Arc::new(registry) as Arc<dyn LeaderRegistry>
+    });
+    let config = StoreConfig {
+        override_membership: opt.override_membership,
+        leader_registry,
+        ..Default::default()
+    };
+    let server = StoreServer::start_with_config(opt.id as u64, peers, transport, config)?;
     let server = Arc::new(server);
 
     let m = {
@@ -61,13 +102,41 @@ async fn main() -> Result<()> {
         })
     };
 
-    let rpc = RpcService::new(server);
+    let ble = BleServer::new(BleService::new(server.clone()));
+    let rpc = RpcService::new(server.clone());
+    let rpc_v2 = RpcV2Service::new(server.clone());
+    // Only the client-facing service is reachable from browsers; peers
+    // exchange Paxos messages over `RpcServer`.
+    #[cfg(feature = "grpc-web")]
+    let rpc_v2 = {
+        let cors = chiselstore::rpc::GrpcWebCors {
+            allowed_origins: opt.cors_origin.clone(),
+            ..Default::default()
+        };
+        chiselstore::rpc::grpc_web_config(&cors).enable(RpcV2Server::new(rpc_v2))
+    };
+    #[cfg(not(feature = "grpc-web"))]
+    let rpc_v2 = RpcV2Server::new(rpc_v2);
+    let rpc = RpcServer::new(rpc);
     let g = tokio::task::spawn(async move {
         println!("RPC listening to {} ...", rpc_listen_addr);
+        // On Ctrl-C, finish the requests in flight before closing the
+        // listener and halting the replica.
+        let drain = {
+            let server = server.clone();
+            async move {
+                let _ = tokio::signal::ctrl_c().await;
+                server.drain(DRAIN_DEADLINE).await;
+            }
+        };
         let ret = Server::builder()
-            .add_service(RpcServer::new(rpc))
-            .serve(rpc_listen_addr)
+            .accept_http1(cfg!(feature = "grpc-web"))
+            .add_service(rpc)
+            .add_service(rpc_v2)
+            .add_service(ble)
+            .serve_with_incoming_shutdown(incoming, drain)
             .await;
+        server.halt(true);
         ret
     });
     let results = tokio::try_join!(m, b, g)?;
@@ -0,0 +1,43 @@
+use anyhow::Result;
+use chiselstore::import::import_database;
+use chiselstore::server::database_path;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "import_upstream")]
+struct Opt {
+    /// Path of the upstream ChiselStore or rqlite database.
+    #[structopt(parse(from_os_str))]
+    source: PathBuf,
+    /// Id of the node to seed.
+    #[structopt(long)]
+    id: u64,
+    /// File with the SQL of the upstream log entries not in the database
+    /// yet, one entry per line, in log order.
+    #[structopt(long, parse(from_os_str))]
+    log: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    let log = match &opt.log {
+        Some(path) => std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(String::from)
+            .collect(),
+        None => vec![],
+    };
+    let report = import_database(&opt.source, &database_path(opt.id), &log)?;
+    println!("imported: {}", report.path.display());
+    println!("log entries: {}", report.log_entries);
+    for table in &report.tables {
+        println!(
+            "table {}: {} rows, checksum {:x}",
+            table.table, table.rows, table.checksum
+        );
+    }
+    println!("ok");
+    Ok(())
+}
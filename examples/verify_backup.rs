@@ -0,0 +1,38 @@
+use anyhow::Result;
+use chiselstore::backup::verify_backup;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "verify_backup")]
+struct Opt {
+    /// Path of the backup to verify.
+    #[structopt(parse(from_os_str))]
+    backup: PathBuf,
+    /// Validation query that must return at least one row.
+    #[structopt(long = "check")]
+    checks: Vec<String>,
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    let report = verify_backup(&opt.backup, &opt.checks)?;
+    println!("backup: {}", report.path.display());
+    println!("applied_idx: {}", report.applied_idx);
+    for error in &report.integrity_errors {
+        println!("integrity: {}", error);
+    }
+    for validation in &report.validations {
+        let outcome = match (&validation.error, validation.passed) {
+            (Some(e), _) => format!("error: {}", e),
+            (None, true) => String::from("passed"),
+            (None, false) => String::from("no rows"),
+        };
+        println!("check {}: {}", validation.sql, outcome);
+    }
+    if !report.passed() {
+        anyhow::bail!("backup verification failed");
+    }
+    println!("ok");
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! Advertising the leader to external registries.
+//!
+//! Load balancers and service discovery can route writes to the leader
+//! without client-side logic if something tells them which node leads.
+//! With `StoreConfig::leader_registry`, a node that becomes the leader
+//! publishes itself to the registry. Only the new leader publishes, so a
+//! registry is only ever written by the node it names, and a node cut off
+//! from the cluster cannot overwrite its successor. Each leadership carries
+//! a term that grows with every election, which registries shared by
+//! several nodes can use to ignore late updates.
+//!
+//! Publishing runs on a thread of its own, so a slow registry does not hold
+//! up leader election. `DnsZoneRegistry` publishes DNS records; other
+//! registries, such as Consul's key-value store, implement
+//! `LeaderRegistry`.
+
+use slog::{warn, Logger};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Time to live of the published DNS records, in seconds, unless told
+/// otherwise. Kept short since the leader can change at any time.
+const DNS_TTL: u32 = 5;
+
+/// A node that became the leader.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeaderInfo {
+    /// Id of the leader.
+    pub id: u64,
+    /// Election round the node became the leader in; later leaders have
+    /// higher terms.
+    pub term: u32,
+}
+
+/// An external registry the leader is published to.
+pub trait LeaderRegistry: Send + Sync {
+    /// Publishes that `leader`, the calling node, leads the cluster.
+    fn publish(&self, leader: &LeaderInfo) -> io::Result<()>;
+}
+
+/// Publishes leadership changes to a registry in the background.
+#[derive(Debug)]
+pub(crate) struct LeaderAdvertiser {
+    changes: Mutex<Sender<LeaderInfo>>,
+}
+
+impl LeaderAdvertiser {
+    /// Starts the thread publishing to `registry`; it ends once the
+    /// advertiser is dropped.
+    pub(crate) fn start(registry: Arc<dyn LeaderRegistry>, logger: Logger) -> Self {
+        let (tx, rx) = mpsc::channel::<LeaderInfo>();
+        std::thread::spawn(move || {
+            while let Ok(mut leader) = rx.recv() {
+                // Only the latest change matters.
+                while let Ok(later) = rx.try_recv() {
+                    leader = later;
+                }
+                if let Err(e) = registry.publish(&leader) {
+                    warn!(
+                        logger,
+                        "Failed to publish node {} as leader for term {}: {}",
+                        leader.id,
+                        leader.term,
+                        e
+                    );
+                }
+            }
+        });
+        Self {
+            changes: Mutex::new(tx),
+        }
+    }
+
+    /// Queues `leader` for publishing.
+    pub(crate) fn advertise(&self, leader: LeaderInfo) {
+        let _ = self.changes.lock().unwrap().send(leader);
+    }
+}
+
+/// Publishes the leader as DNS `SRV` and `TXT` records in a zone file, for
+/// a DNS server serving it (e.g. CoreDNS's `file` plugin) to hand out.
+///
+/// The `SRV` record points `service` at the leader's `target` host and
+/// `port`; the `TXT` record carries its id and term. The file is replaced
+/// atomically on every change.
+#[derive(Debug)]
+pub struct DnsZoneRegistry {
+    path: PathBuf,
+    service: String,
+    target: String,
+    port: u16,
+    ttl: u32,
+    /// Term of the records in the file.
+    published_term: Mutex<Option<u32>>,
+}
+
+impl DnsZoneRegistry {
+    /// Creates a registry writing records for `service`, such as
+    /// `_chiselstore-leader._tcp.example.com.`, to the zone file at `path`.
+    /// `target` and `port` are the address of the node the registry
+    /// belongs to.
+    pub fn new<P: Into<PathBuf>, S: ToString, T: ToString>(
+        path: P,
+        service: S,
+        target: T,
+        port: u16,
+    ) -> Self {
+        Self {
+            path: path.into(),
+            service: service.to_string(),
+            target: target.to_string(),
+            port,
+            ttl: DNS_TTL,
+            published_term: Mutex::new(None),
+        }
+    }
+
+    /// Sets the time to live of the records, in seconds.
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The records naming `leader` the leader.
+    pub fn records(&self, leader: &LeaderInfo) -> String {
+        format!(
+            "{service} {ttl} IN SRV 0 0 {port} {target}\n\
+             {service} {ttl} IN TXT \"id={id}\" \"term={term}\"\n",
+            service = self.service,
+            ttl = self.ttl,
+            port = self.port,
+            target = self.target,
+            id = leader.id,
+            term = leader.term,
+        )
+    }
+}
+
+impl LeaderRegistry for DnsZoneRegistry {
+    fn publish(&self, leader: &LeaderInfo) -> io::Result<()> {
+        let mut published_term = self.published_term.lock().unwrap();
+        if matches!(*published_term, Some(term) if term > leader.term) {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(self.records(leader).as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        *published_term = Some(leader.term);
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! Read snapshots for analytical queries.
+//!
+//! A `ReadSnapshot` is a copy of the replica's database taken with
+//! `VACUUM INTO` while the apply path is paused, so it holds exactly the
+//! entries up to `applied_idx`. Long-running queries against it see a stable
+//! view while fresh writes continue to apply to the live database.
+
+use crate::errors::StoreError;
+#[cfg(feature = "arrow-export")]
+use crate::rows;
+#[cfg(feature = "arrow-export")]
+use crate::server::QueryRow;
+use crate::server::{query_connection, QueryResults};
+use derivative::Derivative;
+use sqlite::{Connection, OpenFlags};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A read-only view of the database pinned to an applied index.
+///
+/// The snapshot file is removed when the snapshot is dropped.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct ReadSnapshot {
+    applied_idx: u64,
+    path: PathBuf,
+    #[derivative(Debug = "ignore")]
+    conn: Mutex<Connection>,
+}
+
+impl ReadSnapshot {
+    /// Opens the snapshot file at `path`, taken at `applied_idx`.
+    pub(crate) fn open(path: PathBuf, applied_idx: u64) -> Result<Self, StoreError> {
+        let flags = OpenFlags::new().set_read_only().set_no_mutex();
+        let conn = Connection::open_with_flags(&path, flags)?;
+        Ok(Self {
+            applied_idx,
+            path,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// The number of log entries applied to the snapshot.
+    pub fn applied_idx(&self) -> u64 {
+        self.applied_idx
+    }
+
+    /// Path of the snapshot file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Runs a read-only query against the snapshot.
+    pub fn query<S: AsRef<str>>(&self, sql: S) -> Result<QueryResults, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        query_connection(&conn, sql.as_ref().to_string())
+    }
+
+    /// Runs a read-only query against the snapshot, handing each row to
+    /// `on_row` as it is read instead of collecting them.
+    #[cfg(feature = "arrow-export")]
+    pub(crate) fn for_each_row(
+        &self,
+        sql: String,
+        on_row: impl FnMut(QueryRow) -> bool,
+    ) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        rows::for_each_row(&conn, sql, &[], |_| {}, on_row)
+    }
+}
+
+impl Drop for ReadSnapshot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,83 @@
+//! Read-only archives of finished configurations.
+//!
+//! When a stop sign is decided, every member of the finished configuration,
+//! including the members that retire, copies its database as of the stop
+//! sign before moving on. The copy stays queryable and exportable for
+//! `StoreConfig::archive_retention`, so the data nodes held when they were
+//! removed can be audited before it is deleted.
+
+use crate::analytics::ReadSnapshot;
+use crate::backup;
+use crate::errors::StoreError;
+use crate::server::QueryResults;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The final state of a finished configuration, as copied by this node.
+///
+/// The archive file is removed when the archive expires and the last
+/// reference to it is dropped.
+#[derive(Debug)]
+pub struct ConfigArchive {
+    config_id: u32,
+    members: Vec<u64>,
+    expires_at: Instant,
+    snapshot: ReadSnapshot,
+}
+
+impl ConfigArchive {
+    pub(crate) fn new(
+        config_id: u32,
+        members: Vec<u64>,
+        retention: Duration,
+        snapshot: ReadSnapshot,
+    ) -> Self {
+        Self {
+            config_id,
+            members,
+            expires_at: Instant::now() + retention,
+            snapshot,
+        }
+    }
+
+    /// Id of the finished configuration.
+    pub fn config_id(&self) -> u32 {
+        self.config_id
+    }
+
+    /// Members of the finished configuration.
+    pub fn members(&self) -> &[u64] {
+        &self.members
+    }
+
+    /// The number of log entries applied when the configuration finished.
+    pub fn applied_idx(&self) -> u64 {
+        self.snapshot.applied_idx()
+    }
+
+    /// Path of the archive file.
+    pub(crate) fn path(&self) -> &Path {
+        self.snapshot.path()
+    }
+
+    /// When the archive is deleted.
+    pub fn expires_at(&self) -> Instant {
+        self.expires_at
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+
+    /// Runs a read-only query against the archive.
+    pub fn query<S: AsRef<str>>(&self, sql: S) -> Result<QueryResults, StoreError> {
+        self.snapshot.query(sql)
+    }
+
+    /// Copies the archive to `path` as a backup, which outlives the
+    /// archive and can be checked with `backup::verify_backup`.
+    pub fn export(&self, path: &Path) -> Result<(), StoreError> {
+        std::fs::copy(self.snapshot.path(), path)?;
+        backup::record_applied_idx(path, self.applied_idx())
+    }
+}
@@ -0,0 +1,147 @@
+//! Database backups and backup verification.
+//!
+//! A backup is a copy of a replica's database taken with `VACUUM INTO` while
+//! applying is paused. The applied index it corresponds to is recorded in the
+//! `chiselstore_backup` table of the copy. `verify_backup` restores a backup
+//! into a scratch directory and checks it, so backup pipelines can run
+//! restore drills.
+
+use crate::errors::StoreError;
+use crate::server::query_connection;
+use sqlite::{Connection, OpenFlags};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Table of a backup recording the applied index it corresponds to.
+pub const BACKUP_TABLE: &str = "chiselstore_backup";
+
+static NEXT_DRILL: AtomicU64 = AtomicU64::new(0);
+
+/// Outcome of one validation query run against a restored backup.
+#[derive(Debug, Clone)]
+pub struct ValidationOutcome {
+    /// The validation query.
+    pub sql: String,
+    /// Whether the query succeeded and returned at least one row.
+    pub passed: bool,
+    /// The error, if the query failed.
+    pub error: Option<String>,
+}
+
+/// Result of verifying a backup.
+#[derive(Debug, Clone)]
+pub struct BackupReport {
+    /// Path of the verified backup.
+    pub path: PathBuf,
+    /// The applied index the backup corresponds to.
+    pub applied_idx: u64,
+    /// Problems reported by `PRAGMA integrity_check` and
+    /// `PRAGMA foreign_key_check`; empty for a sound backup.
+    pub integrity_errors: Vec<String>,
+    /// Outcome of each validation query, in order.
+    pub validations: Vec<ValidationOutcome>,
+}
+
+impl BackupReport {
+    /// Returns true if the integrity checks and all validations passed.
+    pub fn passed(&self) -> bool {
+        self.integrity_errors.is_empty() && self.validations.iter().all(|v| v.passed)
+    }
+}
+
+/// Records `applied_idx` in the backup at `path`.
+pub(crate) fn record_applied_idx(path: &Path, applied_idx: u64) -> Result<(), StoreError> {
+    let conn = Connection::open(path)?;
+    conn.execute(format!(
+        "CREATE TABLE {table} (applied_idx INTEGER NOT NULL); \
+         INSERT INTO {table} VALUES ({idx});",
+        table = BACKUP_TABLE,
+        idx = applied_idx
+    ))?;
+    Ok(())
+}
+
+/// Reads the applied index recorded in the backup at `path`, opened as
+/// `conn`.
+pub(crate) fn read_applied_idx(conn: &Connection, path: &Path) -> Result<u64, StoreError> {
+    query_connection(conn, format!("SELECT applied_idx FROM {}", BACKUP_TABLE))?
+        .rows
+        .first()
+        .and_then(|row| row.values.first())
+        .and_then(|idx| idx.parse().ok())
+        .ok_or_else(|| StoreError::InvalidRequest(format!("{} is not a backup", path.display())))
+}
+
+/// Restores the backup at `path` into a scratch directory and verifies it.
+///
+/// The restored copy passes if SQLite's integrity and foreign key checks
+/// report no problems and every query in `validations` succeeds and returns
+/// at least one row. The scratch copy is removed afterwards; the backup
+/// itself is never modified.
+pub fn verify_backup<S: AsRef<str>>(
+    path: &Path,
+    validations: &[S],
+) -> Result<BackupReport, StoreError> {
+    let dir = std::env::temp_dir().join(format!(
+        "chiselstore-drill-{}-{}",
+        std::process::id(),
+        NEXT_DRILL.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::create_dir_all(&dir)?;
+    let report = restore_and_check(path, &dir, validations);
+    let _ = std::fs::remove_dir_all(&dir);
+    report
+}
+
+fn restore_and_check<S: AsRef<str>>(
+    path: &Path,
+    dir: &Path,
+    validations: &[S],
+) -> Result<BackupReport, StoreError> {
+    let restored = dir.join("restored.db");
+    std::fs::copy(path, &restored)?;
+    let flags = OpenFlags::new().set_read_write().set_no_mutex();
+    let conn = Connection::open_with_flags(&restored, flags)?;
+
+    let applied_idx = read_applied_idx(&conn, path)?;
+
+    let mut integrity_errors: Vec<String> =
+        query_connection(&conn, String::from("PRAGMA integrity_check"))?
+            .rows
+            .into_iter()
+            .map(|row| row.values.join(" "))
+            .filter(|msg| msg != "ok")
+            .collect();
+    integrity_errors.extend(
+        query_connection(&conn, String::from("PRAGMA foreign_key_check"))?
+            .rows
+            .into_iter()
+            .map(|row| format!("foreign key violation: {}", row.values.join(" "))),
+    );
+
+    let validations = validations
+        .iter()
+        .map(|sql| {
+            let sql = sql.as_ref().to_string();
+            match query_connection(&conn, sql.clone()) {
+                Ok(results) => ValidationOutcome {
+                    sql,
+                    passed: !results.rows.is_empty(),
+                    error: None,
+                },
+                Err(e) => ValidationOutcome {
+                    sql,
+                    passed: false,
+                    error: Some(e.to_string()),
+                },
+            }
+        })
+        .collect();
+
+    Ok(BackupReport {
+        path: path.to_path_buf(),
+        applied_idx,
+        integrity_errors,
+        validations,
+    })
+}
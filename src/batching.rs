@@ -0,0 +1,201 @@
+//! Adaptive batching.
+//!
+//! The message event loop of a replica hands everything produced since its
+//! last pass to the transport, and applies the entries decided since then,
+//! once per batching window. A longer window coalesces more proposals into
+//! each replication message and apply pass, raising throughput at the cost
+//! of commit latency.
+//!
+//! Without a `LatencySlo` the window stays at its minimum. With one, the
+//! replica tracks the commit latency of the proposals it makes and adapts
+//! the window to it: the window is halved while the target percentile is
+//! above the target, and grows by `WINDOW_STEP` while it is well below.
+//!
+//! Proposals coalesced in a window stay separate log entries, each applied
+//! and answered on its own, but travel to the followers together in one
+//! pipelined replication message. Once `StoreConfig::max_batch_size`
+//! proposals of a replica are waiting, the window ends early, so bursts of
+//! writes do not wait for a long window to pass and replication messages
+//! stay bounded.
+//!
+//! The current window and latencies are exposed by
+//! `StoreServer::batching` and the `chiselstore_batching` introspection
+//! table.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Shortest batching window, used when no latency SLO is set.
+pub const MIN_WINDOW: Duration = Duration::from_millis(1);
+/// How much the window grows at once while latency is within the SLO.
+const WINDOW_STEP: Duration = Duration::from_millis(1);
+/// Number of recent commit latencies kept.
+const SAMPLES: usize = 1000;
+/// Number of new commit latencies needed before adapting the window again.
+const MIN_FRESH_SAMPLES: usize = 20;
+/// The window only grows while the target percentile is below this share
+/// of the target, so it does not oscillate around it.
+const HEADROOM: f64 = 0.8;
+
+/// Commit latency objective the batching window adapts to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatencySlo {
+    /// Commit latency to stay within.
+    pub target: Duration,
+    /// Percentile of the commit latencies held to `target`, from 0 to 1.
+    pub percentile: f64,
+    /// Longest batching window to grow to.
+    pub max_window: Duration,
+}
+
+impl Default for LatencySlo {
+    fn default() -> Self {
+        Self {
+            target: Duration::from_millis(50),
+            percentile: 0.99,
+            max_window: Duration::from_millis(20),
+        }
+    }
+}
+
+/// Batching window and commit latencies of a replica.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchingStatus {
+    /// Current batching window.
+    pub window: Duration,
+    /// Target of the latency SLO, if one is set.
+    pub target: Option<Duration>,
+    /// Median of the recent commit latencies.
+    pub p50: Duration,
+    /// 99th percentile of the recent commit latencies.
+    pub p99: Duration,
+    /// Number of recent commit latencies.
+    pub samples: usize,
+    /// Number of proposals that end a window early.
+    pub max_batch_size: usize,
+    /// Number of windows ended early by a full batch.
+    pub full_batches: u64,
+}
+
+/// Adapts the batching window of a replica to its latency SLO.
+#[derive(Debug)]
+pub(crate) struct AdaptiveBatching {
+    slo: Option<LatencySlo>,
+    window: Duration,
+    latencies: VecDeque<Duration>,
+    /// Latencies recorded since the window last changed.
+    fresh: usize,
+    max_batch_size: usize,
+    /// Proposals made in the current window.
+    proposals: usize,
+    full_batches: u64,
+}
+
+impl AdaptiveBatching {
+    pub(crate) fn new(slo: Option<LatencySlo>, max_batch_size: usize) -> Self {
+        Self {
+            slo,
+            window: MIN_WINDOW,
+            latencies: VecDeque::with_capacity(SAMPLES),
+            fresh: 0,
+            max_batch_size: max_batch_size.max(1),
+            proposals: 0,
+            full_batches: 0,
+        }
+    }
+
+    /// Records a proposal made in the current window, returning true if it
+    /// fills the batch.
+    pub(crate) fn proposed(&mut self) -> bool {
+        self.proposals += 1;
+        self.proposals == self.max_batch_size
+    }
+
+    /// Returns true if the current window has a full batch of proposals.
+    pub(crate) fn is_full(&self) -> bool {
+        self.proposals >= self.max_batch_size
+    }
+
+    /// Ends the current window.
+    pub(crate) fn end_window(&mut self) {
+        if self.is_full() {
+            self.full_batches += 1;
+        }
+        self.proposals = 0;
+    }
+
+    /// Replaces the latency SLO; the window adapts to it from where it is,
+    /// or goes back to the shortest one without an SLO.
+    pub(crate) fn set_slo(&mut self, slo: Option<LatencySlo>) {
+        if slo.is_none() {
+            self.window = MIN_WINDOW;
+        }
+        self.slo = slo;
+        self.fresh = 0;
+    }
+
+    /// Returns the current batching window.
+    pub(crate) fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Records the commit latency of a proposal.
+    pub(crate) fn record(&mut self, latency: Duration) {
+        if self.latencies.len() == SAMPLES {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+        self.fresh = (self.fresh + 1).min(SAMPLES);
+    }
+
+    /// Adapts the window to the latencies recorded since it last changed,
+    /// returning true if it changed.
+    pub(crate) fn adjust(&mut self) -> bool {
+        let slo = match &self.slo {
+            Some(slo) if self.fresh >= MIN_FRESH_SAMPLES => slo,
+            _ => return false,
+        };
+        let fresh = self
+            .latencies
+            .iter()
+            .skip(self.latencies.len() - self.fresh);
+        let latency = percentile(fresh.copied().collect(), slo.percentile);
+        let window = if latency > slo.target {
+            (self.window / 2).max(MIN_WINDOW)
+        } else if latency.as_secs_f64() < slo.target.as_secs_f64() * HEADROOM {
+            (self.window + WINDOW_STEP).min(slo.max_window.max(MIN_WINDOW))
+        } else {
+            self.window
+        };
+        if window == self.window {
+            return false;
+        }
+        self.window = window;
+        self.fresh = 0;
+        true
+    }
+
+    /// Returns the window and recent commit latencies.
+    pub(crate) fn status(&self) -> BatchingStatus {
+        let latencies: Vec<_> = self.latencies.iter().copied().collect();
+        BatchingStatus {
+            window: self.window,
+            target: self.slo.as_ref().map(|slo| slo.target),
+            p50: percentile(latencies.clone(), 0.5),
+            p99: percentile(latencies, 0.99),
+            samples: self.latencies.len(),
+            max_batch_size: self.max_batch_size,
+            full_batches: self.full_batches,
+        }
+    }
+}
+
+/// Returns the `p` percentile of `latencies`, or zero if there are none.
+fn percentile(mut latencies: Vec<Duration>, p: f64) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    latencies.sort_unstable();
+    let rank = ((latencies.len() - 1) as f64 * p.clamp(0.0, 1.0)).round() as usize;
+    latencies[rank]
+}
@@ -0,0 +1,76 @@
+//! Bootstrapping nodes added by a reconfiguration.
+//!
+//! The members of a new configuration continue on the SQLite state they
+//! had when the stop sign was decided, while the log of the new
+//! configuration starts empty. A node the reconfiguration added has no such
+//! state, so before it first starts, `join_cluster` asks a member of the new
+//! configuration for the database as of the stop sign, read from that
+//! member's archive of the finished configuration, and writes it as the
+//! node's database. The node then starts with the returned
+//! `JoinedConfiguration` in `StoreConfig::join`, in the new configuration at
+//! the applied index of the stop sign, and the leader syncs the log of the
+//! new configuration to it like to any lagging follower.
+//!
+//! Members only keep archives for `StoreConfig::archive_retention`, so a
+//! node must join before they expire.
+
+use crate::errors::StoreError;
+use crate::proto::rpc_client::RpcClient;
+use crate::proto::JoinRequest;
+use crate::server::database_path;
+
+/// The database a node added by a reconfiguration starts from.
+#[derive(Clone, Debug)]
+pub struct JoinSnapshot {
+    /// Id of the configuration that added the node.
+    pub config_id: u32,
+    /// Members of the configuration, the node included.
+    pub members: Vec<u64>,
+    /// Number of log entries applied when the previous configuration
+    /// finished.
+    pub applied_idx: u64,
+    /// Contents of the database as of the stop sign.
+    pub database: Vec<u8>,
+}
+
+/// Where a node bootstrapped with `join_cluster` starts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JoinedConfiguration {
+    /// Id of the configuration that added the node.
+    pub config_id: u32,
+    /// Members of the configuration, the node included.
+    pub members: Vec<u64>,
+    /// Number of log entries applied in its database.
+    pub applied_idx: u64,
+}
+
+/// Writes the database node `id` starts from in the configuration that
+/// added it, fetched from the member at `source`.
+///
+/// Fails without writing anything if the node has a database already.
+pub async fn join_cluster(id: u64, source: &str) -> Result<JoinedConfiguration, StoreError> {
+    let path = database_path(id);
+    if path.exists() {
+        return Err(StoreError::InvalidRequest(format!(
+            "{} already exists; only new nodes can be bootstrapped",
+            path.display()
+        )));
+    }
+    let mut client = RpcClient::connect(source.to_string())
+        .await
+        .map_err(|e| StoreError::Bootstrap(e.to_string()))?;
+    let snapshot = client
+        .join(JoinRequest { node_id: id })
+        .await
+        .map_err(|status| StoreError::Bootstrap(status.message().to_string()))?
+        .into_inner();
+
+    let tmp = path.with_extension("db.join");
+    std::fs::write(&tmp, &snapshot.database)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(JoinedConfiguration {
+        config_id: snapshot.config_id,
+        members: snapshot.members,
+        applied_idx: snapshot.applied_idx,
+    })
+}
@@ -0,0 +1,28 @@
+//! Checksums of query responses.
+//!
+//! A client can ask for a checksum of the result rows; the node computes it
+//! over the rows it serializes and the client recomputes it over the rows
+//! it decoded, catching responses corrupted on the way.
+
+use crate::proto::QueryRow;
+use prost::Message;
+
+/// FNV-1a hash of `bytes`, which is stable across builds and targets.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Checksum of `rows` as serialized in a `QueryResults` response.
+pub fn rows_checksum(rows: &[QueryRow]) -> u64 {
+    let mut buf = Vec::new();
+    for row in rows {
+        row.encode_length_delimited(&mut buf)
+            .expect("a Vec has unlimited capacity");
+    }
+    fnv1a(&buf)
+}
@@ -0,0 +1,1096 @@
+//! ChiselStore client module.
+
+//!
+//! The client is generic over the gRPC transport. On native targets it
+//! defaults to a tonic `Channel`; on `wasm32-unknown-unknown` it is built
+//! with `ChiselStoreClient::with_transport` around a gRPC-Web transport
+//! (for example one backed by the browser's `fetch`), talking to a node
+//! serving the `grpc-web` feature.
+//!
+//! The client speaks the `RpcV2` service, so nodes must serve it.
+//!
+//! A client can talk to several nodes of a cluster; each request goes to the
+//! healthiest node and fails over to the others if it is unreachable.
+//!
+//! With `with_checksums`, the client asks nodes for a checksum of each
+//! result set and rejects responses whose rows do not match it.
+//!
+//! With `with_retry_budget`, failing over is held to a budget and to
+//! requests that are safe to send twice; see `retry`. With `with_hedging`,
+//! slow reads are also sent to a second node.
+
+use crate::checksum;
+use crate::errors::ClientError;
+use crate::nodes::{self, NodeHealth, NodePool};
+use crate::outbox::outbox_statement;
+use crate::proto::rpc_v2_client::RpcV2Client;
+use crate::proto::{
+    batch_result, BatchResult, Capabilities, Consistency, CursorPage, CursorRequest,
+    ExecutePrepared, Leader, PageRequest, PragmaSetting, PrepareStatement, Query, QueryBatch,
+    QueryResults, ResultPage, TenantChange, TenantChecksum, TenantExport, TenantImport,
+    TenantRequest, Transaction, Void, WaitForIndex,
+};
+use crate::retry::{RetryBudget, RetryTracker};
+use crate::statements;
+use crate::value::Value;
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::time::Duration;
+use tonic::body::BoxBody;
+use tonic::client::GrpcService;
+use tonic::codegen::{Body, StdError};
+use tonic::{Code, Streaming};
+
+#[cfg(not(target_arch = "wasm32"))]
+use tonic::transport::{Channel, Endpoint};
+
+/// Client for executing queries against a ChiselStore node.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct ChiselStoreClient<T = Channel> {
+    nodes: NodePool<RpcV2Client<T>>,
+    verify_checksums: bool,
+    read_proofs: bool,
+    offline_queue: Option<OfflineQueue>,
+    key_prefix: String,
+    next_key: u64,
+    tenant: Option<String>,
+    session: Option<u64>,
+    retries: Option<RetryTracker>,
+    hedge_after: Option<Duration>,
+}
+
+/// Client for executing queries against a ChiselStore node.
+#[cfg(target_arch = "wasm32")]
+#[derive(Debug)]
+pub struct ChiselStoreClient<T> {
+    nodes: NodePool<RpcV2Client<T>>,
+    verify_checksums: bool,
+    read_proofs: bool,
+    offline_queue: Option<OfflineQueue>,
+    key_prefix: String,
+    next_key: u64,
+    tenant: Option<String>,
+    session: Option<u64>,
+    retries: Option<RetryTracker>,
+}
+
+/// Outcome of a write submitted with `execute_or_queue`.
+#[derive(Debug)]
+pub enum WriteOutcome {
+    /// The cluster executed the write.
+    Executed(QueryResults),
+    /// The cluster was unreachable and the write was queued for replay.
+    Queued {
+        /// Idempotency key of the queued write.
+        key: String,
+    },
+}
+
+/// Outcome of one queued write during `replay`.
+#[derive(Debug)]
+pub struct ReplayOutcome {
+    /// Idempotency key of the write.
+    pub key: String,
+    /// The SQL statement of the write.
+    pub sql: String,
+    /// What the cluster returned for the write.
+    pub result: Result<QueryResults, ClientError>,
+}
+
+/// A cursor opened with `ChiselStoreClient::open_cursor`, on the node
+/// that opened it.
+#[derive(Debug)]
+pub struct Cursor {
+    node: usize,
+    id: u64,
+    done: bool,
+}
+
+impl Cursor {
+    /// Id of the cursor on its node.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// True once the last page was fetched.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+#[derive(Debug)]
+struct QueuedWrite {
+    key: String,
+    sql: String,
+}
+
+/// Bounded queue of writes buffered while the cluster is unreachable.
+#[derive(Debug)]
+struct OfflineQueue {
+    capacity: usize,
+    writes: VecDeque<QueuedWrite>,
+    keys: HashSet<String>,
+}
+
+impl OfflineQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            writes: VecDeque::new(),
+            keys: HashSet::new(),
+        }
+    }
+
+    fn push(&mut self, key: String, sql: String) -> Result<(), ClientError> {
+        // Re-submitting a write that is already queued is a no-op.
+        if self.keys.contains(&key) {
+            return Ok(());
+        }
+        if self.writes.len() >= self.capacity {
+            return Err(ClientError::QueueFull(self.capacity));
+        }
+        self.keys.insert(key.clone());
+        self.writes.push_back(QueuedWrite { key, sql });
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<QueuedWrite> {
+        let write = self.writes.pop_front()?;
+        self.keys.remove(&write.key);
+        Some(write)
+    }
+
+    fn push_front(&mut self, write: QueuedWrite) {
+        self.keys.insert(write.key.clone());
+        self.writes.push_front(write);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ChiselStoreClient<Channel> {
+    /// Creates a client for the node at `addr`.
+    ///
+    /// The connection is established lazily on the first query, and
+    /// re-established after the node becomes unreachable.
+    pub fn new<S: ToString>(addr: S) -> Result<Self, ClientError> {
+        let addr = addr.to_string();
+        let channel = Endpoint::from_shared(addr.clone())?.connect_lazy()?;
+        Ok(Self::with_transport(channel, addr))
+    }
+
+    /// Creates a client and connects to the node at `addr`.
+    pub async fn connect<S: ToString>(addr: S) -> Result<Self, ClientError> {
+        let addr = addr.to_string();
+        let channel = Endpoint::from_shared(addr.clone())?.connect().await?;
+        Ok(Self::with_transport(channel, addr))
+    }
+
+    /// Creates a client for the cluster nodes at `addrs`, connecting to each
+    /// lazily. Requests go to the healthiest node.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `addrs` is empty.
+    pub fn with_nodes<I, S>(addrs: I) -> Result<Self, ClientError>
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        let mut addrs = addrs.into_iter().map(|addr| addr.to_string());
+        let first = addrs.next().expect("a client needs at least one node");
+        let mut client = Self::new(first)?;
+        for addr in addrs {
+            let channel = Endpoint::from_shared(addr.clone())?.connect_lazy()?;
+            client = client.with_node(channel, addr);
+        }
+        Ok(client)
+    }
+}
+
+impl<T> ChiselStoreClient<T>
+where
+    T: GrpcService<BoxBody> + Clone,
+    T::ResponseBody: Body + Send + Sync + 'static,
+    T::Error: Into<StdError>,
+    <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+{
+    /// Creates a client on top of an existing gRPC transport, which is
+    /// cloned for every request.
+    ///
+    /// `name` prefixes the idempotency keys the client generates.
+    pub fn with_transport<S: ToString>(transport: T, name: S) -> Self {
+        let mut nodes = NodePool::new();
+        nodes.add(name.to_string(), RpcV2Client::new(transport));
+        Self {
+            nodes,
+            verify_checksums: false,
+            read_proofs: false,
+            offline_queue: None,
+            key_prefix: name.to_string(),
+            next_key: 1,
+            tenant: None,
+            session: None,
+            retries: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            hedge_after: None,
+        }
+    }
+
+    /// Adds another node of the cluster, reached over `transport`.
+    pub fn with_node<S: ToString>(mut self, transport: T, name: S) -> Self {
+        self.nodes
+            .add(name.to_string(), RpcV2Client::new(transport));
+        self
+    }
+
+    /// Returns the health of each node, in the order they were added.
+    pub fn node_health(&self) -> Vec<NodeHealth> {
+        self.nodes.health()
+    }
+
+    /// Returns the capabilities the healthiest reachable node advertises,
+    /// including the fingerprint of its custom SQL functions.
+    pub async fn capabilities(&mut self) -> Result<Capabilities, ClientError> {
+        let (_, capabilities) = self
+            .with_failover(Void {}, None, |mut conn, request| async move {
+                conn.get_capabilities(request).await
+            })
+            .await?;
+        Ok(capabilities)
+    }
+
+    /// Returns the leader of the cluster as the healthiest reachable node
+    /// knows it; its `id` is 0 if the node knows none.
+    pub async fn leader(&mut self) -> Result<Leader, ClientError> {
+        let (_, leader) = self
+            .with_failover(Void {}, None, |mut conn, request| async move {
+                conn.get_leader(request).await
+            })
+            .await?;
+        Ok(leader)
+    }
+
+    /// Asks nodes for a checksum of every result set and verifies it,
+    /// failing queries with `ClientError::ChecksumMismatch` on a mismatch.
+    pub fn with_checksums(mut self) -> Self {
+        self.verify_checksums = true;
+        self
+    }
+
+    /// Asks nodes for a `ReadProof` of where each strong query was
+    /// linearized, returned in `QueryResults::proof`.
+    pub fn with_read_proofs(mut self) -> Self {
+        self.read_proofs = true;
+        self
+    }
+
+    /// Runs queries against the database of `tenant` instead of the shared
+    /// one. Tenant queries cannot be streamed, read through cursors or
+    /// conditional.
+    pub fn with_tenant<S: ToString>(mut self, tenant: S) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    /// Makes every query see the writes made earlier through this client,
+    /// on whichever node serves it: the client passes the applied index of
+    /// its latest write as a session token, which nodes wait for.
+    pub fn with_session(self) -> Self {
+        self.with_session_token(0)
+    }
+
+    /// Like `with_session`, continuing the session of `token`, e.g. another
+    /// client's `session_token`.
+    pub fn with_session_token(mut self, token: u64) -> Self {
+        self.session = Some(token);
+        self
+    }
+
+    /// Returns the session token, if the client keeps a session.
+    pub fn session_token(&self) -> Option<u64> {
+        self.session
+    }
+
+    /// Advances the session past the write that produced `results`.
+    fn observe(&mut self, results: &QueryResults) {
+        if let (Some(session), Some(idx)) = (self.session.as_mut(), results.commit_idx) {
+            *session = (*session).max(idx);
+        }
+    }
+
+    /// Holds sending requests again to other nodes to `budget`, and to
+    /// requests that are safe to send twice, which only reads are. Without
+    /// a budget, every request fails over while nodes are unreachable.
+    ///
+    /// Writes are never retried under a budget, even with an idempotency
+    /// key: the cluster does not deduplicate writes by key, so a write sent
+    /// again after a timeout may be applied twice.
+    pub fn with_retry_budget(mut self, budget: RetryBudget) -> Self {
+        self.retries = Some(RetryTracker::new(budget));
+        self
+    }
+
+    /// Sends a read to the next healthiest node as well when the first one
+    /// has not answered within `delay`, taking whichever answer comes
+    /// first. Hedged reads count against the retry budget.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_hedging(mut self, delay: Duration) -> Self {
+        self.hedge_after = Some(delay);
+        self
+    }
+
+    /// Records a request sent for the first time against the retry budget.
+    fn start_request(&mut self) {
+        if let Some(retries) = self.retries.as_mut() {
+            retries.record_request();
+        }
+    }
+
+    /// Returns true if a request that is `safe` to send twice or not may be
+    /// sent again to another node.
+    fn may_retry(&mut self, safe: bool) -> bool {
+        match self.retries.as_mut() {
+            Some(retries) => safe && retries.try_retry(),
+            None => true,
+        }
+    }
+
+    /// Sends `request` with `send` to the healthiest node, failing over to
+    /// the next healthiest one while nodes are unreachable, and returns the
+    /// answer along with the node it came from.
+    ///
+    /// Requests with a `safe` flag, telling whether they may be sent twice,
+    /// count against the retry budget; requests without one always fail
+    /// over.
+    async fn with_failover<Req, R, F, Fut>(
+        &mut self,
+        request: Req,
+        safe: Option<bool>,
+        mut send: F,
+    ) -> Result<(usize, R), ClientError>
+    where
+        Req: Clone,
+        F: FnMut(RpcV2Client<T>, Req) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<R>, tonic::Status>>,
+    {
+        if safe.is_some() {
+            self.start_request();
+        }
+        let mut unreachable = None;
+        for (attempt, idx) in self.nodes.candidates().into_iter().enumerate() {
+            if attempt > 0 && !safe.map_or(true, |safe| self.may_retry(safe)) {
+                break;
+            }
+            let started = nodes::now();
+            match send(self.nodes.conn(idx).clone(), request.clone()).await {
+                Ok(response) => {
+                    self.nodes.record_success(idx, started);
+                    return Ok((idx, response.into_inner()));
+                }
+                Err(status) => {
+                    let e = ClientError::from(status);
+                    if !e.is_unreachable() {
+                        self.nodes.record_success(idx, started);
+                        return Err(e);
+                    }
+                    self.nodes.record_failure(idx);
+                    unreachable = Some(e);
+                }
+            }
+        }
+        Err(unreachable.unwrap())
+    }
+
+    /// Verifies the checksum of `results`, if the client asks for them.
+    fn verify(&self, results: &QueryResults) -> Result<(), ClientError> {
+        if self.verify_checksums && results.checksum != Some(checksum::rows_checksum(&results.rows))
+        {
+            return Err(ClientError::ChecksumMismatch);
+        }
+        Ok(())
+    }
+
+    /// Enables buffering of up to `capacity` writes while the cluster is
+    /// unreachable.
+    pub fn with_offline_queue(mut self, capacity: usize) -> Self {
+        self.offline_queue = Some(OfflineQueue::new(capacity));
+        self
+    }
+
+    /// Number of writes waiting for replay.
+    pub fn queued_writes(&self) -> usize {
+        self.offline_queue
+            .as_ref()
+            .map(|queue| queue.writes.len())
+            .unwrap_or(0)
+    }
+
+    /// Executes a statement with the given consistency, failing over to the
+    /// next healthiest node while nodes are unreachable.
+    pub async fn query<S: ToString>(
+        &mut self,
+        sql: S,
+        consistency: Consistency,
+    ) -> Result<QueryResults, ClientError> {
+        self.query_with_params(sql, vec![], consistency).await
+    }
+
+    /// Executes a statement with `params` bound to its placeholders, instead
+    /// of interpolating the values into the SQL.
+    pub async fn query_with_params<S: ToString>(
+        &mut self,
+        sql: S,
+        params: Vec<Value>,
+        consistency: Consistency,
+    ) -> Result<QueryResults, ClientError> {
+        let query = self.new_query(sql.to_string(), params, consistency);
+        let safe = !statements::is_write(&query.sql);
+        self.execute(query, safe).await
+    }
+
+    /// Executes a statement with `params` bound, returning every row as a
+    /// single `json` column holding an object keyed by column name; see
+    /// `json`.
+    pub async fn query_json_rows<S: ToString>(
+        &mut self,
+        sql: S,
+        params: Vec<Value>,
+        consistency: Consistency,
+    ) -> Result<QueryResults, ClientError> {
+        let mut query = self.new_query(sql.to_string(), params, consistency);
+        query.json_rows = true;
+        let safe = !statements::is_write(&query.sql);
+        self.execute(query, safe).await
+    }
+
+    /// Executes a statement through the replicated log with `pragmas` set
+    /// while every node applies it, e.g. to defer foreign key checks. Only
+    /// some pragmas can be set; see `pragmas`.
+    pub async fn query_with_pragmas<S: ToString>(
+        &mut self,
+        sql: S,
+        params: Vec<Value>,
+        pragmas: Vec<PragmaSetting>,
+    ) -> Result<QueryResults, ClientError> {
+        let mut query = self.new_query(sql.to_string(), params, Consistency::Strong);
+        query.pragmas = pragmas;
+        let safe = !statements::is_write(&query.sql);
+        self.execute(query, safe).await
+    }
+
+    fn new_query(&self, sql: String, params: Vec<Value>, consistency: Consistency) -> Query {
+        Query {
+            sql,
+            params: params.into_iter().map(Into::into).collect(),
+            consistency: consistency as i32,
+            checksum: self.verify_checksums,
+            proof: self.read_proofs,
+            tenant: self.tenant.clone(),
+            after_idx: self.session.unwrap_or(0),
+            ..Default::default()
+        }
+    }
+
+    /// Executes `query`, failing over to the next healthiest node while
+    /// nodes are unreachable and the retry budget allows; `safe` tells
+    /// whether the query may be sent twice.
+    async fn execute(&mut self, query: Query, safe: bool) -> Result<QueryResults, ClientError> {
+        self.start_request();
+        let candidates = self.nodes.candidates();
+        let mut tried = vec![];
+        let mut unreachable = None;
+        for idx in candidates.iter().copied() {
+            if tried.contains(&idx) {
+                continue;
+            }
+            if !tried.is_empty() && !self.may_retry(safe) {
+                break;
+            }
+            tried.push(idx);
+            let started = nodes::now();
+            #[cfg(not(target_arch = "wasm32"))]
+            let (idx, result) = match (tried.len(), self.hedge_after, candidates.get(1)) {
+                (1, Some(delay), Some(&next)) if safe => {
+                    let (idx, result, hedged) = self.hedged_execute(idx, next, delay, &query).await;
+                    tried.extend(hedged);
+                    (idx, result)
+                }
+                _ => (idx, self.nodes.conn(idx).execute(query.clone()).await),
+            };
+            #[cfg(target_arch = "wasm32")]
+            let result = self.nodes.conn(idx).execute(query.clone()).await;
+            match result {
+                Ok(response) => {
+                    self.nodes.record_success(idx, started);
+                    let results = response.into_inner();
+                    self.verify(&results)?;
+                    self.observe(&results);
+                    return Ok(results);
+                }
+                Err(status) => {
+                    let e = ClientError::from(status);
+                    if !e.is_unreachable() {
+                        self.nodes.record_success(idx, started);
+                        return Err(e);
+                    }
+                    self.nodes.record_failure(idx);
+                    unreachable = Some(e);
+                }
+            }
+        }
+        Err(unreachable.unwrap())
+    }
+
+    /// Sends `query` to node `primary`, and to node `secondary` as well if
+    /// `primary` has not answered within `delay` and the retry budget has
+    /// room. Returns the first answer and the node it came from, along with
+    /// the other node the query went to, if any.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn hedged_execute(
+        &mut self,
+        primary: usize,
+        secondary: usize,
+        delay: Duration,
+        query: &Query,
+    ) -> (
+        usize,
+        Result<tonic::Response<QueryResults>, tonic::Status>,
+        Option<usize>,
+    ) {
+        let (first, second) = self.nodes.conn_pair(primary, secondary);
+        let first = first.execute(query.clone());
+        tokio::pin!(first);
+        tokio::select! {
+            result = &mut first => return (primary, result, None),
+            _ = tokio::time::sleep(delay) => {}
+        }
+        if !self.retries.as_mut().map_or(true, RetryTracker::try_retry) {
+            return (primary, first.await, None);
+        }
+        let second = second.execute(query.clone());
+        tokio::pin!(second);
+        tokio::select! {
+            result = &mut first => (primary, result, Some(secondary)),
+            result = &mut second => (secondary, result, Some(primary)),
+        }
+    }
+
+    /// Executes `statements` in order as one atomic write: if any of them
+    /// fails outside a savepoint, none take effect. Savepoints rolled back
+    /// because a statement inside them failed are listed in the results.
+    pub async fn transaction<S: ToString>(
+        &mut self,
+        statements: &[S],
+    ) -> Result<QueryResults, ClientError> {
+        self.transaction_with_pragmas(statements, vec![]).await
+    }
+
+    /// Executes `statements` as one transaction, like `transaction`, with
+    /// `pragmas` set while every node applies it.
+    pub async fn transaction_with_pragmas<S: ToString>(
+        &mut self,
+        statements: &[S],
+        pragmas: Vec<PragmaSetting>,
+    ) -> Result<QueryResults, ClientError> {
+        let transaction = Transaction {
+            statements: statements.iter().map(ToString::to_string).collect(),
+            checksum: self.verify_checksums,
+            proof: self.read_proofs,
+            pragmas,
+        };
+        let (_, results) = self
+            .with_failover(transaction, Some(false), |mut conn, request| async move {
+                conn.execute_transaction(request).await
+            })
+            .await?;
+        self.verify(&results)?;
+        self.observe(&results);
+        Ok(results)
+    }
+
+    /// Executes `statements` as one transaction, like `transaction`, along
+    /// with recording `payload` for `topic` in the outbox; see `outbox`.
+    pub async fn transaction_with_outbox<S: ToString>(
+        &mut self,
+        statements: &[S],
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<QueryResults, ClientError> {
+        let mut statements: Vec<String> = statements.iter().map(ToString::to_string).collect();
+        statements.push(outbox_statement(topic, payload));
+        self.transaction(&statements).await
+    }
+
+    /// Executes independent statements, each with its parameters, in one
+    /// round trip and returns the results of each in order.
+    ///
+    /// A statement failing does not stop the others. The batch as a whole
+    /// fails over like `query` while nodes are unreachable.
+    pub async fn query_batch<S: ToString>(
+        &mut self,
+        statements: Vec<(S, Vec<Value>)>,
+        consistency: Consistency,
+    ) -> Result<Vec<Result<QueryResults, ClientError>>, ClientError> {
+        let batch = QueryBatch {
+            queries: statements
+                .into_iter()
+                .map(|(sql, params)| Query {
+                    sql: sql.to_string(),
+                    params: params.into_iter().map(Into::into).collect(),
+                    consistency: consistency as i32,
+                    checksum: self.verify_checksums,
+                    proof: self.read_proofs,
+                    after_idx: self.session.unwrap_or(0),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let safe = !batch
+            .queries
+            .iter()
+            .any(|query| statements::is_write(&query.sql));
+        let (_, batch) = self
+            .with_failover(batch, Some(safe), |mut conn, request| async move {
+                conn.execute_batch(request).await
+            })
+            .await?;
+        let results: Vec<_> = batch
+            .results
+            .into_iter()
+            .map(|result| self.batch_result(result))
+            .collect();
+        for results in results.iter().flatten() {
+            self.observe(results);
+        }
+        Ok(results)
+    }
+
+    /// Turns the outcome of one statement of a batch into its result.
+    fn batch_result(&self, result: BatchResult) -> Result<QueryResults, ClientError> {
+        match result.outcome {
+            Some(batch_result::Outcome::Results(results)) => {
+                self.verify(&results)?;
+                Ok(results)
+            }
+            Some(batch_result::Outcome::Error(e)) => Err(ClientError::Status(tonic::Status::new(
+                Code::from_i32(e.code),
+                e.message,
+            ))),
+            None => Err(ClientError::Status(tonic::Status::internal(
+                "batch result without an outcome",
+            ))),
+        }
+    }
+
+    /// Prepares `sql`, a single statement, on every node and returns the id
+    /// to run it by with `execute_prepared`.
+    pub async fn prepare<S: ToString>(&mut self, sql: S) -> Result<u64, ClientError> {
+        let prepare = PrepareStatement {
+            sql: sql.to_string(),
+        };
+        let (_, prepared) = self
+            .with_failover(prepare, None, |mut conn, request| async move {
+                conn.prepare_statement(request).await
+            })
+            .await?;
+        Ok(prepared.id)
+    }
+
+    /// Executes the prepared statement `id` with `params` bound to its
+    /// placeholders.
+    pub async fn execute_prepared(
+        &mut self,
+        id: u64,
+        params: Vec<Value>,
+    ) -> Result<QueryResults, ClientError> {
+        let execute = ExecutePrepared {
+            id,
+            params: params.into_iter().map(Into::into).collect(),
+            checksum: self.verify_checksums,
+            proof: self.read_proofs,
+        };
+        // Whether the statement writes is only known to the nodes.
+        let (_, results) = self
+            .with_failover(execute, Some(false), |mut conn, request| async move {
+                conn.execute_prepared(request).await
+            })
+            .await?;
+        self.verify(&results)?;
+        self.observe(&results);
+        Ok(results)
+    }
+
+    /// Exports the database of `tenant` as SQL statements, for importing it
+    /// into another cluster. See `migration::migrate_tenant`.
+    pub async fn export_tenant(&mut self, tenant: &str) -> Result<TenantExport, ClientError> {
+        let request = TenantRequest {
+            tenant: tenant.to_string(),
+            after_idx: 0,
+        };
+        let (_, export) = self
+            .with_failover(request, None, |mut conn, request| async move {
+                conn.export_tenant(request).await
+            })
+            .await?;
+        Ok(export)
+    }
+
+    /// Imports the export of a tenant from another cluster as `tenant`,
+    /// which must not have any tables yet.
+    pub async fn import_tenant(
+        &mut self,
+        tenant: &str,
+        statements: Vec<String>,
+    ) -> Result<(), ClientError> {
+        let request = TenantImport {
+            tenant: tenant.to_string(),
+            statements,
+        };
+        let (_, _) = self
+            .with_failover(request, None, |mut conn, request| async move {
+                conn.import_tenant(request).await
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Reads the writes to `tenant` captured after `after_idx` while the
+    /// tenant is being migrated.
+    pub async fn tenant_changes(
+        &mut self,
+        tenant: &str,
+        after_idx: u64,
+    ) -> Result<Vec<TenantChange>, ClientError> {
+        let request = TenantRequest {
+            tenant: tenant.to_string(),
+            after_idx,
+        };
+        let (_, changes) = self
+            .with_failover(request, None, |mut conn, request| async move {
+                conn.get_tenant_changes(request).await
+            })
+            .await?;
+        Ok(changes.changes)
+    }
+
+    /// Returns the checksum of the database of `tenant` and the applied
+    /// index it was computed at.
+    pub async fn tenant_checksum(&mut self, tenant: &str) -> Result<TenantChecksum, ClientError> {
+        let request = TenantRequest {
+            tenant: tenant.to_string(),
+            after_idx: 0,
+        };
+        let (_, checksum) = self
+            .with_failover(request, None, |mut conn, request| async move {
+                conn.get_tenant_checksum(request).await
+            })
+            .await?;
+        Ok(checksum)
+    }
+
+    /// Waits until everything the cluster applied before the call is
+    /// applied on every node of the client, or fails after `timeout` per
+    /// node. Returns the applied index of the barrier.
+    pub async fn barrier(&mut self, timeout: Duration) -> Result<u64, ClientError> {
+        let names: Vec<String> = self.nodes.health().into_iter().map(|n| n.name).collect();
+        self.barrier_on(&names, timeout).await
+    }
+
+    /// Like `barrier`, but only waits for the nodes named `names`.
+    pub async fn barrier_on<S: AsRef<str>>(
+        &mut self,
+        names: &[S],
+        timeout: Duration,
+    ) -> Result<u64, ClientError> {
+        let mut nodes = vec![];
+        for name in names {
+            match self.nodes.find(name.as_ref()) {
+                Some(idx) => nodes.push(idx),
+                None => return Err(ClientError::UnknownNode(name.as_ref().to_string())),
+            }
+        }
+        let (_, barrier) = self
+            .with_failover(Void {}, None, |mut conn, request| async move {
+                conn.barrier(request).await
+            })
+            .await?;
+        let index = barrier.index;
+        for idx in nodes {
+            self.wait_on(idx, index, timeout).await?;
+        }
+        Ok(index)
+    }
+
+    /// Waits until the node named `name` applied the log up to `index`, or
+    /// fails with `DEADLINE_EXCEEDED` after `timeout`.
+    ///
+    /// With the `commit_idx` of a write, this lets a read on any node,
+    /// even a relaxed one, see the write.
+    pub async fn wait_for_index<S: AsRef<str>>(
+        &mut self,
+        name: S,
+        index: u64,
+        timeout: Duration,
+    ) -> Result<(), ClientError> {
+        let idx = self
+            .nodes
+            .find(name.as_ref())
+            .ok_or_else(|| ClientError::UnknownNode(name.as_ref().to_string()))?;
+        self.wait_on(idx, index, timeout).await
+    }
+
+    /// Waits until the node at `idx` of the pool applied `index`.
+    async fn wait_on(
+        &mut self,
+        idx: usize,
+        index: u64,
+        timeout: Duration,
+    ) -> Result<(), ClientError> {
+        let wait = WaitForIndex {
+            index,
+            timeout_ms: timeout.as_millis() as u64,
+        };
+        self.nodes.conn(idx).wait_applied(wait).await?;
+        Ok(())
+    }
+
+    /// Executes a statement and returns its results as a stream of batches
+    /// of up to `batch_size` rows, or the node's default if 0. The first
+    /// batch carries the columns.
+    ///
+    /// Fails over like `query` until a node accepts the query; a stream
+    /// that breaks off later is not resumed.
+    pub async fn query_stream<S: ToString>(
+        &mut self,
+        sql: S,
+        consistency: Consistency,
+        batch_size: u32,
+    ) -> Result<Streaming<QueryResults>, ClientError> {
+        let query = Query {
+            sql: sql.to_string(),
+            consistency: consistency as i32,
+            batch_size,
+            tenant: self.tenant.clone(),
+            after_idx: self.session.unwrap_or(0),
+            ..Default::default()
+        };
+        let safe = !statements::is_write(&query.sql);
+        let (_, stream) = self
+            .with_failover(query, Some(safe), |mut conn, request| async move {
+                conn.execute_stream(request).await
+            })
+            .await?;
+        Ok(stream)
+    }
+
+    /// Opens a cursor over the rows of the `SELECT` query `sql` and returns
+    /// it along with its first page of up to `page_size` rows, or the
+    /// node's default if 0. The first page carries the columns.
+    ///
+    /// Fails over like `query` until a node opens the cursor; the following
+    /// pages are fetched from that node with `fetch_cursor`.
+    pub async fn open_cursor<S: ToString>(
+        &mut self,
+        sql: S,
+        consistency: Consistency,
+        page_size: u32,
+    ) -> Result<(Cursor, QueryResults), ClientError> {
+        let query = Query {
+            sql: sql.to_string(),
+            consistency: consistency as i32,
+            checksum: self.verify_checksums,
+            batch_size: page_size,
+            tenant: self.tenant.clone(),
+            after_idx: self.session.unwrap_or(0),
+            ..Default::default()
+        };
+        let (node, page) = self
+            .with_failover(query, Some(true), |mut conn, request| async move {
+                conn.open_cursor(request).await
+            })
+            .await?;
+        let mut cursor = Cursor {
+            node,
+            id: page.cursor_id,
+            done: false,
+        };
+        let results = self.page_results(&mut cursor, page)?;
+        Ok((cursor, results))
+    }
+
+    /// Fetches the next page of `cursor`, or no rows once it is done.
+    ///
+    /// Fails with `NOT_FOUND` if the node closed the cursor, for instance
+    /// because it was left idle for too long.
+    pub async fn fetch_cursor(&mut self, cursor: &mut Cursor) -> Result<QueryResults, ClientError> {
+        if cursor.done {
+            return Ok(QueryResults::default());
+        }
+        let request = CursorRequest {
+            cursor_id: cursor.id,
+            checksum: self.verify_checksums,
+        };
+        let page = self.nodes.conn(cursor.node).fetch_cursor(request).await?;
+        self.page_results(cursor, page.into_inner())
+    }
+
+    /// Closes `cursor` before its last page, so the node frees it at once.
+    pub async fn close_cursor(&mut self, cursor: Cursor) -> Result<(), ClientError> {
+        if cursor.done {
+            return Ok(());
+        }
+        let request = CursorRequest {
+            cursor_id: cursor.id,
+            checksum: false,
+        };
+        self.nodes.conn(cursor.node).close_cursor(request).await?;
+        Ok(())
+    }
+
+    /// Reads the page of the rows of the `SELECT` query `sql`, ordered by the
+    /// result columns `key`, that `token` starts, or the first page if
+    /// `None`. Pages hold up to `page_size` rows, or the node's default if
+    /// 0; `ResultPage::next_token` is the token of the next page, and empty
+    /// on the last one.
+    ///
+    /// Nodes keep nothing between pages, so every page fails over like
+    /// `query`. See `pagination`.
+    pub async fn query_page<S: ToString>(
+        &mut self,
+        sql: S,
+        key: &[&str],
+        page_size: u32,
+        token: Option<&str>,
+        consistency: Consistency,
+    ) -> Result<ResultPage, ClientError> {
+        let request = PageRequest {
+            sql: sql.to_string(),
+            key: key.iter().map(|column| column.to_string()).collect(),
+            page_size,
+            token: token.unwrap_or_default().to_string(),
+            consistency: consistency as i32,
+            checksum: self.verify_checksums,
+        };
+        let (_, page) = self
+            .with_failover(request, Some(true), |mut conn, request| async move {
+                conn.query_page(request).await
+            })
+            .await?;
+        if let Some(results) = &page.results {
+            self.verify(results)?;
+        }
+        Ok(page)
+    }
+
+    /// Takes the results of a page of `cursor`, verifying their checksum.
+    fn page_results(
+        &mut self,
+        cursor: &mut Cursor,
+        page: CursorPage,
+    ) -> Result<QueryResults, ClientError> {
+        cursor.done = page.done;
+        let results = page.results.unwrap_or_default();
+        self.verify(&results)?;
+        self.observe(&results);
+        Ok(results)
+    }
+
+    /// Executes a write, queueing it for replay if the cluster is unreachable
+    /// and the offline queue is enabled.
+    ///
+    /// The idempotency key identifies the write in replay outcomes and makes
+    /// re-submitting an already queued write a no-op; one is generated when
+    /// `key` is `None`. The key stays on the client: the cluster does not
+    /// deduplicate writes by key, so the write is not retried on another
+    /// node under a retry budget.
+    pub async fn execute_or_queue<S: ToString>(
+        &mut self,
+        sql: S,
+        key: Option<String>,
+    ) -> Result<WriteOutcome, ClientError> {
+        let sql = sql.to_string();
+        let key = key.unwrap_or_else(|| self.generate_key());
+        if self.queued_writes() > 0 {
+            // Preserve ordering behind writes that are already queued.
+            self.enqueue(key.clone(), sql)?;
+            return Ok(WriteOutcome::Queued { key });
+        }
+        match self.execute_keyed(sql.clone()).await {
+            Ok(results) => Ok(WriteOutcome::Executed(results)),
+            Err(e) if e.is_unreachable() && self.offline_queue.is_some() => {
+                self.enqueue(key.clone(), sql)?;
+                Ok(WriteOutcome::Queued { key })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replays queued writes in order, returning the outcome of each write
+    /// the cluster answered.
+    ///
+    /// Replay stops at the first write the cluster is unreachable for; that
+    /// write and the ones after it stay queued.
+    pub async fn replay(&mut self) -> Vec<ReplayOutcome> {
+        let mut outcomes = Vec::new();
+        loop {
+            let write = match self.offline_queue.as_mut().and_then(|queue| queue.pop()) {
+                Some(write) => write,
+                None => break,
+            };
+            match self.execute_keyed(write.sql.clone()).await {
+                Err(e) if e.is_unreachable() => {
+                    self.offline_queue.as_mut().unwrap().push_front(write);
+                    break;
+                }
+                result => outcomes.push(ReplayOutcome {
+                    key: write.key,
+                    sql: write.sql,
+                    result,
+                }),
+            }
+        }
+        outcomes
+    }
+
+    /// Executes a write submitted with an idempotency key. The key stays on
+    /// the client, so the write is not safe to send twice.
+    async fn execute_keyed(&mut self, sql: String) -> Result<QueryResults, ClientError> {
+        let query = self.new_query(sql, vec![], Consistency::Strong);
+        self.execute(query, false).await
+    }
+
+    fn enqueue(&mut self, key: String, sql: String) -> Result<(), ClientError> {
+        match self.offline_queue.as_mut() {
+            Some(queue) => queue.push(key, sql),
+            None => Err(ClientError::QueueFull(0)),
+        }
+    }
+
+    fn generate_key(&mut self) -> String {
+        let key = format!("{}-{}", self.key_prefix, self.next_key);
+        self.next_key += 1;
+        key
+    }
+}
+
+impl ClientError {
+    /// Returns true if the error means the cluster could not be reached.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            ClientError::Transport(_) => true,
+            ClientError::Status(status) => status.code() == Code::Unavailable,
+            _ => false,
+        }
+    }
+
+    /// Returns the address of the leader to redirect the request to, if
+    /// the node that rejected it named one in its `leader-addr` metadata.
+    pub fn leader_addr(&self) -> Option<&str> {
+        match self {
+            ClientError::Status(status) => status.metadata().get("leader-addr")?.to_str().ok(),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Declarative cluster reconciliation.
+//!
+//! A `ClusterController` holds a `ClusterSpec`, the members and
+//! application settings the cluster should have, and reconciles the live
+//! cluster toward it: settings that differ are written with
+//! `StoreServer::set_config`, and a membership that differs is changed with
+//! `StoreServer::reconfigure`, after the settings since the stop sign ends
+//! the configuration. An orchestrator only has to keep the spec of the
+//! controller up to date with `ClusterController::set_spec`.
+//!
+//! Only the controller of the leader acts, so one may run on every node:
+//! the others plan nothing until their node leads. While a reconfiguration
+//! the controller proposed is under way nothing is proposed, and the next
+//! pass resumes in the new configuration.
+//!
+//! Every action taken, and every action that failed, is published to the
+//! subscribers of the controller as a `ReconcileEvent`. Every node of a
+//! configuration is a voting member; learners follow the cluster outside
+//! of it, see `learner`.
+
+use crate::errors::StoreError;
+use crate::server::{Lifecycle, SequencePaxosStoreTransport, StoreServer};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// How long `ClusterController::run` waits between passes by default.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of events a subscriber may fall behind before it is lagged.
+const EVENT_CAPACITY: usize = 256;
+
+/// The desired state of a cluster.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClusterSpec {
+    /// Node ids of the members; empty to leave the membership as it is.
+    pub members: Vec<u64>,
+    /// Application settings, by key. Settings not listed are left as they
+    /// are.
+    pub settings: BTreeMap<String, String>,
+}
+
+/// A change that brings the cluster closer to its spec.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReconcileAction {
+    /// Set the application setting `key` to `value`.
+    SetConfig {
+        /// The setting.
+        key: String,
+        /// Its desired value.
+        value: String,
+    },
+    /// Move the cluster to a configuration of `members`.
+    Reconfigure {
+        /// Node ids of the members, sorted.
+        members: Vec<u64>,
+    },
+}
+
+/// What a reconciliation pass did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReconcileEvent {
+    /// The action was proposed and, for settings, applied.
+    Applied(ReconcileAction),
+    /// The action failed; the next pass plans it again.
+    Failed {
+        /// The action.
+        action: ReconcileAction,
+        /// Why it failed.
+        error: String,
+    },
+}
+
+/// Reconciles a cluster toward a `ClusterSpec` through one of its nodes.
+#[derive(Debug)]
+pub struct ClusterController<T: SequencePaxosStoreTransport + Send + Sync> {
+    server: Arc<StoreServer<T>>,
+    spec: Mutex<ClusterSpec>,
+    interval: Duration,
+    /// Configuration the controller proposed to leave, until it ends.
+    reconfiguring: Mutex<Option<u32>>,
+    events: broadcast::Sender<ReconcileEvent>,
+}
+
+impl<T: SequencePaxosStoreTransport + Send + Sync> ClusterController<T> {
+    /// Creates a controller reconciling the cluster of `server` toward
+    /// `spec`.
+    pub fn new(server: Arc<StoreServer<T>>, spec: ClusterSpec) -> Self {
+        Self {
+            server,
+            spec: Mutex::new(spec),
+            interval: RECONCILE_INTERVAL,
+            reconfiguring: Mutex::new(None),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Sets how long `run` waits between passes.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Returns the spec the cluster is reconciled toward.
+    pub fn spec(&self) -> ClusterSpec {
+        self.spec.lock().unwrap().clone()
+    }
+
+    /// Replaces the spec; the next pass reconciles toward it.
+    pub fn set_spec(&self, spec: ClusterSpec) {
+        *self.spec.lock().unwrap() = spec;
+    }
+
+    /// Subscribes to the events of the passes from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ReconcileEvent> {
+        self.events.subscribe()
+    }
+
+    /// Returns the actions that would bring the cluster to its spec, in the
+    /// order they are taken; none unless this node leads and no
+    /// reconfiguration is under way. Settings are read linearizably.
+    pub async fn plan(&self) -> Result<Vec<ReconcileAction>, StoreError> {
+        let config_id = self.server.config_id();
+        let reconfiguring = *self.reconfiguring.lock().unwrap();
+        if self.server.get_cluster_leader() != self.server.id() || reconfiguring == Some(config_id)
+        {
+            return Ok(vec![]);
+        }
+        let spec = self.spec();
+        let mut actions = vec![];
+        for (key, value) in &spec.settings {
+            if self.server.get_config(key).await?.as_ref() != Some(value) {
+                actions.push(ReconcileAction::SetConfig {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+        let mut members = spec.members;
+        members.sort_unstable();
+        members.dedup();
+        let current: Vec<u64> = self
+            .server
+            .cluster_status()
+            .members
+            .iter()
+            .map(|member| member.id)
+            .collect();
+        if !members.is_empty() && members != current {
+            actions.push(ReconcileAction::Reconfigure { members });
+        }
+        Ok(actions)
+    }
+
+    /// Runs one pass, taking the planned actions in order, and returns the
+    /// actions taken. Stops at the first action that fails.
+    pub async fn reconcile(&self) -> Result<Vec<ReconcileAction>, StoreError> {
+        let mut applied = vec![];
+        for action in self.plan().await? {
+            let result = match &action {
+                ReconcileAction::SetConfig { key, value } => {
+                    self.server.set_config(key, value).await
+                }
+                ReconcileAction::Reconfigure { members } => {
+                    let config_id = self.server.config_id();
+                    self.server.reconfigure(members.clone()).map(|()| {
+                        *self.reconfiguring.lock().unwrap() = Some(config_id);
+                    })
+                }
+            };
+            match result {
+                Ok(()) => {
+                    let _ = self.events.send(ReconcileEvent::Applied(action.clone()));
+                    applied.push(action);
+                }
+                Err(e) => {
+                    let _ = self.events.send(ReconcileEvent::Failed {
+                        action,
+                        error: e.to_string(),
+                    });
+                    return Err(e);
+                }
+            }
+        }
+        Ok(applied)
+    }
+
+    /// Reconciles the cluster continuously, until the node shuts down or
+    /// retires. Failed passes are reported as events and retried.
+    pub async fn run(&self) {
+        while self.server.lifecycle() != Lifecycle::ShuttingDown {
+            let _ = self.reconcile().await;
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Buffered replicated counters.
+//!
+//! Replicating every increment of a hot counter, such as page views or a
+//! rate counter, costs a log entry each. `Counters` instead adds increments
+//! to a local buffer and replicates the aggregated deltas of all its
+//! counters as a single command every `flush_interval`. Since deltas
+//! commute, replicas and other nodes buffering the same counters converge,
+//! like a PN-counter; a counter read on any replica lags by at most the
+//! flush interval of the nodes incrementing it.
+//!
+//! Deltas still buffered when a node stops or fails are lost.
+
+use crate::errors::StoreError;
+use crate::server::{Consistency, SequencePaxosStoreTransport, StoreServer};
+use crate::value::{quote_literal, Value};
+use sqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Reserved table holding the counter values.
+pub const COUNTERS_TABLE: &str = "chiselstore_counters";
+
+/// How often buffered deltas are replicated unless told otherwise.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Creates the counters table on `conn` if it does not exist yet.
+pub(crate) fn create_table(conn: &Connection) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} (name TEXT PRIMARY KEY, value INTEGER NOT NULL)",
+        COUNTERS_TABLE
+    ))?;
+    Ok(())
+}
+
+/// Statement adding `delta` to counter `name`.
+fn delta_statement(name: &str, delta: i64) -> String {
+    format!(
+        "INSERT INTO {} (name, value) VALUES ({}, {}) \
+         ON CONFLICT (name) DO UPDATE SET value = value + excluded.value",
+        COUNTERS_TABLE,
+        quote_literal(name),
+        delta
+    )
+}
+
+/// Counters whose increments are buffered locally and replicated in
+/// batches.
+#[derive(Debug)]
+pub struct Counters<T: SequencePaxosStoreTransport + Send + Sync> {
+    server: Arc<StoreServer<T>>,
+    flush_interval: Duration,
+    /// Deltas not replicated yet, by counter name.
+    pending: Mutex<HashMap<String, i64>>,
+}
+
+impl<T: SequencePaxosStoreTransport + Send + Sync> Counters<T> {
+    /// Creates counters replicated through `server`.
+    pub fn new(server: Arc<StoreServer<T>>) -> Self {
+        Self {
+            server,
+            flush_interval: FLUSH_INTERVAL,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets how often `run` replicates the buffered deltas, which bounds how
+    /// stale the replicated values are.
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Adds `delta`, which may be negative, to counter `name`.
+    pub fn increment(&self, name: &str, delta: i64) {
+        let mut pending = self.pending.lock().unwrap();
+        let entry = pending.entry(name.to_string()).or_default();
+        *entry = entry.saturating_add(delta);
+    }
+
+    /// Returns the increments of `name` buffered on this node.
+    pub fn pending(&self, name: &str) -> i64 {
+        self.pending
+            .lock()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of counter `name` as far as the replica has applied
+    /// the log, with the increments buffered on this node.
+    pub async fn get(&self, name: &str) -> Result<i64, StoreError> {
+        let stmt = format!(
+            "SELECT value FROM {} WHERE name = {}",
+            COUNTERS_TABLE,
+            quote_literal(name)
+        );
+        let results = self.server.query(stmt, Consistency::RelaxedReads).await?;
+        let replicated = match results
+            .rows
+            .first()
+            .and_then(|row| row.typed_values.first())
+        {
+            Some(Value::Integer(value)) => *value,
+            _ => 0,
+        };
+        Ok(replicated.saturating_add(self.pending(name)))
+    }
+
+    /// Replicates the buffered deltas of every counter as one command,
+    /// returning the number of counters updated. On failure, the deltas are
+    /// buffered again.
+    pub async fn flush(&self) -> Result<usize, StoreError> {
+        let deltas: Vec<_> = std::mem::take(&mut *self.pending.lock().unwrap())
+            .into_iter()
+            .filter(|(_, delta)| *delta != 0)
+            .collect();
+        if deltas.is_empty() {
+            return Ok(0);
+        }
+        let statements: Vec<_> = deltas
+            .iter()
+            .map(|(name, delta)| delta_statement(name, *delta))
+            .collect();
+        if let Err(e) = self.server.transaction(&statements).await {
+            for (name, delta) in deltas {
+                self.increment(&name, delta);
+            }
+            return Err(e);
+        }
+        Ok(deltas.len())
+    }
+
+    /// Replicates the buffered deltas every flush interval, until a flush
+    /// fails.
+    pub async fn run(&self) -> Result<(), StoreError> {
+        loop {
+            tokio::time::sleep(self.flush_interval).await;
+            self.flush().await?;
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Server-side cursors.
+//!
+//! A cursor walks the rows of a query a page at a time, so clients with
+//! small memory budgets can read large tables over several requests.
+//! `StoreServer::open_cursor` starts the query like a streamed one and
+//! returns its first page; `StoreServer::fetch_cursor` returns the next
+//! page until the last one, which closes the cursor.
+//!
+//! A relaxed read keeps its read slot and connection for as long as its
+//! cursor is open, and reads ahead by a page only; other reads are
+//! answered in full before their first page. Cursors left idle for longer
+//! than `StoreConfig::cursor_ttl` are closed, and a node keeps at most
+//! `StoreConfig::max_cursors` open at a time. A cursor lives on the node
+//! that opened it.
+
+use crate::errors::StoreError;
+use crate::server::QueryResults;
+use derivative::Derivative;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// A page of rows read through a cursor.
+#[derive(Debug)]
+pub struct CursorPage {
+    /// Id to fetch the next page with.
+    pub cursor_id: u64,
+    /// The rows of the page; the first page carries the columns.
+    pub results: QueryResults,
+    /// True for the last page, after which the cursor is closed.
+    pub done: bool,
+}
+
+/// The batches of an open cursor, one page read ahead so the last page is
+/// known to be last.
+struct Cursor {
+    batches: mpsc::Receiver<Result<QueryResults, StoreError>>,
+    next: Option<Result<QueryResults, StoreError>>,
+}
+
+struct Entry {
+    cursor: Arc<tokio::sync::Mutex<Cursor>>,
+    last_used: Instant,
+}
+
+/// The cursors open on a node.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub(crate) struct Cursors {
+    ttl: Duration,
+    max_open: usize,
+    next_id: AtomicU64,
+    #[derivative(Debug = "ignore")]
+    open: Mutex<HashMap<u64, Entry>>,
+}
+
+impl Cursors {
+    pub(crate) fn new(ttl: Duration, max_open: usize) -> Self {
+        Self {
+            ttl,
+            max_open,
+            next_id: AtomicU64::new(1),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Opens a cursor over `batches`, the pages of a streamed query, and
+    /// returns its first page. A query with a single page leaves no cursor
+    /// open.
+    pub(crate) async fn open(
+        &self,
+        mut batches: mpsc::Receiver<Result<QueryResults, StoreError>>,
+    ) -> Result<CursorPage, StoreError> {
+        if self.open.lock().unwrap().len() >= self.max_open {
+            return Err(StoreError::TooManyCursors(self.max_open));
+        }
+        let first = batches
+            .recv()
+            .await
+            .unwrap_or_else(|| Ok(QueryResults::new(vec![])))?;
+        let next = batches.recv().await;
+        let cursor_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let done = next.is_none();
+        if !done {
+            let cursor = Cursor { batches, next };
+            self.open.lock().unwrap().insert(
+                cursor_id,
+                Entry {
+                    cursor: Arc::new(tokio::sync::Mutex::new(cursor)),
+                    last_used: Instant::now(),
+                },
+            );
+        }
+        Ok(CursorPage {
+            cursor_id,
+            results: first,
+            done,
+        })
+    }
+
+    /// Returns the next page of cursor `cursor_id`, closing the cursor after
+    /// the last page or a failed read.
+    pub(crate) async fn fetch(&self, cursor_id: u64) -> Result<CursorPage, StoreError> {
+        let cursor = {
+            let mut open = self.open.lock().unwrap();
+            let entry = open
+                .get_mut(&cursor_id)
+                .ok_or(StoreError::UnknownCursor(cursor_id))?;
+            entry.last_used = Instant::now();
+            entry.cursor.clone()
+        };
+        let mut cursor = cursor.lock().await;
+        let page = match cursor.next.take() {
+            Some(page) => page,
+            // A concurrent fetch took the last page.
+            None => return Err(StoreError::UnknownCursor(cursor_id)),
+        };
+        let results = match page {
+            Ok(results) => results,
+            Err(e) => {
+                self.close(cursor_id);
+                return Err(e);
+            }
+        };
+        cursor.next = cursor.batches.recv().await;
+        let done = cursor.next.is_none();
+        if done {
+            self.close(cursor_id);
+        }
+        Ok(CursorPage {
+            cursor_id,
+            results,
+            done,
+        })
+    }
+
+    /// Closes cursor `cursor_id`, returning false if it was not open.
+    pub(crate) fn close(&self, cursor_id: u64) -> bool {
+        self.open.lock().unwrap().remove(&cursor_id).is_some()
+    }
+
+    /// Closes the cursors idle for longer than the time to live.
+    pub(crate) fn expire(&self) {
+        let ttl = self.ttl;
+        self.open
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.last_used.elapsed() < ttl);
+    }
+}
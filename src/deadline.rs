@@ -0,0 +1,68 @@
+//! Statement deadlines.
+//!
+//! SQLite calls a connection's progress handler every few virtual machine
+//! instructions; returning non-zero interrupts the running statement. A
+//! `StatementDeadline` installs such a handler to bound how long a statement
+//! may run, so a pathological statement cannot block the caller forever.
+
+use crate::errors::StoreError;
+use sqlite::Connection;
+use sqlite3_sys as ffi;
+use std::os::raw::{c_int, c_void};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of virtual machine instructions between deadline checks.
+const PROGRESS_INTERVAL: c_int = 1000;
+
+/// Deadline checked by a connection's progress handler.
+#[derive(Debug, Default)]
+pub(crate) struct StatementDeadline {
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl StatementDeadline {
+    /// Installs a progress handler on `conn` enforcing the returned deadline.
+    ///
+    /// The deadline must outlive the connection.
+    pub(crate) fn install(conn: &mut Connection) -> Arc<Self> {
+        let deadline = Arc::new(Self::default());
+        unsafe {
+            ffi::sqlite3_progress_handler(
+                conn.as_raw(),
+                PROGRESS_INTERVAL,
+                Some(check_deadline),
+                Arc::as_ptr(&deadline) as *mut c_void,
+            );
+        }
+        deadline
+    }
+
+    /// Runs `f`, interrupting the statements it runs on the connection after
+    /// `timeout`.
+    pub(crate) fn run<R>(
+        &self,
+        timeout: Option<Duration>,
+        f: impl FnOnce() -> Result<R, StoreError>,
+    ) -> Result<R, StoreError> {
+        *self.deadline.lock().unwrap() = timeout.map(|timeout| Instant::now() + timeout);
+        let result = f();
+        *self.deadline.lock().unwrap() = None;
+        match (result, timeout) {
+            (Err(StoreError::SQLiteError(e)), Some(timeout))
+                if e.code == Some(ffi::SQLITE_INTERRUPT as isize) =>
+            {
+                Err(StoreError::StatementTimeout(timeout))
+            }
+            (result, _) => result,
+        }
+    }
+}
+
+extern "C" fn check_deadline(arg: *mut c_void) -> c_int {
+    let deadline = unsafe { &*(arg as *const StatementDeadline) };
+    match *deadline.deadline.lock().unwrap() {
+        Some(deadline) if Instant::now() >= deadline => 1,
+        _ => 0,
+    }
+}
@@ -0,0 +1,55 @@
+//! Usage of deprecated API methods.
+//!
+//! The client-facing methods of the `RPC` service are superseded by the
+//! `RpcV2` service and kept only so that older clients keep working. Every
+//! call to one of them is counted, and the first one logged, so operators
+//! can tell from `StoreServer::deprecated_calls` or the
+//! `chiselstore_deprecated_calls` introspection table when no client uses
+//! them anymore and they can be removed.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Usage of one deprecated method.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DeprecatedUsage {
+    /// Number of calls.
+    pub calls: u64,
+    /// When the method was last called, in milliseconds since the Unix
+    /// epoch.
+    pub last_called_ms: u64,
+}
+
+/// Calls to deprecated methods on a replica, keyed by method name.
+#[derive(Debug, Default)]
+pub struct DeprecatedCalls {
+    usage: Mutex<HashMap<&'static str, DeprecatedUsage>>,
+}
+
+impl DeprecatedCalls {
+    /// Records a call to `method`, returning true if it is the first one.
+    pub fn record(&self, method: &'static str) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_millis() as u64)
+            .unwrap_or_default();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(method).or_default();
+        entry.calls += 1;
+        entry.last_called_ms = now;
+        entry.calls == 1
+    }
+
+    /// Returns the usage of every deprecated method called so far, ordered
+    /// by method name.
+    pub fn snapshot(&self) -> Vec<(String, DeprecatedUsage)> {
+        let usage = self.usage.lock().unwrap();
+        let mut snapshot: Vec<_> = usage
+            .iter()
+            .map(|(method, usage)| (method.to_string(), usage.clone()))
+            .collect();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+        snapshot
+    }
+}
@@ -0,0 +1,260 @@
+//! Deterministic rewriting of non-deterministic SQL.
+//!
+//! Every replica applies the SQL of a command itself, so an expression
+//! whose value depends on when or where it is evaluated would leave the
+//! replicas with different data. With `StoreConfig::resolve_nondeterminism`,
+//! the node proposing a command evaluates such expressions once and
+//! replicates their values as literals instead:
+//!
+//! - `RANDOM()` becomes a random integer, drawn for each call;
+//! - `CURRENT_TIMESTAMP`, `CURRENT_DATE` and `CURRENT_TIME` become the
+//!   proposal time, in UTC like SQLite renders them;
+//! - `'now'` passed to a date and time function becomes the proposal time
+//!   with milliseconds.
+//!
+//! `last_insert_rowid()` is deterministic already, since every command
+//! starts with it reset. Schema statements are left as they are, so a
+//! column's `DEFAULT CURRENT_TIMESTAMP` keeps meaning the time of each
+//! insert; such defaults are still evaluated by every replica.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::iter::Peekable;
+use std::str::Chars;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Date and time functions that take `'now'` as a time value.
+const TIME_FUNCTIONS: [&str; 7] = [
+    "date",
+    "time",
+    "datetime",
+    "julianday",
+    "unixepoch",
+    "strftime",
+    "timediff",
+];
+
+/// Returns `sql` with its non-deterministic expressions replaced by their
+/// values at `now`.
+pub(crate) fn resolve(sql: &str, now: SystemTime) -> String {
+    let now = Timestamp::new(now);
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    // Name of the function each open parenthesis calls, if any.
+    let mut calls: Vec<Option<String>> = vec![];
+    // The last word, while only whitespace followed it.
+    let mut last_word: Option<String> = None;
+    let mut statement_start = true;
+    let mut schema_statement = false;
+    // Inside the body of a trigger, along with the `CASE`s open in it.
+    let mut in_trigger = false;
+    let mut cases = 0usize;
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let literal = read_quoted(c, &mut chars);
+                let in_time_function = matches!(
+                    calls.last(),
+                    Some(Some(name)) if TIME_FUNCTIONS.contains(&name.as_str())
+                );
+                if in_time_function && !schema_statement && literal.eq_ignore_ascii_case("'now'") {
+                    out.push_str(&format!("'{}'", now.datetime_millis()));
+                } else {
+                    out.push_str(&literal);
+                }
+            }
+            '"' | '`' | '[' => out.push_str(&read_quoted(c, &mut chars)),
+            '-' if chars.peek() == Some(&'-') => {
+                out.push(c);
+                for c in chars.by_ref() {
+                    out.push(c);
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                out.push(c);
+                out.push(chars.next().unwrap());
+                let mut star = false;
+                for c in chars.by_ref() {
+                    out.push(c);
+                    if star && c == '/' {
+                        break;
+                    }
+                    star = c == '*';
+                }
+                continue;
+            }
+            '(' => {
+                calls.push(last_word.take());
+                out.push(c);
+            }
+            ')' => {
+                calls.pop();
+                out.push(c);
+            }
+            // The statements of a trigger body belong to its `CREATE`.
+            ';' => {
+                if !in_trigger {
+                    statement_start = true;
+                    schema_statement = false;
+                }
+                out.push(c);
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = String::from(c);
+                while let Some(&d) = chars.peek() {
+                    if !(d.is_alphanumeric() || d == '_' || d == '$') {
+                        break;
+                    }
+                    word.push(d);
+                    chars.next();
+                }
+                let lower = word.to_lowercase();
+                if statement_start {
+                    schema_statement = matches!(lower.as_str(), "create" | "alter");
+                    statement_start = false;
+                }
+                match lower.as_str() {
+                    "begin" if schema_statement => in_trigger = true,
+                    "case" if in_trigger => cases += 1,
+                    "end" if in_trigger && cases > 0 => cases -= 1,
+                    "end" if in_trigger => in_trigger = false,
+                    _ => {}
+                }
+                let value = match lower.as_str() {
+                    _ if schema_statement => None,
+                    "current_timestamp" => Some(format!("'{}'", now.datetime())),
+                    "current_date" => Some(format!("'{}'", now.date())),
+                    "current_time" => Some(format!("'{}'", now.time())),
+                    // Parenthesized, so a negative value cannot turn a
+                    // preceding `-` into a comment.
+                    "random" => take_empty_args(&mut chars).then(|| format!("({})", random_i64())),
+                    _ => None,
+                };
+                match value {
+                    Some(value) => {
+                        out.push_str(&value);
+                        last_word = None;
+                    }
+                    None => {
+                        out.push_str(&word);
+                        last_word = Some(lower);
+                    }
+                }
+                continue;
+            }
+            c if c.is_whitespace() => {
+                out.push(c);
+                continue;
+            }
+            c => out.push(c),
+        }
+        last_word = None;
+    }
+    out
+}
+
+/// Reads a quoted literal or identifier whose opening `open` was just read,
+/// returning it with its quotes. A doubled quote is an escaped one.
+fn read_quoted(open: char, chars: &mut Peekable<Chars>) -> String {
+    let close = match open {
+        '[' => ']',
+        c => c,
+    };
+    let mut quoted = String::from(open);
+    while let Some(c) = chars.next() {
+        quoted.push(c);
+        if c == close {
+            if close != ']' && chars.peek() == Some(&close) {
+                quoted.push(chars.next().unwrap());
+                continue;
+            }
+            break;
+        }
+    }
+    quoted
+}
+
+/// Consumes `()`, possibly with whitespace, if it comes next.
+fn take_empty_args(chars: &mut Peekable<Chars>) -> bool {
+    fn skip_whitespace(chars: &mut Peekable<Chars>) {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+    }
+    let mut lookahead = chars.clone();
+    skip_whitespace(&mut lookahead);
+    if lookahead.next() != Some('(') {
+        return false;
+    }
+    skip_whitespace(&mut lookahead);
+    if lookahead.next() != Some(')') {
+        return false;
+    }
+    *chars = lookahead;
+    true
+}
+
+/// A random integer over the whole `i64` range.
+fn random_i64() -> i64 {
+    // Every `RandomState` is seeded differently.
+    RandomState::new().build_hasher().finish() as i64
+}
+
+/// A point in time, broken down in UTC.
+struct Timestamp {
+    year: i64,
+    month: u32,
+    day: u32,
+    seconds_of_day: u64,
+    millis: u32,
+}
+
+impl Timestamp {
+    fn new(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+        Self {
+            year,
+            month,
+            day,
+            seconds_of_day: secs % 86_400,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+
+    fn date(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    fn time(&self) -> String {
+        let secs = self.seconds_of_day;
+        format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+
+    fn datetime(&self) -> String {
+        format!("{} {}", self.date(), self.time())
+    }
+
+    fn datetime_millis(&self) -> String {
+        format!("{}.{:03}", self.datetime(), self.millis)
+    }
+}
+
+/// Converts days since the Unix epoch into a proleptic Gregorian date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
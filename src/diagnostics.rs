@@ -0,0 +1,166 @@
+//! ChiselStore diagnostics.
+//!
+//! A write stall is when the applied index stops advancing while client
+//! proposals are still waiting for their results. When that happens, the
+//! server captures a diagnostics bundle to disk so that a "cluster is stuck"
+//! report comes with the state needed to investigate it, including where
+//! the node stands in Paxos.
+
+use omnipaxos_core::ballot_leader_election::Ballot;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAX_RECENT_EVENTS: usize = 64;
+
+/// State captured when a write stall is detected.
+#[derive(Debug, Clone)]
+pub struct StallReport {
+    /// The node that detected the stall.
+    pub node_id: u64,
+    /// When the stall was detected.
+    pub detected_at: SystemTime,
+    /// How long the applied index has not advanced.
+    pub stalled_for: Duration,
+    /// The applied index the node is stuck at.
+    pub applied_idx: u64,
+    /// The ballot the node promised.
+    pub ballot: Ballot,
+    /// The decided index as the node knows it.
+    pub decided_idx: u64,
+    /// The index up to which the node accepted entries into its log.
+    pub accepted_idx: u64,
+    /// The leader as seen by this node (0 if unknown).
+    pub leader: u64,
+    /// Ids of the proposals waiting for a result.
+    pub pending_commands: Vec<u64>,
+    /// Last accepted index reported by each peer, as seen by this node.
+    pub peer_accepted: Vec<(u64, u64)>,
+    /// Recent server events, oldest first.
+    pub recent_events: Vec<String>,
+    /// Where the bundle was written, if writing it succeeded.
+    pub bundle_path: Option<PathBuf>,
+}
+
+impl StallReport {
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "node: {}", self.node_id);
+        let _ = writeln!(out, "detected_at_ms: {}", unix_millis(self.detected_at));
+        let _ = writeln!(out, "stalled_for_ms: {}", self.stalled_for.as_millis());
+        let _ = writeln!(out, "applied_idx: {}", self.applied_idx);
+        let _ = writeln!(
+            out,
+            "ballot: n {} priority {} pid {}",
+            self.ballot.n, self.ballot.priority, self.ballot.pid
+        );
+        let _ = writeln!(out, "decided_idx: {}", self.decided_idx);
+        let _ = writeln!(out, "accepted_idx: {}", self.accepted_idx);
+        let _ = writeln!(out, "leader: {}", self.leader);
+        let _ = writeln!(out, "pending_commands: {}", self.pending_commands.len());
+        for id in &self.pending_commands {
+            let _ = writeln!(out, "  command {}", id);
+        }
+        let _ = writeln!(out, "peer_accepted:");
+        for (peer, la) in &self.peer_accepted {
+            let _ = writeln!(out, "  peer {} accepted {}", peer, la);
+        }
+        let _ = writeln!(out, "recent_events:");
+        for event in &self.recent_events {
+            let _ = writeln!(out, "  {}", event);
+        }
+        out
+    }
+
+    /// Writes the report into `dir`, returning the path of the bundle.
+    pub(crate) fn write_bundle(&mut self, dir: &Path) -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "node{}-stall-{}.txt",
+            self.node_id,
+            unix_millis(self.detected_at)
+        ));
+        std::fs::write(&path, self.render())?;
+        self.bundle_path = Some(path.clone());
+        Ok(path)
+    }
+}
+
+/// Paxos state of a node, kept up to date by its storage for the stall
+/// reports.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct PaxosProgress {
+    pub(crate) promised: Ballot,
+    pub(crate) decided_idx: u64,
+    pub(crate) accepted_idx: u64,
+}
+
+/// Tracks applied index progress to detect write stalls.
+#[derive(Debug)]
+pub(crate) struct StallDetector {
+    timeout: Duration,
+    last_idx: u64,
+    last_progress: Instant,
+    reported: bool,
+}
+
+impl StallDetector {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_idx: 0,
+            last_progress: Instant::now(),
+            reported: false,
+        }
+    }
+
+    /// Records the current applied index and number of pending proposals.
+    ///
+    /// Returns how long the node has been stalled the first time a stall
+    /// exceeds the timeout; a stall is reported once until progress resumes.
+    pub(crate) fn observe(&mut self, applied_idx: u64, pending: usize) -> Option<Duration> {
+        let now = Instant::now();
+        if applied_idx != self.last_idx || pending == 0 {
+            self.last_idx = applied_idx;
+            self.last_progress = now;
+            self.reported = false;
+            return None;
+        }
+        let stalled_for = now.duration_since(self.last_progress);
+        if !self.reported && stalled_for >= self.timeout {
+            self.reported = true;
+            return Some(stalled_for);
+        }
+        None
+    }
+}
+
+/// Bounded log of recent server events included in diagnostics bundles.
+#[derive(Debug, Default)]
+pub(crate) struct EventLog {
+    events: VecDeque<String>,
+}
+
+impl EventLog {
+    pub(crate) fn record<S: AsRef<str>>(&mut self, event: S) {
+        if self.events.len() == MAX_RECENT_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(format!(
+            "{} {}",
+            unix_millis(SystemTime::now()),
+            event.as_ref()
+        ));
+    }
+
+    pub(crate) fn recent(&self) -> Vec<String> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+fn unix_millis(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
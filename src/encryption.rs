@@ -0,0 +1,163 @@
+//! Column-level encryption.
+//!
+//! `encrypt(key_name, plaintext)` and `decrypt(key_name, ciphertext)` SQL
+//! functions encrypt values with AES-SIV, a deterministic authenticated
+//! cipher: the same plaintext and key always give the same ciphertext, so
+//! every replica stores identical bytes when it applies an `encrypt` call,
+//! and encrypted columns can still be compared for equality. Ciphertexts
+//! are hex-encoded text.
+//!
+//! Being deterministic, the encryption leaks equality: anyone who can read
+//! the ciphertexts can tell which rows hold the same value, and match a
+//! ciphertext against the encryption of a guessed value. Only encrypt
+//! columns with it whose values are hard to guess, or where revealing
+//! which values are equal is acceptable.
+//!
+//! The plaintext is tagged with the type of the value, so `decrypt`
+//! returns the integer, real, text or blob that was encrypted. Decrypting
+//! a plaintext without a known type tag fails.
+//!
+//! Keys come from a `SecretsProvider`, which must return the same 64-byte key
+//! for a name on every replica.
+
+use crate::functions::FunctionDef;
+use crate::value::Value;
+use aes_siv::aead::generic_array::GenericArray;
+use aes_siv::aead::{Aead, NewAead};
+use aes_siv::Aes256SivAead;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Length in bytes of encryption keys.
+pub const KEY_LEN: usize = 64;
+
+/// Tags of the plaintext types.
+const INTEGER_TAG: u8 = 0xf8;
+const REAL_TAG: u8 = 0xf9;
+const TEXT_TAG: u8 = 0xfa;
+const BLOB_TAG: u8 = 0xfb;
+
+/// Source of named secrets, such as encryption keys.
+pub trait SecretsProvider: Send + Sync {
+    /// Returns the secret called `name`, if there is one.
+    fn secret(&self, name: &str) -> Option<Vec<u8>>;
+}
+
+/// Secrets held in memory.
+#[derive(Default)]
+pub struct StaticSecrets {
+    secrets: HashMap<String, Vec<u8>>,
+}
+
+impl StaticSecrets {
+    /// Creates an empty set of secrets.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the secret called `name`.
+    pub fn with_secret<S: ToString>(mut self, name: S, secret: Vec<u8>) -> Self {
+        self.secrets.insert(name.to_string(), secret);
+        self
+    }
+}
+
+impl SecretsProvider for StaticSecrets {
+    fn secret(&self, name: &str) -> Option<Vec<u8>> {
+        self.secrets.get(name).cloned()
+    }
+}
+
+/// The `encrypt` and `decrypt` SQL functions, with keys from `secrets`.
+pub fn encryption_functions(secrets: Arc<dyn SecretsProvider>) -> Vec<FunctionDef> {
+    let encrypt_secrets = secrets.clone();
+    vec![
+        FunctionDef::new("encrypt", 2, move |args| match &args[1] {
+            Value::Null => Ok(Value::Null),
+            plaintext => {
+                let cipher = cipher(encrypt_secrets.as_ref(), &args[0])?;
+                let ciphertext = cipher
+                    .encrypt(&nonce(), encode(plaintext).as_slice())
+                    .map_err(|_| String::from("encryption failed"))?;
+                Ok(Value::Text(to_hex(&ciphertext)))
+            }
+        }),
+        FunctionDef::new("decrypt", 2, move |args| match &args[1] {
+            Value::Null => Ok(Value::Null),
+            ciphertext => {
+                let cipher = cipher(secrets.as_ref(), &args[0])?;
+                let ciphertext = from_hex(&ciphertext.to_string())
+                    .ok_or_else(|| String::from("ciphertext is not hex"))?;
+                let plaintext = cipher
+                    .decrypt(&nonce(), ciphertext.as_slice())
+                    .map_err(|_| String::from("decryption failed"))?;
+                decode(plaintext)
+            }
+        }),
+    ]
+}
+
+/// Encodes `value` as a plaintext tagged with its type.
+fn encode(value: &Value) -> Vec<u8> {
+    let (tag, bytes) = match value {
+        Value::Integer(i) => (INTEGER_TAG, i.to_be_bytes().to_vec()),
+        Value::Real(r) => (REAL_TAG, r.to_be_bytes().to_vec()),
+        Value::Text(s) => (TEXT_TAG, s.as_bytes().to_vec()),
+        Value::Blob(b) => (BLOB_TAG, b.clone()),
+        Value::Null => unreachable!("NULL is never encrypted"),
+    };
+    let mut plaintext = Vec::with_capacity(bytes.len() + 1);
+    plaintext.push(tag);
+    plaintext.extend(bytes);
+    plaintext
+}
+
+/// Decodes a plaintext written by `encode`.
+fn decode(plaintext: Vec<u8>) -> Result<Value, String> {
+    let number = |bytes: &[u8]| -> Result<[u8; 8], String> {
+        bytes
+            .try_into()
+            .map_err(|_| String::from("plaintext is not a number"))
+    };
+    match plaintext.split_first() {
+        Some((&INTEGER_TAG, bytes)) => Ok(Value::Integer(i64::from_be_bytes(number(bytes)?))),
+        Some((&REAL_TAG, bytes)) => Ok(Value::Real(f64::from_be_bytes(number(bytes)?))),
+        Some((&TEXT_TAG, bytes)) => String::from_utf8(bytes.to_vec())
+            .map(Value::Text)
+            .map_err(|_| String::from("plaintext is not UTF-8")),
+        Some((&BLOB_TAG, bytes)) => Ok(Value::Blob(bytes.to_vec())),
+        Some((tag, _)) => Err(format!("plaintext has unknown type tag {:#04x}", tag)),
+        None => Err(String::from("plaintext has no type tag")),
+    }
+}
+
+fn cipher(secrets: &dyn SecretsProvider, key_name: &Value) -> Result<Aes256SivAead, String> {
+    let key_name = key_name.to_string();
+    let key = secrets
+        .secret(&key_name)
+        .ok_or_else(|| format!("unknown key {}", key_name))?;
+    if key.len() != KEY_LEN {
+        return Err(format!("key {} is not {} bytes", key_name, KEY_LEN));
+    }
+    Ok(Aes256SivAead::new(GenericArray::from_slice(&key)))
+}
+
+/// Fixed nonce: with SIV, leaving out a random nonce is what makes the
+/// encryption deterministic.
+fn nonce() -> GenericArray<u8, <Aes256SivAead as aes_siv::aead::AeadCore>::NonceSize> {
+    GenericArray::default()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -3,6 +3,7 @@
 use thiserror::Error;
 
 /// Errors encountered in the store layer.
+#[cfg(not(target_arch = "wasm32"))]
 #[derive(Error, Debug)]
 pub enum StoreError {
     /// SQLite error.
@@ -11,4 +12,133 @@ pub enum StoreError {
     /// This node is not a leader and cannot therefore execute the command.
     #[error("Node is not a leader")]
     NotLeader,
+    /// A statement ran past its timeout and was interrupted.
+    #[error("Statement timed out after {0:?}")]
+    StatementTimeout(std::time::Duration),
+    /// I/O error.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The replica was started with members other than the recorded ones.
+    #[error("Cluster members {requested:?} conflict with the recorded members {recorded:?}")]
+    MembershipMismatch {
+        /// Members recorded on first boot.
+        recorded: Vec<u64>,
+        /// Members the replica was started with.
+        requested: Vec<u64>,
+    },
+    /// A peer message came from a node outside the configuration or was
+    /// addressed to another node.
+    #[error("Rejected message from unknown peer {from} to {to}")]
+    UnknownPeer {
+        /// Claimed sender of the message.
+        from: u64,
+        /// Addressee of the message.
+        to: u64,
+    },
+    /// The request is malformed.
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    /// The command calls a custom SQL function this replica has not
+    /// registered.
+    #[error("Custom SQL function not registered: {0}")]
+    MissingFunction(String),
+    /// The command uses a custom collation this replica has not registered.
+    #[error("Custom SQL collation not registered: {0}")]
+    MissingCollation(String),
+    /// The command creates an FTS5 table but this replica's SQLite is built
+    /// without FTS5.
+    #[error("SQLite is built without FTS5")]
+    FullTextSearchUnavailable,
+    /// A proposal validator rejected the command before it was proposed.
+    #[error("Proposal rejected: {0}")]
+    ProposalRejected(String),
+    /// A stop sign ended the configuration, so it takes no more commands.
+    #[error("Configuration {0} is stopped")]
+    ConfigurationStopped(u32),
+    /// No statement was prepared with the id.
+    #[error("Unknown prepared statement {0}")]
+    UnknownStatement(u64),
+    /// No cursor is open with the id; it may have expired.
+    #[error("Unknown cursor {0}")]
+    UnknownCursor(u64),
+    /// The node has not applied the index of a page token in time.
+    #[error("Index {index} of the page token not applied, at {applied}")]
+    PageIndexNotApplied {
+        /// Index the page token was read at.
+        index: u64,
+        /// Index the node applied.
+        applied: u64,
+    },
+    /// The leadership was not handed over to the node in time.
+    #[error("Leadership handover to {0} timed out")]
+    HandoverTimedOut(u64),
+    /// Fetching the database of a node added by a reconfiguration failed.
+    #[error("Bootstrap failed: {0}")]
+    Bootstrap(String),
+    /// A learner asked for entries the node no longer retains; it must be
+    /// restored from a newer backup.
+    #[error("Learner at index {applied} is behind the oldest retained entry {oldest}")]
+    LearnerBehind {
+        /// Index the learner applied.
+        applied: u64,
+        /// Index of the oldest entry the node retains for learners.
+        oldest: u64,
+    },
+    /// Fetching entries for a learner from its source failed.
+    #[error("Learner failed: {0}")]
+    Learner(String),
+    /// The node has as many cursors open as it allows.
+    #[error("Too many open cursors, at most {0}")]
+    TooManyCursors(usize),
+    /// The tenant moved to another cluster, which now takes its commands.
+    #[error("Tenant {tenant} moved to cluster {cluster}")]
+    TenantMoved {
+        /// The tenant.
+        tenant: String,
+        /// Name of the cluster the tenant moved to.
+        cluster: String,
+    },
+    /// Building Arrow data failed.
+    #[cfg(feature = "arrow-export")]
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow::error::ArrowError),
+    /// Writing a Parquet file failed.
+    #[cfg(feature = "arrow-export")]
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+}
+
+/// Errors encountered in the client.
+#[derive(Error, Debug)]
+pub enum ClientError {
+    /// Connecting to the node failed.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("Transport error: {0}")]
+    Transport(#[from] tonic::transport::Error),
+    /// The node address is not a valid URI.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("Invalid node address: {0}")]
+    InvalidUri(#[from] tonic::codegen::http::uri::InvalidUri),
+    /// The node returned an error status.
+    #[error("Request failed: {0}")]
+    Status(#[from] tonic::Status),
+    /// No cluster serves the namespace.
+    #[error("No cluster for namespace {0}")]
+    UnknownNamespace(String),
+    /// The client has no node of that name.
+    #[error("Unknown node {0}")]
+    UnknownNode(String),
+    /// The offline queue has no room for another write.
+    #[error("Offline queue is full ({0} writes)")]
+    QueueFull(usize),
+    /// The response rows do not match the checksum the node sent, or the
+    /// node sent none.
+    #[error("Response checksum mismatch")]
+    ChecksumMismatch,
+    /// The results have no column of that name.
+    #[error("Unknown column {0}")]
+    UnknownColumn(String),
+    /// A JSON column or row does not deserialize into the requested type.
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
 }
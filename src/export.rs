@@ -0,0 +1,301 @@
+//! Exports of the applied state for analytics.
+//!
+//! Scanning whole tables through the cluster competes with client traffic.
+//! `export_tables` instead writes selected tables of a `ReadSnapshot` to
+//! Parquet or Arrow IPC files, one per table, which analytical systems can
+//! ingest on their own. All files of an export are read from the same
+//! snapshot, so they hold a consistent point in time: the applied index it
+//! was taken at, which is also recorded in the schema metadata of every
+//! file under `chiselstore.applied_idx`.
+//!
+//! SQLite columns have no fixed type, so each column is typed after the
+//! storage classes of its values: integers become `Int64`, reals (or a mix
+//! of integers and reals) `Float64`, texts `Utf8` and blobs `Binary`.
+//! Columns mixing other storage classes are exported as their text
+//! rendering, and columns holding only NULLs as `Utf8`.
+
+use crate::analytics::ReadSnapshot;
+use crate::errors::StoreError;
+use crate::server::QueryRow;
+use crate::value::{quote_identifier, Value};
+use arrow::array::{ArrayRef, BinaryBuilder, Float64Builder, Int64Builder, StringBuilder};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Schema metadata key holding the applied index of the export.
+pub const APPLIED_IDX_KEY: &str = "chiselstore.applied_idx";
+
+/// Rows written per record batch.
+const BATCH_ROWS: usize = 8192;
+
+/// File format of an export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Parquet files, with the `.parquet` extension.
+    Parquet,
+    /// Arrow IPC files, with the `.arrow` extension.
+    Arrow,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Arrow => "arrow",
+        }
+    }
+}
+
+/// A table written by an export.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportedTable {
+    /// Name of the table.
+    pub table: String,
+    /// Path of the file the table was written to.
+    pub path: PathBuf,
+    /// Number of rows written.
+    pub rows: u64,
+}
+
+/// The outcome of an export.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportReport {
+    /// The applied index the exported data corresponds to.
+    pub applied_idx: u64,
+    /// The tables written, in the order they were asked for.
+    pub tables: Vec<ExportedTable>,
+}
+
+/// Writes `tables` of `snapshot` to files named after them in `dir`,
+/// creating it if needed. Existing files are overwritten.
+///
+/// Take the snapshot with `StoreServer::read_snapshot`; exporting from it
+/// does not hold up the replica applying new entries.
+pub fn export_tables<S: AsRef<str>>(
+    snapshot: &ReadSnapshot,
+    tables: &[S],
+    dir: &Path,
+    format: ExportFormat,
+) -> Result<ExportReport, StoreError> {
+    std::fs::create_dir_all(dir)?;
+    let mut report = ExportReport {
+        applied_idx: snapshot.applied_idx(),
+        tables: vec![],
+    };
+    for table in tables {
+        let table = table.as_ref();
+        let path = dir.join(format!("{}.{}", file_stem(table)?, format.extension()));
+        let rows = export_table(snapshot, table, &path, format)?;
+        report.tables.push(ExportedTable {
+            table: table.to_string(),
+            path,
+            rows,
+        });
+    }
+    Ok(report)
+}
+
+/// Table names become file names, so only plain identifiers are exported.
+fn file_stem(table: &str) -> Result<&str, StoreError> {
+    let plain = !table.is_empty() && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !plain {
+        return Err(StoreError::InvalidRequest(format!(
+            "cannot export table {:?}: only letters, digits and underscores are allowed",
+            table
+        )));
+    }
+    Ok(table)
+}
+
+fn export_table(
+    snapshot: &ReadSnapshot,
+    table: &str,
+    path: &Path,
+    format: ExportFormat,
+) -> Result<u64, StoreError> {
+    let columns = table_columns(snapshot, table)?;
+    let mut fields = vec![];
+    for column in &columns {
+        let data_type = column_type(snapshot, table, column)?;
+        fields.push(Field::new(column, data_type, true));
+    }
+    let metadata = HashMap::from([(
+        APPLIED_IDX_KEY.to_string(),
+        snapshot.applied_idx().to_string(),
+    )]);
+    let schema = Arc::new(Schema::new_with_metadata(fields, metadata));
+    let mut writer = Writer::create(path, schema.clone(), format)?;
+    let select = format!(
+        "SELECT {} FROM {}",
+        columns
+            .iter()
+            .map(|column| quote_identifier(column))
+            .collect::<Vec<_>>()
+            .join(", "),
+        quote_identifier(table)
+    );
+    let mut batch = Vec::with_capacity(BATCH_ROWS);
+    let mut rows = 0;
+    let mut failed = None;
+    snapshot.for_each_row(select, |row| {
+        batch.push(row);
+        if batch.len() < BATCH_ROWS {
+            return true;
+        }
+        rows += batch.len() as u64;
+        failed = write_batch(&mut writer, &schema, &std::mem::take(&mut batch)).err();
+        failed.is_none()
+    })?;
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    if !batch.is_empty() {
+        rows += batch.len() as u64;
+        write_batch(&mut writer, &schema, &batch)?;
+    }
+    writer.finish()?;
+    Ok(rows)
+}
+
+/// The columns of `table`, in declaration order.
+fn table_columns(snapshot: &ReadSnapshot, table: &str) -> Result<Vec<String>, StoreError> {
+    let results = snapshot.query(format!("PRAGMA table_info({})", quote_identifier(table)))?;
+    if results.rows.is_empty() {
+        return Err(StoreError::InvalidRequest(format!(
+            "no such table: {}",
+            table
+        )));
+    }
+    Ok(results
+        .rows
+        .into_iter()
+        .filter_map(|row| row.values.into_iter().nth(1))
+        .collect())
+}
+
+/// The Arrow type of `column`, after the storage classes of its values.
+fn column_type(snapshot: &ReadSnapshot, table: &str, column: &str) -> Result<DataType, StoreError> {
+    let results = snapshot.query(format!(
+        "SELECT DISTINCT typeof({}) FROM {}",
+        quote_identifier(column),
+        quote_identifier(table)
+    ))?;
+    let classes: BTreeSet<_> = results
+        .rows
+        .into_iter()
+        .filter_map(|row| row.values.into_iter().next())
+        .filter(|class| class != "null")
+        .collect();
+    let classes: Vec<_> = classes.iter().map(String::as_str).collect();
+    Ok(match classes.as_slice() {
+        ["integer"] => DataType::Int64,
+        ["real"] | ["integer", "real"] => DataType::Float64,
+        ["blob"] => DataType::Binary,
+        _ => DataType::Utf8,
+    })
+}
+
+/// Appends `rows` to the file as one record batch.
+fn write_batch(
+    writer: &mut Writer,
+    schema: &Arc<Schema>,
+    rows: &[QueryRow],
+) -> Result<(), StoreError> {
+    let mut arrays: Vec<ArrayRef> = vec![];
+    for (i, field) in schema.fields().iter().enumerate() {
+        arrays.push(column_array(field.data_type(), rows, i)?);
+    }
+    let batch = RecordBatch::try_new(schema.clone(), arrays)?;
+    writer.write(&batch)
+}
+
+/// Builds the array of column `i` of `rows`, typed `data_type`.
+fn column_array(data_type: &DataType, rows: &[QueryRow], i: usize) -> Result<ArrayRef, StoreError> {
+    let values = rows
+        .iter()
+        .map(|row| (&row.typed_values[i], &row.values[i]));
+    let array: ArrayRef = match data_type {
+        DataType::Int64 => {
+            let mut builder = Int64Builder::new(rows.len());
+            for (value, _) in values {
+                match value {
+                    Value::Integer(v) => builder.append_value(*v)?,
+                    _ => builder.append_null()?,
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Float64 => {
+            let mut builder = Float64Builder::new(rows.len());
+            for (value, _) in values {
+                match value {
+                    Value::Real(v) => builder.append_value(*v)?,
+                    Value::Integer(v) => builder.append_value(*v as f64)?,
+                    _ => builder.append_null()?,
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Binary => {
+            let mut builder = BinaryBuilder::new(rows.len());
+            for (value, _) in values {
+                match value {
+                    Value::Blob(v) => builder.append_value(v)?,
+                    _ => builder.append_null()?,
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        _ => {
+            let mut builder = StringBuilder::new(rows.len());
+            for (value, text) in values {
+                match value {
+                    Value::Null => builder.append_null()?,
+                    _ => builder.append_value(text)?,
+                }
+            }
+            Arc::new(builder.finish())
+        }
+    };
+    Ok(array)
+}
+
+/// Writes record batches to a file in either format.
+enum Writer {
+    Parquet(ArrowWriter<File>),
+    Arrow(FileWriter<File>),
+}
+
+impl Writer {
+    fn create(path: &Path, schema: Arc<Schema>, format: ExportFormat) -> Result<Self, StoreError> {
+        let file = File::create(path)?;
+        Ok(match format {
+            ExportFormat::Parquet => Writer::Parquet(ArrowWriter::try_new(file, schema, None)?),
+            ExportFormat::Arrow => Writer::Arrow(FileWriter::try_new(file, &schema)?),
+        })
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), StoreError> {
+        match self {
+            Writer::Parquet(writer) => writer.write(batch)?,
+            Writer::Arrow(writer) => writer.write(batch)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), StoreError> {
+        match self {
+            Writer::Parquet(writer) => {
+                writer.close()?;
+            }
+            Writer::Arrow(mut writer) => writer.finish()?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! Injected client errors for testing retry logic.
+//!
+//! With the `fault-injection` feature, a node can be set up to fail a share
+//! of the client requests it admits with the retryable errors a struggling
+//! cluster returns: no leader, a timeout or overload. The requests are
+//! failed before any work is done, so applications can exercise their retry
+//! logic against a healthy cluster without their writes being applied
+//! twice. Injected errors carry the `injected-fault` metadata key, set to
+//! the name of the fault.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A retryable error to inject.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// `UNAVAILABLE` with a `leader-hint`, as from a node that lost the
+    /// leadership.
+    NotLeader,
+    /// `DEADLINE_EXCEEDED`, as from a request that timed out.
+    Timeout,
+    /// `RESOURCE_EXHAUSTED` with a `retry-after-ms`, as from an overloaded
+    /// node.
+    Busy,
+}
+
+impl Fault {
+    /// Name of the fault, sent in the `injected-fault` metadata key.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fault::NotLeader => "not-leader",
+            Fault::Timeout => "timeout",
+            Fault::Busy => "busy",
+        }
+    }
+}
+
+/// Which client requests fail, and how.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultInjection {
+    /// Share of the requests that fail, from 0 to 1.
+    pub probability: f64,
+    /// Faults to pick from, uniformly, for each failed request.
+    pub faults: Vec<Fault>,
+    /// Seed of the draws, to replay a sequence of faults; `None` to seed
+    /// from the clock.
+    pub seed: Option<u64>,
+}
+
+impl Default for FaultInjection {
+    fn default() -> Self {
+        Self {
+            probability: 0.1,
+            faults: vec![Fault::NotLeader, Fault::Timeout, Fault::Busy],
+            seed: None,
+        }
+    }
+}
+
+/// Draws the faults of a `FaultInjection`.
+#[derive(Debug)]
+pub(crate) struct FaultInjector {
+    config: FaultInjection,
+    state: AtomicU64,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(config: FaultInjection) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+        Self {
+            config,
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    /// Returns the fault to fail the next request with, if any.
+    pub(crate) fn draw(&self) -> Option<Fault> {
+        if self.config.faults.is_empty() {
+            return None;
+        }
+        // Compare with 53 random bits, the precision of an f64.
+        let sample = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        if sample >= self.config.probability {
+            return None;
+        }
+        let pick = self.next() % self.config.faults.len() as u64;
+        Some(self.config.faults[pick as usize])
+    }
+
+    /// Next output of a SplitMix64 generator.
+    fn next(&self) -> u64 {
+        const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut z = self
+            .state
+            .fetch_add(GAMMA, Ordering::Relaxed)
+            .wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
@@ -0,0 +1,298 @@
+//! Full-text search with FTS5.
+//!
+//! With `StoreConfig::full_text_search`, commands may create FTS5 virtual
+//! tables with `CREATE VIRTUAL TABLE ... USING fts5(...)` and query them with
+//! `MATCH` like any other table; without it, proposing such a command
+//! fails. A node only starts with the flag if its SQLite is built with FTS5.
+//!
+//! An FTS5 index holds the tokens of the indexed text, so the replicas only
+//! keep identical indexes if they tokenize alike. Tables may therefore only
+//! use the tokenizers built into FTS5, and the node proposing a table
+//! without a `tokenize` option pins FTS5's default in the replicated
+//! statement, so the tokenizer is part of the log rather than of each
+//! replica's build. A replica without FTS5 halts when it is to apply a
+//! command creating an FTS5 table, instead of diverging from the others.
+
+use crate::errors::StoreError;
+use crate::rows;
+use crate::value::Value;
+use sqlite::Connection;
+
+/// The tokenizers built into FTS5.
+pub const TOKENIZERS: [&str; 4] = ["ascii", "porter", "trigram", "unicode61"];
+
+/// The `tokenize` option of tables that do not set one.
+const DEFAULT_TOKENIZE: &str = "tokenize = 'unicode61'";
+
+/// Returns true if the SQLite of `conn` is built with FTS5.
+pub(crate) fn available(conn: &Connection) -> Result<bool, StoreError> {
+    let results = rows::run(
+        conn,
+        "SELECT sqlite_compileoption_used('ENABLE_FTS5')".to_string(),
+        &[],
+    )?;
+    Ok(matches!(
+        results.rows.first().and_then(|row| row.typed_values.first()),
+        Some(Value::Integer(used)) if *used != 0
+    ))
+}
+
+/// Returns true if `sql` creates an FTS5 table.
+pub(crate) fn creates_table(sql: &str) -> bool {
+    mentions_fts5(sql) && !tables(sql).is_empty()
+}
+
+/// Fails with `StoreError::InvalidRequest` if `sql` creates an FTS5 table
+/// while full-text search is not `enabled`, or one using a tokenizer that is
+/// not built into FTS5.
+pub(crate) fn check(sql: &str, enabled: bool) -> Result<(), StoreError> {
+    if !mentions_fts5(sql) {
+        return Ok(());
+    }
+    for table in tables(sql) {
+        if !enabled {
+            return Err(StoreError::InvalidRequest(
+                "full-text search is disabled; enable StoreConfig::full_text_search to create FTS5 tables"
+                    .to_string(),
+            ));
+        }
+        if let Some(tokenize) = &table.tokenize {
+            let mut words = tokenize.split_whitespace();
+            let tokenizer = words.next().unwrap_or_default();
+            // `porter` wraps another tokenizer, given as its first argument.
+            let wrapped = match tokenizer {
+                "porter" => words.next(),
+                _ => None,
+            };
+            for name in std::iter::once(tokenizer).chain(wrapped) {
+                if !TOKENIZERS.contains(&name) {
+                    return Err(StoreError::InvalidRequest(format!(
+                        "FTS5 tokenizer {:?} is not built into FTS5, so replicas could index differently",
+                        name
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns `sql` with FTS5's default tokenizer set on the FTS5 tables it
+/// creates without a `tokenize` option.
+pub(crate) fn pin_tokenizers(sql: &str) -> String {
+    if !mentions_fts5(sql) {
+        return sql.to_string();
+    }
+    let mut out = String::with_capacity(sql.len() + DEFAULT_TOKENIZE.len());
+    let mut copied = 0;
+    for table in tables(sql) {
+        if table.tokenize.is_some() {
+            continue;
+        }
+        out.push_str(&sql[copied..table.args_end]);
+        if !sql[table.args_start..table.args_end].trim().is_empty() {
+            out.push_str(", ");
+        }
+        out.push_str(DEFAULT_TOKENIZE);
+        copied = table.args_end;
+    }
+    out.push_str(&sql[copied..]);
+    out
+}
+
+fn mentions_fts5(sql: &str) -> bool {
+    sql.to_ascii_lowercase().contains("fts5")
+}
+
+/// An FTS5 table created by a statement.
+struct Table {
+    /// Byte range of the module arguments, between the parentheses.
+    args_start: usize,
+    args_end: usize,
+    /// Value of the `tokenize` option, unquoted.
+    tokenize: Option<String>,
+}
+
+/// The FTS5 tables `sql` creates, in order.
+fn tables(sql: &str) -> Vec<Table> {
+    let tokens = tokenize(sql);
+    let mut tables = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        match parse_table(sql, &tokens[i..]) {
+            Some((table, consumed)) => {
+                tables.push(table);
+                i += consumed;
+            }
+            None => i += 1,
+        }
+    }
+    tables
+}
+
+/// Parses `CREATE VIRTUAL TABLE [IF NOT EXISTS] name USING fts5(...)` at
+/// the start of `tokens`, returning the table and the number of tokens it
+/// spans.
+fn parse_table(sql: &str, tokens: &[Token]) -> Option<(Table, usize)> {
+    let mut i = 0;
+    for keyword in ["create", "virtual", "table"] {
+        if !tokens.get(i)?.is_word(keyword) {
+            return None;
+        }
+        i += 1;
+    }
+    if tokens.get(i)?.is_word("if") {
+        i += 3;
+    }
+    // The table name, possibly qualified with its schema.
+    i += 1;
+    if tokens.get(i)?.is_punct('.') {
+        i += 2;
+    }
+    if !tokens.get(i)?.is_word("using") || !tokens.get(i + 1)?.is_word("fts5") {
+        return None;
+    }
+    i += 2;
+    let open = tokens.get(i)?;
+    if !open.is_punct('(') {
+        return None;
+    }
+    i += 1;
+    let args_start = open.end;
+    let mut depth = 0usize;
+    // Tokens of the argument being read.
+    let mut arg: Vec<&Token> = vec![];
+    let mut tokenize = None;
+    loop {
+        let token = tokens.get(i)?;
+        i += 1;
+        match token.kind {
+            Kind::Punct('(') => depth += 1,
+            Kind::Punct(')') if depth > 0 => depth -= 1,
+            Kind::Punct(',' | ')') if depth == 0 => {
+                if let [name, equals, value, ..] = arg.as_slice() {
+                    if name.is_word("tokenize") && equals.is_punct('=') {
+                        tokenize = Some(unquote(&sql[value.start..value.end]));
+                    }
+                }
+                arg.clear();
+                if token.is_punct(')') {
+                    let table = Table {
+                        args_start,
+                        args_end: token.start,
+                        tokenize,
+                    };
+                    return Some((table, i));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        arg.push(token);
+    }
+}
+
+/// Strips the quotes of a string literal or quoted identifier.
+fn unquote(s: &str) -> String {
+    let (open, close) = match s.chars().next() {
+        Some('\'') => ('\'', '\''),
+        Some('"') => ('"', '"'),
+        Some('`') => ('`', '`'),
+        Some('[') => ('[', ']'),
+        _ => return s.to_string(),
+    };
+    if s.len() < 2 || !s.ends_with(close) {
+        return s.to_string();
+    }
+    let inner = &s[open.len_utf8()..s.len() - close.len_utf8()];
+    match open {
+        '[' => inner.to_string(),
+        quote => inner.replace(&format!("{}{}", quote, quote), &quote.to_string()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Word,
+    Quoted,
+    Punct(char),
+}
+
+#[derive(Debug)]
+struct Token<'a> {
+    kind: Kind,
+    text: &'a str,
+    start: usize,
+    end: usize,
+}
+
+impl Token<'_> {
+    fn is_word(&self, word: &str) -> bool {
+        self.kind == Kind::Word && self.text.eq_ignore_ascii_case(word)
+    }
+
+    fn is_punct(&self, c: char) -> bool {
+        self.kind == Kind::Punct(c)
+    }
+}
+
+/// Splits `sql` into words, quoted literals and identifiers, and
+/// punctuation, skipping whitespace and comments.
+fn tokenize(sql: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut chars = sql.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            c if c.is_whitespace() => continue,
+            '-' if matches!(chars.peek(), Some((_, '-'))) => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '/' if matches!(chars.peek(), Some((_, '*'))) => {
+                chars.next();
+                let mut star = false;
+                for (_, c) in chars.by_ref() {
+                    if star && c == '/' {
+                        break;
+                    }
+                    star = c == '*';
+                }
+                continue;
+            }
+            '\'' | '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                while let Some((_, d)) = chars.next() {
+                    if d == close {
+                        // A doubled quote is an escaped one.
+                        if close != ']' && matches!(chars.peek(), Some((_, d)) if *d == close) {
+                            chars.next();
+                            continue;
+                        }
+                        break;
+                    }
+                }
+                Kind::Quoted
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                while matches!(chars.peek(), Some((_, d)) if d.is_alphanumeric() || *d == '_' || *d == '$')
+                {
+                    chars.next();
+                }
+                Kind::Word
+            }
+            c => Kind::Punct(c),
+        };
+        let end = chars.peek().map(|(i, _)| *i).unwrap_or(sql.len());
+        tokens.push(Token {
+            kind,
+            text: &sql[start..end],
+            start,
+            end,
+        });
+    }
+    tokens
+}
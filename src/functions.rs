@@ -0,0 +1,457 @@
+//! Custom scalar SQL functions and collations.
+//!
+//! Functions are registered with `sqlite3_create_function_v2`, and
+//! collations with `sqlite3_create_collation_v2`, on every pooled
+//! connection, so replicated statements can use them. They must be
+//! deterministic: every replica applies the same statements and has to
+//! compute the same results.
+//!
+//! Embedders register their functions and collations in a
+//! `FunctionRegistry`, each with a version to bump whenever its results
+//! change. Each replicated command records the registered functions its SQL
+//! calls and the collations it uses, as `name@version`, and a replica
+//! missing one of them, or having another version, refuses to apply the
+//! command instead of failing or sorting differently from the others.
+//! Replicas also advertise a fingerprint of their registry so mismatches
+//! can be spotted up front.
+//!
+//! Panics never unwind into SQLite: a function that panics fails the
+//! statement with an error, and a collation that panics compares the two
+//! texts as equal.
+
+use crate::checksum;
+use crate::errors::StoreError;
+use crate::rows::transient;
+use crate::value::Value;
+use sqlite::Connection;
+use sqlite3_sys as ffi;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+/// A scalar SQL function.
+pub type ScalarFunction = dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync;
+
+/// A comparison of two texts for `COLLATE`.
+pub type CollationFunction = dyn Fn(&str, &str) -> Ordering + Send + Sync;
+
+/// `SQLITE_DETERMINISTIC`: the function always returns the same result for
+/// the same arguments, so SQLite may use it in indexes and constraints.
+const DETERMINISTIC: c_int = 0x800;
+
+/// A named scalar function with a fixed number of arguments.
+#[derive(Clone)]
+pub struct FunctionDef {
+    /// Name the function is called by in SQL.
+    pub name: String,
+    /// Number of arguments, or -1 for any number.
+    pub n_args: i32,
+    /// Version of the function's behavior, part of the registry fingerprint.
+    pub version: u32,
+    /// The function.
+    pub func: Arc<ScalarFunction>,
+}
+
+impl std::fmt::Debug for FunctionDef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FunctionDef")
+            .field("name", &self.name)
+            .field("n_args", &self.n_args)
+            .field("version", &self.version)
+            .finish()
+    }
+}
+
+impl FunctionDef {
+    /// Creates a function definition.
+    pub fn new<F>(name: &str, n_args: i32, func: F) -> Self
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        Self {
+            name: name.to_lowercase(),
+            n_args,
+            version: 1,
+            func: Arc::new(func),
+        }
+    }
+
+    /// Sets the version of the function's behavior. Bump it whenever the
+    /// function starts returning different results.
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+}
+
+/// A named collation.
+#[derive(Clone)]
+pub struct CollationDef {
+    /// Name the collation is used by in SQL, as in `COLLATE name`.
+    pub name: String,
+    /// Version of the collation's ordering, part of the registry
+    /// fingerprint.
+    pub version: u32,
+    /// The comparison.
+    pub compare: Arc<CollationFunction>,
+}
+
+impl std::fmt::Debug for CollationDef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CollationDef")
+            .field("name", &self.name)
+            .field("version", &self.version)
+            .finish()
+    }
+}
+
+impl CollationDef {
+    /// Creates a collation definition.
+    pub fn new<F>(name: &str, compare: F) -> Self
+    where
+        F: Fn(&str, &str) -> Ordering + Send + Sync + 'static,
+    {
+        Self {
+            name: name.to_lowercase(),
+            version: 1,
+            compare: Arc::new(compare),
+        }
+    }
+
+    /// Sets the version of the collation's ordering. Bump it whenever the
+    /// collation starts ordering texts differently.
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+}
+
+/// Custom SQL functions and collations registered on every replica.
+#[derive(Debug, Clone, Default)]
+pub struct FunctionRegistry {
+    functions: BTreeMap<String, FunctionDef>,
+    collations: BTreeMap<String, CollationDef>,
+}
+
+impl FunctionRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `def`, replacing a function of the same name.
+    pub fn register(&mut self, def: FunctionDef) {
+        self.functions.insert(def.name.clone(), def);
+    }
+
+    /// Adds `def`, replacing a function of the same name.
+    pub fn with(mut self, def: FunctionDef) -> Self {
+        self.register(def);
+        self
+    }
+
+    /// Adds `def`, replacing a collation of the same name.
+    pub fn register_collation(&mut self, def: CollationDef) {
+        self.collations.insert(def.name.clone(), def);
+    }
+
+    /// Adds `def`, replacing a collation of the same name.
+    pub fn with_collation(mut self, def: CollationDef) -> Self {
+        self.register_collation(def);
+        self
+    }
+
+    /// Returns true if a function called `name` is registered.
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(&name.to_lowercase())
+    }
+
+    /// Returns true if the function `reference` names is registered:
+    /// `name@version` requires that version, a bare name any.
+    pub fn has_function(&self, reference: &str) -> bool {
+        let (name, version) = parse_reference(reference);
+        matches!(
+            self.functions.get(&name),
+            Some(def) if version.map_or(true, |version| version == def.version)
+        )
+    }
+
+    /// Returns true if the collation `reference` names is registered, like
+    /// `has_function`.
+    pub fn has_collation(&self, reference: &str) -> bool {
+        let (name, version) = parse_reference(reference);
+        matches!(
+            self.collations.get(&name),
+            Some(def) if version.map_or(true, |version| version == def.version)
+        )
+    }
+
+    /// Returns the registered functions, ordered by name.
+    pub fn functions(&self) -> impl Iterator<Item = &FunctionDef> {
+        self.functions.values()
+    }
+
+    /// Returns the registered collations, ordered by name.
+    pub fn collations(&self) -> impl Iterator<Item = &CollationDef> {
+        self.collations.values()
+    }
+
+    /// Returns the registered functions that `sql` calls, ordered by name.
+    pub fn called_by(&self, sql: &str) -> Vec<String> {
+        let sql = sql.to_lowercase();
+        self.functions
+            .keys()
+            .filter(|name| calls(&sql, name))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the registered collations that `sql` uses, ordered by name.
+    pub fn collated_by(&self, sql: &str) -> Vec<String> {
+        let sql = sql.to_lowercase();
+        self.collations
+            .keys()
+            .filter(|name| collates(&sql, name))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the registered functions `sql` calls, as the `name@version`
+    /// references commands record.
+    pub fn function_references(&self, sql: &str) -> Vec<String> {
+        self.called_by(sql)
+            .into_iter()
+            .map(|name| reference(&name, self.functions[&name].version))
+            .collect()
+    }
+
+    /// Returns the registered collations `sql` uses, as the `name@version`
+    /// references commands record.
+    pub fn collation_references(&self, sql: &str) -> Vec<String> {
+        self.collated_by(sql)
+            .into_iter()
+            .map(|name| reference(&name, self.collations[&name].version))
+            .collect()
+    }
+
+    /// Fingerprint of the registered names, arities and versions. Replicas
+    /// with the same fingerprint have the same functions and collations.
+    pub fn fingerprint(&self) -> u64 {
+        let functions = self
+            .functions
+            .values()
+            .map(|def| format!("{}/{}/{};", def.name, def.n_args, def.version));
+        let collations = self
+            .collations
+            .values()
+            .map(|def| format!("collate {}/{};", def.name, def.version));
+        let entries: String = functions.chain(collations).collect();
+        checksum::fnv1a(entries.as_bytes())
+    }
+}
+
+fn reference(name: &str, version: u32) -> String {
+    format!("{}@{}", name, version)
+}
+
+/// Splits a `name@version` reference; commands recorded before versions
+/// were carry bare names.
+fn parse_reference(reference: &str) -> (String, Option<u32>) {
+    match reference.rsplit_once('@') {
+        Some((name, version)) => match version.parse() {
+            Ok(version) => (name.to_lowercase(), Some(version)),
+            Err(_) => (reference.to_lowercase(), None),
+        },
+        None => (reference.to_lowercase(), None),
+    }
+}
+
+/// Returns true if lowercased `sql` contains a call of `name`.
+fn calls(sql: &str, name: &str) -> bool {
+    sql.match_indices(name).any(|(i, _)| {
+        let before = sql[..i].chars().last();
+        let after = sql[i + name.len()..].trim_start().chars().next();
+        !matches!(before, Some(c) if c.is_alphanumeric() || c == '_') && after == Some('(')
+    })
+}
+
+/// Returns true if lowercased `sql` contains `COLLATE name`, with `name`
+/// possibly quoted.
+fn collates(sql: &str, name: &str) -> bool {
+    sql.match_indices("collate").any(|(i, _)| {
+        let before = sql[..i].chars().last();
+        let rest = &sql[i + "collate".len()..];
+        if matches!(before, Some(c) if c.is_alphanumeric() || c == '_')
+            || !rest.starts_with(char::is_whitespace)
+        {
+            return false;
+        }
+        let rest = rest
+            .trim_start()
+            .trim_start_matches(|c| matches!(c, '"' | '`' | '[' | '\''));
+        rest.starts_with(name)
+            && !matches!(rest[name.len()..].chars().next(), Some(c) if c.is_alphanumeric() || c == '_')
+    })
+}
+
+/// Registers `def` on `conn`.
+pub(crate) fn register(conn: &mut Connection, def: &FunctionDef) -> Result<(), StoreError> {
+    let name = CString::new(def.name.clone())
+        .map_err(|_| StoreError::InvalidRequest(format!("invalid function name {}", def.name)))?;
+    let app = Box::into_raw(Box::new(def.func.clone())) as *mut c_void;
+    let rc = unsafe {
+        ffi::sqlite3_create_function_v2(
+            conn.as_raw(),
+            name.as_ptr(),
+            def.n_args as c_int,
+            ffi::SQLITE_UTF8 as c_int | DETERMINISTIC,
+            app,
+            Some(call_scalar),
+            None,
+            None,
+            Some(drop_scalar),
+        )
+    };
+    if rc != ffi::SQLITE_OK as c_int {
+        return Err(StoreError::InvalidRequest(format!(
+            "registering function {} failed with code {}",
+            def.name, rc
+        )));
+    }
+    Ok(())
+}
+
+/// Registers the collation `def` on `conn`.
+pub(crate) fn register_collation(
+    conn: &mut Connection,
+    def: &CollationDef,
+) -> Result<(), StoreError> {
+    let name = CString::new(def.name.clone())
+        .map_err(|_| StoreError::InvalidRequest(format!("invalid collation name {}", def.name)))?;
+    let app = Box::into_raw(Box::new(def.compare.clone())) as *mut c_void;
+    let rc = unsafe {
+        ffi::sqlite3_create_collation_v2(
+            conn.as_raw(),
+            name.as_ptr(),
+            ffi::SQLITE_UTF8 as c_int,
+            app,
+            Some(call_collation),
+            Some(drop_collation),
+        )
+    };
+    if rc != ffi::SQLITE_OK as c_int {
+        return Err(StoreError::InvalidRequest(format!(
+            "registering collation {} failed with code {}",
+            def.name, rc
+        )));
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn call_collation(
+    app: *mut c_void,
+    len_a: c_int,
+    a: *const c_void,
+    len_b: c_int,
+    b: *const c_void,
+) -> c_int {
+    let compare = &*(app as *const Arc<CollationFunction>);
+    let text = |s: *const c_void, len: c_int| match s.is_null() {
+        true => String::new(),
+        false => String::from_utf8_lossy(std::slice::from_raw_parts(s as *const u8, len as usize))
+            .into_owned(),
+    };
+    let ordering = panic::catch_unwind(AssertUnwindSafe(|| {
+        compare(&text(a, len_a), &text(b, len_b))
+    }));
+    match ordering.unwrap_or(Ordering::Equal) {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }
+}
+
+unsafe extern "C" fn drop_collation(app: *mut c_void) {
+    drop(Box::from_raw(app as *mut Arc<CollationFunction>));
+}
+
+unsafe extern "C" fn call_scalar(
+    ctx: *mut ffi::sqlite3_context,
+    argc: c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) {
+    let func = &*(ffi::sqlite3_user_data(ctx) as *const Arc<ScalarFunction>);
+    let args: Vec<Value> = (0..argc as isize)
+        .map(|i| read_value(*argv.offset(i)))
+        .collect();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| func(&args)))
+        .unwrap_or_else(|payload| Err(panic_message(payload.as_ref())));
+    match result {
+        Ok(value) => write_value(ctx, value),
+        Err(msg) => {
+            let msg = CString::new(msg).unwrap_or_default();
+            ffi::sqlite3_result_error(ctx, msg.as_ptr(), -1);
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    let msg = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    format!("function panicked: {}", msg)
+}
+
+unsafe extern "C" fn drop_scalar(app: *mut c_void) {
+    drop(Box::from_raw(app as *mut Arc<ScalarFunction>));
+}
+
+unsafe fn read_value(value: *mut ffi::sqlite3_value) -> Value {
+    match ffi::sqlite3_value_type(value) as u32 {
+        ffi::SQLITE_INTEGER => Value::Integer(ffi::sqlite3_value_int64(value)),
+        ffi::SQLITE_FLOAT => Value::Real(ffi::sqlite3_value_double(value)),
+        ffi::SQLITE_NULL => Value::Null,
+        ffi::SQLITE_BLOB => {
+            let blob = ffi::sqlite3_value_blob(value) as *const u8;
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            if blob.is_null() {
+                return Value::Blob(vec![]);
+            }
+            Value::Blob(std::slice::from_raw_parts(blob, len).to_vec())
+        }
+        _ => {
+            let text = ffi::sqlite3_value_text(value);
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            if text.is_null() {
+                return Value::Null;
+            }
+            let bytes = std::slice::from_raw_parts(text, len);
+            Value::Text(String::from_utf8_lossy(bytes).into_owned())
+        }
+    }
+}
+
+unsafe fn write_value(ctx: *mut ffi::sqlite3_context, value: Value) {
+    match value {
+        Value::Null => ffi::sqlite3_result_null(ctx),
+        Value::Integer(i) => ffi::sqlite3_result_int64(ctx, i),
+        Value::Real(f) => ffi::sqlite3_result_double(ctx, f),
+        Value::Text(s) => ffi::sqlite3_result_text(
+            ctx,
+            s.as_ptr() as *const c_char,
+            s.len() as c_int,
+            transient(),
+        ),
+        Value::Blob(b) => ffi::sqlite3_result_blob(
+            ctx,
+            b.as_ptr() as *const c_void,
+            b.len() as c_int,
+            transient(),
+        ),
+    }
+}
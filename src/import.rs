@@ -0,0 +1,164 @@
+//! Seeding nodes from upstream ChiselStore databases.
+//!
+//! Nodes of the original, Raft-based ChiselStore, like those of rqlite,
+//! keep their data in a plain SQLite database. `import_database` turns such
+//! a database into the database of a node of a new cluster: it copies it
+//! consistently with `VACUUM INTO`, checks that every table of the copy
+//! has the row count and checksum of the source, and then applies the
+//! statements of the upstream log not reflected in the database yet, if
+//! any. The report lists the row count and checksum of every table of the
+//! result.
+//!
+//! Like restoring a tenant, importing bypasses the log, so it must be done
+//! before the new cluster first starts, on every node, from the same
+//! database and log. Nodes seeded alike report the same tables.
+
+use crate::checksum::fnv1a;
+use crate::errors::StoreError;
+use crate::server::query_connection;
+use crate::value::quote_identifier;
+use sqlite::{Connection, OpenFlags};
+use std::path::{Path, PathBuf};
+
+/// Row count and checksum of a table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableSummary {
+    /// Name of the table.
+    pub table: String,
+    /// Number of rows.
+    pub rows: u64,
+    /// Checksum of the rows, independent of their order.
+    pub checksum: u64,
+}
+
+/// Outcome of an import.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Path of the database written.
+    pub path: PathBuf,
+    /// Number of log entries applied after copying.
+    pub log_entries: usize,
+    /// The tables of the imported database, ordered by name.
+    pub tables: Vec<TableSummary>,
+}
+
+/// Imports the upstream database at `source` into a new node database at
+/// `target`, then applies the SQL of the entries of `log`, in log order.
+///
+/// Fails without writing `target` if it exists already, if the copy does
+/// not match the source, or if a log entry fails. Use
+/// `server::database_path` for the database of a node.
+pub fn import_database<S: AsRef<str>>(
+    source: &Path,
+    target: &Path,
+    log: &[S],
+) -> Result<ImportReport, StoreError> {
+    if target.exists() {
+        return Err(StoreError::InvalidRequest(format!(
+            "{} already exists; only new nodes can be seeded",
+            target.display()
+        )));
+    }
+    let flags = OpenFlags::new().set_read_only().set_no_mutex();
+    let source_conn = Connection::open_with_flags(source, flags)?;
+    let tables = user_tables(&source_conn)?;
+    if let Some(table) = tables
+        .iter()
+        .find(|table| table.starts_with("chiselstore_"))
+    {
+        return Err(StoreError::InvalidRequest(format!(
+            "table {} uses a name reserved for ChiselStore's own tables",
+            table
+        )));
+    }
+
+    let tmp = target.with_extension("db.import");
+    let _ = std::fs::remove_file(&tmp);
+    let result = seed(&source_conn, &tables, &tmp, log);
+    match result {
+        Ok((log_entries, tables)) => {
+            std::fs::rename(&tmp, target)?;
+            Ok(ImportReport {
+                path: target.to_path_buf(),
+                log_entries,
+                tables,
+            })
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// Copies the database of `source_conn` to `path`, checks the copy, and
+/// applies `log` to it.
+fn seed<S: AsRef<str>>(
+    source_conn: &Connection,
+    tables: &[String],
+    path: &Path,
+    log: &[S],
+) -> Result<(usize, Vec<TableSummary>), StoreError> {
+    let path_literal = path.to_string_lossy().replace('\'', "''");
+    source_conn.execute(format!("VACUUM INTO '{}'", path_literal))?;
+    let conn = Connection::open_with_flags(path, OpenFlags::new().set_read_write().set_no_mutex())?;
+    for table in tables {
+        let expected = summarize(source_conn, table)?;
+        let copied = summarize(&conn, table)?;
+        if copied != expected {
+            return Err(StoreError::InvalidRequest(format!(
+                "table {} has {} rows with checksum {:x} after copying, expected {} rows with checksum {:x}",
+                table, copied.rows, copied.checksum, expected.rows, expected.checksum
+            )));
+        }
+    }
+
+    conn.execute("BEGIN")?;
+    for entry in log {
+        if let Err(e) = conn.execute(entry.as_ref()) {
+            let _ = conn.execute("ROLLBACK");
+            return Err(e.into());
+        }
+    }
+    conn.execute("COMMIT")?;
+
+    let mut summaries = vec![];
+    for table in user_tables(&conn)? {
+        summaries.push(summarize(&conn, &table)?);
+    }
+    Ok((log.len(), summaries))
+}
+
+/// The tables of `conn` other than SQLite's own, ordered by name.
+fn user_tables(conn: &Connection) -> Result<Vec<String>, StoreError> {
+    let results = query_connection(
+        conn,
+        "SELECT name FROM sqlite_master \
+         WHERE type = 'table' AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\' ORDER BY name"
+            .to_string(),
+    )?;
+    Ok(results
+        .rows
+        .into_iter()
+        .filter_map(|row| row.values.into_iter().next())
+        .collect())
+}
+
+/// Counts the rows of `table` and sums the checksums of their values.
+fn summarize(conn: &Connection, table: &str) -> Result<TableSummary, StoreError> {
+    let results = query_connection(conn, format!("SELECT * FROM {}", quote_identifier(table)))?;
+    let mut checksum = 0u64;
+    for row in &results.rows {
+        let literals = row
+            .typed_values
+            .iter()
+            .map(|v| v.to_sql_literal())
+            .collect::<Result<Vec<_>, _>>()?;
+        checksum = checksum.wrapping_add(fnv1a(literals.join(",").as_bytes()));
+    }
+    Ok(TableSummary {
+        table: table.to_string(),
+        rows: results.rows.len() as u64,
+        checksum,
+    })
+}
@@ -0,0 +1,148 @@
+//! SQL introspection of cluster internals.
+//!
+//! The `chiselstore_members`, `chiselstore_log_stats`, `chiselstore_status`,
+//! `chiselstore_statements`, `chiselstore_deprecated_calls` and
+//! `chiselstore_batching` tables expose the replica's view of the cluster
+//! to plain `SELECT`s. They
+//! are materialized as `TEMP` tables on the connection serving the query,
+//! refreshed right before the query runs, so they never reach the database
+//! file or the replicated log.
+
+use crate::batching::BatchingStatus;
+use crate::deprecation::DeprecatedUsage;
+use crate::statements::StatementStats;
+use crate::value::quote_literal;
+
+/// Tables served by the introspection path.
+pub const INTROSPECTION_TABLES: &[&str] = &[
+    "chiselstore_members",
+    "chiselstore_log_stats",
+    "chiselstore_status",
+    "chiselstore_statements",
+    "chiselstore_deprecated_calls",
+    "chiselstore_batching",
+];
+
+/// A replica's view of one cluster member.
+#[derive(Debug, Clone)]
+pub struct MemberStatus {
+    /// Node id of the member.
+    pub id: u64,
+    /// Whether the member is the replica serving the query.
+    pub is_self: bool,
+    /// Whether the member is the current leader.
+    pub is_leader: bool,
+    /// Last accepted index the member reported, if known.
+    pub accepted_idx: Option<u64>,
+}
+
+/// Snapshot of replica state rendered into the introspection tables.
+#[derive(Debug, Clone)]
+pub struct ClusterStatus {
+    /// Node id of the replica serving the query.
+    pub node_id: u64,
+    /// Lifecycle of the replica.
+    pub lifecycle: String,
+    /// Current leader (0 if unknown).
+    pub leader: u64,
+    /// Number of entries applied by the replica.
+    pub applied_idx: u64,
+    /// Number of proposals waiting for their result.
+    pub pending_commands: u64,
+    /// Number of commands skipped because they failed to apply.
+    pub apply_failures: u64,
+    /// Cluster members, including this replica.
+    pub members: Vec<MemberStatus>,
+    /// Statement statistics by fingerprint.
+    pub statements: Vec<(String, StatementStats)>,
+    /// Usage of deprecated API methods by method name.
+    pub deprecated_calls: Vec<(String, DeprecatedUsage)>,
+    /// Batching window and recent commit latencies.
+    pub batching: BatchingStatus,
+}
+
+/// Returns true if the statement reads one of the introspection tables.
+pub fn references_introspection(stmt: &str) -> bool {
+    let stmt = stmt.to_lowercase();
+    INTROSPECTION_TABLES
+        .iter()
+        .any(|table| stmt.contains(table))
+}
+
+impl ClusterStatus {
+    /// Returns SQL that (re)creates the introspection tables with this status.
+    pub(crate) fn refresh_sql(&self) -> String {
+        let mut sql = String::from(
+            "CREATE TEMP TABLE IF NOT EXISTS chiselstore_members \
+                (id INTEGER, is_self INTEGER, is_leader INTEGER, accepted_idx INTEGER);
+             CREATE TEMP TABLE IF NOT EXISTS chiselstore_log_stats \
+                (applied_idx INTEGER, pending_commands INTEGER, apply_failures INTEGER);
+             CREATE TEMP TABLE IF NOT EXISTS chiselstore_status \
+                (node_id INTEGER, lifecycle TEXT, leader INTEGER, applied_idx INTEGER);
+             CREATE TEMP TABLE IF NOT EXISTS chiselstore_statements \
+                (fingerprint TEXT, calls INTEGER, total_ms REAL, mean_ms REAL, rows INTEGER);
+             CREATE TEMP TABLE IF NOT EXISTS chiselstore_deprecated_calls \
+                (method TEXT, calls INTEGER, last_called_ms INTEGER);
+             CREATE TEMP TABLE IF NOT EXISTS chiselstore_batching \
+                (window_ms REAL, target_ms REAL, p50_ms REAL, p99_ms REAL, samples INTEGER, \
+                 max_batch_size INTEGER, full_batches INTEGER);
+             DELETE FROM temp.chiselstore_members;
+             DELETE FROM temp.chiselstore_log_stats;
+             DELETE FROM temp.chiselstore_status;
+             DELETE FROM temp.chiselstore_statements;
+             DELETE FROM temp.chiselstore_deprecated_calls;
+             DELETE FROM temp.chiselstore_batching;",
+        );
+        for member in &self.members {
+            let accepted_idx = match member.accepted_idx {
+                Some(idx) => idx.to_string(),
+                None => String::from("NULL"),
+            };
+            sql.push_str(&format!(
+                "INSERT INTO temp.chiselstore_members VALUES ({}, {}, {}, {});",
+                member.id, member.is_self as u8, member.is_leader as u8, accepted_idx
+            ));
+        }
+        sql.push_str(&format!(
+            "INSERT INTO temp.chiselstore_log_stats VALUES ({}, {}, {});",
+            self.applied_idx, self.pending_commands, self.apply_failures
+        ));
+        sql.push_str(&format!(
+            "INSERT INTO temp.chiselstore_status VALUES ({}, '{}', {}, {});",
+            self.node_id, self.lifecycle, self.leader, self.applied_idx
+        ));
+        for (fingerprint, stats) in &self.statements {
+            sql.push_str(&format!(
+                "INSERT INTO temp.chiselstore_statements VALUES ({}, {}, {:?}, {:?}, {});",
+                quote_literal(fingerprint),
+                stats.calls,
+                stats.total_time.as_secs_f64() * 1000.0,
+                stats.mean_time().as_secs_f64() * 1000.0,
+                stats.rows
+            ));
+        }
+        for (method, usage) in &self.deprecated_calls {
+            sql.push_str(&format!(
+                "INSERT INTO temp.chiselstore_deprecated_calls VALUES ({}, {}, {});",
+                quote_literal(method),
+                usage.calls,
+                usage.last_called_ms
+            ));
+        }
+        let target_ms = match self.batching.target {
+            Some(target) => format!("{:?}", target.as_secs_f64() * 1000.0),
+            None => String::from("NULL"),
+        };
+        sql.push_str(&format!(
+            "INSERT INTO temp.chiselstore_batching VALUES ({:?}, {}, {:?}, {:?}, {}, {}, {});",
+            self.batching.window.as_secs_f64() * 1000.0,
+            target_ms,
+            self.batching.p50.as_secs_f64() * 1000.0,
+            self.batching.p99.as_secs_f64() * 1000.0,
+            self.batching.samples,
+            self.batching.max_batch_size,
+            self.batching.full_batches
+        ));
+        sql
+    }
+}
@@ -0,0 +1,121 @@
+//! JSON rows and columns.
+//!
+//! SQLite's JSON1 functions, such as `json_object`, `json_extract` or the
+//! `->>` operator, work in any statement and return JSON as text. Two
+//! helpers make such results easier to consume:
+//!
+//! - With `Query::json_rows`, a node returns every row as a single `json`
+//!   column holding an object keyed by column name, for clients passing
+//!   rows on to JSON consumers as they are.
+//! - `QueryResults::json_column` and `QueryResults::json_objects`
+//!   deserialize JSON columns, and whole rows, into Rust types on the
+//!   client.
+//!
+//! In row objects, integers and reals become numbers, texts strings, blobs
+//! strings of their hex digits like `hex()` renders them, and NULLs nulls.
+//! Reals that JSON cannot represent become nulls, and of columns sharing a
+//! name the last one is kept.
+
+use crate::errors::ClientError;
+use crate::proto::QueryResults;
+use crate::value::{hex, Value};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number};
+
+/// Name of the column of JSON rows.
+pub const JSON_COLUMN: &str = "json";
+
+/// Converts `value` to JSON as row objects hold it.
+pub fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => serde_json::Value::from(*i),
+        Value::Real(f) => Number::from_f64(*f)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        Value::Text(s) => serde_json::Value::String(s.clone()),
+        Value::Blob(b) => serde_json::Value::String(hex(b)),
+    }
+}
+
+/// The object of a row with columns `names` and values `values`.
+fn row_object<'a>(
+    names: impl Iterator<Item = &'a str>,
+    values: impl Iterator<Item = Value>,
+) -> serde_json::Value {
+    let object: Map<_, _> = names
+        .zip(values)
+        .map(|(name, value)| (name.to_string(), to_json(&value)))
+        .collect();
+    serde_json::Value::Object(object)
+}
+
+/// Replaces the rows of `results` with their JSON objects.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn rows_as_objects(results: &mut crate::server::QueryResults) {
+    use crate::server::{Column, QueryRow};
+
+    let columns = std::mem::replace(
+        &mut results.columns,
+        vec![Column {
+            name: JSON_COLUMN.to_string(),
+            decl_type: None,
+            table: None,
+        }],
+    );
+    for row in &mut results.rows {
+        let values = std::mem::take(&mut row.typed_values);
+        let object = row_object(
+            columns.iter().map(|column| column.name.as_str()),
+            values.into_iter(),
+        )
+        .to_string();
+        *row = QueryRow {
+            values: vec![object.clone()],
+            typed_values: vec![Value::Text(object)],
+        };
+    }
+}
+
+impl QueryResults {
+    /// Returns the index of the column called `name`.
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+
+    /// Deserializes the JSON text of column `name` in every row; NULLs are
+    /// deserialized from JSON `null`.
+    ///
+    /// Use it on columns holding JSON, such as the results of JSON1
+    /// functions or the `json` column of a query with `json_rows`.
+    pub fn json_column<T: DeserializeOwned>(&self, name: &str) -> Result<Vec<T>, ClientError> {
+        let idx = self
+            .column_index(name)
+            .ok_or_else(|| ClientError::UnknownColumn(name.to_string()))?;
+        self.rows
+            .iter()
+            .map(|row| {
+                let value = row.typed_values.get(idx).cloned().map(Value::from);
+                Ok(match value {
+                    Some(Value::Text(text)) => serde_json::from_str(&text)?,
+                    Some(Value::Null) | None => T::deserialize(serde_json::Value::Null)?,
+                    Some(value) => T::deserialize(to_json(&value))?,
+                })
+            })
+            .collect()
+    }
+
+    /// Deserializes every row from its object keyed by column name.
+    pub fn json_objects<T: DeserializeOwned>(&self) -> Result<Vec<T>, ClientError> {
+        self.rows
+            .iter()
+            .map(|row| {
+                let object = row_object(
+                    self.columns.iter().map(|column| column.name.as_str()),
+                    row.typed_values.iter().cloned().map(Value::from),
+                );
+                Ok(T::deserialize(object)?)
+            })
+            .collect()
+    }
+}
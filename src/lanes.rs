@@ -0,0 +1,149 @@
+//! Admission lanes for local reads.
+//!
+//! Relaxed reads are admitted through one of two lanes, each with its own
+//! concurrency limit and connection pool, so that long analytical scans
+//! queue behind each other instead of in front of short lookups.
+
+use crate::errors::StoreError;
+use crate::rows;
+use crate::server::{query_connection_with_params, QueryResults, SQLiteConnection};
+use crate::value::Value;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{mpsc, Semaphore};
+
+/// Number of row batches read ahead of a streamed query's consumer.
+const STREAM_READ_AHEAD: usize = 2;
+
+/// The lane a local read is admitted through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryLane {
+    /// Short, latency-sensitive reads.
+    Transactional,
+    /// Long-running scans and reports.
+    Analytical,
+}
+
+/// A bounded admission queue in front of a connection pool.
+#[derive(Debug)]
+pub(crate) struct Lane {
+    permits: Arc<Semaphore>,
+    pool: Arc<Mutex<SQLiteConnection>>,
+}
+
+impl Lane {
+    pub(crate) fn new(concurrency: usize, pool: Arc<Mutex<SQLiteConnection>>) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency)),
+            pool,
+        }
+    }
+
+    /// Waits for a free slot in the lane and runs `sql` with `params` bound
+    /// on one of its connections, off the async runtime.
+    pub(crate) async fn query(
+        &self,
+        sql: String,
+        params: Vec<Value>,
+    ) -> Result<QueryResults, StoreError> {
+        self.query_on(&self.pool, sql, params).await
+    }
+
+    /// Like `query`, but runs `sql` on one of the connections of `pool`
+    /// instead of the lane's own.
+    pub(crate) async fn query_on(
+        &self,
+        pool: &Mutex<SQLiteConnection>,
+        sql: String,
+        params: Vec<Value>,
+    ) -> Result<QueryResults, StoreError> {
+        let queued = Instant::now();
+        let _permit = self.permits.acquire().await.unwrap();
+        let queue = queued.elapsed();
+        let conn = pool.lock().unwrap().get_connection();
+        let mut results = tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            let conn = conn.lock().unwrap();
+            query_connection_with_params(&conn, sql, &params).map(|mut results| {
+                results.timing.apply = started.elapsed();
+                results
+            })
+        })
+        .await
+        .unwrap()?;
+        results.timing.queue = queue;
+        Ok(results)
+    }
+}
+
+impl Lane {
+    /// Waits for a free slot in the lane and runs `sql` with `params` bound
+    /// on one of its connections, sending the rows in batches of up to
+    /// `batch_size` as they are read. The first batch carries the columns.
+    ///
+    /// The slot and the connection are held until the last batch is taken
+    /// or the receiver is dropped, which stops the read.
+    pub(crate) async fn stream(
+        &self,
+        sql: String,
+        params: Vec<Value>,
+        batch_size: usize,
+    ) -> mpsc::Receiver<Result<QueryResults, StoreError>> {
+        let permit = self.permits.clone().acquire_owned().await.unwrap();
+        let conn = self.pool.lock().unwrap().get_connection();
+        let (tx, rx) = mpsc::channel(STREAM_READ_AHEAD);
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let conn = conn.lock().unwrap();
+            let batch = RefCell::new(QueryResults::new(vec![]));
+            let mut batches = 0;
+            let read = rows::for_each_row(
+                &conn,
+                sql,
+                &params,
+                |columns| batch.borrow_mut().columns = columns,
+                |row| {
+                    let mut batch = batch.borrow_mut();
+                    batch.rows.push(row);
+                    if batch.rows.len() < batch_size {
+                        return true;
+                    }
+                    let full = std::mem::replace(&mut *batch, QueryResults::new(vec![]));
+                    batches += 1;
+                    tx.blocking_send(Ok(full)).is_ok()
+                },
+            );
+            let last = read.map(|()| batch.into_inner());
+            // A query without rows still sends one batch with the columns.
+            if matches!(&last, Ok(last) if last.rows.is_empty() && batches > 0) {
+                return;
+            }
+            let _ = tx.blocking_send(last);
+        });
+        rx
+    }
+}
+
+/// The transactional and analytical lanes of a replica.
+#[derive(Debug)]
+pub(crate) struct ReadLanes {
+    transactional: Lane,
+    analytical: Lane,
+}
+
+impl ReadLanes {
+    pub(crate) fn new(transactional: Lane, analytical: Lane) -> Self {
+        Self {
+            transactional,
+            analytical,
+        }
+    }
+
+    pub(crate) fn get(&self, lane: QueryLane) -> &Lane {
+        match lane {
+            QueryLane::Transactional => &self.transactional,
+            QueryLane::Analytical => &self.analytical,
+        }
+    }
+}
@@ -0,0 +1,598 @@
+//! Learners: non-voting replicas.
+//!
+//! A learner follows the log of a cluster without being a member of it: it
+//! never votes, never becomes leader and does not count towards any quorum,
+//! so adding learners costs the cluster nothing but the entries they fetch.
+//! Voters keep the commands they applied last, up to
+//! `StoreConfig::learner_feed_capacity`, and a learner started from a
+//! backup of a voter, see `StoreServer::backup`, fetches and applies the
+//! commands following it, serving relaxed reads from its own database.
+//!
+//! A learner also stages a node for a reconfiguration: once a
+//! reconfiguration adds the learner's id to the cluster, the learner stops
+//! at the stop sign, where its database matches the state the members of
+//! the new configuration start from. `Learner::promote` then makes it the
+//! database of the node, which starts in the new configuration with the
+//! returned `JoinedConfiguration` in `StoreConfig::join`, like a node
+//! bootstrapped with `bootstrap::join_cluster`, without copying the whole
+//! database at the reconfiguration.
+//!
+//! A learner applies each batch of commands it fetches in one transaction,
+//! together with the index it applied through, and each command of a
+//! tenant along with the index recorded in the tenant's database, so a
+//! learner that restarts in the middle of a batch picks up where its
+//! databases left off without applying any command twice. Commands that
+//! begin or end a transaction themselves are the exception: they are
+//! applied outside of one, and a crash right after one of them applies it
+//! again.
+//!
+//! A learner that falls further behind than the voter retains commands
+//! fails with `StoreError::LearnerBehind` and must be restored from a newer
+//! backup.
+
+use crate::backup::{self, BACKUP_TABLE};
+use crate::bootstrap::JoinedConfiguration;
+use crate::errors::StoreError;
+use crate::functions::FunctionRegistry;
+use crate::migration::{self, TenantChange};
+use crate::proto::rpc_client::RpcClient;
+use crate::proto::LearnerFetch;
+use crate::replay::{self, ReplayFailure};
+use crate::rpc::get_entry_from_proto;
+use crate::server::{database_path, query_connection, QueryResults, StoreCommand};
+use crate::statements;
+use crate::tenants;
+use derivative::Derivative;
+use sqlite::Connection;
+use sqlite3_sys as ffi;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of commands a learner fetches at once.
+const LEARNER_BATCH_SIZE: u32 = 256;
+
+/// Where a configuration started in the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigurationStart {
+    /// Id of the configuration.
+    pub config_id: u32,
+    /// Members of the configuration.
+    pub members: Vec<u64>,
+    /// Number of log entries applied when its stop sign was decided.
+    pub applied_idx: u64,
+}
+
+/// Commands a voter serves a learner.
+#[derive(Clone, Debug, Default)]
+pub struct LearnerBatch {
+    /// Applied commands with their applied index, in log order.
+    pub entries: Vec<(u64, StoreCommand)>,
+    /// Configurations that started at or after the index the learner asked
+    /// from.
+    pub configurations: Vec<ConfigurationStart>,
+}
+
+/// The commands a voter applied last, kept for learners.
+#[derive(Debug)]
+pub(crate) struct LearnerFeed {
+    capacity: usize,
+    /// Applied index the next recorded command gets.
+    next_idx: u64,
+    entries: VecDeque<(u64, StoreCommand)>,
+    configurations: Vec<ConfigurationStart>,
+}
+
+impl LearnerFeed {
+    pub(crate) fn new(capacity: usize, applied_idx: u64) -> Self {
+        Self {
+            capacity,
+            next_idx: applied_idx + 1,
+            entries: VecDeque::new(),
+            configurations: vec![],
+        }
+    }
+
+    /// Records `cmd`, applied at `applied_idx`, dropping the oldest command
+    /// if the feed is full.
+    pub(crate) fn record(&mut self, applied_idx: u64, cmd: &StoreCommand) {
+        self.next_idx = applied_idx + 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((applied_idx, cmd.clone()));
+        let oldest = self.oldest_idx();
+        self.configurations
+            .retain(|start| start.applied_idx + 1 >= oldest);
+    }
+
+    /// Records that configuration `config_id` of `members` started after
+    /// `applied_idx` entries.
+    pub(crate) fn start_configuration(
+        &mut self,
+        config_id: u32,
+        members: Vec<u64>,
+        applied_idx: u64,
+    ) {
+        self.configurations.push(ConfigurationStart {
+            config_id,
+            members,
+            applied_idx,
+        });
+    }
+
+    /// Returns up to `max` commands following `after_idx`.
+    pub(crate) fn entries_after(
+        &self,
+        after_idx: u64,
+        max: usize,
+    ) -> Result<LearnerBatch, StoreError> {
+        let oldest = self.oldest_idx();
+        if after_idx + 1 < oldest {
+            return Err(StoreError::LearnerBehind {
+                applied: after_idx,
+                oldest,
+            });
+        }
+        Ok(LearnerBatch {
+            entries: self
+                .entries
+                .iter()
+                .filter(|(idx, _)| *idx > after_idx)
+                .take(max)
+                .cloned()
+                .collect(),
+            configurations: self
+                .configurations
+                .iter()
+                .filter(|start| start.applied_idx >= after_idx)
+                .cloned()
+                .collect(),
+        })
+    }
+
+    fn oldest_idx(&self) -> u64 {
+        self.entries
+            .front()
+            .map(|(idx, _)| *idx)
+            .unwrap_or(self.next_idx)
+    }
+}
+
+/// A non-voting replica following the cluster through a voter.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Learner {
+    id: u64,
+    source: String,
+    path: PathBuf,
+    functions: FunctionRegistry,
+    tenant_dir: PathBuf,
+    #[derivative(Debug = "ignore")]
+    conn: Mutex<Connection>,
+    /// Open tenant databases, with the index each applied through.
+    #[derivative(Debug = "ignore")]
+    tenants: Mutex<HashMap<String, (Connection, u64)>>,
+    applied_idx: AtomicU64,
+    failures: Mutex<Vec<ReplayFailure>>,
+    promotion: Mutex<Option<JoinedConfiguration>>,
+}
+
+impl Learner {
+    /// Opens learner `id` on the database at `path`, a backup of a voter
+    /// written by `StoreServer::backup`, following the voter at `source`.
+    ///
+    /// Custom SQL functions and collations the commands use must be in
+    /// `functions`, as on the voters. `id` must not be the id of a member;
+    /// it is the id the learner gets if it is promoted.
+    pub fn open<P: Into<PathBuf>>(
+        id: u64,
+        path: P,
+        source: &str,
+        functions: FunctionRegistry,
+    ) -> Result<Self, StoreError> {
+        let path = path.into();
+        let conn = replay::open_database(&path, &functions)?;
+        let applied_idx = backup::read_applied_idx(&conn, &path)?;
+        Ok(Self {
+            id,
+            source: source.to_string(),
+            path,
+            functions,
+            tenant_dir: PathBuf::from("."),
+            conn: Mutex::new(conn),
+            tenants: Mutex::new(HashMap::new()),
+            applied_idx: AtomicU64::new(applied_idx),
+            failures: Mutex::new(vec![]),
+            promotion: Mutex::new(None),
+        })
+    }
+
+    /// Keeps the tenant databases under `dir`, laid out like those of node
+    /// `id` with `StoreConfig::tenant_dir` set to `dir`.
+    ///
+    /// The databases of tenants that exist at the backup the learner starts
+    /// from must be restored there first, with `tenants::restore_tenant`
+    /// from backups of the same voter written by
+    /// `StoreServer::backup_tenant`; the databases of later tenants are
+    /// created as their first command is applied.
+    pub fn with_tenant_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.tenant_dir = dir.into();
+        self
+    }
+
+    /// Returns the number of log entries applied to the learner's database.
+    pub fn applied_idx(&self) -> u64 {
+        self.applied_idx.load(Ordering::SeqCst)
+    }
+
+    /// Returns the commands that failed to apply, which the learner skips
+    /// like replicas running with `ApplyErrorPolicy::SkipAndRecord`.
+    pub fn failures(&self) -> Vec<ReplayFailure> {
+        self.failures.lock().unwrap().clone()
+    }
+
+    /// Returns the configuration the learner can be promoted into, once a
+    /// reconfiguration added its id.
+    pub fn promotion(&self) -> Option<JoinedConfiguration> {
+        self.promotion.lock().unwrap().clone()
+    }
+
+    /// Runs the read-only `stmt` against the learner's database.
+    pub fn query<S: AsRef<str>>(&self, stmt: S) -> Result<QueryResults, StoreError> {
+        let stmt = stmt.as_ref();
+        if statements::is_write(stmt) {
+            return Err(StoreError::InvalidRequest(format!(
+                "learners only serve reads: {}",
+                stmt
+            )));
+        }
+        query_connection(&self.conn.lock().unwrap(), stmt.to_string())
+    }
+
+    /// Runs the read-only `stmt` against the learner's database of
+    /// `tenant`.
+    pub fn query_tenant<S: AsRef<str>>(
+        &self,
+        tenant: &str,
+        stmt: S,
+    ) -> Result<QueryResults, StoreError> {
+        tenants::check_tenant_id(tenant)?;
+        let stmt = stmt.as_ref();
+        if statements::is_write(stmt) {
+            return Err(StoreError::InvalidRequest(format!(
+                "learners only serve reads: {}",
+                stmt
+            )));
+        }
+        if let Some((conn, _)) = self.tenants.lock().unwrap().get(tenant) {
+            return query_connection(conn, stmt.to_string());
+        }
+        let path = tenants::tenant_path(&self.tenant_dir, self.id, tenant);
+        if !path.exists() {
+            return Err(StoreError::InvalidRequest(format!(
+                "no database of tenant {}",
+                tenant
+            )));
+        }
+        let conn = replay::open_database(&path, &self.functions)?;
+        query_connection(&conn, stmt.to_string())
+    }
+
+    /// Fetches and applies the commands the source applied since the
+    /// learner last caught up, returning the learner's applied index.
+    pub async fn catch_up(&self) -> Result<u64, StoreError> {
+        let mut client = RpcClient::connect(self.source.clone())
+            .await
+            .map_err(|e| StoreError::Learner(e.to_string()))?;
+        while self.promotion().is_none() {
+            let batch = client
+                .fetch_learner_entries(LearnerFetch {
+                    after_idx: self.applied_idx(),
+                    max: LEARNER_BATCH_SIZE,
+                })
+                .await
+                .map_err(|status| StoreError::Learner(status.message().to_string()))?
+                .into_inner();
+            let batch = LearnerBatch {
+                entries: batch
+                    .entries
+                    .into_iter()
+                    .map(|entry| {
+                        let cmd = get_entry_from_proto(entry.entry.unwrap_or_default());
+                        (entry.applied_idx, cmd)
+                    })
+                    .collect(),
+                configurations: batch
+                    .configurations
+                    .into_iter()
+                    .map(|start| ConfigurationStart {
+                        config_id: start.config_id,
+                        members: start.members,
+                        applied_idx: start.applied_idx,
+                    })
+                    .collect(),
+            };
+            let done = batch.entries.len() < LEARNER_BATCH_SIZE as usize;
+            self.apply(batch)?;
+            if done {
+                break;
+            }
+        }
+        Ok(self.applied_idx())
+    }
+
+    /// Catches up every `interval` until a reconfiguration adds the
+    /// learner, returning the configuration to promote it into, or until
+    /// catching up fails.
+    pub async fn follow(&self, interval: Duration) -> Result<JoinedConfiguration, StoreError> {
+        loop {
+            self.catch_up().await?;
+            if let Some(promotion) = self.promotion() {
+                return Ok(promotion);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Makes the learner's database the database of node `id`, which then
+    /// starts with the returned configuration in `StoreConfig::join`. Its
+    /// tenant databases are in place already if the node's
+    /// `StoreConfig::tenant_dir` is the learner's.
+    ///
+    /// Fails unless a reconfiguration added the learner and it stopped at
+    /// its stop sign, or if the node has a database already.
+    pub fn promote(self) -> Result<JoinedConfiguration, StoreError> {
+        let promotion = self.promotion().ok_or_else(|| {
+            StoreError::InvalidRequest(format!(
+                "learner {} is not a member of a later configuration",
+                self.id
+            ))
+        })?;
+        let target = database_path(self.id);
+        if target.exists() {
+            return Err(StoreError::InvalidRequest(format!(
+                "{} already exists; only new nodes can be promoted",
+                target.display()
+            )));
+        }
+        drop(self.conn);
+        drop(self.tenants);
+        std::fs::rename(&self.path, &target)?;
+        Ok(promotion)
+    }
+
+    /// Applies `batch` in log order, stopping at the start of the first
+    /// configuration the learner is a member of.
+    ///
+    /// The commands are applied in one transaction along with the applied
+    /// index, so a learner restarted in the middle of a batch applies none
+    /// of it twice. A command that fails to apply because a function or
+    /// collation is missing ends the batch after the commands before it.
+    fn apply(&self, batch: LearnerBatch) -> Result<(), StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let applied_idx = self.applied_idx();
+        let promotion = batch
+            .configurations
+            .iter()
+            .filter(|start| start.applied_idx >= applied_idx && start.members.contains(&self.id))
+            .min_by_key(|start| start.applied_idx)
+            .map(|start| JoinedConfiguration {
+                config_id: start.config_id,
+                members: start.members.clone(),
+                applied_idx: start.applied_idx,
+            });
+        let stop = promotion
+            .as_ref()
+            .map_or(u64::MAX, |start| start.applied_idx);
+        let mut entries: Vec<_> = batch
+            .entries
+            .into_iter()
+            .filter(|(idx, _)| *idx <= stop)
+            .collect();
+        for (expected, (idx, _)) in (applied_idx + 1..).zip(&entries) {
+            if *idx != expected {
+                return Err(StoreError::Learner(format!(
+                    "expected entry {}, got {}",
+                    expected, idx
+                )));
+            }
+        }
+        let missing = entries.iter().enumerate().find_map(|(pos, (_, cmd))| {
+            replay::check_available(&self.functions, cmd)
+                .err()
+                .map(|e| (pos, e))
+        });
+        let missing = missing.map(|(pos, e)| {
+            entries.truncate(pos);
+            e
+        });
+
+        // Commands that begin or end a transaction themselves cannot run in
+        // the one of the batch, and are applied on their own.
+        let mut rest = entries.as_slice();
+        while !rest.is_empty() {
+            let atomic = !statements::controls_transaction(&rest[0].1.sql);
+            let len = match atomic {
+                true => rest
+                    .iter()
+                    .position(|(_, cmd)| statements::controls_transaction(&cmd.sql))
+                    .unwrap_or(rest.len()),
+                false => 1,
+            };
+            let (segment, next) = rest.split_at(len);
+            let failures = self.apply_segment(&mut conn, segment, atomic)?;
+            self.failures.lock().unwrap().extend(failures);
+            self.applied_idx.store(segment[len - 1].0, Ordering::SeqCst);
+            rest = next;
+        }
+        if let Some(e) = missing {
+            return Err(e);
+        }
+        if promotion.is_some() && self.applied_idx() == stop {
+            *self.promotion.lock().unwrap() = promotion;
+        }
+        Ok(())
+    }
+
+    /// Applies the consecutive commands of `segment` and records the index
+    /// of the last one, all in one transaction if `atomic` is set, and
+    /// returns the commands that failed.
+    fn apply_segment(
+        &self,
+        conn: &mut Connection,
+        segment: &[(u64, StoreCommand)],
+        atomic: bool,
+    ) -> Result<Vec<ReplayFailure>, StoreError> {
+        // Commands that rolled the whole transaction back, with an `OR
+        // ROLLBACK` conflict clause; like on the voters, where they roll
+        // back only themselves, they leave no changes, so the segment is
+        // applied again without them.
+        let mut aborted: HashMap<u64, String> = HashMap::new();
+        'segment: loop {
+            if atomic {
+                conn.execute("BEGIN IMMEDIATE")?;
+            }
+            let mut failures = vec![];
+            for (idx, cmd) in segment {
+                let result = match aborted.get(idx) {
+                    Some(error) => Err(StoreError::Learner(error.clone())),
+                    None => match self.apply_command(conn, *idx, cmd, atomic) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            if atomic && in_transaction(conn) {
+                                let _ = conn.execute("ROLLBACK");
+                            }
+                            return Err(e);
+                        }
+                    },
+                };
+                if atomic && !in_transaction(conn) {
+                    let error = result.err().map(|e| e.to_string()).unwrap_or_default();
+                    aborted.insert(*idx, error);
+                    continue 'segment;
+                }
+                if let Err(e) = result {
+                    failures.push(ReplayFailure {
+                        applied_idx: *idx,
+                        sql: cmd.sql.clone(),
+                        error: e.to_string(),
+                    });
+                }
+            }
+            let last = segment[segment.len() - 1].0;
+            let recorded = conn
+                .execute(format!(
+                    "UPDATE {} SET applied_idx = {}",
+                    BACKUP_TABLE, last
+                ))
+                .and_then(|()| match atomic {
+                    true => conn.execute("COMMIT"),
+                    false => Ok(()),
+                });
+            if let Err(e) = recorded {
+                if atomic && in_transaction(conn) {
+                    let _ = conn.execute("ROLLBACK");
+                }
+                return Err(e.into());
+            }
+            return Ok(failures);
+        }
+    }
+
+    /// Applies `cmd` at `idx`, to the shared database or the database of
+    /// its tenant. The outer error fails the batch; the inner one only the
+    /// command, which the learner skips.
+    fn apply_command(
+        &self,
+        conn: &Connection,
+        idx: u64,
+        cmd: &StoreCommand,
+        nested: bool,
+    ) -> Result<Result<(), StoreError>, StoreError> {
+        let tenant = match &cmd.tenant {
+            Some(tenant) => tenant,
+            None if nested => return Ok(replay::apply_nested(conn, cmd, idx)),
+            None => return Ok(replay::apply(conn, cmd, idx)),
+        };
+        // Routes and captured writes live in the shared database, so they
+        // commit with the batch.
+        let route = migration::route(conn, tenant)?;
+        if route.moved_to.is_some() {
+            return Ok(Ok(()));
+        }
+        let result = self.apply_tenant_command(tenant, idx, cmd)?;
+        if result.is_ok() && route.capturing && !statements::is_read(&cmd.sql) {
+            let change = TenantChange {
+                applied_idx: idx,
+                sql: cmd.sql.clone(),
+                params: cmd.params.clone(),
+            };
+            migration::record_change(conn, tenant, &change)?;
+        }
+        Ok(result)
+    }
+
+    /// Applies `cmd` at `idx` to the database of `tenant`, along with the
+    /// index it applied through, unless it applied it before the learner
+    /// restarted.
+    fn apply_tenant_command(
+        &self,
+        tenant: &str,
+        idx: u64,
+        cmd: &StoreCommand,
+    ) -> Result<Result<(), StoreError>, StoreError> {
+        let mut tenants = self.tenants.lock().unwrap();
+        if !tenants.contains_key(tenant) {
+            tenants::check_tenant_id(tenant)?;
+            std::fs::create_dir_all(tenants::tenants_dir(&self.tenant_dir, self.id))?;
+            let path = tenants::tenant_path(&self.tenant_dir, self.id, tenant);
+            let conn = replay::open_database(&path, &self.functions)?;
+            // A tenant created after the backup starts with this command.
+            conn.execute(format!(
+                "CREATE TABLE IF NOT EXISTS {table} (applied_idx INTEGER NOT NULL); \
+                 INSERT INTO {table} SELECT {idx} WHERE NOT EXISTS (SELECT 1 FROM {table});",
+                table = BACKUP_TABLE,
+                idx = idx - 1
+            ))?;
+            let applied_idx = backup::read_applied_idx(&conn, &path)?;
+            tenants.insert(tenant.to_string(), (conn, applied_idx));
+        }
+        let (conn, applied_idx) = tenants.get_mut(tenant).unwrap();
+        if idx <= *applied_idx {
+            return Ok(Ok(()));
+        }
+        let atomic = !statements::controls_transaction(&cmd.sql);
+        if atomic {
+            conn.execute("BEGIN IMMEDIATE")?;
+        }
+        let result = match atomic {
+            true => replay::apply_nested(conn, cmd, idx),
+            false => replay::apply(conn, cmd, idx),
+        };
+        let recorded = conn
+            .execute(format!("UPDATE {} SET applied_idx = {}", BACKUP_TABLE, idx))
+            .and_then(|()| match atomic && in_transaction(conn) {
+                true => conn.execute("COMMIT"),
+                false => Ok(()),
+            });
+        if let Err(e) = recorded {
+            if in_transaction(conn) {
+                let _ = conn.execute("ROLLBACK");
+            }
+            return Err(e.into());
+        }
+        *applied_idx = idx;
+        Ok(result)
+    }
+}
+
+/// Returns true if `conn` is in a transaction.
+fn in_transaction(conn: &mut Connection) -> bool {
+    unsafe { ffi::sqlite3_get_autocommit(conn.as_raw()) == 0 }
+}
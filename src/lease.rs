@@ -0,0 +1,84 @@
+//! Leader leases.
+//!
+//! `Consistency::LeaderLease` reads are served by the leader from its local
+//! state, without a round through the log, while it holds a lease. The
+//! lease is held while a majority of the cluster, the leader included, has
+//! answered the leader's heartbeats within `StoreConfig::leader_lease`.
+//! Followers only elect a new leader after missing the heartbeats of a
+//! whole round, so as long as the lease is well below the round and clocks
+//! advance at similar rates, no other node can have been elected while the
+//! lease is held.
+//!
+//! A new leader may not have learned yet that entries of earlier leaders
+//! were decided, so its lease is only established once a command it
+//! proposed has been applied.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Lease bookkeeping of a replica.
+#[derive(Debug)]
+pub(crate) struct LeaderLease {
+    duration: Duration,
+    /// When each peer last answered a heartbeat.
+    acks: HashMap<u64, Instant>,
+    /// Incremented whenever the leader changes.
+    epoch: u64,
+    established: bool,
+}
+
+impl LeaderLease {
+    pub(crate) fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            acks: HashMap::new(),
+            epoch: 0,
+            established: false,
+        }
+    }
+
+    /// Records that `peer` answered a heartbeat.
+    pub(crate) fn record_ack(&mut self, peer: u64) {
+        self.acks.insert(peer, Instant::now());
+    }
+
+    /// Forgets the acks and the establishment of the lease, when the leader
+    /// changes.
+    pub(crate) fn reset(&mut self) {
+        self.acks.clear();
+        self.epoch += 1;
+        self.established = false;
+    }
+
+    /// Returns true if a majority of the cluster made of this node and
+    /// `peers` answered heartbeats within the lease duration.
+    pub(crate) fn is_held(&self, peers: &[u64]) -> bool {
+        let now = Instant::now();
+        let live = peers
+            .iter()
+            .filter(|peer| {
+                self.acks
+                    .get(peer)
+                    .map_or(false, |at| now.duration_since(*at) < self.duration)
+            })
+            .count();
+        (live + 1) * 2 > peers.len() + 1
+    }
+
+    /// Returns the current leader epoch.
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns true if the lease was established in the current epoch.
+    pub(crate) fn is_established(&self) -> bool {
+        self.established
+    }
+
+    /// Establishes the lease, unless the leader changed since `epoch`.
+    pub(crate) fn establish(&mut self, epoch: u64) {
+        if self.epoch == epoch {
+            self.established = true;
+        }
+    }
+}
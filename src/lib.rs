@@ -1,11 +1,162 @@
+#[cfg(not(target_arch = "wasm32"))]
+pub mod advertise;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod analytics;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod archive;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod backup;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod batching;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod bootstrap;
+pub mod checksum;
+pub mod client;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod controller;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod counters;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cursors;
+#[cfg(not(target_arch = "wasm32"))]
+mod deadline;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod deprecation;
+#[cfg(not(target_arch = "wasm32"))]
+mod determinism;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod diagnostics;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod encryption;
 pub mod errors;
+#[cfg(all(feature = "arrow-export", not(target_arch = "wasm32")))]
+pub mod export;
+#[cfg(all(feature = "fault-injection", not(target_arch = "wasm32")))]
+pub mod faults;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fulltext;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod functions;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod import;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod introspection;
+pub mod json;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod lanes;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod learner;
+#[cfg(not(target_arch = "wasm32"))]
+mod lease;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod listener;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod logger;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mailbox;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod membership;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod migration;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod network;
+pub mod nodes;
+pub mod outbox;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pagination;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pragmas;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod prepared;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pubsub;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod read_index;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod reconfiguration;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod replay;
+#[cfg(all(feature = "resp", not(target_arch = "wasm32")))]
+pub mod resp;
+pub mod retry;
+pub mod router;
+#[cfg(not(target_arch = "wasm32"))]
+mod rows;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod rpc;
+#[cfg(not(target_arch = "wasm32"))]
+mod savepoints;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod schema;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod server;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod settings;
+pub mod statements;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tenants;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tombstones;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod upsert;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod validation;
+pub mod value;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod warmup;
 
+/// Protocol types and the generated gRPC client (and server, on native
+/// targets).
+#[allow(missing_docs)]
+pub mod proto {
+    tonic::include_proto!("proto");
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use analytics::ReadSnapshot;
+pub use client::ChiselStoreClient;
+pub use errors::ClientError;
+#[cfg(not(target_arch = "wasm32"))]
 pub use errors::StoreError;
+#[cfg(not(target_arch = "wasm32"))]
+pub use functions::FunctionRegistry;
+#[cfg(not(target_arch = "wasm32"))]
+pub use lanes::QueryLane;
+#[cfg(not(target_arch = "wasm32"))]
+pub use learner::Learner;
+#[cfg(not(target_arch = "wasm32"))]
+pub use listener::ServerConfig;
+pub use router::MultiClusterClient;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::ApplyErrorAction;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::ApplyErrorPolicy;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::ApplyFailure;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::BatchQuery;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::Column;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::CommandKind;
+#[cfg(not(target_arch = "wasm32"))]
 pub use server::Consistency;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::LeaderStatus;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::Lifecycle;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::ReadProof;
+#[cfg(not(target_arch = "wasm32"))]
 pub use server::SequencePaxosStoreTransport;
+#[cfg(not(target_arch = "wasm32"))]
 pub use server::Store;
+#[cfg(not(target_arch = "wasm32"))]
 pub use server::StoreCommand;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::StoreConfig;
+#[cfg(not(target_arch = "wasm32"))]
 pub use server::StoreServer;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::WriteHandle;
+pub use value::Value;
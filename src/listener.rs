@@ -0,0 +1,101 @@
+//! Listener sockets for the RPC server.
+//!
+//! By default tonic binds a single listening socket. Servers handling many
+//! short-lived client connections can instead bind several acceptor sockets
+//! to the same address with `SO_REUSEPORT`, letting the kernel spread new
+//! connections across them, and tune the listen backlog.
+
+use futures_util::stream::{self, Stream, StreamExt};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::wrappers::TcpListenerStream;
+
+/// Socket settings of the RPC server.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Number of acceptor sockets bound to the listen address. More than one
+    /// requires `reuse_port`.
+    pub acceptors: usize,
+    /// Bind with `SO_REUSEPORT`, so several sockets can share the address.
+    pub reuse_port: bool,
+    /// Listen backlog of each acceptor socket.
+    pub backlog: i32,
+    /// Set `TCP_NODELAY` on accepted connections.
+    pub nodelay: bool,
+    /// TCP keepalive idle time of accepted connections.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            acceptors: 1,
+            reuse_port: false,
+            backlog: 1024,
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Binds the acceptor sockets to `addr`.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<Vec<TcpListener>> {
+        if self.acceptors > 1 && !self.reuse_port {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "multiple acceptors require reuse_port",
+            ));
+        }
+        (0..self.acceptors.max(1))
+            .map(|_| {
+                let socket =
+                    Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+                socket.set_reuse_address(true)?;
+                if self.reuse_port {
+                    set_reuse_port(&socket)?;
+                }
+                socket.set_nonblocking(true)?;
+                socket.bind(&addr.into())?;
+                socket.listen(self.backlog)?;
+                TcpListener::from_std(socket.into())
+            })
+            .collect()
+    }
+
+    /// Binds the acceptor sockets to `addr` and returns the connections
+    /// accepted on any of them, for `Server::serve_with_incoming`.
+    pub fn incoming(
+        &self,
+        addr: SocketAddr,
+    ) -> io::Result<impl Stream<Item = io::Result<TcpStream>>> {
+        let listeners = self.bind(addr)?;
+        let nodelay = self.nodelay;
+        let keepalive = self.keepalive;
+        let incoming = stream::select_all(listeners.into_iter().map(TcpListenerStream::new));
+        Ok(incoming.map(move |conn| {
+            let conn = conn?;
+            conn.set_nodelay(nodelay)?;
+            if let Some(idle) = keepalive {
+                SockRef::from(&conn).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+            }
+            Ok(conn)
+        }))
+    }
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
@@ -0,0 +1,156 @@
+//! Admission control for inbound peer messages.
+//!
+//! Every peer message is handed to leader election or replication as the
+//! RPC carrying it arrives, so during a recovery storm RPCs pile up waiting
+//! for the replica, each holding its decoded message. The mailbox bounds how
+//! many peer messages a node handles at a time, to
+//! `StoreConfig::peer_mailbox_capacity`, and sheds the least valuable ones
+//! first once it fills up:
+//!
+//! * A heartbeat from a peer that already has a heartbeat of the same kind
+//!   pending is dropped, as is any heartbeat once half of the mailbox is in
+//!   use. Leader election sends heartbeats every round, so a dropped one
+//!   only delays the next.
+//! * Read index messages are refused past half of the mailbox too; the read
+//!   waiting for them falls back to a strong read.
+//! * Replication messages use the whole mailbox and are refused once it is
+//!   full, which the sending peer sees as a failed send.
+//!
+//! `StoreServer::peer_mailbox` counts the messages admitted, shed and
+//! refused.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Kind of an inbound peer message, by how much it matters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PeerMessage {
+    /// A sequence paxos message.
+    Replication,
+    /// A read index or leadership check message.
+    ReadIndex,
+    /// A leader election heartbeat request.
+    HeartbeatRequest,
+    /// A leader election heartbeat reply.
+    HeartbeatReply,
+}
+
+impl PeerMessage {
+    fn is_heartbeat(self) -> bool {
+        matches!(
+            self,
+            PeerMessage::HeartbeatRequest | PeerMessage::HeartbeatReply
+        )
+    }
+}
+
+/// Whether an inbound peer message may be handled.
+#[derive(Debug)]
+pub enum Admission<'a> {
+    /// The message is handled, and counts as pending until the slot is
+    /// dropped.
+    Admitted(MailboxSlot<'a>),
+    /// The message is dropped and acknowledged, as losing it is harmless.
+    Shed,
+    /// The message is refused, and the peer told the node is overloaded.
+    Rejected,
+}
+
+/// Counts of the peer messages a node admitted, shed and refused.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MailboxStats {
+    /// Messages handled.
+    pub admitted: u64,
+    /// Heartbeats dropped.
+    pub shed_heartbeats: u64,
+    /// Messages refused.
+    pub rejected: u64,
+}
+
+/// The peer messages a node is handling.
+#[derive(Debug)]
+pub struct PeerMailbox {
+    capacity: usize,
+    pending: AtomicUsize,
+    /// Peers with a heartbeat pending, by kind.
+    heartbeats: Mutex<HashSet<(u64, PeerMessage)>>,
+    admitted: AtomicU64,
+    shed_heartbeats: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl PeerMailbox {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            pending: AtomicUsize::new(0),
+            heartbeats: Mutex::new(HashSet::new()),
+            admitted: AtomicU64::new(0),
+            shed_heartbeats: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Decides whether a message of kind `message` from peer `from` may be
+    /// handled.
+    pub(crate) fn admit(&self, from: u64, message: PeerMessage) -> Admission<'_> {
+        let limit = match message {
+            PeerMessage::Replication => self.capacity,
+            _ => (self.capacity / 2).max(1),
+        };
+        if message.is_heartbeat() && !self.heartbeats.lock().unwrap().insert((from, message)) {
+            self.shed_heartbeats.fetch_add(1, Ordering::Relaxed);
+            return Admission::Shed;
+        }
+        let slot = MailboxSlot {
+            mailbox: self,
+            heartbeat: message.is_heartbeat().then_some((from, message)),
+        };
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= limit {
+            drop(slot);
+            return match message.is_heartbeat() {
+                true => {
+                    self.shed_heartbeats.fetch_add(1, Ordering::Relaxed);
+                    Admission::Shed
+                }
+                false => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    Admission::Rejected
+                }
+            };
+        }
+        self.admitted.fetch_add(1, Ordering::Relaxed);
+        Admission::Admitted(slot)
+    }
+
+    /// Returns the number of peer messages being handled.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Returns the counts of the messages admitted, shed and refused so far.
+    pub fn stats(&self) -> MailboxStats {
+        MailboxStats {
+            admitted: self.admitted.load(Ordering::Relaxed),
+            shed_heartbeats: self.shed_heartbeats.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A peer message admitted by `PeerMailbox::admit`, pending until dropped.
+#[derive(Debug)]
+pub struct MailboxSlot<'a> {
+    mailbox: &'a PeerMailbox,
+    heartbeat: Option<(u64, PeerMessage)>,
+}
+
+impl Drop for MailboxSlot<'_> {
+    fn drop(&mut self) {
+        self.mailbox.pending.fetch_sub(1, Ordering::SeqCst);
+        if let Some(heartbeat) = self.heartbeat {
+            self.mailbox.heartbeats.lock().unwrap().remove(&heartbeat);
+        }
+    }
+}
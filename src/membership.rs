@@ -0,0 +1,69 @@
+//! Initial cluster membership fencing.
+//!
+//! On first boot a replica records the cluster members it was started with
+//! next to its database. Later restarts with a different member list are
+//! refused, since a replica that silently joins a different configuration
+//! can form a second majority with other misconfigured nodes.
+
+use crate::errors::StoreError;
+use std::path::{Path, PathBuf};
+
+/// Path of the file recording the initial membership of node `id`.
+pub fn membership_path(id: u64) -> PathBuf {
+    PathBuf::from(format!("node{}.members", id))
+}
+
+/// Records the cluster members on first boot, or checks that they match the
+/// recorded ones.
+///
+/// With `allow_override`, a mismatching record is replaced instead.
+pub fn check_initial_membership(
+    path: &Path,
+    id: u64,
+    peers: &[u64],
+    allow_override: bool,
+) -> Result<(), StoreError> {
+    let mut members: Vec<u64> = peers.to_vec();
+    members.push(id);
+    members.sort_unstable();
+    members.dedup();
+
+    if path.exists() {
+        let recorded = read_members(path)?;
+        if recorded == members {
+            return Ok(());
+        }
+        if !allow_override {
+            return Err(StoreError::MembershipMismatch {
+                recorded,
+                requested: members,
+            });
+        }
+    }
+    write_members(path, &members)
+}
+
+fn read_members(path: &Path) -> Result<Vec<u64>, StoreError> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut members = vec![];
+    for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let member = line.parse().map_err(|_| {
+            StoreError::InvalidRequest(format!(
+                "{} has an invalid member id: {}",
+                path.display(),
+                line
+            ))
+        })?;
+        members.push(member);
+    }
+    members.sort_unstable();
+    Ok(members)
+}
+
+fn write_members(path: &Path, members: &[u64]) -> Result<(), StoreError> {
+    let contents: String = members.iter().map(|m| format!("{}\n", m)).collect();
+    let tmp = path.with_extension("members.tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
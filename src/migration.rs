@@ -0,0 +1,311 @@
+//! Moving a tenant to another cluster.
+//!
+//! `migrate_tenant` copies a tenant's database to another cluster while the
+//! tenant stays writable, then cuts writes over. The source cluster first
+//! starts capturing the tenant's writes into the reserved
+//! `chiselstore_tenant_changes` table. The tenant's database is then
+//! exported, imported into the destination, and the captured tail of
+//! changes replayed there. The cut-over is a single replicated write to the
+//! `chiselstore_tenant_routes` table: from its applied index on, every
+//! replica of the source refuses the tenant's commands with
+//! `StoreError::TenantMoved`, naming the destination. The last changes are
+//! then replayed and the checksums of both copies compared.
+
+use crate::checksum::fnv1a;
+use crate::client::ChiselStoreClient;
+use crate::errors::{ClientError, StoreError};
+use crate::proto::{self, Consistency};
+use crate::server::query_connection;
+use crate::value::{quote_literal, Value};
+use prost::Message;
+use sqlite::Connection;
+
+/// Reserved table routing tenants that moved to another cluster.
+pub const ROUTES_TABLE: &str = "chiselstore_tenant_routes";
+
+/// Reserved table holding the captured changes of migrating tenants.
+pub const CHANGES_TABLE: &str = "chiselstore_tenant_changes";
+
+/// Number of statements of an import applied per replicated transaction.
+pub(crate) const IMPORT_BATCH: usize = 500;
+
+/// A tenant's database as SQL statements, as of an applied index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantExport {
+    /// Applied index of the source replica when it was exported.
+    pub applied_idx: u64,
+    /// Statements recreating the tables, their rows and then the indexes,
+    /// triggers and views.
+    pub statements: Vec<String>,
+}
+
+/// A write to a tenant captured during a migration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantChange {
+    /// Applied index of the write on the source cluster.
+    pub applied_idx: u64,
+    /// The statement of the write.
+    pub sql: String,
+    /// Values bound to the placeholders of `sql`.
+    pub params: Vec<Value>,
+}
+
+/// Outcome of `migrate_tenant`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Applied index of the source the export was taken at.
+    pub export_idx: u64,
+    /// Number of statements imported.
+    pub statements: usize,
+    /// Number of captured changes replayed.
+    pub changes: usize,
+    /// Checksum both copies of the tenant ended up with.
+    pub checksum: u64,
+}
+
+/// Where a replica sends a tenant's commands.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TenantRoute {
+    /// Cluster the tenant moved to, if it did.
+    pub(crate) moved_to: Option<String>,
+    /// Whether the tenant's writes are captured.
+    pub(crate) capturing: bool,
+}
+
+/// Creates the routing and change capture tables on `conn` if they do not
+/// exist yet.
+pub(crate) fn create_tables(conn: &Connection) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} \
+         (tenant TEXT PRIMARY KEY, cluster TEXT, capturing INTEGER NOT NULL DEFAULT 0)",
+        ROUTES_TABLE
+    ))?;
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} \
+         (applied_idx INTEGER PRIMARY KEY, tenant TEXT NOT NULL, sql TEXT NOT NULL, \
+         params BLOB NOT NULL)",
+        CHANGES_TABLE
+    ))?;
+    Ok(())
+}
+
+/// Statement starting or, with `capture` false, stopping and discarding
+/// the capture of `tenant`'s writes.
+pub fn capture_statement(tenant: &str, capture: bool) -> String {
+    let tenant = quote_literal(tenant);
+    if capture {
+        return format!(
+            "INSERT INTO {} (tenant, capturing) VALUES ({}, 1) \
+             ON CONFLICT(tenant) DO UPDATE SET capturing = 1",
+            ROUTES_TABLE, tenant
+        );
+    }
+    format!(
+        "UPDATE {} SET capturing = 0 WHERE tenant = {}; DELETE FROM {} WHERE tenant = {}",
+        ROUTES_TABLE, tenant, CHANGES_TABLE, tenant
+    )
+}
+
+/// Statement routing `tenant` to `cluster`, or back to this cluster if
+/// `None`.
+pub fn route_statement(tenant: &str, cluster: Option<&str>) -> String {
+    let cluster = cluster.map_or_else(|| String::from("NULL"), quote_literal);
+    format!(
+        "INSERT INTO {} (tenant, cluster) VALUES ({}, {}) \
+         ON CONFLICT(tenant) DO UPDATE SET cluster = excluded.cluster",
+        ROUTES_TABLE,
+        quote_literal(tenant),
+        cluster
+    )
+}
+
+/// Reads the route of `tenant`.
+pub(crate) fn route(conn: &Connection, tenant: &str) -> Result<TenantRoute, StoreError> {
+    let stmt = format!(
+        "SELECT cluster, capturing FROM {} WHERE tenant = {}",
+        ROUTES_TABLE,
+        quote_literal(tenant)
+    );
+    let route = match query_connection(conn, stmt)?.rows.into_iter().next() {
+        Some(row) => TenantRoute {
+            moved_to: match &row.typed_values[0] {
+                Value::Text(cluster) => Some(cluster.clone()),
+                _ => None,
+            },
+            capturing: row.values[1] != "0",
+        },
+        None => TenantRoute::default(),
+    };
+    Ok(route)
+}
+
+/// Records a captured write of `tenant`.
+pub(crate) fn record_change(
+    conn: &Connection,
+    tenant: &str,
+    change: &TenantChange,
+) -> Result<(), StoreError> {
+    let params = proto::QueryRow {
+        values: vec![],
+        typed_values: change.params.iter().cloned().map(Into::into).collect(),
+    };
+    let mut buf = Vec::new();
+    params
+        .encode(&mut buf)
+        .expect("a Vec has unlimited capacity");
+    conn.execute(format!(
+        "INSERT INTO {} VALUES ({}, {}, {}, {})",
+        CHANGES_TABLE,
+        change.applied_idx,
+        quote_literal(tenant),
+        quote_literal(&change.sql),
+        Value::Blob(buf)
+            .to_sql_literal()
+            .expect("blobs have a SQL literal")
+    ))?;
+    Ok(())
+}
+
+/// Reads the captured writes of `tenant` applied after `after_idx`, in
+/// log order.
+pub(crate) fn changes_after(
+    conn: &Connection,
+    tenant: &str,
+    after_idx: u64,
+) -> Result<Vec<TenantChange>, StoreError> {
+    let stmt = format!(
+        "SELECT applied_idx, sql, params FROM {} WHERE tenant = {} AND applied_idx > {} \
+         ORDER BY applied_idx",
+        CHANGES_TABLE,
+        quote_literal(tenant),
+        after_idx
+    );
+    let mut changes = vec![];
+    for row in query_connection(conn, stmt)?.rows {
+        let params = match &row.typed_values[2] {
+            Value::Blob(bytes) => proto::QueryRow::decode(bytes.as_slice())
+                .map_err(|e| StoreError::InvalidRequest(e.to_string()))?,
+            _ => proto::QueryRow::default(),
+        };
+        changes.push(TenantChange {
+            applied_idx: row.values[0].parse().unwrap_or_default(),
+            sql: row.values[1].clone(),
+            params: params.typed_values.into_iter().map(Into::into).collect(),
+        });
+    }
+    Ok(changes)
+}
+
+/// Dumps the tables of `conn` as SQL statements, leaving out SQLite's and
+/// ChiselStore's reserved tables.
+pub(crate) fn dump(conn: &Connection) -> Result<Vec<String>, StoreError> {
+    let schema = query_connection(
+        conn,
+        String::from(
+            "SELECT type, name, sql FROM sqlite_master WHERE sql IS NOT NULL \
+             AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\' \
+             AND name NOT LIKE 'chiselstore\\_%' ESCAPE '\\' \
+             ORDER BY name",
+        ),
+    )?;
+    let mut tables = vec![];
+    let mut others = vec![];
+    for row in schema.rows {
+        match row.values[0].as_str() {
+            "table" => tables.push((row.values[1].clone(), row.values[2].clone())),
+            _ => others.push(row.values[2].clone()),
+        }
+    }
+    let mut statements = vec![];
+    for (name, sql) in tables {
+        statements.push(sql);
+        let name = format!("\"{}\"", name.replace('"', "\"\""));
+        for row in query_connection(conn, format!("SELECT * FROM {}", name))?.rows {
+            let values = row
+                .typed_values
+                .iter()
+                .map(Value::to_sql_literal)
+                .collect::<Result<Vec<_>, _>>()?;
+            statements.push(format!(
+                "INSERT INTO {} VALUES ({})",
+                name,
+                values.join(", ")
+            ));
+        }
+    }
+    // Indexes and triggers come last, so the rows are not indexed one by
+    // one and no trigger fires on them.
+    statements.extend(others);
+    Ok(statements)
+}
+
+/// Checksum of an export's statements.
+pub fn checksum(statements: &[String]) -> u64 {
+    fnv1a(statements.join("\n").as_bytes())
+}
+
+/// Moves `tenant` from the cluster of `source` to the cluster of
+/// `destination`, which the source routes the tenant's commands to as
+/// `destination_name` once the writes are cut over.
+///
+/// Neither client may be set up with a tenant. The tenant must not have a
+/// database on the destination yet. If the copies end up with different
+/// checksums, the tenant is routed back to the source and
+/// `ClientError::ChecksumMismatch` returned; the destination's copy must
+/// then be discarded before trying again.
+pub async fn migrate_tenant(
+    source: &mut ChiselStoreClient,
+    destination: ChiselStoreClient,
+    destination_name: &str,
+    tenant: &str,
+) -> Result<MigrationReport, ClientError> {
+    source
+        .query(capture_statement(tenant, true), Consistency::Strong)
+        .await?;
+    let export = source.export_tenant(tenant).await?;
+    let mut destination = destination.with_tenant(tenant);
+    destination
+        .import_tenant(tenant, export.statements.clone())
+        .await?;
+
+    // Replay the changes made while the copy was made, then cut over and
+    // replay the rest, which the source stopped taking.
+    let mut replayed_idx = export.applied_idx;
+    let mut changes = 0;
+    for cut_over in [false, true] {
+        if cut_over {
+            source
+                .query(
+                    route_statement(tenant, Some(destination_name)),
+                    Consistency::Strong,
+                )
+                .await?;
+        }
+        for change in source.tenant_changes(tenant, replayed_idx).await? {
+            let params = change.params.into_iter().map(Into::into).collect();
+            destination
+                .query_with_params(change.sql, params, Consistency::Strong)
+                .await?;
+            replayed_idx = change.applied_idx;
+            changes += 1;
+        }
+    }
+
+    let expected = source.tenant_checksum(tenant).await?;
+    let actual = destination.tenant_checksum(tenant).await?;
+    if expected.checksum != actual.checksum {
+        source
+            .query(route_statement(tenant, None), Consistency::Strong)
+            .await?;
+        return Err(ClientError::ChecksumMismatch);
+    }
+    source
+        .query(capture_statement(tenant, false), Consistency::Strong)
+        .await?;
+    Ok(MigrationReport {
+        export_idx: export.applied_idx,
+        statements: export.statements.len(),
+        changes,
+        checksum: expected.checksum,
+    })
+}
@@ -0,0 +1,92 @@
+//! Simulated links between replicas of a local cluster.
+//!
+//! Reproduces WAN topologies, e.g. three nodes across two regions, on one
+//! machine to measure commit latency before deploying. Each replica's
+//! transport, set up with `RpcTransport::with_network`, asks the shared
+//! `Network` how long to hold a message before sending it, so link profiles
+//! can be changed while the cluster runs.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Latency, jitter and bandwidth of a link in one direction.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkProfile {
+    /// Delay added to every message.
+    pub latency: Duration,
+    /// Upper bound of a random delay added on top of `latency`.
+    pub jitter: Duration,
+    /// Link bandwidth in bytes per second; messages are delayed by their
+    /// transfer time.
+    pub bandwidth: Option<u64>,
+}
+
+impl LinkProfile {
+    /// A link with a fixed `latency`.
+    pub fn latency(latency: Duration) -> Self {
+        Self {
+            latency,
+            ..Default::default()
+        }
+    }
+}
+
+/// Link profiles between replicas, shared by their transports.
+#[derive(Clone, Debug, Default)]
+pub struct Network {
+    links: Arc<Mutex<HashMap<(u64, u64), LinkProfile>>>,
+    rng: Arc<AtomicU64>,
+}
+
+impl Network {
+    /// Sets the profile of the link from `from` to `to`.
+    pub fn set_link(&self, from: u64, to: u64, profile: LinkProfile) {
+        self.links.lock().unwrap().insert((from, to), profile);
+    }
+
+    /// Sets the profile of the links between every replica of `a` and
+    /// every replica of `b`, in both directions.
+    pub fn set_links_between(&self, a: &[u64], b: &[u64], profile: LinkProfile) {
+        for &x in a {
+            for &y in b {
+                if x != y {
+                    self.set_link(x, y, profile);
+                    self.set_link(y, x, profile);
+                }
+            }
+        }
+    }
+
+    /// Removes all link profiles.
+    pub fn clear(&self) {
+        self.links.lock().unwrap().clear();
+    }
+
+    /// Delay of a message of `size` bytes from `from` to `to`.
+    pub fn delay(&self, from: u64, to: u64, size: usize) -> Duration {
+        let profile = match self.links.lock().unwrap().get(&(from, to)) {
+            Some(profile) => *profile,
+            None => return Duration::ZERO,
+        };
+        let mut delay = profile.latency;
+        if !profile.jitter.is_zero() {
+            let jitter = self.next_random() % (profile.jitter.as_micros() as u64 + 1);
+            delay += Duration::from_micros(jitter);
+        }
+        if let Some(bandwidth) = profile.bandwidth {
+            delay += Duration::from_secs_f64(size as f64 / bandwidth as f64);
+        }
+        delay
+    }
+
+    /// Xorshift, which is plenty for jitter and needs no dependencies.
+    fn next_random(&self) -> u64 {
+        let mut x = self.rng.fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed) | 1;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        x
+    }
+}
@@ -0,0 +1,177 @@
+//! Health tracking for the nodes a client talks to.
+//!
+//! A client keeps one connection per cluster node and sends each request to
+//! the healthiest node first: nodes are ordered by a moving average of their
+//! latency, penalized by their recent error rate. A node that is unreachable
+//! several times in a row is evicted to the back of the order until it has
+//! sat out a number of requests, after which it is probed again.
+
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+/// Consecutive failures after which a node is evicted.
+const EVICT_AFTER_FAILURES: u32 = 3;
+/// Number of requests an evicted node sits out before it is probed again.
+const EVICTION_REQUESTS: u32 = 16;
+/// Weight of the latest sample in the moving averages.
+const SMOOTHING: f64 = 0.2;
+
+/// Health of one node as seen by a client.
+#[derive(Debug, Clone)]
+pub struct NodeHealth {
+    /// Name (usually the address) of the node.
+    pub name: String,
+    /// Moving average of the request latency, if any request succeeded.
+    pub latency: Option<Duration>,
+    /// Moving average of the fraction of requests that failed, from 0 to 1.
+    pub error_rate: f64,
+    /// Whether the node is evicted.
+    pub evicted: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Node<C> {
+    pub(crate) conn: C,
+    name: String,
+    latency_ms: Option<f64>,
+    error_rate: f64,
+    consecutive_failures: u32,
+    evicted_for: u32,
+}
+
+impl<C> Node<C> {
+    fn score(&self) -> f64 {
+        self.latency_ms.unwrap_or(0.0) * (1.0 + 10.0 * self.error_rate)
+    }
+}
+
+/// The nodes of a cluster and their health.
+#[derive(Debug)]
+pub(crate) struct NodePool<C> {
+    nodes: Vec<Node<C>>,
+}
+
+impl<C> NodePool<C> {
+    pub(crate) fn new() -> Self {
+        Self { nodes: Vec::new() }
+    }
+
+    pub(crate) fn add(&mut self, name: String, conn: C) {
+        self.nodes.push(Node {
+            conn,
+            name,
+            latency_ms: None,
+            error_rate: 0.0,
+            consecutive_failures: 0,
+            evicted_for: 0,
+        });
+    }
+
+    /// Returns the index of the node named `name`.
+    pub(crate) fn find(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.name == name)
+    }
+
+    pub(crate) fn conn(&mut self, idx: usize) -> &mut C {
+        &mut self.nodes[idx].conn
+    }
+
+    /// Returns the connections of two different nodes at once.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn conn_pair(&mut self, a: usize, b: usize) -> (&mut C, &mut C) {
+        assert_ne!(a, b, "a node cannot be paired with itself");
+        if a < b {
+            let (head, tail) = self.nodes.split_at_mut(b);
+            (&mut head[a].conn, &mut tail[0].conn)
+        } else {
+            let (head, tail) = self.nodes.split_at_mut(a);
+            (&mut tail[0].conn, &mut head[b].conn)
+        }
+    }
+
+    /// Returns the indexes of the nodes in the order to try them for the
+    /// next request: healthy nodes by score, then evicted ones.
+    pub(crate) fn candidates(&mut self) -> Vec<usize> {
+        for node in self.nodes.iter_mut().filter(|node| node.evicted_for > 0) {
+            node.evicted_for -= 1;
+            if node.evicted_for == 0 {
+                // Back on probation: one more failure evicts it again.
+                node.consecutive_failures = EVICT_AFTER_FAILURES - 1;
+            }
+        }
+        let mut order: Vec<usize> = (0..self.nodes.len()).collect();
+        order.sort_by(|a, b| {
+            let (a, b) = (&self.nodes[*a], &self.nodes[*b]);
+            (a.evicted_for > 0)
+                .cmp(&(b.evicted_for > 0))
+                .then(a.score().partial_cmp(&b.score()).unwrap())
+        });
+        order
+    }
+
+    /// Records that node `idx` answered a request sent at `started`.
+    pub(crate) fn record_success(&mut self, idx: usize, started: Option<Timestamp>) {
+        let node = &mut self.nodes[idx];
+        node.consecutive_failures = 0;
+        node.error_rate *= 1.0 - SMOOTHING;
+        if let Some(elapsed) = started.map(elapsed_ms) {
+            node.latency_ms = Some(match node.latency_ms {
+                Some(avg) => avg + SMOOTHING * (elapsed - avg),
+                None => elapsed,
+            });
+        }
+    }
+
+    /// Records that node `idx` could not be reached.
+    pub(crate) fn record_failure(&mut self, idx: usize) {
+        let node = &mut self.nodes[idx];
+        node.error_rate += SMOOTHING * (1.0 - node.error_rate);
+        node.consecutive_failures += 1;
+        if node.consecutive_failures >= EVICT_AFTER_FAILURES {
+            node.evicted_for = EVICTION_REQUESTS;
+        }
+    }
+
+    pub(crate) fn health(&self) -> Vec<NodeHealth> {
+        self.nodes
+            .iter()
+            .map(|node| NodeHealth {
+                name: node.name.clone(),
+                latency: node
+                    .latency_ms
+                    .map(|ms| Duration::from_secs_f64(ms / 1000.0)),
+                error_rate: node.error_rate,
+                evicted: node.evicted_for > 0,
+            })
+            .collect()
+    }
+}
+
+/// When a request was sent. Latency is not tracked on `wasm32`, which has no
+/// monotonic clock in `std`.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type Timestamp = Instant;
+#[cfg(target_arch = "wasm32")]
+pub(crate) type Timestamp = ();
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now() -> Option<Timestamp> {
+    Some(Instant::now())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> Option<Timestamp> {
+    None
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn elapsed_ms(started: Timestamp) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+#[cfg(target_arch = "wasm32")]
+fn elapsed_ms(_started: Timestamp) -> f64 {
+    0.0
+}
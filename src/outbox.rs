@@ -0,0 +1,194 @@
+//! Transactional outbox.
+//!
+//! A write that must notify another system records the notification as a
+//! row of the reserved `chiselstore_outbox` table in the same replicated
+//! transaction as its business rows, with `outbox_statement`, so that the
+//! notification exists if and only if the write took effect. An
+//! `OutboxConsumer` then follows the changes applied by a replica and hands
+//! every undelivered row to the application, in commit order, marking it
+//! delivered through the log once it was handled.
+//!
+//! Delivery is at least once: a message handled right before the consumer
+//! stops or its replica fails may be handed out again, so handlers must be
+//! idempotent. Run one consumer per cluster.
+
+use crate::value::{quote_literal, Value};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    errors::StoreError,
+    server::{Consistency, SequencePaxosStoreTransport, StoreServer},
+};
+#[cfg(not(target_arch = "wasm32"))]
+use sqlite::Connection;
+#[cfg(not(target_arch = "wasm32"))]
+use std::future::Future;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+
+/// Reserved table holding the outbox messages.
+pub const OUTBOX_TABLE: &str = "chiselstore_outbox";
+
+/// Number of messages a consumer reads at once unless told otherwise.
+#[cfg(not(target_arch = "wasm32"))]
+const BATCH_SIZE: usize = 100;
+
+/// How long a consumer with nothing to deliver waits for the replica to
+/// apply more of the log before looking again.
+#[cfg(not(target_arch = "wasm32"))]
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A message of the outbox.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboxMessage {
+    /// Id of the message, increasing in commit order.
+    pub id: i64,
+    /// Topic the message is for.
+    pub topic: String,
+    /// The message.
+    pub payload: Vec<u8>,
+}
+
+/// Statement recording a message for `topic` in the outbox, to run in the
+/// same transaction as the write it announces.
+pub fn outbox_statement(topic: &str, payload: &[u8]) -> String {
+    format!(
+        "INSERT INTO {} (topic, payload) VALUES ({}, {})",
+        OUTBOX_TABLE,
+        quote_literal(topic),
+        Value::from(payload)
+            .to_sql_literal()
+            .expect("blobs have a SQL literal")
+    )
+}
+
+/// Creates the outbox table on `conn` if it does not exist yet.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn create_table(conn: &Connection) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} \
+         (id INTEGER PRIMARY KEY AUTOINCREMENT, topic TEXT NOT NULL, payload BLOB NOT NULL, \
+         delivered INTEGER NOT NULL DEFAULT 0)",
+        OUTBOX_TABLE
+    ))?;
+    Ok(())
+}
+
+/// Delivers the outbox messages committed to a cluster.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct OutboxConsumer<T: SequencePaxosStoreTransport + Send + Sync> {
+    server: Arc<StoreServer<T>>,
+    batch_size: usize,
+    poll_interval: Duration,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: SequencePaxosStoreTransport + Send + Sync> OutboxConsumer<T> {
+    /// Creates a consumer following the changes applied by `server`.
+    pub fn new(server: Arc<StoreServer<T>>) -> Self {
+        Self {
+            server,
+            batch_size: BATCH_SIZE,
+            poll_interval: POLL_INTERVAL,
+        }
+    }
+
+    /// Sets the number of messages read at once.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Returns the oldest undelivered messages, as far as the replica has
+    /// applied the log.
+    pub async fn pending(&self) -> Result<Vec<OutboxMessage>, StoreError> {
+        let stmt = format!(
+            "SELECT id, topic, payload FROM {} WHERE delivered = 0 ORDER BY id LIMIT {}",
+            OUTBOX_TABLE, self.batch_size
+        );
+        let results = self.server.query(stmt, Consistency::RelaxedReads).await?;
+        let messages = results
+            .rows
+            .into_iter()
+            .map(|row| {
+                let mut values = row.typed_values.into_iter();
+                let id = match values.next() {
+                    Some(Value::Integer(id)) => id,
+                    _ => 0,
+                };
+                let topic = row.values[1].clone();
+                let payload = match values.nth(1) {
+                    Some(Value::Blob(payload)) => payload,
+                    _ => vec![],
+                };
+                OutboxMessage { id, topic, payload }
+            })
+            .collect();
+        Ok(messages)
+    }
+
+    /// Marks the messages `ids` delivered on every replica.
+    pub async fn mark_delivered(&self, ids: &[i64]) -> Result<(), StoreError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<_> = ids.iter().map(ToString::to_string).collect();
+        let stmt = format!(
+            "UPDATE {} SET delivered = 1 WHERE id IN ({})",
+            OUTBOX_TABLE,
+            ids.join(", ")
+        );
+        self.server.query(stmt, Consistency::Strong).await?;
+        Ok(())
+    }
+
+    /// Deletes the delivered messages on every replica, returning how many
+    /// there were.
+    pub async fn purge_delivered(&self) -> Result<u64, StoreError> {
+        let stmt = format!("DELETE FROM {} WHERE delivered = 1", OUTBOX_TABLE);
+        let results = self.server.query(stmt, Consistency::Strong).await?;
+        Ok(results.rows_affected)
+    }
+
+    /// Hands every undelivered message, in commit order, to `deliver` and
+    /// marks it delivered once `deliver` succeeds, waiting for new messages
+    /// as the replica applies the log.
+    ///
+    /// Runs until `deliver` or the store fails; messages after the one
+    /// `deliver` failed on stay undelivered.
+    pub async fn run<F, Fut, E>(&self, mut deliver: F) -> Result<(), E>
+    where
+        F: FnMut(OutboxMessage) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: From<StoreError>,
+    {
+        loop {
+            let applied_idx = self.server.applied_idx();
+            let batch = self.pending().await?;
+            if batch.is_empty() {
+                self.server
+                    .wait_applied(applied_idx + 1, self.poll_interval)
+                    .await;
+                continue;
+            }
+            let mut delivered = vec![];
+            let mut failure = None;
+            for message in batch {
+                let id = message.id;
+                match deliver(message).await {
+                    Ok(()) => delivered.push(id),
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
+                }
+            }
+            self.mark_delivered(&delivered).await?;
+            if let Some(e) = failure {
+                return Err(e);
+            }
+        }
+    }
+}
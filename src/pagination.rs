@@ -0,0 +1,132 @@
+//! Keyset pagination with stable page tokens.
+//!
+//! `StoreServer::query_page` reads the rows of a `SELECT` a page at a time
+//! without keeping anything open between pages. Rows are ordered by a key of
+//! result columns, which must be unique and never NULL, and every page
+//! after the first starts right after the key of the last row of the
+//! previous one, so rows inserted or deleted meanwhile never shift rows
+//! into another page or repeat them.
+//!
+//! Each page comes with an opaque token for the next one, encoding the key
+//! of its last row and the applied index it was read at. A node serving
+//! the next page first waits until it applied that index, so paging never
+//! goes back in time even when the pages are read from different nodes,
+//! and `ResultPage::pinned` tells whether the page was read at exactly the
+//! same index as the previous one.
+
+use crate::errors::StoreError;
+use crate::proto::EncodedPageToken;
+use crate::server::QueryResults;
+use crate::value::{hex, quote_identifier, Value};
+use prost::Message;
+
+/// Where the next page of a query starts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageToken {
+    /// Applied index the previous page was read at.
+    pub applied_idx: u64,
+    /// Key of the last row of the previous page.
+    pub after: Vec<Value>,
+}
+
+impl PageToken {
+    /// Encodes the token as an opaque string.
+    pub fn encode(&self) -> String {
+        let token = EncodedPageToken {
+            applied_idx: self.applied_idx,
+            after: self.after.iter().cloned().map(Into::into).collect(),
+        };
+        hex(&token.encode_to_vec())
+    }
+
+    /// Decodes a token made by `encode`.
+    pub fn decode(token: &str) -> Result<Self, StoreError> {
+        let invalid = || StoreError::InvalidRequest(format!("invalid page token {:?}", token));
+        if token.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(token.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        let token = EncodedPageToken::decode(bytes.as_slice()).map_err(|_| invalid())?;
+        Ok(Self {
+            applied_idx: token.applied_idx,
+            after: token.after.into_iter().map(Into::into).collect(),
+        })
+    }
+}
+
+/// A page of rows read by `StoreServer::query_page`.
+#[derive(Debug)]
+pub struct ResultPage {
+    /// The rows of the page, with the columns.
+    pub results: QueryResults,
+    /// Token of the next page; `None` on the last page.
+    pub next: Option<String>,
+    /// Applied index the page was read at.
+    pub applied_idx: u64,
+    /// True if the page was read at the index of the previous page, or is
+    /// the first one.
+    pub pinned: bool,
+}
+
+/// Returns the statement reading the page of up to `limit` rows of `sql`
+/// ordered by `key` that follows `after`, along with its parameters.
+pub(crate) fn page_statement(
+    sql: &str,
+    key: &[String],
+    after: Option<&[Value]>,
+    limit: usize,
+) -> Result<(String, Vec<Value>), StoreError> {
+    if key.is_empty() {
+        return Err(StoreError::InvalidRequest(String::from(
+            "pagination needs a key to order rows by",
+        )));
+    }
+    let columns: Vec<_> = key.iter().map(|column| quote_identifier(column)).collect();
+    let columns = columns.join(", ");
+    let sql = sql.trim().trim_end_matches(';');
+    let (filter, params) = match after {
+        Some(after) if after.len() != key.len() => {
+            return Err(StoreError::InvalidRequest(format!(
+                "page token has {} key values, expected {}",
+                after.len(),
+                key.len()
+            )))
+        }
+        Some(after) => {
+            let placeholders = vec!["?"; after.len()].join(", ");
+            let filter = format!(" WHERE ({}) > ({})", columns, placeholders);
+            (filter, after.to_vec())
+        }
+        None => (String::new(), vec![]),
+    };
+    let stmt = format!(
+        "SELECT * FROM ({}){} ORDER BY {} LIMIT {}",
+        sql, filter, columns, limit
+    );
+    Ok((stmt, params))
+}
+
+/// Returns the key of the last row of `results`, which holds the columns
+/// of `key`.
+pub(crate) fn last_key(results: &QueryResults, key: &[String]) -> Result<Vec<Value>, StoreError> {
+    let row = match results.rows.last() {
+        Some(row) => row,
+        None => return Ok(vec![]),
+    };
+    key.iter()
+        .map(|column| {
+            let idx = results
+                .columns
+                .iter()
+                .position(|c| &c.name == column)
+                .ok_or_else(|| {
+                    StoreError::InvalidRequest(format!("key column {} is not selected", column))
+                })?;
+            Ok(row.typed_values[idx].clone())
+        })
+        .collect()
+}
@@ -0,0 +1,121 @@
+//! Per-command pragma settings.
+//!
+//! A statement or transaction can carry pragma settings that hold while it
+//! is applied, and only then: every replica sets them on the connection it
+//! applies the command on, and restores the previous values afterwards.
+//! Only the pragmas in `ALLOWED` can be set, and only to the values listed
+//! there, since the others either change how later commands are applied or
+//! could let data violate the schema; `ignore_check_constraints` may only
+//! be turned off, for instance.
+
+use crate::errors::StoreError;
+use crate::proto;
+use crate::rows;
+use crate::value::Value;
+use sqlite::Connection;
+
+/// Pragmas a command may set, along with the values it may set them to.
+const ALLOWED: [(&str, &[bool]); 3] = [
+    ("defer_foreign_keys", &[true, false]),
+    ("ignore_check_constraints", &[false]),
+    ("recursive_triggers", &[true, false]),
+];
+
+/// A pragma set while a command is applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PragmaSetting {
+    /// Name of the pragma, e.g. `defer_foreign_keys`.
+    pub name: String,
+    /// Value to set the pragma to.
+    pub enabled: bool,
+}
+
+impl PragmaSetting {
+    pub fn new<S: ToString>(name: S, enabled: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            enabled,
+        }
+    }
+}
+
+impl From<proto::PragmaSetting> for PragmaSetting {
+    fn from(pragma: proto::PragmaSetting) -> Self {
+        Self {
+            name: pragma.name,
+            enabled: pragma.enabled,
+        }
+    }
+}
+
+impl From<PragmaSetting> for proto::PragmaSetting {
+    fn from(pragma: PragmaSetting) -> Self {
+        Self {
+            name: pragma.name,
+            enabled: pragma.enabled,
+        }
+    }
+}
+
+/// Fails with `StoreError::InvalidRequest` unless every setting is allowed
+/// and sets a different pragma.
+pub fn check(pragmas: &[PragmaSetting]) -> Result<(), StoreError> {
+    for (i, pragma) in pragmas.iter().enumerate() {
+        let allowed = ALLOWED
+            .iter()
+            .any(|(name, values)| pragma.name == *name && values.contains(&pragma.enabled));
+        if !allowed {
+            return Err(StoreError::InvalidRequest(format!(
+                "pragma {} cannot be set to {} for a command",
+                pragma.name, pragma.enabled
+            )));
+        }
+        if pragmas[..i].iter().any(|other| other.name == pragma.name) {
+            return Err(StoreError::InvalidRequest(format!(
+                "pragma {} is set twice",
+                pragma.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Runs `apply` on `conn` with `pragmas` set, restoring their previous
+/// values afterwards.
+pub(crate) fn with_pragmas<R>(
+    conn: &Connection,
+    pragmas: &[PragmaSetting],
+    apply: impl FnOnce() -> Result<R, StoreError>,
+) -> Result<R, StoreError> {
+    if pragmas.is_empty() {
+        return apply();
+    }
+    // Checked again so a command that slipped past the proposing node fails
+    // the same way on every replica.
+    check(pragmas)?;
+    let mut previous = vec![];
+    for pragma in pragmas {
+        previous.push(read(conn, &pragma.name)?);
+    }
+    let set = pragmas
+        .iter()
+        .try_for_each(|pragma| write(conn, &pragma.name, pragma.enabled));
+    let result = set.and_then(|_| apply());
+    for (pragma, enabled) in pragmas.iter().zip(previous) {
+        write(conn, &pragma.name, enabled)?;
+    }
+    result
+}
+
+fn read(conn: &Connection, name: &str) -> Result<bool, StoreError> {
+    let results = rows::run(conn, format!("PRAGMA {}", name), &[])?;
+    Ok(matches!(
+        results.rows.first().and_then(|row| row.typed_values.first()),
+        Some(Value::Integer(value)) if *value != 0
+    ))
+}
+
+fn write(conn: &Connection, name: &str, enabled: bool) -> Result<(), StoreError> {
+    conn.execute(format!("PRAGMA {} = {}", name, enabled as u8))?;
+    Ok(())
+}
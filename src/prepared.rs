@@ -0,0 +1,117 @@
+//! Server-side prepared statements.
+//!
+//! `StoreServer::prepare` replicates a statement into the reserved
+//! `chiselstore_prepared` table under an id derived from its text. Commands
+//! executing it then carry only the id and the parameters, and each replica
+//! compiles the statement once per pooled connection and reuses it on every
+//! later apply, instead of parsing it again each time.
+
+use crate::checksum::fnv1a;
+use crate::errors::StoreError;
+use crate::rows::{self, CompiledStatement};
+use crate::server::{query_connection, QueryResults};
+use crate::value::{quote_literal, Value};
+use sqlite::Connection;
+use std::collections::HashMap;
+
+/// Reserved table holding the prepared statements.
+pub const PREPARED_TABLE: &str = "chiselstore_prepared";
+
+/// Maximum number of statements compiled on one connection; the cache is
+/// cleared when it is full.
+const MAX_CACHED: usize = 256;
+
+/// Id of the prepared statement `sql`, the same on every node.
+pub fn statement_id(sql: &str) -> u64 {
+    fnv1a(sql.as_bytes())
+}
+
+/// Creates the prepared statements table on `conn` if it does not exist yet.
+pub(crate) fn create_table(conn: &Connection) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, sql TEXT NOT NULL)",
+        PREPARED_TABLE
+    ))?;
+    Ok(())
+}
+
+/// Checks that `sql` compiles on `conn` and records it as a prepared
+/// statement, returning its id.
+pub(crate) fn apply_prepare(conn: &Connection, sql: &str) -> Result<u64, StoreError> {
+    CompiledStatement::compile(conn, sql)?;
+    let id = statement_id(sql);
+    match lookup(conn, id)? {
+        Some(existing) if existing == sql => return Ok(id),
+        Some(_) => {
+            return Err(StoreError::InvalidRequest(format!(
+                "prepared statement id {} is taken by another statement",
+                id
+            )))
+        }
+        None => {}
+    }
+    conn.execute(format!(
+        "INSERT INTO {} VALUES ({}, {})",
+        PREPARED_TABLE,
+        id as i64,
+        quote_literal(sql)
+    ))?;
+    Ok(id)
+}
+
+/// Reads the SQL of the prepared statement `id`, if there is one.
+pub(crate) fn lookup(conn: &Connection, id: u64) -> Result<Option<String>, StoreError> {
+    let stmt = format!(
+        "SELECT sql FROM {} WHERE id = {}",
+        PREPARED_TABLE, id as i64
+    );
+    Ok(query_connection(conn, stmt)?
+        .rows
+        .into_iter()
+        .next()
+        .and_then(|row| row.values.into_iter().next()))
+}
+
+/// Runs the prepared statement `id` on `conn` without caching it.
+pub(crate) fn execute_uncached(
+    conn: &Connection,
+    id: u64,
+    params: &[Value],
+) -> Result<QueryResults, StoreError> {
+    let sql = lookup(conn, id)?.ok_or(StoreError::UnknownStatement(id))?;
+    rows::run(conn, sql, params)
+}
+
+/// The prepared statements compiled on one pooled connection.
+#[derive(Default)]
+pub(crate) struct StatementCache {
+    compiled: HashMap<u64, CompiledStatement>,
+}
+
+impl StatementCache {
+    /// Runs the prepared statement `id` on `conn`, the connection the cache
+    /// belongs to, compiling it on first use.
+    pub(crate) fn execute(
+        &mut self,
+        conn: &Connection,
+        id: u64,
+        params: &[Value],
+    ) -> Result<QueryResults, StoreError> {
+        self.compile(conn, id)?;
+        self.compiled[&id].run(conn, params)
+    }
+
+    /// Compiles the prepared statement `id` on `conn`, unless it is compiled
+    /// already.
+    pub(crate) fn compile(&mut self, conn: &Connection, id: u64) -> Result<(), StoreError> {
+        if !self.compiled.contains_key(&id) {
+            let sql = lookup(conn, id)?.ok_or(StoreError::UnknownStatement(id))?;
+            if self.compiled.len() >= MAX_CACHED {
+                self.compiled.clear();
+            }
+            self.compiled
+                .insert(id, CompiledStatement::compile(conn, &sql)?);
+        }
+        Ok(())
+    }
+}
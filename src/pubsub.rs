@@ -0,0 +1,57 @@
+//! Replicated publish/subscribe topics.
+//!
+//! Publishing is a replicated command; when a replica applies it, the
+//! message is handed to that replica's local subscribers. Since commands are
+//! applied in log order, every subscriber sees the messages of a topic in
+//! the same total order, interleaved consistently with data changes.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Number of messages a subscriber may fall behind before it is lagged.
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// A message delivered to the subscribers of a topic.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Publication {
+    /// The topic the message was published to.
+    pub topic: String,
+    /// The published payload.
+    pub payload: Vec<u8>,
+    /// The applied index of the publish command.
+    pub index: u64,
+}
+
+/// The topics with local subscribers on a replica.
+#[derive(Debug, Default)]
+pub struct Topics {
+    senders: Mutex<HashMap<String, broadcast::Sender<Publication>>>,
+}
+
+impl Topics {
+    /// Subscribes to the messages applied to `topic` from now on.
+    pub fn subscribe(&self, topic: &str) -> broadcast::Receiver<Publication> {
+        let mut senders = self.senders.lock().unwrap();
+        senders
+            .entry(topic.to_string())
+            .or_insert_with(|| broadcast::channel(SUBSCRIBER_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Delivers an applied publish command to the local subscribers.
+    pub(crate) fn publish(&self, topic: &str, payload: Vec<u8>, index: u64) {
+        let mut senders = self.senders.lock().unwrap();
+        if let Some(sender) = senders.get(topic) {
+            if sender.receiver_count() == 0 {
+                senders.remove(topic);
+                return;
+            }
+            let _ = sender.send(Publication {
+                topic: topic.to_string(),
+                payload,
+                index,
+            });
+        }
+    }
+}
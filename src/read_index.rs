@@ -0,0 +1,124 @@
+//! Leader-confirmed follower reads.
+//!
+//! `Consistency::ReadIndex` reads are served by any replica from its local
+//! state, once it has applied everything decided when the read arrived.
+//! The replica asks the leader for a read index; the leader confirms that it
+//! still leads and answers with its applied index, which covers every entry
+//! it decided. The replica then waits to apply that index. With
+//! `StoreConfig::follower_reads`, followers serve `Consistency::Strong`
+//! reads the same way, spreading them across the cluster.
+//!
+//! The leader confirms that it leads with its lease (see `lease`) or else a
+//! round of leadership checks: it is still the leader if a majority of the
+//! cluster, itself included, still follows it. A new leader first commits a
+//! barrier, like for its lease. The same round lets the leader serve
+//! `Consistency::Strong` reads from its local state instead of appending
+//! them to the log, when `StoreConfig::leader_reads` is set.
+
+use std::collections::HashMap;
+use tokio::sync::oneshot;
+
+/// A read index message between two replicas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadIndexMessage {
+    pub from: u64,
+    pub to: u64,
+    pub msg: ReadIndexMsg,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReadIndexMsg {
+    /// Asks the leader for a read index.
+    Request { id: u64 },
+    /// The read index of request `id`, unset if the node could not confirm
+    /// that it leads.
+    Reply { id: u64, index: Option<u64> },
+    /// Asks a peer whether it follows the sender.
+    Check { id: u64 },
+    /// Answers leadership check `id`.
+    Ack { id: u64, follows: bool },
+}
+
+/// Read index requests of a replica waiting for the leader's reply.
+#[derive(Debug, Default)]
+pub(crate) struct PendingReadIndexes {
+    next_id: u64,
+    waiting: HashMap<u64, oneshot::Sender<Option<u64>>>,
+}
+
+impl PendingReadIndexes {
+    /// Registers a new request, returning its id and where its reply is
+    /// delivered.
+    pub(crate) fn register(&mut self) -> (u64, oneshot::Receiver<Option<u64>>) {
+        self.next_id += 1;
+        let (tx, rx) = oneshot::channel();
+        self.waiting.insert(self.next_id, tx);
+        (self.next_id, rx)
+    }
+
+    /// Delivers the reply to request `id`, if it is still waiting.
+    pub(crate) fn resolve(&mut self, id: u64, index: Option<u64>) {
+        if let Some(tx) = self.waiting.remove(&id) {
+            let _ = tx.send(index);
+        }
+    }
+
+    /// Forgets request `id`, which timed out.
+    pub(crate) fn cancel(&mut self, id: u64) {
+        self.waiting.remove(&id);
+    }
+}
+
+/// A leadership check round waiting for its acks.
+#[derive(Debug)]
+struct Round {
+    needed: usize,
+    acks: Vec<u64>,
+    done: oneshot::Sender<Vec<u64>>,
+}
+
+/// Leadership check rounds of a leader waiting for a majority.
+#[derive(Debug, Default)]
+pub(crate) struct PendingChecks {
+    next_id: u64,
+    rounds: HashMap<u64, Round>,
+}
+
+impl PendingChecks {
+    /// Registers a new round needing `needed` peers to follow this node,
+    /// returning its id and where the peers that did are delivered.
+    pub(crate) fn register(&mut self, needed: usize) -> (u64, oneshot::Receiver<Vec<u64>>) {
+        self.next_id += 1;
+        let (done, rx) = oneshot::channel();
+        let round = Round {
+            needed,
+            acks: vec![],
+            done,
+        };
+        self.rounds.insert(self.next_id, round);
+        (self.next_id, rx)
+    }
+
+    /// Records that `peer` follows this node in round `id`, completing the
+    /// round once enough peers do.
+    pub(crate) fn ack(&mut self, id: u64, peer: u64) {
+        let complete = match self.rounds.get_mut(&id) {
+            Some(round) => {
+                if !round.acks.contains(&peer) {
+                    round.acks.push(peer);
+                }
+                round.acks.len() >= round.needed
+            }
+            None => false,
+        };
+        if complete {
+            let round = self.rounds.remove(&id).unwrap();
+            let _ = round.done.send(round.acks);
+        }
+    }
+
+    /// Forgets round `id`, which timed out.
+    pub(crate) fn cancel(&mut self, id: u64) {
+        self.rounds.remove(&id);
+    }
+}
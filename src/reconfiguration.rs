@@ -0,0 +1,99 @@
+//! Moving to the next configuration once a stop sign is decided.
+//!
+//! A reconfiguration ends the current Sequence Paxos instance with a stop
+//! sign naming the members of the next one. Once the stop sign is decided,
+//! every node that is a member of the next configuration starts a new
+//! instance with the next configuration id on top of the SQLite state it
+//! already has, while the nodes left out retire.
+
+use omnipaxos_core::storage::StopSign;
+
+/// What a node does once the stop sign of its configuration is decided.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transition {
+    /// The node is a member of the next configuration.
+    Continue {
+        /// Id of the next configuration.
+        config_id: u32,
+        /// The other members of the next configuration.
+        peers: Vec<u64>,
+    },
+    /// The node is not a member of the next configuration.
+    Retire {
+        /// Id of the next configuration.
+        config_id: u32,
+    },
+}
+
+/// Tracks the configuration a node is in and decides, exactly once per
+/// decided stop sign, how the node moves on from it.
+#[derive(Clone, Debug)]
+pub struct ReconfigurationManager {
+    id: u64,
+    config_id: u32,
+    peers: Vec<u64>,
+    metadata: Option<Vec<u8>>,
+    retired: bool,
+}
+
+impl ReconfigurationManager {
+    /// Creates a manager for node `id` in configuration `config_id`.
+    pub fn new(id: u64, config_id: u32, peers: Vec<u64>) -> Self {
+        Self {
+            id,
+            config_id,
+            peers,
+            metadata: None,
+            retired: false,
+        }
+    }
+
+    /// Id of the configuration the node is in.
+    pub fn config_id(&self) -> u32 {
+        self.config_id
+    }
+
+    /// The other members of the configuration the node is in.
+    pub fn peers(&self) -> &[u64] {
+        &self.peers
+    }
+
+    /// Metadata of the stop sign that started the configuration the node
+    /// is in; `None` for the first configuration the node ran since it
+    /// started.
+    pub fn metadata(&self) -> Option<&[u8]> {
+        self.metadata.as_deref()
+    }
+
+    /// True once the node has left the cluster.
+    pub fn is_retired(&self) -> bool {
+        self.retired
+    }
+
+    /// Returns the transition for a decided stop sign, or `None` if the
+    /// transition to its configuration was already returned.
+    pub fn on_decided(&mut self, stopsign: &StopSign) -> Option<Transition> {
+        if self.retired || stopsign.config_id <= self.config_id {
+            return None;
+        }
+        self.config_id = stopsign.config_id;
+        self.metadata = stopsign.metadata.clone();
+        if !stopsign.nodes.contains(&self.id) {
+            self.retired = true;
+            self.peers.clear();
+            return Some(Transition::Retire {
+                config_id: self.config_id,
+            });
+        }
+        self.peers = stopsign
+            .nodes
+            .iter()
+            .copied()
+            .filter(|node| *node != self.id)
+            .collect();
+        Some(Transition::Continue {
+            config_id: self.config_id,
+            peers: self.peers.clone(),
+        })
+    }
+}
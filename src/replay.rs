@@ -0,0 +1,231 @@
+//! Replaying commands into a standalone database.
+//!
+//! `replay` copies a backup and applies a sequence of log commands to the
+//! copy the way replicas apply them, without a cluster. It is meant for
+//! forensic analysis and for checking that applying is deterministic: a
+//! replay of the commands after a backup should match a later backup.
+
+use crate::backup::{self, BACKUP_TABLE};
+use crate::errors::StoreError;
+use crate::functions::{self, FunctionRegistry};
+use crate::pragmas::with_pragmas;
+use crate::prepared;
+use crate::savepoints;
+use crate::schema;
+use crate::server::{
+    query_connection, query_connection_in_transaction, query_connection_with_params, CommandKind,
+    StoreCommand,
+};
+use crate::settings;
+use crate::tombstones::{self, Tombstone};
+use sqlite::{Connection, OpenFlags};
+use std::path::Path;
+
+/// Savepoint a transaction command applied with `apply_nested` runs in.
+const NESTED_SAVEPOINT: &str = "chiselstore_nested";
+
+/// A command that failed to apply during a replay.
+#[derive(Debug, Clone)]
+pub struct ReplayFailure {
+    /// Applied index of the command.
+    pub applied_idx: u64,
+    /// The command's SQL statement.
+    pub sql: String,
+    /// Why it failed.
+    pub error: String,
+}
+
+/// Result of a replay.
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+    /// Applied index of the backup the replay started from.
+    pub start_idx: u64,
+    /// Applied index of the replayed database.
+    pub applied_idx: u64,
+    /// Commands that failed to apply, in log order. Like replicas running
+    /// with `ApplyErrorPolicy::SkipAndRecord`, the replay skips them.
+    pub failures: Vec<ReplayFailure>,
+}
+
+/// Replays `commands` onto a copy of the backup at `backup`, writing the
+/// result to `output`.
+///
+/// `commands` must be the log commands directly following the backup's
+/// applied index, in log order. Custom SQL functions and collations the
+/// commands use must be in `functions`, at the versions they record; replay
+/// stops with `StoreError::MissingFunction` or `MissingCollation`
+/// otherwise, as a replica would. The backup itself is never modified.
+///
+/// Commands for a tenant database are counted but not applied, since the
+/// backup only holds the shared database.
+pub fn replay<I>(
+    backup: &Path,
+    output: &Path,
+    functions: &FunctionRegistry,
+    commands: I,
+) -> Result<ReplayReport, StoreError>
+where
+    I: IntoIterator<Item = StoreCommand>,
+{
+    std::fs::copy(backup, output)?;
+    let conn = open_database(output, functions)?;
+
+    let start_idx = backup::read_applied_idx(&conn, backup)?;
+    let mut applied_idx = start_idx;
+    let mut failures = vec![];
+    for cmd in commands {
+        check_available(functions, &cmd)?;
+        applied_idx += 1;
+        if cmd.tenant.is_some() {
+            continue;
+        }
+        if let Err(e) = apply(&conn, &cmd, applied_idx) {
+            failures.push(ReplayFailure {
+                applied_idx,
+                sql: cmd.sql,
+                error: e.to_string(),
+            });
+        }
+    }
+    conn.execute(format!(
+        "UPDATE {} SET applied_idx = {}",
+        BACKUP_TABLE, applied_idx
+    ))?;
+
+    Ok(ReplayReport {
+        start_idx,
+        applied_idx,
+        failures,
+    })
+}
+
+/// Opens the standalone database at `path` with the functions and
+/// collations of `functions` registered.
+pub(crate) fn open_database(
+    path: &Path,
+    functions: &FunctionRegistry,
+) -> Result<Connection, StoreError> {
+    let flags = OpenFlags::new().set_read_write().set_no_mutex();
+    let mut conn = Connection::open_with_flags(path, flags)?;
+    for def in functions.functions() {
+        functions::register(&mut conn, def)?;
+    }
+    for def in functions.collations() {
+        functions::register_collation(&mut conn, def)?;
+    }
+    settings::create_table(&conn)?;
+    prepared::create_table(&conn)?;
+    schema::create_table(&conn)?;
+    Ok(conn)
+}
+
+/// Fails if `cmd` uses a function or collation missing from `functions`.
+pub(crate) fn check_available(
+    functions: &FunctionRegistry,
+    cmd: &StoreCommand,
+) -> Result<(), StoreError> {
+    if let Some(name) = cmd
+        .functions
+        .iter()
+        .find(|name| !functions.has_function(name))
+    {
+        return Err(StoreError::MissingFunction(name.clone()));
+    }
+    if let Some(name) = cmd
+        .collations
+        .iter()
+        .find(|name| !functions.has_collation(name))
+    {
+        return Err(StoreError::MissingCollation(name.clone()));
+    }
+    Ok(())
+}
+
+/// Applies `cmd` at `applied_idx` to a standalone database.
+pub(crate) fn apply(
+    conn: &Connection,
+    cmd: &StoreCommand,
+    applied_idx: u64,
+) -> Result<(), StoreError> {
+    with_pragmas(conn, &cmd.pragmas, || {
+        apply_kind(conn, cmd, applied_idx, false)
+    })
+}
+
+/// Applies `cmd` at `applied_idx` like `apply`, on a connection that is
+/// already in a transaction: transaction commands run in a savepoint.
+pub(crate) fn apply_nested(
+    conn: &Connection,
+    cmd: &StoreCommand,
+    applied_idx: u64,
+) -> Result<(), StoreError> {
+    with_pragmas(conn, &cmd.pragmas, || {
+        apply_kind(conn, cmd, applied_idx, true)
+    })
+}
+
+/// Runs the transaction `sql` in a savepoint, rolling it back if any
+/// statement fails outside a savepoint of its own.
+fn query_connection_in_savepoint(conn: &Connection, sql: String) -> Result<(), StoreError> {
+    conn.execute(format!("SAVEPOINT {}", NESTED_SAVEPOINT))?;
+    if let Err(e) = savepoints::run(conn, sql) {
+        conn.execute(format!(
+            "ROLLBACK TO {name}; RELEASE {name}",
+            name = NESTED_SAVEPOINT
+        ))?;
+        return Err(e);
+    }
+    conn.execute(format!("RELEASE {}", NESTED_SAVEPOINT))?;
+    Ok(())
+}
+
+fn apply_kind(
+    conn: &Connection,
+    cmd: &StoreCommand,
+    applied_idx: u64,
+    nested: bool,
+) -> Result<(), StoreError> {
+    match &cmd.kind {
+        CommandKind::Statement => {
+            query_connection_with_params(conn, cmd.sql.clone(), &cmd.params)?;
+        }
+        // Publications only reach live subscribers.
+        CommandKind::Publish { .. } => {}
+        CommandKind::Conditional { predicate } => {
+            if !query_connection(conn, predicate.clone())?.rows.is_empty() {
+                query_connection(conn, cmd.sql.clone())?;
+            }
+        }
+        CommandKind::HardDelete { subject } => {
+            let tombstone = Tombstone {
+                subject: subject.clone(),
+                sql: cmd.sql.clone(),
+                applied_idx,
+            };
+            tombstones::apply_hard_delete(conn, &tombstone)?;
+        }
+        CommandKind::SetConfig { key, value } => {
+            settings::apply_set_config(conn, key, value)?;
+        }
+        CommandKind::Transaction if nested => {
+            query_connection_in_savepoint(conn, cmd.sql.clone())?;
+        }
+        CommandKind::Transaction => {
+            query_connection_in_transaction(conn, cmd.sql.clone())?;
+        }
+        CommandKind::Prepare => {
+            prepared::apply_prepare(conn, &cmd.sql)?;
+        }
+        CommandKind::ExecutePrepared { statement } => {
+            prepared::execute_uncached(conn, *statement, &cmd.params)?;
+        }
+        CommandKind::Migration {
+            version,
+            name,
+            checksum,
+        } => {
+            schema::apply_migration(conn, *version, name, *checksum, &cmd.sql)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,459 @@
+//! A Redis-compatible (RESP) facade over a replicated key-value table.
+//!
+//! `serve` accepts Redis clients and translates `GET`, `SET`, `DEL`, `SCAN`
+//! and `EXPIRE` into statements on the reserved `chiselstore_kv` table.
+//! Writes are always replicated. Reads are linearizable unless the key, or
+//! the literal prefix of a `SCAN` pattern, starts with one of
+//! `RespConfig::relaxed_prefixes`, in which case they are served from the
+//! node's local state.
+//!
+//! Expiry times are fixed by the node a write is submitted to, so every
+//! replica stores the same ones. Expired keys are hidden from reads and
+//! overwritten by later writes but not purged.
+
+use crate::errors::StoreError;
+use crate::server::{Consistency, QueryResults, SequencePaxosStoreTransport, StoreServer};
+use crate::value::Value;
+use crate::QueryLane;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Reserved table holding the keys.
+pub const KV_TABLE: &str = "chiselstore_kv";
+
+/// Number of keys a `SCAN` returns when it does not ask for a count.
+const SCAN_COUNT: u64 = 10;
+
+/// Largest bulk string a client may send.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Settings of the RESP facade.
+#[derive(Debug, Clone)]
+pub struct RespConfig {
+    /// Address to accept Redis clients on.
+    pub addr: SocketAddr,
+    /// Keys starting with one of these are read with relaxed consistency.
+    pub relaxed_prefixes: Vec<Vec<u8>>,
+}
+
+/// Serves Redis clients on `config.addr` until the listener fails.
+pub async fn serve<T>(server: Arc<StoreServer<T>>, config: RespConfig) -> io::Result<()>
+where
+    T: SequencePaxosStoreTransport + Send + Sync + 'static,
+{
+    let create = format!(
+        "CREATE TABLE IF NOT EXISTS {} \
+         (key BLOB PRIMARY KEY, value BLOB NOT NULL, expires_at INTEGER)",
+        KV_TABLE
+    );
+    server
+        .query(create, Consistency::Strong)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let listener = TcpListener::bind(config.addr).await?;
+    let config = Arc::new(config);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = server.clone();
+        let config = config.clone();
+        tokio::task::spawn(async move {
+            let _ = handle_connection(stream, server, config).await;
+        });
+    }
+}
+
+/// A RESP reply.
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => {
+                let e = e.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{}\r\n", e).as_bytes())
+            }
+            Reply::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+impl From<StoreError> for Reply {
+    fn from(e: StoreError) -> Self {
+        Reply::Error(format!("ERR {}", e))
+    }
+}
+
+async fn handle_connection<T>(
+    stream: TcpStream,
+    server: Arc<StoreServer<T>>,
+    config: Arc<RespConfig>,
+) -> io::Result<()>
+where
+    T: SequencePaxosStoreTransport + Send + Sync + 'static,
+{
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    while let Some(command) = read_command(&mut reader).await? {
+        let quit = command
+            .first()
+            .map_or(false, |name| name.eq_ignore_ascii_case(b"quit"));
+        let reply = match quit {
+            true => Reply::Simple("OK"),
+            false => execute(&server, &config, command).await,
+        };
+        let mut out = Vec::new();
+        reply.encode(&mut out);
+        writer.write_all(&out).await?;
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the next command, either a RESP array of bulk strings or an inline
+/// command, returning `None` once the client hangs up.
+async fn read_command<R>(reader: &mut BufReader<R>) -> io::Result<Option<Vec<Vec<u8>>>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let inline = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(inline));
+    }
+    let count = parse_len(&line[1..])?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let header = read_line(reader).await?.ok_or_else(unexpected_eof)?;
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error("expected a bulk string"));
+        }
+        let len = parse_len(&header[1..])?;
+        if len > MAX_BULK_LEN {
+            return Err(protocol_error("bulk string too long"));
+        }
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+async fn read_line<R>(reader: &mut BufReader<R>) -> io::Result<Option<Vec<u8>>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    while matches!(line.last(), Some(b'\n') | Some(b'\r')) {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8]) -> io::Result<usize> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed mid-command",
+    )
+}
+
+/// Milliseconds since the Unix epoch on this node.
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// SQL condition selecting the keys that have not expired at `?`.
+const LIVE: &str = "(expires_at IS NULL OR expires_at > ?)";
+
+fn consistency(config: &RespConfig, key: &[u8]) -> Consistency {
+    match config
+        .relaxed_prefixes
+        .iter()
+        .any(|prefix| key.starts_with(prefix))
+    {
+        true => Consistency::RelaxedReads,
+        false => Consistency::Strong,
+    }
+}
+
+async fn run<T>(
+    server: &StoreServer<T>,
+    sql: String,
+    params: Vec<Value>,
+    consistency: Consistency,
+) -> Result<QueryResults, StoreError>
+where
+    T: SequencePaxosStoreTransport + Send + Sync + 'static,
+{
+    server
+        .query_with_params(sql, params, consistency, QueryLane::Transactional)
+        .await
+}
+
+fn wrong_args(name: &[u8]) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        String::from_utf8_lossy(name).to_lowercase()
+    ))
+}
+
+fn parse_int(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+async fn execute<T>(server: &StoreServer<T>, config: &RespConfig, args: Vec<Vec<u8>>) -> Reply
+where
+    T: SequencePaxosStoreTransport + Send + Sync + 'static,
+{
+    let name = match args.first() {
+        Some(name) => name.to_ascii_lowercase(),
+        None => return Reply::Error(String::from("ERR empty command")),
+    };
+    let args = &args[1..];
+    let result = match (name.as_slice(), args) {
+        (b"ping", []) => Ok(Reply::Simple("PONG")),
+        (b"ping", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+        // Sent by redis-cli on connect.
+        (b"command", _) => Ok(Reply::Array(vec![])),
+        (b"get", [key]) => get(server, config, key).await,
+        (b"set", [key, value, options @ ..]) => set(server, key, value, options).await,
+        (b"del", keys) if !keys.is_empty() => del(server, keys).await,
+        (b"expire", [key, seconds]) => expire(server, key, seconds).await,
+        (b"scan", [cursor, options @ ..]) => scan(server, config, cursor, options).await,
+        (b"get" | b"set" | b"del" | b"expire" | b"scan" | b"ping", _) => {
+            return wrong_args(&name);
+        }
+        _ => {
+            return Reply::Error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(&name)
+            ))
+        }
+    };
+    result.unwrap_or_else(Reply::from)
+}
+
+async fn get<T>(
+    server: &StoreServer<T>,
+    config: &RespConfig,
+    key: &[u8],
+) -> Result<Reply, StoreError>
+where
+    T: SequencePaxosStoreTransport + Send + Sync + 'static,
+{
+    let sql = format!("SELECT value FROM {} WHERE key = ? AND {}", KV_TABLE, LIVE);
+    let params = vec![Value::Blob(key.to_vec()), Value::Integer(now_ms())];
+    let results = run(server, sql, params, consistency(config, key)).await?;
+    let value = results
+        .rows
+        .into_iter()
+        .next()
+        .and_then(|row| row.typed_values.into_iter().next())
+        .map(|value| match value {
+            Value::Blob(bytes) => bytes,
+            other => other.to_string().into_bytes(),
+        });
+    Ok(Reply::Bulk(value))
+}
+
+async fn set<T>(
+    server: &StoreServer<T>,
+    key: &[u8],
+    value: &[u8],
+    options: &[Vec<u8>],
+) -> Result<Reply, StoreError>
+where
+    T: SequencePaxosStoreTransport + Send + Sync + 'static,
+{
+    let expires_at = match options {
+        [] => None,
+        [unit, amount] => {
+            let scale = match unit.to_ascii_lowercase().as_slice() {
+                b"ex" => 1000,
+                b"px" => 1,
+                _ => return Ok(Reply::Error(String::from("ERR syntax error"))),
+            };
+            match parse_int(amount) {
+                Some(amount) if amount > 0 => Some(now_ms().saturating_add(amount * scale)),
+                _ => {
+                    return Ok(Reply::Error(String::from(
+                        "ERR invalid expire time in 'set' command",
+                    )))
+                }
+            }
+        }
+        _ => return Ok(Reply::Error(String::from("ERR syntax error"))),
+    };
+    let sql = format!(
+        "INSERT INTO {} VALUES (?, ?, ?) ON CONFLICT(key) DO UPDATE \
+         SET value = excluded.value, expires_at = excluded.expires_at",
+        KV_TABLE
+    );
+    let params = vec![
+        Value::Blob(key.to_vec()),
+        Value::Blob(value.to_vec()),
+        expires_at.map_or(Value::Null, Value::Integer),
+    ];
+    run(server, sql, params, Consistency::Strong).await?;
+    Ok(Reply::Simple("OK"))
+}
+
+async fn del<T>(server: &StoreServer<T>, keys: &[Vec<u8>]) -> Result<Reply, StoreError>
+where
+    T: SequencePaxosStoreTransport + Send + Sync + 'static,
+{
+    let placeholders = vec!["?"; keys.len()].join(", ");
+    let sql = format!(
+        "DELETE FROM {} WHERE key IN ({}) AND {}",
+        KV_TABLE, placeholders, LIVE
+    );
+    let mut params: Vec<_> = keys.iter().map(|key| Value::Blob(key.clone())).collect();
+    params.push(Value::Integer(now_ms()));
+    let results = run(server, sql, params, Consistency::Strong).await?;
+    Ok(Reply::Integer(results.rows_affected as i64))
+}
+
+async fn expire<T>(server: &StoreServer<T>, key: &[u8], seconds: &[u8]) -> Result<Reply, StoreError>
+where
+    T: SequencePaxosStoreTransport + Send + Sync + 'static,
+{
+    let seconds = match parse_int(seconds) {
+        Some(seconds) => seconds,
+        None => {
+            return Ok(Reply::Error(String::from(
+                "ERR value is not an integer or out of range",
+            )))
+        }
+    };
+    let now = now_ms();
+    let sql = format!(
+        "UPDATE {} SET expires_at = ? WHERE key = ? AND {}",
+        KV_TABLE, LIVE
+    );
+    let params = vec![
+        Value::Integer(now.saturating_add(seconds.saturating_mul(1000))),
+        Value::Blob(key.to_vec()),
+        Value::Integer(now),
+    ];
+    let results = run(server, sql, params, Consistency::Strong).await?;
+    Ok(Reply::Integer(results.rows_affected as i64))
+}
+
+/// `SCAN` over the keys in rowid order; the cursor is the last rowid
+/// returned, or 0 once the scan is complete.
+async fn scan<T>(
+    server: &StoreServer<T>,
+    config: &RespConfig,
+    cursor: &[u8],
+    options: &[Vec<u8>],
+) -> Result<Reply, StoreError>
+where
+    T: SequencePaxosStoreTransport + Send + Sync + 'static,
+{
+    let cursor = match parse_int(cursor) {
+        Some(cursor) if cursor >= 0 => cursor,
+        _ => return Ok(Reply::Error(String::from("ERR invalid cursor"))),
+    };
+    let mut pattern = None;
+    let mut count = SCAN_COUNT;
+    for option in options.chunks(2) {
+        match (option[0].to_ascii_lowercase().as_slice(), option.get(1)) {
+            (b"match", Some(p)) => pattern = Some(p.clone()),
+            (b"count", Some(n)) => match parse_int(n) {
+                Some(n) if n > 0 => count = n as u64,
+                _ => return Ok(Reply::Error(String::from("ERR syntax error"))),
+            },
+            _ => return Ok(Reply::Error(String::from("ERR syntax error"))),
+        }
+    }
+    let mut sql = format!(
+        "SELECT rowid, key FROM {} WHERE rowid > ? AND {}",
+        KV_TABLE, LIVE
+    );
+    let mut params = vec![Value::Integer(cursor), Value::Integer(now_ms())];
+    // Redis and SQLite glob patterns share `*`, `?` and `[...]`.
+    let prefix: Vec<u8> = match &pattern {
+        Some(pattern) => {
+            sql.push_str(" AND CAST(key AS TEXT) GLOB ?");
+            params.push(Value::Text(String::from_utf8_lossy(pattern).into_owned()));
+            pattern
+                .iter()
+                .take_while(|b| !matches!(b, b'*' | b'?' | b'[' | b'\\'))
+                .copied()
+                .collect()
+        }
+        None => vec![],
+    };
+    sql.push_str(&format!(" ORDER BY rowid LIMIT {}", count));
+    let results = run(server, sql, params, consistency(config, &prefix)).await?;
+    let next = match results.rows.len() as u64 {
+        n if n < count => 0,
+        _ => results
+            .rows
+            .last()
+            .and_then(|row| row.values[0].parse().ok())
+            .unwrap_or(0),
+    };
+    let keys = results
+        .rows
+        .into_iter()
+        .map(|row| match row.typed_values.into_iter().nth(1) {
+            Some(Value::Blob(key)) => Reply::Bulk(Some(key)),
+            other => Reply::Bulk(other.map(|key| key.to_string().into_bytes())),
+        })
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string().into_bytes())),
+        Reply::Array(keys),
+    ]))
+}
@@ -0,0 +1,97 @@
+//! Retry budgets for clients.
+//!
+//! A client fails a request over to the next node while nodes are
+//! unreachable. During a partial outage, every request doing so multiplies
+//! the load on the nodes that are left. With a `RetryBudget`, a client only
+//! sends a request again, or hedges a slow read to a second node, while its
+//! retries in the current second stay within a fraction of its requests,
+//! and only if sending the request twice is safe, which only reads are.
+//! Writes are not deduplicated by the cluster, so a write sent again after
+//! a timeout may be applied twice.
+
+use crate::nodes::{self, Timestamp};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+
+/// Length of the window retries are counted in.
+#[cfg(not(target_arch = "wasm32"))]
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// How many retries a client may send.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryBudget {
+    /// Retries allowed per request, from 0 to 1.
+    pub ratio: f64,
+    /// Retries allowed per second regardless of traffic, so clients sending
+    /// few requests can still fail over.
+    pub min_per_sec: u32,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self {
+            ratio: 0.1,
+            min_per_sec: 10,
+        }
+    }
+}
+
+/// Counts the requests and retries of a client against its budget.
+///
+/// On `wasm32`, which has no monotonic clock in `std`, the window never
+/// ends, so retries are held to a fraction of all requests.
+#[derive(Debug)]
+pub(crate) struct RetryTracker {
+    budget: RetryBudget,
+    window_start: Option<Timestamp>,
+    requests: u32,
+    retries: u32,
+}
+
+impl RetryTracker {
+    pub(crate) fn new(budget: RetryBudget) -> Self {
+        Self {
+            budget,
+            window_start: nodes::now(),
+            requests: 0,
+            retries: 0,
+        }
+    }
+
+    /// Records a request sent for the first time.
+    pub(crate) fn record_request(&mut self) {
+        self.roll_window();
+        self.requests = self.requests.saturating_add(1);
+    }
+
+    /// Takes a retry from the budget, returning false if none is left.
+    pub(crate) fn try_retry(&mut self) -> bool {
+        self.roll_window();
+        let allowed = self.budget.min_per_sec as f64 + self.budget.ratio * self.requests as f64;
+        if self.retries as f64 + 1.0 > allowed {
+            return false;
+        }
+        self.retries += 1;
+        true
+    }
+
+    /// Starts a new window once the current one is over.
+    fn roll_window(&mut self) {
+        if !window_over(self.window_start) {
+            return;
+        }
+        self.window_start = nodes::now();
+        self.requests = 0;
+        self.retries = 0;
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn window_over(start: Option<Timestamp>) -> bool {
+    start.map_or(false, |start| start.elapsed() >= WINDOW)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn window_over(_start: Option<Timestamp>) -> bool {
+    false
+}
@@ -0,0 +1,122 @@
+//! Routing queries across several ChiselStore clusters.
+//!
+//! Applications running several small clusters map each logical namespace to
+//! the cluster holding its data, and address queries by namespace.
+
+use crate::client::ChiselStoreClient;
+use crate::errors::ClientError;
+use crate::proto::{Consistency, QueryResults};
+use std::collections::HashMap;
+use tonic::body::BoxBody;
+use tonic::client::GrpcService;
+use tonic::codegen::{Body, StdError};
+
+#[cfg(not(target_arch = "wasm32"))]
+use tonic::transport::Channel;
+
+/// Client routing queries to clusters by namespace.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct MultiClusterClient<T = Channel> {
+    clusters: HashMap<String, ChiselStoreClient<T>>,
+    default_namespace: Option<String>,
+}
+
+/// Client routing queries to clusters by namespace.
+#[cfg(target_arch = "wasm32")]
+#[derive(Debug)]
+pub struct MultiClusterClient<T> {
+    clusters: HashMap<String, ChiselStoreClient<T>>,
+    default_namespace: Option<String>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl MultiClusterClient<Channel> {
+    /// Creates a client from a map of namespaces to the node addresses of
+    /// their cluster.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a namespace has no addresses.
+    pub fn from_config<N, A>(config: HashMap<N, Vec<A>>) -> Result<Self, ClientError>
+    where
+        N: ToString,
+        A: ToString,
+    {
+        let mut client = Self::new();
+        for (namespace, addrs) in config {
+            client = client.with_cluster(namespace, ChiselStoreClient::with_nodes(addrs)?);
+        }
+        Ok(client)
+    }
+}
+
+impl<T> MultiClusterClient<T>
+where
+    T: GrpcService<BoxBody> + Clone,
+    T::ResponseBody: Body + Send + Sync + 'static,
+    T::Error: Into<StdError>,
+    <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+{
+    /// Creates a client without any clusters.
+    pub fn new() -> Self {
+        Self {
+            clusters: HashMap::new(),
+            default_namespace: None,
+        }
+    }
+
+    /// Routes `namespace` to the cluster reached by `client`.
+    pub fn with_cluster<N: ToString>(mut self, namespace: N, client: ChiselStoreClient<T>) -> Self {
+        self.clusters.insert(namespace.to_string(), client);
+        self
+    }
+
+    /// Routes namespaces without a cluster of their own to the cluster of
+    /// `namespace`.
+    pub fn with_default_namespace<N: ToString>(mut self, namespace: N) -> Self {
+        self.default_namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// Returns the namespaces with a cluster, in no particular order.
+    pub fn namespaces(&self) -> impl Iterator<Item = &str> {
+        self.clusters.keys().map(String::as_str)
+    }
+
+    /// Returns the client of the cluster serving `namespace`.
+    pub fn cluster(&mut self, namespace: &str) -> Result<&mut ChiselStoreClient<T>, ClientError> {
+        let namespace = if self.clusters.contains_key(namespace) {
+            namespace
+        } else {
+            self.default_namespace
+                .as_deref()
+                .ok_or_else(|| ClientError::UnknownNamespace(namespace.to_string()))?
+        };
+        self.clusters
+            .get_mut(namespace)
+            .ok_or_else(|| ClientError::UnknownNamespace(namespace.to_string()))
+    }
+
+    /// Executes a statement on the cluster serving `namespace`.
+    pub async fn query<S: ToString>(
+        &mut self,
+        namespace: &str,
+        sql: S,
+        consistency: Consistency,
+    ) -> Result<QueryResults, ClientError> {
+        self.cluster(namespace)?.query(sql, consistency).await
+    }
+}
+
+impl<T> Default for MultiClusterClient<T>
+where
+    T: GrpcService<BoxBody>,
+    T::ResponseBody: Body + Send + Sync + 'static,
+    T::Error: Into<StdError>,
+    <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,300 @@
+//! Running SQL and reading typed rows.
+//!
+//! Statements are prepared and stepped through the SQLite C API, so each
+//! column is read with its storage class (integer, real, text, blob or
+//! NULL) next to the text rendering results always carried.
+
+use crate::errors::StoreError;
+use crate::server::{Column, QueryResults, QueryRow};
+use crate::value::Value;
+use sqlite::Connection;
+use sqlite3_sys as ffi;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+/// A prepared statement, finalized on drop.
+struct RawStatement(*mut ffi::sqlite3_stmt);
+
+impl Drop for RawStatement {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_finalize(self.0);
+        }
+    }
+}
+
+/// Runs the statements of `sql` on `conn` and returns the rows they
+/// produce, along with the columns of the last statement that has any and
+/// the changes they made. `params` are bound to the placeholders of the
+/// first statement; with parameters, only the first statement runs.
+pub(crate) fn run(
+    conn: &Connection,
+    sql: String,
+    params: &[Value],
+) -> Result<QueryResults, StoreError> {
+    collect(conn, |columns, rows| {
+        for_each_row(
+            conn,
+            sql,
+            params,
+            |c| *columns = c,
+            |row| {
+                rows.push(row);
+                true
+            },
+        )
+    })
+}
+
+/// Collects the columns and rows `read` hands over into results, along with
+/// the changes made on `conn` meanwhile.
+fn collect(
+    conn: &Connection,
+    read: impl FnOnce(&mut Vec<Column>, &mut Vec<QueryRow>) -> Result<(), StoreError>,
+) -> Result<QueryResults, StoreError> {
+    let mut results = QueryResults::new(vec![]);
+    let db = conn.as_raw();
+    let changes_before = unsafe {
+        // Connections are pooled, so forget rowids of earlier queries.
+        ffi::sqlite3_set_last_insert_rowid(db, 0);
+        ffi::sqlite3_total_changes(db)
+    };
+    read(&mut results.columns, &mut results.rows)?;
+    unsafe {
+        results.rows_affected = (ffi::sqlite3_total_changes(db) - changes_before) as u64;
+        results.last_insert_rowid = match ffi::sqlite3_last_insert_rowid(db) {
+            0 => None,
+            rowid => Some(rowid),
+        };
+    }
+    Ok(results)
+}
+
+/// Runs `sql` like `run`, handing the columns of each statement that has
+/// any to `on_columns` and each row to `on_row` as it is stepped, instead
+/// of collecting them. Stops early once `on_row` returns false.
+pub(crate) fn for_each_row(
+    conn: &Connection,
+    sql: String,
+    params: &[Value],
+    mut on_columns: impl FnMut(Vec<Column>),
+    mut on_row: impl FnMut(QueryRow) -> bool,
+) -> Result<(), StoreError> {
+    let sql = CString::new(sql)
+        .map_err(|_| StoreError::InvalidRequest(String::from("SQL contains a NUL byte")))?;
+    let db = conn.as_raw();
+    let mut tail: *const c_char = sql.as_ptr();
+    unsafe {
+        while *tail != 0 {
+            let mut raw = ptr::null_mut();
+            let rc = ffi::sqlite3_prepare_v2(db, tail, -1, &mut raw, &mut tail);
+            if rc != ffi::SQLITE_OK as c_int {
+                return Err(error(db, rc));
+            }
+            // Only whitespace or a comment was left.
+            if raw.is_null() {
+                continue;
+            }
+            let statement = RawStatement(raw);
+            if !step(db, statement.0, params, &mut on_columns, &mut on_row)? {
+                return Ok(());
+            }
+            if !params.is_empty() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Binds `params` to `statement` and steps it to completion, returning
+/// false if `on_row` stopped it early.
+unsafe fn step(
+    db: *mut ffi::sqlite3,
+    statement: *mut ffi::sqlite3_stmt,
+    params: &[Value],
+    on_columns: &mut impl FnMut(Vec<Column>),
+    on_row: &mut impl FnMut(QueryRow) -> bool,
+) -> Result<bool, StoreError> {
+    if ffi::sqlite3_column_count(statement) > 0 {
+        on_columns(read_columns(statement));
+    }
+    for (i, param) in params.iter().enumerate() {
+        let rc = bind(statement, i as c_int + 1, param);
+        if rc != ffi::SQLITE_OK as c_int {
+            return Err(error(db, rc));
+        }
+    }
+    loop {
+        match ffi::sqlite3_step(statement) {
+            rc if rc == ffi::SQLITE_ROW as c_int => {
+                if !on_row(read_row(statement)) {
+                    return Ok(false);
+                }
+            }
+            rc if rc == ffi::SQLITE_DONE as c_int => return Ok(true),
+            rc => return Err(error(db, rc)),
+        }
+    }
+}
+
+/// A single statement compiled once and run any number of times on the
+/// connection it was compiled on.
+pub(crate) struct CompiledStatement(RawStatement);
+
+// The statement is only ever used with the lock of its connection held.
+unsafe impl Send for CompiledStatement {}
+
+impl CompiledStatement {
+    /// Compiles `sql`, which must be a single statement, on `conn`.
+    pub(crate) fn compile(conn: &Connection, sql: &str) -> Result<Self, StoreError> {
+        let sql = CString::new(sql)
+            .map_err(|_| StoreError::InvalidRequest(String::from("SQL contains a NUL byte")))?;
+        let db = conn.as_raw();
+        let mut tail: *const c_char = ptr::null();
+        let mut raw = ptr::null_mut();
+        unsafe {
+            let rc = ffi::sqlite3_prepare_v2(db, sql.as_ptr(), -1, &mut raw, &mut tail);
+            if rc != ffi::SQLITE_OK as c_int {
+                return Err(error(db, rc));
+            }
+            if raw.is_null() {
+                return Err(StoreError::InvalidRequest(String::from(
+                    "no statement to prepare",
+                )));
+            }
+            let statement = RawStatement(raw);
+            if !CStr::from_ptr(tail).to_string_lossy().trim().is_empty() {
+                return Err(StoreError::InvalidRequest(String::from(
+                    "prepared statements must be a single statement",
+                )));
+            }
+            Ok(Self(statement))
+        }
+    }
+
+    /// Runs the statement on `conn`, the connection it was compiled on,
+    /// with `params` bound, like `run`.
+    pub(crate) fn run(
+        &self,
+        conn: &Connection,
+        params: &[Value],
+    ) -> Result<QueryResults, StoreError> {
+        let db = conn.as_raw();
+        let statement = (self.0).0;
+        collect(conn, |columns, rows| unsafe {
+            ffi::sqlite3_reset(statement);
+            ffi::sqlite3_clear_bindings(statement);
+            let stepped = step(db, statement, params, &mut |c| *columns = c, &mut |row| {
+                rows.push(row);
+                true
+            });
+            // Release the statement's locks until it runs again.
+            ffi::sqlite3_reset(statement);
+            stepped.map(|_| ())
+        })
+    }
+}
+
+unsafe fn bind(statement: *mut ffi::sqlite3_stmt, i: c_int, value: &Value) -> c_int {
+    match value {
+        Value::Null => ffi::sqlite3_bind_null(statement, i),
+        Value::Integer(v) => ffi::sqlite3_bind_int64(statement, i, *v),
+        Value::Real(v) => ffi::sqlite3_bind_double(statement, i, *v),
+        Value::Text(v) => ffi::sqlite3_bind_text(
+            statement,
+            i,
+            v.as_ptr() as *const c_char,
+            v.len() as c_int,
+            transient(),
+        ),
+        Value::Blob(v) => ffi::sqlite3_bind_blob(
+            statement,
+            i,
+            v.as_ptr() as *const c_void,
+            v.len() as c_int,
+            transient(),
+        ),
+    }
+}
+
+/// Reads the column metadata of a prepared statement. Origin tables need
+/// SQLite built with `SQLITE_ENABLE_COLUMN_METADATA`, as most distributions
+/// ship it.
+unsafe fn read_columns(statement: *mut ffi::sqlite3_stmt) -> Vec<Column> {
+    (0..ffi::sqlite3_column_count(statement))
+        .map(|i| Column {
+            name: c_string(ffi::sqlite3_column_name(statement, i)).unwrap_or_default(),
+            decl_type: c_string(ffi::sqlite3_column_decltype(statement, i)),
+            table: c_string(ffi::sqlite3_column_table_name(statement, i)),
+        })
+        .collect()
+}
+
+unsafe fn c_string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+}
+
+unsafe fn read_row(statement: *mut ffi::sqlite3_stmt) -> QueryRow {
+    let mut row = QueryRow::new();
+    for i in 0..ffi::sqlite3_column_count(statement) {
+        // The storage class must be read before any conversion.
+        let value = match ffi::sqlite3_column_type(statement, i) as u32 {
+            ffi::SQLITE_NULL => Value::Null,
+            ffi::SQLITE_INTEGER => Value::Integer(ffi::sqlite3_column_int64(statement, i)),
+            ffi::SQLITE_FLOAT => Value::Real(ffi::sqlite3_column_double(statement, i)),
+            ffi::SQLITE_BLOB => Value::Blob(column_bytes(
+                ffi::sqlite3_column_blob(statement, i) as *const u8,
+                ffi::sqlite3_column_bytes(statement, i),
+            )),
+            _ => Value::Text(column_text(statement, i)),
+        };
+        let text = match &value {
+            Value::Null => value.to_string(),
+            Value::Text(text) => text.clone(),
+            _ => column_text(statement, i),
+        };
+        row.values.push(text);
+        row.typed_values.push(value);
+    }
+    row
+}
+
+unsafe fn column_text(statement: *mut ffi::sqlite3_stmt, i: c_int) -> String {
+    let bytes = column_bytes(
+        ffi::sqlite3_column_text(statement, i),
+        ffi::sqlite3_column_bytes(statement, i),
+    );
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+unsafe fn column_bytes(data: *const u8, len: c_int) -> Vec<u8> {
+    if data.is_null() {
+        return vec![];
+    }
+    std::slice::from_raw_parts(data, len as usize).to_vec()
+}
+
+unsafe fn error(db: *mut ffi::sqlite3, rc: c_int) -> StoreError {
+    let message = CStr::from_ptr(ffi::sqlite3_errmsg(db))
+        .to_string_lossy()
+        .into_owned();
+    StoreError::SQLiteError(sqlite::Error {
+        code: Some(rc as isize),
+        message: Some(message),
+    })
+}
+
+/// `SQLITE_TRANSIENT`: SQLite copies the value before the call returns.
+pub(crate) fn transient() -> Option<unsafe extern "C" fn(*mut c_void)> {
+    unsafe {
+        Some(std::mem::transmute::<
+            isize,
+            unsafe extern "C" fn(*mut c_void),
+        >(-1))
+    }
+}
@@ -1,36 +1,70 @@
 //! ChiselStore RPC module.
 
+use crate::checksum;
+use crate::cursors::CursorPage;
+#[cfg(feature = "fault-injection")]
+use crate::faults::Fault;
+use crate::json;
+use crate::mailbox::{Admission, MailboxSlot, PeerMessage};
+use crate::migration;
+use crate::network::Network;
+use crate::read_index::{ReadIndexMessage, ReadIndexMsg};
+use crate::rpc::proto::ble_server::Ble;
 use crate::rpc::proto::rpc_server::Rpc;
-use crate::{Consistency, SequencePaxosStoreTransport, StoreCommand, StoreServer};
+use crate::rpc::proto::rpc_v2_server::RpcV2;
+use crate::server::{InFlight, Lifecycle, QueryResults, QueryTiming, BLE_TICK, HEARTBEAT_DELAY};
+use crate::{
+    BatchQuery, CommandKind, Consistency, QueryLane, SequencePaxosStoreTransport, StoreCommand,
+    StoreError, StoreServer,
+};
 use async_mutex::Mutex;
 use async_trait::async_trait;
 use crossbeam::queue::ArrayQueue;
 use derivative::Derivative;
 use omnipaxos_core::{ballot_leader_election as ble, messages, storage, util};
+use slog::{debug, o, warn, Logger};
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
-use tonic::{Request, Response, Status};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Response, Status};
 
-#[allow(missing_docs)]
-pub mod proto {
-    tonic::include_proto!("proto");
-}
+pub use crate::proto;
 
+use proto::ble_client::BleClient;
 use proto::rpc_client::RpcClient;
+use proto::rpc_v2_client::RpcV2Client;
 
 #[derive(Debug)]
 struct ConnectionPool {
-    connections: ArrayQueue<RpcClient<tonic::transport::Channel>>,
+    connections: ArrayQueue<Channel>,
 }
 
 struct Connection {
-    conn: RpcClient<tonic::transport::Channel>,
+    channel: Channel,
     pool: Arc<ConnectionPool>,
 }
 
+impl Connection {
+    /// Client of the peer's log replication service.
+    fn rpc(&self) -> RpcClient<Channel> {
+        RpcClient::new(self.channel.clone())
+    }
+
+    /// Client of the peer's leader election service.
+    fn ble(&self) -> BleClient<Channel> {
+        BleClient::new(self.channel.clone())
+    }
+}
+
 impl Drop for Connection {
     fn drop(&mut self) {
-        self.pool.replenish(self.conn.clone())
+        self.pool.replenish(self.channel.clone())
     }
 }
 
@@ -41,16 +75,20 @@ impl ConnectionPool {
         })
     }
 
-    async fn connection<S: ToString>(&self, addr: S) -> RpcClient<tonic::transport::Channel> {
+    async fn connection<S: ToString>(&self, addr: S) -> Channel {
         let addr = addr.to_string();
         match self.connections.pop() {
             Some(x) => x,
-            None => RpcClient::connect(addr).await.unwrap(),
+            None => Endpoint::from_shared(addr)
+                .unwrap()
+                .connect()
+                .await
+                .unwrap(),
         }
     }
 
-    fn replenish(&self, conn: RpcClient<tonic::transport::Channel>) {
-        let _ = self.connections.push(conn);
+    fn replenish(&self, channel: Channel) {
+        let _ = self.connections.push(channel);
     }
 }
 
@@ -69,7 +107,7 @@ impl Connections {
             .entry(addr.clone())
             .or_insert_with(ConnectionPool::new);
         Connection {
-            conn: pool.connection(addr).await,
+            channel: pool.connection(addr).await,
             pool: pool.clone(),
         }
     }
@@ -77,13 +115,76 @@ impl Connections {
 
 type NodeAddrFn = dyn Fn(usize) -> String + Send + Sync;
 
+/// Delay before sending a message of the given size in bytes to the given
+/// node.
+type LinkDelayFn = dyn Fn(u64, usize) -> Duration + Send + Sync;
+
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct RpcTransport {
     /// Node address mapping function.
     #[derivative(Debug = "ignore")]
     node_addr: Box<NodeAddrFn>,
+    /// Injected link delay, for simulating slow or distant peers.
+    #[derivative(Debug = "ignore")]
+    link_delay: Option<Box<LinkDelayFn>>,
+    deadlines: PeerDeadlines,
+    /// Sends of each superseding message kind, per peer.
+    #[derivative(Debug = "ignore")]
+    pending: Arc<std::sync::Mutex<HashMap<(u64, Superseding), Coalesced>>>,
     connections: Connections,
+    /// Channels to the nodes client requests were forwarded to, by node.
+    #[derivative(Debug = "ignore")]
+    forwarding: std::sync::Mutex<HashMap<u64, Channel>>,
+    /// Logger failed sends are reported to; the server's once it started.
+    #[derivative(Debug = "ignore")]
+    logger: std::sync::Mutex<Logger>,
+}
+
+/// Deadlines for outbound peer RPCs, not counting any injected link delay.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerDeadlines {
+    /// Leader election heartbeats, which are worthless once the next round
+    /// has started.
+    pub heartbeat: Duration,
+    /// Promises and `AcceptSync` messages, which may carry a large log
+    /// suffix to a lagging follower.
+    pub sync: Duration,
+    /// Every other log replication message.
+    pub message: Duration,
+}
+
+impl Default for PeerDeadlines {
+    fn default() -> Self {
+        Self {
+            // A reply arriving later than this leaves little of the round.
+            heartbeat: Duration::from_millis(HEARTBEAT_DELAY * BLE_TICK / 4),
+            sync: Duration::from_secs(30),
+            message: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Message kinds where a newer message to a peer makes any older one not
+/// sent yet redundant, as they carry a round or a monotonic log index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Superseding {
+    Accepted,
+    Decide,
+    HeartbeatRequest,
+    HeartbeatReply,
+}
+
+/// A peer send that has not started yet.
+type PeerSend = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Sends of one superseding message kind to one peer. At most one is in
+/// flight; of the messages produced meanwhile only the latest is kept, and
+/// sent once the one in flight finishes.
+#[derive(Default)]
+struct Coalesced {
+    in_flight: bool,
+    next: Option<PeerSend>,
 }
 
 impl RpcTransport {
@@ -91,11 +192,125 @@ impl RpcTransport {
     pub fn new(node_addr: Box<NodeAddrFn>) -> Self {
         RpcTransport {
             node_addr,
+            link_delay: None,
+            deadlines: PeerDeadlines::default(),
+            pending: Arc::new(std::sync::Mutex::new(HashMap::new())),
             connections: Connections::new(),
+            forwarding: std::sync::Mutex::new(HashMap::new()),
+            logger: std::sync::Mutex::new(Logger::root(slog::Discard, o!())),
+        }
+    }
+
+    fn logger(&self) -> Logger {
+        self.logger.lock().unwrap().clone()
+    }
+
+    /// Returns a client of the v2 service of node `id`, for forwarding
+    /// client requests to it. Connects on first use.
+    fn forwarding_client(&self, id: u64) -> Result<RpcV2Client<Channel>, Status> {
+        let mut forwarding = self.forwarding.lock().unwrap();
+        let channel = match forwarding.get(&id) {
+            Some(channel) => channel.clone(),
+            None => {
+                let endpoint = Endpoint::from_shared((self.node_addr)(id as usize))
+                    .map_err(|e| Status::unavailable(e.to_string()))?;
+                let channel = endpoint
+                    .connect_lazy()
+                    .map_err(|e| Status::unavailable(e.to_string()))?;
+                forwarding.insert(id, channel.clone());
+                channel
+            }
+        };
+        Ok(RpcV2Client::new(channel))
+    }
+
+    /// Delays every message by what `link_delay` returns for its destination
+    /// and encoded size. Meant for reproducing WAN topologies in tests.
+    pub fn with_link_delay(mut self, link_delay: Box<LinkDelayFn>) -> Self {
+        self.link_delay = Some(link_delay);
+        self
+    }
+
+    /// Delays the messages node `id` sends by the profiles of its links in
+    /// `network`.
+    pub fn with_network(self, id: u64, network: Network) -> Self {
+        self.with_link_delay(Box::new(move |to, size| network.delay(id, to, size)))
+    }
+
+    /// Overrides the default deadlines of outbound peer RPCs.
+    pub fn with_deadlines(mut self, deadlines: PeerDeadlines) -> Self {
+        self.deadlines = deadlines;
+        self
+    }
+
+    /// Sends `send` as the latest message of `kind` to `to`. If a send of
+    /// that kind is in flight, `send` waits for it, replacing any message
+    /// already waiting, which never started. Sends in flight are never
+    /// cancelled, so a stream of messages cannot starve the peer.
+    fn supersede(&self, to: u64, kind: Superseding, send: PeerSend) {
+        let key = (to, kind);
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let coalesced = pending.entry(key).or_default();
+            if coalesced.in_flight {
+                coalesced.next = Some(send);
+                return;
+            }
+            coalesced.in_flight = true;
+        }
+        let pending = self.pending.clone();
+        tokio::task::spawn(async move {
+            let mut send = send;
+            loop {
+                send.await;
+                let mut sends = pending.lock().unwrap();
+                let coalesced = sends.get_mut(&key).unwrap();
+                match coalesced.next.take() {
+                    Some(next) => send = next,
+                    None => {
+                        coalesced.in_flight = false;
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    fn link_delay<M: prost::Message>(&self, to: u64, request: &M) -> Duration {
+        match &self.link_delay {
+            Some(link_delay) => link_delay(to, request.encoded_len()),
+            None => Duration::ZERO,
         }
     }
 }
 
+/// Waits out an injected link delay.
+async fn link_wait(delay: Duration) {
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Runs a peer send after the injected link `delay`, giving up on it once
+/// `deadline` has passed since the delay ended.
+async fn deliver<T>(
+    logger: Logger,
+    to: u64,
+    delay: Duration,
+    deadline: Duration,
+    send: impl Future<Output = Result<T, Status>>,
+) {
+    link_wait(delay).await;
+    match tokio::time::timeout(deadline, send).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(status)) if status.code() == Code::DeadlineExceeded => {
+            warn!(logger, "Peer {} missed deadline", to)
+        }
+        Ok(Err(_)) => warn!(logger, "Peer {} halted", to),
+        Err(_) => warn!(logger, "Peer {} missed deadline", to),
+    }
+}
+
 // Helping functions to get proto buffers from paxos or ble structs
 
 fn get_proto_ballot(ballot: ble::Ballot) -> Option<proto::Ballot> {
@@ -107,9 +322,47 @@ fn get_proto_ballot(ballot: ble::Ballot) -> Option<proto::Ballot> {
 }
 
 fn get_proto_entry(cmd: StoreCommand) -> proto::Entry {
+    let kind = match cmd.kind {
+        CommandKind::Statement => None,
+        CommandKind::Publish { topic, payload } => {
+            Some(proto::entry::Kind::Publish(proto::TopicMessage {
+                topic,
+                payload,
+                index: 0,
+            }))
+        }
+        CommandKind::Conditional { predicate } => Some(proto::entry::Kind::Predicate(predicate)),
+        CommandKind::HardDelete { subject } => Some(proto::entry::Kind::HardDelete(subject)),
+        CommandKind::SetConfig { key, value } => {
+            Some(proto::entry::Kind::SetConfig(proto::ConfigEntry {
+                key,
+                value,
+            }))
+        }
+        CommandKind::Transaction => Some(proto::entry::Kind::Transaction(true)),
+        CommandKind::Prepare => Some(proto::entry::Kind::Prepare(true)),
+        CommandKind::ExecutePrepared { statement } => {
+            Some(proto::entry::Kind::ExecutePrepared(statement))
+        }
+        CommandKind::Migration {
+            version,
+            name,
+            checksum,
+        } => Some(proto::entry::Kind::Migration(proto::SchemaMigration {
+            version,
+            name,
+            checksum,
+        })),
+    };
     proto::Entry {
         id: cmd.id as u64,
         sql: cmd.sql,
+        kind,
+        functions: cmd.functions,
+        collations: cmd.collations,
+        params: cmd.params.into_iter().map(Into::into).collect(),
+        tenant: cmd.tenant,
+        pragmas: cmd.pragmas.into_iter().map(Into::into).collect(),
     }
 }
 
@@ -181,14 +434,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.prepare_request(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().prepare_request(request).await
+                }));
             }
 
             messages::PaxosMsg::Prepare(prep) => {
@@ -209,14 +462,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
                 };
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.prepare_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().prepare_message(request).await
+                }));
             }
 
             messages::PaxosMsg::Promise(prom) => {
@@ -251,14 +504,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
                 };
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.promise_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.sync;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().promise_message(request).await
+                }));
             }
 
             messages::PaxosMsg::AcceptSync(acc_sync) => {
@@ -290,14 +543,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.accept_sync_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.sync;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().accept_sync_message(request).await
+                }));
             }
 
             messages::PaxosMsg::FirstAccept(f) => {
@@ -320,14 +573,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.first_accept_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().first_accept_message(request).await
+                }));
             }
 
             messages::PaxosMsg::AcceptDecide(acc) => {
@@ -352,14 +605,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.accept_decide_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().accept_decide_message(request).await
+                }));
             }
 
             messages::PaxosMsg::Accepted(accepted) => {
@@ -372,14 +625,15 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.accepted_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                let send = Box::pin(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().accepted_message(request).await
+                }));
+                self.supersede(to, Superseding::Accepted, send);
             }
 
             messages::PaxosMsg::Decide(dec) => {
@@ -392,14 +646,15 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.decide_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                let send = Box::pin(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().decide_message(request).await
+                }));
+                self.supersede(to, Superseding::Decide, send);
             }
 
             messages::PaxosMsg::ProposalForward(props) => {
@@ -419,14 +674,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.proposal_forward_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().proposal_forward_message(request).await
+                }));
             }
 
             messages::PaxosMsg::Compaction(comps) => {
@@ -442,14 +697,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.compaction_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().compaction_message(request).await
+                }));
             }
 
             messages::PaxosMsg::ForwardCompaction(comps) => {
@@ -465,14 +720,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.forward_compaction_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().forward_compaction_message(request).await
+                }));
             }
 
             messages::PaxosMsg::AcceptStopSign(acc_ss) => {
@@ -492,14 +747,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.accept_stop_sign_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().accept_stop_sign_message(request).await
+                }));
             }
 
             messages::PaxosMsg::AcceptedStopSign(acc_ss) => {
@@ -511,14 +766,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.accepted_stop_sign_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().accepted_stop_sign_message(request).await
+                }));
             }
 
             messages::PaxosMsg::DecideStopSign(d_ss) => {
@@ -530,14 +785,14 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.decide_stop_sign_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.message;
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().decide_stop_sign_message(request).await
+                }));
             }
         }
     }
@@ -553,14 +808,15 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.heartbeat_request_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+                let delay = self.link_delay(to, &request);
+                let deadline = self.deadlines.heartbeat;
+                let send = Box::pin(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.ble().heartbeat_request_message(request).await
+                }));
+                self.supersede(to, Superseding::HeartbeatRequest, send);
             }
 
             ble::messages::HeartbeatMsg::Reply(reply) => {
@@ -580,18 +836,89 @@ impl SequencePaxosStoreTransport for RpcTransport {
 
                 let peer = (self.node_addr)(to as usize);
                 let pool = self.connections.clone();
+                let delay = self.link_delay(to, &request);
+
+                let deadline = self.deadlines.heartbeat;
+                let send = Box::pin(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.ble().heartbeat_reply_message(request).await
+                }));
+                self.supersede(to, Superseding::HeartbeatReply, send);
+            }
+        }
+    }
 
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let request = tonic::Request::new(request.clone());
-                    match client.conn.heartbeat_reply_message(request).await {
-                        Ok(_) => {}
-                        Err(_) => println!("Peer {} halted", to),
-                    }
-                });
+    fn send_read_index_message(&self, msg: ReadIndexMessage) {
+        let (from, to) = (msg.from, msg.to);
+        let peer = (self.node_addr)(to as usize);
+        let pool = self.connections.clone();
+        let deadline = self.deadlines.message;
+        match msg.msg {
+            ReadIndexMsg::Request { id } => {
+                let request = proto::ReadIndexRequest { from, to, id };
+                let delay = self.link_delay(to, &request);
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().read_index_request_message(request).await
+                }));
+            }
+
+            ReadIndexMsg::Reply { id, index } => {
+                let request = proto::ReadIndexReply {
+                    from,
+                    to,
+                    id,
+                    index,
+                };
+                let delay = self.link_delay(to, &request);
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().read_index_reply_message(request).await
+                }));
+            }
+
+            ReadIndexMsg::Check { id } => {
+                let request = proto::LeadershipCheck { from, to, id };
+                let delay = self.link_delay(to, &request);
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().leadership_check_message(request).await
+                }));
+            }
+
+            ReadIndexMsg::Ack { id, follows } => {
+                let request = proto::LeadershipAck {
+                    from,
+                    to,
+                    id,
+                    follows,
+                };
+                let delay = self.link_delay(to, &request);
+                tokio::task::spawn(deliver(self.logger(), to, delay, deadline, async move {
+                    let client = pool.connection(peer).await;
+                    let mut request = tonic::Request::new(request);
+                    request.set_timeout(deadline);
+                    client.rpc().leadership_ack_message(request).await
+                }));
             }
         }
     }
+
+    fn node_addr(&self, id: u64) -> Option<String> {
+        Some((self.node_addr)(id as usize))
+    }
+
+    fn set_logger(&self, logger: Logger) {
+        *self.logger.lock().unwrap() = logger;
+    }
 }
 
 // functions to get ble or paxos structs from proto messages
@@ -604,10 +931,39 @@ fn get_ballot_from_proto(proto_ballot: proto::Ballot) -> ble::Ballot {
     }
 }
 
-fn get_entry_from_proto(proto_entry: proto::Entry) -> StoreCommand {
+pub(crate) fn get_entry_from_proto(proto_entry: proto::Entry) -> StoreCommand {
+    let kind = match proto_entry.kind {
+        None => CommandKind::Statement,
+        Some(proto::entry::Kind::Publish(msg)) => CommandKind::Publish {
+            topic: msg.topic,
+            payload: msg.payload,
+        },
+        Some(proto::entry::Kind::Predicate(predicate)) => CommandKind::Conditional { predicate },
+        Some(proto::entry::Kind::HardDelete(subject)) => CommandKind::HardDelete { subject },
+        Some(proto::entry::Kind::SetConfig(entry)) => CommandKind::SetConfig {
+            key: entry.key,
+            value: entry.value,
+        },
+        Some(proto::entry::Kind::Transaction(_)) => CommandKind::Transaction,
+        Some(proto::entry::Kind::Prepare(_)) => CommandKind::Prepare,
+        Some(proto::entry::Kind::ExecutePrepared(statement)) => {
+            CommandKind::ExecutePrepared { statement }
+        }
+        Some(proto::entry::Kind::Migration(migration)) => CommandKind::Migration {
+            version: migration.version,
+            name: migration.name,
+            checksum: migration.checksum,
+        },
+    };
     StoreCommand {
         id: proto_entry.id as usize,
         sql: proto_entry.sql,
+        kind,
+        functions: proto_entry.functions,
+        collations: proto_entry.collations,
+        params: proto_entry.params.into_iter().map(Into::into).collect(),
+        tenant: proto_entry.tenant,
+        pragmas: proto_entry.pragmas.into_iter().map(Into::into).collect(),
     }
 }
 
@@ -654,16 +1010,364 @@ fn get_forward_compaction_from_proto(
     }
 }
 
+/// CORS settings for serving the client-facing RPC service over gRPC-Web.
+#[cfg(feature = "grpc-web")]
+#[derive(Debug, Clone, Default)]
+pub struct GrpcWebCors {
+    /// Origins allowed to call the service; browsers on any other origin,
+    /// and on every origin if empty, are refused.
+    pub allowed_origins: Vec<String>,
+    /// Whether browsers may send credentials with requests.
+    pub allow_credentials: bool,
+    /// How long browsers may cache preflight responses.
+    pub max_age: Option<std::time::Duration>,
+    /// Response headers exposed to browser clients.
+    pub expose_headers: Vec<String>,
+}
+
+/// Returns a gRPC-Web layer configured with `cors`.
+///
+/// Wrap the client-facing `RpcV2` service with it, and enable HTTP/1 on the
+/// server, to accept gRPC-Web requests without a separate proxy. Never wrap
+/// the `RPC` service, which peers exchange Paxos messages over, or web
+/// pages could reach it:
+///
+/// ```ignore
+/// let service = grpc_web_config(&cors).enable(RpcV2Server::new(rpc));
+/// Server::builder().accept_http1(true).add_service(service);
+/// ```
+#[cfg(feature = "grpc-web")]
+pub fn grpc_web_config(cors: &GrpcWebCors) -> tonic_web::Config {
+    let mut config = tonic_web::config().allow_credentials(cors.allow_credentials);
+    config = config.allow_origins(cors.allowed_origins.clone());
+    if let Some(max_age) = cors.max_age {
+        config = config.max_age(max_age);
+    }
+    if !cors.expose_headers.is_empty() {
+        config = config.expose_headers(cors.expose_headers.clone());
+    }
+    config
+}
+
 #[derive(Debug)]
 pub struct RpcService {
     /// The ChiselStore server access via this RPC service.
     pub server: Arc<StoreServer<RpcTransport>>,
+    /// Whether client calls are calls to the deprecated v1 API.
+    v1: bool,
+}
+
+/// How long clients should wait before retrying a request rejected because
+/// the server is not ready.
+const RETRY_AFTER_MS: &str = "100";
+
+/// How long a query waits for the node to apply its session token before
+/// it is rejected for the client to try another node.
+const SESSION_WAIT: Duration = Duration::from_secs(2);
+
+/// Number of topic messages buffered per subscription stream.
+const SUBSCRIPTION_BUFFER: usize = 128;
+
+/// Number of row batches buffered per streamed query.
+const STREAM_BUFFER: usize = 4;
+
+/// Metadata key of the leader's node id, sent when rejecting requests.
+pub const LEADER_HINT_HEADER: &str = "leader-hint";
+
+/// Metadata key of the leader's node id, sent along with its address when
+/// rejecting requests another node should serve.
+pub const LEADER_ID_HEADER: &str = "leader-id";
+
+/// Metadata key of the leader's address, for clients to redirect to.
+pub const LEADER_ADDR_HEADER: &str = "leader-addr";
+
+/// Metadata key of the node a request was forwarded from, so that it is
+/// never forwarded again.
+pub const FORWARDED_FROM_HEADER: &str = "forwarded-from";
+
+/// Metadata key naming the fault a request was failed with, if it was
+/// injected.
+#[cfg(feature = "fault-injection")]
+pub const INJECTED_FAULT_HEADER: &str = "injected-fault";
+
+/// Metadata key of the client-supplied request id, echoed in responses.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Metadata key of the server-side latency breakdown of a query, in the
+/// `Server-Timing` header format with durations in milliseconds.
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+fn echo_request_id(metadata: &mut MetadataMap, request_id: &Option<String>) {
+    if let Some(value) = request_id.as_deref().and_then(|id| id.parse().ok()) {
+        metadata.insert(REQUEST_ID_HEADER, value);
+    }
+}
+
+/// Reads the consistency and lane of a query.
+fn query_mode(query: &proto::Query) -> (Consistency, QueryLane) {
+    let consistency = consistency(query.consistency);
+    let lane = match proto::Lane::from_i32(query.lane).unwrap_or(proto::Lane::Transactional) {
+        proto::Lane::Transactional => QueryLane::Transactional,
+        proto::Lane::Analytical => QueryLane::Analytical,
+    };
+    (consistency, lane)
+}
+
+/// The consistency of the protocol value `consistency`, strong if unknown.
+fn consistency(consistency: i32) -> Consistency {
+    match proto::Consistency::from_i32(consistency).unwrap_or(proto::Consistency::Strong) {
+        proto::Consistency::Strong => Consistency::Strong,
+        proto::Consistency::RelaxedReads => Consistency::RelaxedReads,
+        proto::Consistency::LeaderLease => Consistency::LeaderLease,
+        proto::Consistency::ReadIndex => Consistency::ReadIndex,
+    }
+}
+
+fn query_status(e: &StoreError) -> Status {
+    match e {
+        StoreError::ProposalRejected(_) | StoreError::InvalidRequest(_) => {
+            Status::invalid_argument(format!("{}", e))
+        }
+        // Retryable once the next configuration is running.
+        StoreError::ConfigurationStopped(_) => Status::unavailable(format!("{}", e)),
+        // Retryable once a new leader is elected.
+        StoreError::NotLeader => Status::unavailable(format!("{}", e)),
+        // Retryable once the node caught up, or on another node.
+        StoreError::PageIndexNotApplied { .. } => Status::unavailable(format!("{}", e)),
+        StoreError::UnknownStatement(_) | StoreError::UnknownCursor(_) => {
+            Status::not_found(format!("{}", e))
+        }
+        StoreError::TooManyCursors(_) => Status::resource_exhausted(format!("{}", e)),
+        StoreError::TenantMoved { .. } | StoreError::LearnerBehind { .. } => {
+            Status::failed_precondition(format!("{}", e))
+        }
+        _ => Status::internal(format!("{}", e)),
+    }
+}
+
+fn get_proto_results(results: QueryResults, checksum: bool, proof: bool) -> proto::QueryResults {
+    let columns = results
+        .columns
+        .into_iter()
+        .map(|column| proto::Column {
+            name: column.name,
+            decl_type: column.decl_type,
+            table: column.table,
+        })
+        .collect();
+    let mut rows = vec![];
+    for row in results.rows {
+        rows.push(proto::QueryRow {
+            values: row.values,
+            typed_values: row.typed_values.into_iter().map(Into::into).collect(),
+        })
+    }
+    let checksum = match checksum {
+        true => Some(checksum::rows_checksum(&rows)),
+        false => None,
+    };
+    let commit_idx = results.proof.as_ref().map(|proof| proof.decided_idx);
+    let commit_ballot = results
+        .proof
+        .as_ref()
+        .and_then(|proof| get_proto_ballot(proof.ballot));
+    let proof = match proof {
+        true => results.proof.map(|proof| proto::ReadProof {
+            ballot: get_proto_ballot(proof.ballot),
+            decided_idx: proof.decided_idx,
+            quorum: proof.quorum,
+        }),
+        false => None,
+    };
+    proto::QueryResults {
+        rows,
+        applied: results.applied,
+        checksum,
+        proof,
+        columns,
+        rows_affected: results.rows_affected,
+        last_insert_rowid: results.last_insert_rowid,
+        commit_idx,
+        rolled_back: results.rolled_back,
+        commit_ballot,
+    }
+}
+
+fn get_proto_page(page: CursorPage, checksum: bool, proof: bool) -> proto::CursorPage {
+    proto::CursorPage {
+        cursor_id: page.cursor_id,
+        results: Some(get_proto_results(page.results, checksum, proof)),
+        done: page.done,
+    }
+}
+
+fn server_timing(timing: &QueryTiming, serialize: Duration) -> String {
+    let phases = [
+        ("queue", timing.queue),
+        ("replicate", timing.replicate),
+        ("apply", timing.apply),
+        ("serialize", serialize),
+    ];
+    phases
+        .iter()
+        .map(|(name, d)| format!("{};dur={:.3}", name, d.as_secs_f64() * 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 impl RpcService {
     /// Creates a new RPC service.
     pub fn new(server: Arc<StoreServer<RpcTransport>>) -> Self {
-        Self { server }
+        Self { server, v1: true }
+    }
+
+    /// Records a call to the v1 client method `method`, logging the first.
+    fn deprecated(&self, method: &'static str) {
+        if self.v1 && self.server.deprecated_calls().record(method) {
+            warn!(
+                self.server.logger(),
+                "Deprecated method RPC/{} called; clients should use RpcV2", method
+            );
+        }
+    }
+
+    /// Rejects peer messages with `PERMISSION_DENIED` unless they come from a
+    /// member of the current configuration and are addressed to this node.
+    fn ensure_known_peer(&self, from: u64, to: u64) -> Result<(), Status> {
+        self.server
+            .verify_peer(from, to)
+            .map_err(|e| Status::permission_denied(e.to_string()))
+    }
+
+    /// Rejects peer messages with `UNAVAILABLE` unless the server takes
+    /// part in replication.
+    fn ensure_ready(&self) -> Result<(), Status> {
+        match self.server.lifecycle() {
+            Lifecycle::Ready | Lifecycle::Draining => Ok(()),
+            lifecycle => Err(self.unavailable(lifecycle)),
+        }
+    }
+
+    /// Admits a peer message of kind `message` from `from` to the mailbox,
+    /// returning `None` if it is shed and rejecting it with
+    /// `RESOURCE_EXHAUSTED` if it is refused. The message is pending until
+    /// the slot is dropped.
+    fn admit_peer(
+        &self,
+        from: u64,
+        message: PeerMessage,
+    ) -> Result<Option<MailboxSlot<'_>>, Status> {
+        match self.server.peer_mailbox().admit(from, message) {
+            Admission::Admitted(slot) => Ok(Some(slot)),
+            Admission::Shed => Ok(None),
+            Admission::Rejected => Err(Status::resource_exhausted(format!(
+                "node {} is overloaded with peer messages",
+                self.server.id()
+            ))),
+        }
+    }
+
+    /// Admits a client request, rejecting it with `UNAVAILABLE` unless the
+    /// server is ready. The request is in flight until the guard is dropped.
+    fn admit(&self) -> Result<InFlight<'_>, Status> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(fault) = self.server.inject_fault() {
+            return Err(self.injected(fault));
+        }
+        self.server
+            .begin_request()
+            .ok_or_else(|| self.unavailable(self.server.lifecycle()))
+    }
+
+    /// Waits for this node to apply the session token `after_idx`,
+    /// rejecting the request with `UNAVAILABLE` if it does not in time.
+    async fn await_session(&self, after_idx: u64) -> Result<(), Status> {
+        if self.server.wait_applied(after_idx, SESSION_WAIT).await {
+            return Ok(());
+        }
+        Err(Status::with_metadata(
+            Code::Unavailable,
+            format!(
+                "index {} not applied, at {}",
+                after_idx,
+                self.server.applied_idx()
+            ),
+            self.retry_metadata(false),
+        ))
+    }
+
+    /// `UNAVAILABLE` status with a retry delay and, if another node leads
+    /// the cluster, a `leader-hint` to redirect to.
+    fn unavailable(&self, lifecycle: Lifecycle) -> Status {
+        Status::with_metadata(
+            Code::Unavailable,
+            format!("replica is {}", lifecycle),
+            self.retry_metadata(true),
+        )
+    }
+
+    /// Metadata asking the client to retry after a delay, with a
+    /// `leader-hint`, `leader-id` and `leader-addr` if asked for and another
+    /// node leads the cluster.
+    fn retry_metadata(&self, leader_hint: bool) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("retry-after-ms", MetadataValue::from_static(RETRY_AFTER_MS));
+        let leader = self.server.get_cluster_leader();
+        if leader_hint && leader != 0 && leader != self.server.id() {
+            if let Ok(hint) = leader.to_string().parse::<MetadataValue<_>>() {
+                metadata.insert(LEADER_HINT_HEADER, hint.clone());
+                metadata.insert(LEADER_ID_HEADER, hint);
+            }
+            let addr = self.server.transport().node_addr(leader);
+            if let Some(Ok(addr)) = addr.map(|addr| addr.parse()) {
+                metadata.insert(LEADER_ADDR_HEADER, addr);
+            }
+        }
+        metadata
+    }
+
+    /// Executes `query` on the leader `leader`, returning its response.
+    async fn forward(
+        &self,
+        leader: u64,
+        query: proto::Query,
+        request_id: &Option<String>,
+    ) -> Result<Response<proto::QueryResults>, Status> {
+        let mut client = self.server.transport().forwarding_client(leader)?;
+        let mut request = Request::new(query);
+        let metadata = request.metadata_mut();
+        if let Ok(from) = self.server.id().to_string().parse() {
+            metadata.insert(FORWARDED_FROM_HEADER, from);
+        }
+        echo_request_id(metadata, request_id);
+        client.execute(request).await
+    }
+
+    /// The status a query failed with `e` is rejected with. A query that
+    /// failed since the leader changed under it is `UNAVAILABLE`, with the
+    /// new leader to redirect to if it is known.
+    fn store_status(&self, e: &StoreError) -> Status {
+        match e {
+            StoreError::NotLeader => {
+                Status::with_metadata(Code::Unavailable, e.to_string(), self.retry_metadata(true))
+            }
+            e => query_status(e),
+        }
+    }
+
+    /// The status a request failed with `fault` is rejected with.
+    #[cfg(feature = "fault-injection")]
+    fn injected(&self, fault: Fault) -> Status {
+        let (code, mut metadata) = match fault {
+            Fault::NotLeader => (Code::Unavailable, self.retry_metadata(true)),
+            Fault::Timeout => (Code::DeadlineExceeded, MetadataMap::new()),
+            Fault::Busy => (Code::ResourceExhausted, self.retry_metadata(false)),
+        };
+        metadata.insert(
+            INJECTED_FAULT_HEADER,
+            MetadataValue::from_static(fault.name()),
+        );
+        Status::with_metadata(code, format!("injected fault: {}", fault.name()), metadata)
     }
 }
 
@@ -673,36 +1377,457 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::Query>,
     ) -> Result<Response<proto::QueryResults>, tonic::Status> {
+        self.deprecated("Execute");
+        let _in_flight = self.admit()?;
+        let request_id = request
+            .metadata()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .map(String::from);
+        let logger = self.server.logger().new(o!(
+            "request_id" => request_id.clone().unwrap_or_else(|| String::from("-"))
+        ));
+        let forwarded = request.metadata().get(FORWARDED_FROM_HEADER).is_some();
         let query = request.into_inner();
-        let consistency =
-            proto::Consistency::from_i32(query.consistency).unwrap_or(proto::Consistency::Strong);
-        let consistency = match consistency {
-            proto::Consistency::Strong => Consistency::Strong,
-            proto::Consistency::RelaxedReads => Consistency::RelaxedReads,
+        let (consistency, lane) = query_mode(&query);
+        if let Err(mut status) = self.await_session(query.after_idx).await {
+            echo_request_id(status.metadata_mut(), &request_id);
+            return Err(status);
+        }
+        let leader = match (forwarded, &query.tenant) {
+            (false, None) => self.server.forward_target(&query.sql, &consistency),
+            _ => None,
         };
+        if let Some(leader) = leader {
+            match self.forward(leader, query.clone(), &request_id).await {
+                Ok(response) => return Ok(response),
+                // The leader is unreachable or lost its leadership, so
+                // propose the write here instead.
+                Err(status) if status.code() == Code::Unavailable => {
+                    debug!(
+                        logger,
+                        "Forwarding to {} failed: {}",
+                        leader,
+                        status.message()
+                    );
+                }
+                Err(mut status) => {
+                    echo_request_id(status.metadata_mut(), &request_id);
+                    return Err(status);
+                }
+            }
+        }
 
         let server = self.server.clone();
-        let results = match server.query(query.sql, consistency).await {
+        if query.predicate.is_some() && !query.params.is_empty() {
+            let mut status =
+                Status::invalid_argument("parameters cannot be combined with a predicate");
+            echo_request_id(status.metadata_mut(), &request_id);
+            return Err(status);
+        }
+        if query.predicate.is_some() && query.tenant.is_some() {
+            let mut status = Status::invalid_argument("tenant queries cannot have a predicate");
+            echo_request_id(status.metadata_mut(), &request_id);
+            return Err(status);
+        }
+        if !query.pragmas.is_empty() && (query.predicate.is_some() || query.tenant.is_some()) {
+            let mut status =
+                Status::invalid_argument("pragmas cannot be combined with a predicate or a tenant");
+            echo_request_id(status.metadata_mut(), &request_id);
+            return Err(status);
+        }
+        let params = query.params.into_iter().map(Into::into).collect();
+        let pragmas: Vec<_> = query.pragmas.into_iter().map(Into::into).collect();
+        let results = match (query.predicate, query.tenant) {
+            (Some(predicate), _) => server.execute_if(predicate, query.sql).await,
+            (None, None) if !pragmas.is_empty() => {
+                server.query_with_pragmas(query.sql, params, pragmas).await
+            }
+            (None, Some(tenant)) => {
+                server
+                    .query_tenant(&tenant, query.sql, params, consistency, lane)
+                    .await
+            }
+            (None, None) => {
+                server
+                    .query_with_params(query.sql, params, consistency, lane)
+                    .await
+            }
+        };
+        let mut results = match results {
             Ok(results) => results,
-            Err(e) => return Err(Status::internal(format!("{}", e))),
+            Err(e) => {
+                debug!(logger, "Query failed: {}", e);
+                let mut status = self.store_status(&e);
+                echo_request_id(status.metadata_mut(), &request_id);
+                return Err(status);
+            }
         };
 
-        let mut rows = vec![];
-        for row in results.rows {
-            rows.push(proto::QueryRow {
-                values: row.values.clone(),
+        let serialize_started = Instant::now();
+        let timing = results.timing;
+        if query.json_rows {
+            json::rows_as_objects(&mut results);
+        }
+        let mut response = Response::new(get_proto_results(results, query.checksum, query.proof));
+        let serialize = serialize_started.elapsed();
+        debug!(
+            logger,
+            "Query served: queue {:?}, replicate {:?}, apply {:?}, serialize {:?}",
+            timing.queue,
+            timing.replicate,
+            timing.apply,
+            serialize
+        );
+        let metadata = response.metadata_mut();
+        echo_request_id(metadata, &request_id);
+        if let Ok(value) = server_timing(&timing, serialize).parse() {
+            metadata.insert(SERVER_TIMING_HEADER, value);
+        }
+        Ok(response)
+    }
+
+    async fn execute_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<proto::QueryResults>, tonic::Status> {
+        self.deprecated("ExecuteTransaction");
+        let _in_flight = self.admit()?;
+        let transaction = request.into_inner();
+        let pragmas = transaction.pragmas.into_iter().map(Into::into).collect();
+        match self
+            .server
+            .transaction_with_pragmas(&transaction.statements, pragmas)
+            .await
+        {
+            Ok(results) => Ok(Response::new(get_proto_results(
+                results,
+                transaction.checksum,
+                transaction.proof,
+            ))),
+            Err(StoreError::InvalidRequest(e)) => Err(Status::invalid_argument(e)),
+            Err(e) => Err(self.store_status(&e)),
+        }
+    }
+
+    async fn execute_batch(
+        &self,
+        request: Request<proto::QueryBatch>,
+    ) -> Result<Response<proto::BatchResults>, tonic::Status> {
+        self.deprecated("ExecuteBatch");
+        let _in_flight = self.admit()?;
+        let batch = request.into_inner();
+        if batch.queries.iter().any(|query| {
+            query.predicate.is_some() || query.tenant.is_some() || !query.pragmas.is_empty()
+        }) {
+            return Err(Status::invalid_argument(
+                "batched queries cannot have a predicate, a tenant or pragmas",
+            ));
+        }
+        let after_idx = batch.queries.iter().map(|query| query.after_idx).max();
+        self.await_session(after_idx.unwrap_or(0)).await?;
+        let options: Vec<_> = batch
+            .queries
+            .iter()
+            .map(|query| (query.checksum, query.proof))
+            .collect();
+        let queries = batch
+            .queries
+            .into_iter()
+            .map(|query| {
+                let (consistency, lane) = query_mode(&query);
+                BatchQuery {
+                    sql: query.sql,
+                    params: query.params.into_iter().map(Into::into).collect(),
+                    consistency,
+                    lane,
+                }
+            })
+            .collect();
+        let results = self.server.query_batch(queries).await;
+        let results = results
+            .into_iter()
+            .zip(options)
+            .map(|(results, (checksum, proof))| {
+                let outcome = match results {
+                    Ok(results) => proto::batch_result::Outcome::Results(get_proto_results(
+                        results, checksum, proof,
+                    )),
+                    Err(e) => {
+                        let status = query_status(&e);
+                        proto::batch_result::Outcome::Error(proto::QueryError {
+                            code: status.code() as i32,
+                            message: status.message().to_string(),
+                        })
+                    }
+                };
+                proto::BatchResult {
+                    outcome: Some(outcome),
+                }
             })
+            .collect();
+        Ok(Response::new(proto::BatchResults { results }))
+    }
+
+    type ExecuteStreamStream = ReceiverStream<Result<proto::QueryResults, Status>>;
+
+    async fn execute_stream(
+        &self,
+        request: Request<proto::Query>,
+    ) -> Result<Response<Self::ExecuteStreamStream>, tonic::Status> {
+        self.deprecated("ExecuteStream");
+        let _in_flight = self.admit()?;
+        let query = request.into_inner();
+        if query.predicate.is_some() {
+            return Err(Status::invalid_argument(
+                "conditional queries cannot be streamed",
+            ));
+        }
+        if query.tenant.is_some() {
+            return Err(Status::invalid_argument(
+                "tenant queries cannot be streamed",
+            ));
+        }
+        if !query.pragmas.is_empty() {
+            return Err(Status::invalid_argument(
+                "queries with pragmas cannot be streamed",
+            ));
+        }
+        self.await_session(query.after_idx).await?;
+        let (consistency, lane) = query_mode(&query);
+        let (checksum, proof, json_rows) = (query.checksum, query.proof, query.json_rows);
+        let params = query.params.into_iter().map(Into::into).collect();
+        let mut batches = self
+            .server
+            .query_stream(
+                query.sql,
+                params,
+                consistency,
+                lane,
+                query.batch_size as usize,
+            )
+            .await
+            .map_err(|e| query_status(&e))?;
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn(async move {
+            while let Some(batch) = batches.recv().await {
+                let batch = batch
+                    .map(|mut batch| {
+                        if json_rows {
+                            json::rows_as_objects(&mut batch);
+                        }
+                        get_proto_results(batch, checksum, proof)
+                    })
+                    .map_err(|e| query_status(&e));
+                let failed = batch.is_err();
+                if tx.send(batch).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn publish(
+        &self,
+        request: Request<proto::TopicMessage>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.deprecated("Publish");
+        let _in_flight = self.admit()?;
+        let msg = request.into_inner();
+        let server = self.server.clone();
+        match server.publish(msg.topic, msg.payload).await {
+            Ok(()) => Ok(Response::new(proto::Void {})),
+            Err(e) => Err(Status::internal(format!("{}", e))),
+        }
+    }
+
+    type SubscribeStream = ReceiverStream<Result<proto::TopicMessage, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::Subscription>,
+    ) -> Result<Response<Self::SubscribeStream>, tonic::Status> {
+        self.deprecated("Subscribe");
+        let _in_flight = self.admit()?;
+        let topic = request.into_inner().topic;
+        let mut subscription = self.server.subscribe(topic);
+        let (tx, rx) = mpsc::channel(SUBSCRIPTION_BUFFER);
+        tokio::task::spawn(async move {
+            loop {
+                let msg = match subscription.recv().await {
+                    Ok(publication) => Ok(proto::TopicMessage {
+                        topic: publication.topic,
+                        payload: publication.payload,
+                        index: publication.index,
+                    }),
+                    Err(RecvError::Lagged(missed)) => Err(Status::data_loss(format!(
+                        "subscriber fell behind by {} messages",
+                        missed
+                    ))),
+                    Err(RecvError::Closed) => break,
+                };
+                let lagged = msg.is_err();
+                if tx.send(msg).await.is_err() || lagged {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn prepare_statement(
+        &self,
+        request: Request<proto::PrepareStatement>,
+    ) -> Result<Response<proto::PreparedStatement>, tonic::Status> {
+        self.deprecated("PrepareStatement");
+        let _in_flight = self.admit()?;
+        match self.server.prepare(request.into_inner().sql).await {
+            Ok(id) => Ok(Response::new(proto::PreparedStatement { id })),
+            Err(e) => Err(query_status(&e)),
+        }
+    }
+
+    async fn execute_prepared(
+        &self,
+        request: Request<proto::ExecutePrepared>,
+    ) -> Result<Response<proto::QueryResults>, tonic::Status> {
+        self.deprecated("ExecutePrepared");
+        let _in_flight = self.admit()?;
+        let execute = request.into_inner();
+        let params = execute.params.into_iter().map(Into::into).collect();
+        match self.server.execute_prepared(execute.id, params).await {
+            Ok(results) => Ok(Response::new(get_proto_results(
+                results,
+                execute.checksum,
+                execute.proof,
+            ))),
+            Err(e) => Err(self.store_status(&e)),
+        }
+    }
+
+    async fn export_tenant(
+        &self,
+        request: Request<proto::TenantRequest>,
+    ) -> Result<Response<proto::TenantExport>, tonic::Status> {
+        self.deprecated("ExportTenant");
+        let _in_flight = self.admit()?;
+        match self.server.export_tenant(&request.into_inner().tenant) {
+            Ok(export) => Ok(Response::new(proto::TenantExport {
+                applied_idx: export.applied_idx,
+                statements: export.statements,
+            })),
+            Err(e) => Err(query_status(&e)),
+        }
+    }
+
+    async fn import_tenant(
+        &self,
+        request: Request<proto::TenantImport>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.deprecated("ImportTenant");
+        let _in_flight = self.admit()?;
+        let import = request.into_inner();
+        match self
+            .server
+            .import_tenant(&import.tenant, &import.statements)
+            .await
+        {
+            Ok(()) => Ok(Response::new(proto::Void {})),
+            Err(e) => Err(query_status(&e)),
+        }
+    }
+
+    async fn get_tenant_changes(
+        &self,
+        request: Request<proto::TenantRequest>,
+    ) -> Result<Response<proto::TenantChanges>, tonic::Status> {
+        self.deprecated("GetTenantChanges");
+        let request = request.into_inner();
+        match self
+            .server
+            .tenant_changes(&request.tenant, request.after_idx)
+        {
+            Ok(changes) => Ok(Response::new(proto::TenantChanges {
+                changes: changes
+                    .into_iter()
+                    .map(|change| proto::TenantChange {
+                        applied_idx: change.applied_idx,
+                        sql: change.sql,
+                        params: change.params.into_iter().map(Into::into).collect(),
+                    })
+                    .collect(),
+            })),
+            Err(e) => Err(query_status(&e)),
         }
-        Ok(Response::new(proto::QueryResults { rows }))
+    }
+
+    async fn get_tenant_checksum(
+        &self,
+        request: Request<proto::TenantRequest>,
+    ) -> Result<Response<proto::TenantChecksum>, tonic::Status> {
+        self.deprecated("GetTenantChecksum");
+        match self.server.export_tenant(&request.into_inner().tenant) {
+            Ok(export) => Ok(Response::new(proto::TenantChecksum {
+                applied_idx: export.applied_idx,
+                checksum: migration::checksum(&export.statements),
+            })),
+            Err(e) => Err(query_status(&e)),
+        }
+    }
+
+    async fn barrier(
+        &self,
+        _request: Request<proto::Void>,
+    ) -> Result<Response<proto::AppliedIndex>, tonic::Status> {
+        self.deprecated("Barrier");
+        let _in_flight = self.admit()?;
+        match self.server.barrier().await {
+            Ok(index) => Ok(Response::new(proto::AppliedIndex { index })),
+            Err(e) => Err(query_status(&e)),
+        }
+    }
+
+    async fn wait_applied(
+        &self,
+        request: Request<proto::WaitForIndex>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.deprecated("WaitApplied");
+        let wait = request.into_inner();
+        let timeout = Duration::from_millis(wait.timeout_ms);
+        match self.server.wait_applied(wait.index, timeout).await {
+            true => Ok(Response::new(proto::Void {})),
+            false => Err(Status::deadline_exceeded(format!(
+                "index {} not applied, at {}",
+                wait.index,
+                self.server.applied_idx()
+            ))),
+        }
+    }
+
+    async fn get_capabilities(
+        &self,
+        _request: Request<proto::Void>,
+    ) -> Result<Response<proto::Capabilities>, tonic::Status> {
+        self.deprecated("GetCapabilities");
+        let functions = self.server.functions();
+        Ok(Response::new(proto::Capabilities {
+            function_fingerprint: functions.fingerprint(),
+            functions: functions.functions().map(|def| def.name.clone()).collect(),
+            collations: functions.collations().map(|def| def.name.clone()).collect(),
+        }))
     }
 
     async fn prepare_request(
         &self,
         request: Request<proto::PrepareReq>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from as u64;
         let to_id = msg.to as u64;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let msg = messages::Message::with(from_id, to_id, messages::PaxosMsg::PrepareReq);
 
@@ -715,9 +1840,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::Prepare>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let ld = msg.ld;
@@ -735,9 +1863,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::Promise>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let n_accepted = get_ballot_from_proto(msg.n_accepted.unwrap());
@@ -765,9 +1896,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::AcceptSync>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let sync_item = msg.sync_item;
@@ -791,9 +1925,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::FirstAccept>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let entries = msg
@@ -814,9 +1951,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::AcceptDecide>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let ld = msg.ld;
@@ -838,9 +1978,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::Accepted>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let la = msg.la;
@@ -856,9 +1999,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::Decide>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let ld = msg.ld;
@@ -874,9 +2020,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::ProposalForward>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let proposals = msg
             .proposals
@@ -895,9 +2044,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::Compaction>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let compaction = get_compaction_from_proto(msg.compaction.unwrap());
         let com = messages::PaxosMsg::Compaction(compaction);
@@ -912,9 +2064,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::ForwardCompaction>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let compaction = get_forward_compaction_from_proto(msg.compaction.unwrap());
         let com = messages::PaxosMsg::ForwardCompaction(compaction);
@@ -929,9 +2084,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::AcceptStopSign>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let stopsign = get_stopsign_from_proto(msg.stopsign.unwrap());
@@ -948,9 +2106,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::AcceptedStopSign>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let acced_ss = messages::AcceptedStopSign::with(n);
@@ -969,9 +2130,12 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::DecideStopSign>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.ensure_known_peer(from_id, to_id)?;
+        let _slot = self.admit_peer(from_id, PeerMessage::Replication)?;
 
         let n = get_ballot_from_proto(msg.n.unwrap());
         let dec_ss = messages::DecideStopSign::with(n);
@@ -983,13 +2147,399 @@ impl Rpc for RpcService {
         Ok(Response::new(proto::Void {}))
     }
 
+    async fn read_index_request_message(
+        &self,
+        request: Request<proto::ReadIndexRequest>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
+        let msg = request.into_inner();
+        self.ensure_known_peer(msg.from, msg.to)?;
+        let _slot = self.admit_peer(msg.from, PeerMessage::ReadIndex)?;
+
+        let msg = ReadIndexMessage {
+            from: msg.from,
+            to: msg.to,
+            msg: ReadIndexMsg::Request { id: msg.id },
+        };
+        // Confirming the read index may take a barrier; reply when done.
+        let server = self.server.clone();
+        tokio::task::spawn(async move { server.recv_read_index_msg(msg).await });
+        Ok(Response::new(proto::Void {}))
+    }
+
+    async fn read_index_reply_message(
+        &self,
+        request: Request<proto::ReadIndexReply>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
+        let msg = request.into_inner();
+        self.ensure_known_peer(msg.from, msg.to)?;
+        let _slot = self.admit_peer(msg.from, PeerMessage::ReadIndex)?;
+
+        let msg = ReadIndexMessage {
+            from: msg.from,
+            to: msg.to,
+            msg: ReadIndexMsg::Reply {
+                id: msg.id,
+                index: msg.index,
+            },
+        };
+        self.server.recv_read_index_msg(msg).await;
+        Ok(Response::new(proto::Void {}))
+    }
+
+    async fn leadership_check_message(
+        &self,
+        request: Request<proto::LeadershipCheck>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
+        let msg = request.into_inner();
+        self.ensure_known_peer(msg.from, msg.to)?;
+        let _slot = self.admit_peer(msg.from, PeerMessage::ReadIndex)?;
+
+        let msg = ReadIndexMessage {
+            from: msg.from,
+            to: msg.to,
+            msg: ReadIndexMsg::Check { id: msg.id },
+        };
+        self.server.recv_read_index_msg(msg).await;
+        Ok(Response::new(proto::Void {}))
+    }
+
+    async fn join(
+        &self,
+        request: Request<proto::JoinRequest>,
+    ) -> Result<Response<proto::JoinSnapshot>, tonic::Status> {
+        self.ensure_ready()?;
+        let node = request.into_inner().node_id;
+        self.ensure_known_peer(node, self.server.id())?;
+        let snapshot = self
+            .server
+            .join_snapshot(node)
+            .map_err(|e| query_status(&e))?;
+        Ok(Response::new(proto::JoinSnapshot {
+            config_id: snapshot.config_id,
+            members: snapshot.members,
+            applied_idx: snapshot.applied_idx,
+            database: snapshot.database,
+        }))
+    }
+
+    async fn fetch_learner_entries(
+        &self,
+        request: Request<proto::LearnerFetch>,
+    ) -> Result<Response<proto::LearnerEntries>, tonic::Status> {
+        self.ensure_ready()?;
+        // Learners are not members, so any caller may follow the log.
+        let fetch = request.into_inner();
+        let batch = self
+            .server
+            .learner_entries(fetch.after_idx, fetch.max as usize)
+            .map_err(|e| query_status(&e))?;
+        Ok(Response::new(proto::LearnerEntries {
+            entries: batch
+                .entries
+                .into_iter()
+                .map(|(applied_idx, cmd)| proto::LearnerEntry {
+                    applied_idx,
+                    entry: Some(get_proto_entry(cmd)),
+                })
+                .collect(),
+            configurations: batch
+                .configurations
+                .into_iter()
+                .map(|start| proto::ConfigurationStart {
+                    config_id: start.config_id,
+                    members: start.members,
+                    applied_idx: start.applied_idx,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn leadership_ack_message(
+        &self,
+        request: Request<proto::LeadershipAck>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.ensure_ready()?;
+        let msg = request.into_inner();
+        self.ensure_known_peer(msg.from, msg.to)?;
+        let _slot = self.admit_peer(msg.from, PeerMessage::ReadIndex)?;
+
+        let msg = ReadIndexMessage {
+            from: msg.from,
+            to: msg.to,
+            msg: ReadIndexMsg::Ack {
+                id: msg.id,
+                follows: msg.follows,
+            },
+        };
+        self.server.recv_read_index_msg(msg).await;
+        Ok(Response::new(proto::Void {}))
+    }
+}
+
+/// The v2 client-facing service of a node. It shares the implementation of
+/// the v1 methods, which `RpcService` keeps serving as deprecated.
+#[derive(Debug)]
+pub struct RpcV2Service {
+    rpc: RpcService,
+}
+
+impl RpcV2Service {
+    /// Creates a new v2 RPC service.
+    pub fn new(server: Arc<StoreServer<RpcTransport>>) -> Self {
+        Self {
+            rpc: RpcService { server, v1: false },
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl RpcV2 for RpcV2Service {
+    type ExecuteStreamStream = <RpcService as Rpc>::ExecuteStreamStream;
+    type SubscribeStream = <RpcService as Rpc>::SubscribeStream;
+
+    async fn execute(
+        &self,
+        request: Request<proto::Query>,
+    ) -> Result<Response<proto::QueryResults>, tonic::Status> {
+        Rpc::execute(&self.rpc, request).await
+    }
+
+    async fn execute_stream(
+        &self,
+        request: Request<proto::Query>,
+    ) -> Result<Response<Self::ExecuteStreamStream>, tonic::Status> {
+        Rpc::execute_stream(&self.rpc, request).await
+    }
+
+    async fn execute_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<proto::QueryResults>, tonic::Status> {
+        Rpc::execute_transaction(&self.rpc, request).await
+    }
+
+    async fn execute_batch(
+        &self,
+        request: Request<proto::QueryBatch>,
+    ) -> Result<Response<proto::BatchResults>, tonic::Status> {
+        Rpc::execute_batch(&self.rpc, request).await
+    }
+
+    async fn prepare_statement(
+        &self,
+        request: Request<proto::PrepareStatement>,
+    ) -> Result<Response<proto::PreparedStatement>, tonic::Status> {
+        Rpc::prepare_statement(&self.rpc, request).await
+    }
+
+    async fn execute_prepared(
+        &self,
+        request: Request<proto::ExecutePrepared>,
+    ) -> Result<Response<proto::QueryResults>, tonic::Status> {
+        Rpc::execute_prepared(&self.rpc, request).await
+    }
+
+    async fn export_tenant(
+        &self,
+        request: Request<proto::TenantRequest>,
+    ) -> Result<Response<proto::TenantExport>, tonic::Status> {
+        Rpc::export_tenant(&self.rpc, request).await
+    }
+
+    async fn import_tenant(
+        &self,
+        request: Request<proto::TenantImport>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        Rpc::import_tenant(&self.rpc, request).await
+    }
+
+    async fn get_tenant_changes(
+        &self,
+        request: Request<proto::TenantRequest>,
+    ) -> Result<Response<proto::TenantChanges>, tonic::Status> {
+        Rpc::get_tenant_changes(&self.rpc, request).await
+    }
+
+    async fn get_tenant_checksum(
+        &self,
+        request: Request<proto::TenantRequest>,
+    ) -> Result<Response<proto::TenantChecksum>, tonic::Status> {
+        Rpc::get_tenant_checksum(&self.rpc, request).await
+    }
+
+    async fn publish(
+        &self,
+        request: Request<proto::TopicMessage>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        Rpc::publish(&self.rpc, request).await
+    }
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::Subscription>,
+    ) -> Result<Response<Self::SubscribeStream>, tonic::Status> {
+        Rpc::subscribe(&self.rpc, request).await
+    }
+
+    async fn get_capabilities(
+        &self,
+        request: Request<proto::Void>,
+    ) -> Result<Response<proto::Capabilities>, tonic::Status> {
+        Rpc::get_capabilities(&self.rpc, request).await
+    }
+
+    async fn barrier(
+        &self,
+        request: Request<proto::Void>,
+    ) -> Result<Response<proto::AppliedIndex>, tonic::Status> {
+        Rpc::barrier(&self.rpc, request).await
+    }
+
+    async fn wait_applied(
+        &self,
+        request: Request<proto::WaitForIndex>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        Rpc::wait_applied(&self.rpc, request).await
+    }
+
+    async fn open_cursor(
+        &self,
+        request: Request<proto::Query>,
+    ) -> Result<Response<proto::CursorPage>, tonic::Status> {
+        let _in_flight = self.rpc.admit()?;
+        let query = request.into_inner();
+        if query.predicate.is_some() || query.tenant.is_some() || !query.pragmas.is_empty() {
+            return Err(Status::invalid_argument(
+                "cursors cannot be opened for conditional, tenant or pragma queries",
+            ));
+        }
+        if query.json_rows {
+            return Err(Status::invalid_argument("cursors cannot return JSON rows"));
+        }
+        self.rpc.await_session(query.after_idx).await?;
+        let (consistency, lane) = query_mode(&query);
+        let params = query.params.into_iter().map(Into::into).collect();
+        let page = self
+            .rpc
+            .server
+            .open_cursor(
+                query.sql,
+                params,
+                consistency,
+                lane,
+                query.batch_size as usize,
+            )
+            .await
+            .map_err(|e| query_status(&e))?;
+        Ok(Response::new(get_proto_page(
+            page,
+            query.checksum,
+            query.proof,
+        )))
+    }
+
+    async fn fetch_cursor(
+        &self,
+        request: Request<proto::CursorRequest>,
+    ) -> Result<Response<proto::CursorPage>, tonic::Status> {
+        let _in_flight = self.rpc.admit()?;
+        let request = request.into_inner();
+        let page = self
+            .rpc
+            .server
+            .fetch_cursor(request.cursor_id)
+            .await
+            .map_err(|e| query_status(&e))?;
+        Ok(Response::new(get_proto_page(page, request.checksum, false)))
+    }
+
+    async fn close_cursor(
+        &self,
+        request: Request<proto::CursorRequest>,
+    ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.rpc.server.close_cursor(request.into_inner().cursor_id);
+        Ok(Response::new(proto::Void {}))
+    }
+
+    async fn get_leader(
+        &self,
+        _request: Request<proto::Void>,
+    ) -> Result<Response<proto::Leader>, tonic::Status> {
+        let leader = match self.rpc.server.current_leader() {
+            Some(leader) => proto::Leader {
+                id: leader.id,
+                ballot: get_proto_ballot(leader.ballot),
+                addr: leader.addr.unwrap_or_default(),
+            },
+            None => proto::Leader::default(),
+        };
+        Ok(Response::new(leader))
+    }
+
+    async fn query_page(
+        &self,
+        request: Request<proto::PageRequest>,
+    ) -> Result<Response<proto::ResultPage>, tonic::Status> {
+        let _in_flight = self.rpc.admit()?;
+        let request = request.into_inner();
+        let token = Some(request.token.as_str()).filter(|token| !token.is_empty());
+        let page = self
+            .rpc
+            .server
+            .query_page(
+                &request.sql,
+                &request.key,
+                request.page_size as usize,
+                token,
+                consistency(request.consistency),
+            )
+            .await
+            .map_err(|e| query_status(&e))?;
+        Ok(Response::new(proto::ResultPage {
+            results: Some(get_proto_results(page.results, request.checksum, false)),
+            next_token: page.next.unwrap_or_default(),
+            applied_idx: page.applied_idx,
+            pinned: page.pinned,
+        }))
+    }
+}
+
+/// The leader election service of a node, served apart from `RpcService`.
+#[derive(Debug)]
+pub struct BleService {
+    rpc: RpcService,
+}
+
+impl BleService {
+    /// Creates a new leader election service.
+    pub fn new(server: Arc<StoreServer<RpcTransport>>) -> Self {
+        Self {
+            rpc: RpcService::new(server),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Ble for BleService {
     async fn heartbeat_request_message(
         &self,
         request: Request<proto::HeartbeatRequest>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.rpc.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.rpc.ensure_known_peer(from_id, to_id)?;
+        let _slot = match self
+            .rpc
+            .admit_peer(from_id, PeerMessage::HeartbeatRequest)?
+        {
+            Some(slot) => slot,
+            None => return Ok(Response::new(proto::Void {})),
+        };
 
         let round = msg.round;
         let req = ble::messages::HeartbeatRequest::with(round);
@@ -999,7 +2549,7 @@ impl Rpc for RpcService {
             ble::messages::HeartbeatMsg::Request(req),
         );
 
-        let server = self.server.clone();
+        let server = self.rpc.server.clone();
         server.recv_ble_msg(msg);
         Ok(Response::new(proto::Void {}))
     }
@@ -1008,9 +2558,15 @@ impl Rpc for RpcService {
         &self,
         request: Request<proto::HeartbeatReply>,
     ) -> Result<Response<proto::Void>, tonic::Status> {
+        self.rpc.ensure_ready()?;
         let msg = request.into_inner();
         let from_id = msg.from;
         let to_id = msg.to;
+        self.rpc.ensure_known_peer(from_id, to_id)?;
+        let _slot = match self.rpc.admit_peer(from_id, PeerMessage::HeartbeatReply)? {
+            Some(slot) => slot,
+            None => return Ok(Response::new(proto::Void {})),
+        };
         let round = msg.round;
 
         let ballot = get_ballot_from_proto(msg.ballot.unwrap());
@@ -1022,7 +2578,7 @@ impl Rpc for RpcService {
             ble::messages::HeartbeatMsg::Reply(rep),
         );
 
-        let server = self.server.clone();
+        let server = self.rpc.server.clone();
         server.recv_ble_msg(msg);
         Ok(Response::new(proto::Void {}))
     }
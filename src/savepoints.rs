@@ -0,0 +1,165 @@
+//! Savepoints inside replicated transactions.
+//!
+//! The statements of a transaction may open savepoints with `SAVEPOINT`,
+//! release them with `RELEASE` and undo their work with `ROLLBACK TO`. A
+//! statement that fails while a savepoint is open does not fail the
+//! transaction: the replica rolls back to the innermost open savepoint,
+//! releases it, and carries on after the `RELEASE` that closes it, skipping
+//! the rest of its statements. Since this only depends on the statements
+//! of the command and the state they run on, every replica handles the
+//! failure the same way. The names of the savepoints rolled back this way
+//! are reported in `QueryResults::rolled_back`. A statement failing outside
+//! any savepoint still fails the whole transaction.
+
+use crate::errors::StoreError;
+use crate::rows;
+use crate::server::QueryResults;
+use crate::value::quote_identifier;
+use sqlite::Connection;
+use sqlite3_sys as ffi;
+use std::ffi::CString;
+
+/// How a statement of a transaction handles savepoints.
+#[derive(Debug, PartialEq, Eq)]
+enum Control {
+    Savepoint(String),
+    Release(String),
+    RollbackTo(String),
+    Other,
+}
+
+/// Returns true if `stmt` is a `SAVEPOINT`, `RELEASE` or `ROLLBACK TO`
+/// statement.
+pub(crate) fn is_savepoint_statement(stmt: &str) -> bool {
+    control(stmt) != Control::Other
+}
+
+fn control(stmt: &str) -> Control {
+    let tokens: Vec<_> = stmt
+        .trim()
+        .trim_end_matches(';')
+        .split_whitespace()
+        .collect();
+    let is = |token: &&str, keyword: &str| token.eq_ignore_ascii_case(keyword);
+    // `[SAVEPOINT] <name>`.
+    let name = |tokens: &[&str]| match tokens {
+        [savepoint, name] if is(savepoint, "savepoint") => Some(unquote(name)),
+        [name] => Some(unquote(name)),
+        _ => None,
+    };
+    let parsed = match tokens.split_first() {
+        Some((keyword, [name])) if is(keyword, "savepoint") => {
+            Some(Control::Savepoint(unquote(name)))
+        }
+        Some((keyword, rest)) if is(keyword, "release") => name(rest).map(Control::Release),
+        Some((keyword, rest)) if is(keyword, "rollback") => {
+            // `ROLLBACK [TRANSACTION] TO [SAVEPOINT] <name>`.
+            let rest = match rest.split_first() {
+                Some((transaction, rest)) if is(transaction, "transaction") => rest,
+                _ => rest,
+            };
+            match rest.split_first() {
+                Some((to, rest)) if is(to, "to") => name(rest).map(Control::RollbackTo),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    parsed.unwrap_or(Control::Other)
+}
+
+/// Savepoint names are case-insensitive identifiers, possibly quoted.
+fn unquote(name: &str) -> String {
+    name.trim_matches(|c| matches!(c, '"' | '`' | '[' | ']' | '\''))
+        .to_string()
+}
+
+/// Splits `sql` into its statements, where SQLite's tokenizer ends them.
+fn split_statements(sql: &str) -> Vec<&str> {
+    let mut statements = vec![];
+    let mut start = 0;
+    for (end, _) in sql.match_indices(';') {
+        let candidate = &sql[start..=end];
+        let complete = match CString::new(candidate) {
+            Ok(candidate) => unsafe { ffi::sqlite3_complete(candidate.as_ptr()) != 0 },
+            Err(_) => false,
+        };
+        if complete {
+            statements.push(candidate);
+            start = end + 1;
+        }
+    }
+    if !sql[start..].trim().is_empty() {
+        statements.push(&sql[start..]);
+    }
+    statements
+}
+
+/// Runs the statements of the transaction `sql` on `conn`, which is already
+/// in a transaction, handling failures inside savepoints as the module
+/// describes.
+pub(crate) fn run(conn: &Connection, sql: String) -> Result<QueryResults, StoreError> {
+    let statements = split_statements(&sql);
+    if statements
+        .iter()
+        .all(|stmt| control(stmt) == Control::Other)
+    {
+        return rows::run(conn, sql, &[]);
+    }
+    let mut results = QueryResults::new(vec![]);
+    // Savepoints open, innermost last.
+    let mut open: Vec<String> = vec![];
+    // While skipping the rest of a rolled back savepoint, the number of
+    // savepoints opened inside it that are not released yet.
+    let mut skipping: Option<usize> = None;
+    for stmt in statements {
+        let control = control(stmt);
+        if let Some(nested) = skipping {
+            skipping = match control {
+                Control::Savepoint(_) => Some(nested + 1),
+                Control::Release(_) if nested == 0 => None,
+                Control::Release(_) => Some(nested - 1),
+                _ => Some(nested),
+            };
+            continue;
+        }
+        match rows::run(conn, stmt.to_string(), &[]) {
+            Ok(stmt_results) => merge(&mut results, stmt_results),
+            Err(e) if control != Control::Other || open.is_empty() => return Err(e),
+            Err(_) => {
+                let name = open.pop().unwrap();
+                conn.execute(format!(
+                    "ROLLBACK TO {name}; RELEASE {name}",
+                    name = quote_identifier(&name)
+                ))?;
+                results.rolled_back.push(name);
+                skipping = Some(0);
+                continue;
+            }
+        }
+        match control {
+            Control::Savepoint(name) => open.push(name),
+            // Releasing a savepoint releases those opened after it too.
+            Control::Release(name) => {
+                if let Some(i) = open
+                    .iter()
+                    .rposition(|open| open.eq_ignore_ascii_case(&name))
+                {
+                    open.truncate(i);
+                }
+            }
+            Control::RollbackTo(_) | Control::Other => {}
+        }
+    }
+    Ok(results)
+}
+
+/// Adds the results of a statement to those of the transaction so far.
+fn merge(results: &mut QueryResults, stmt_results: QueryResults) {
+    if !stmt_results.columns.is_empty() {
+        results.columns = stmt_results.columns;
+    }
+    results.rows.extend(stmt_results.rows);
+    results.rows_affected += stmt_results.rows_affected;
+    results.last_insert_rowid = stmt_results.last_insert_rowid.or(results.last_insert_rowid);
+}
@@ -0,0 +1,227 @@
+//! Replicated schema migrations.
+//!
+//! `SchemaMigrator` takes the ordered migration scripts of an application
+//! and brings the schema up to the latest one. Each migration is replicated
+//! as a single command carrying its version and script, which every
+//! replica applies in one transaction together with recording the version
+//! in the reserved `chiselstore_schema_migrations` table. A replica that
+//! already recorded the version skips the command, so applying a migration
+//! again, as when a recovering node replays its log or two nodes migrate
+//! at once, is a no-op. Migration `n` only applies on top of version
+//! `n - 1`, so every replica goes through the same versions in order.
+//!
+//! A version is recorded with the checksum of its script as written, and
+//! applying it again with another script fails.
+
+use crate::checksum::fnv1a;
+use crate::errors::StoreError;
+use crate::server::{
+    query_connection, query_connection_in_transaction, Consistency, QueryResults,
+    SequencePaxosStoreTransport, StoreServer,
+};
+use crate::value::{quote_literal, Value};
+use sqlite::Connection;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Reserved table recording the applied migrations.
+pub const SCHEMA_TABLE: &str = "chiselstore_schema_migrations";
+
+/// Creates the migrations table on `conn` if it does not exist yet.
+pub(crate) fn create_table(conn: &Connection) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} \
+         (version INTEGER PRIMARY KEY, name TEXT NOT NULL, checksum INTEGER NOT NULL)",
+        SCHEMA_TABLE
+    ))?;
+    Ok(())
+}
+
+/// Checksum of a migration script, recorded to catch a version applied
+/// with another script.
+pub(crate) fn script_checksum(sql: &str) -> u64 {
+    fnv1a(sql.as_bytes())
+}
+
+/// Applies migration `version` with script `sql` on `conn`, unless it is
+/// recorded already. `QueryResults::applied` tells whether it ran.
+pub(crate) fn apply_migration(
+    conn: &Connection,
+    version: u64,
+    name: &str,
+    checksum: u64,
+    sql: &str,
+) -> Result<QueryResults, StoreError> {
+    let checksum = checksum as i64;
+    let recorded = query_connection(
+        conn,
+        format!(
+            "SELECT checksum FROM {} WHERE version = {}",
+            SCHEMA_TABLE, version as i64
+        ),
+    )?;
+    match recorded
+        .rows
+        .first()
+        .and_then(|row| row.typed_values.first())
+    {
+        Some(Value::Integer(recorded)) if *recorded == checksum => {
+            return Ok(QueryResults::skipped())
+        }
+        Some(_) => {
+            return Err(StoreError::InvalidRequest(format!(
+                "schema migration {} was applied with a different script",
+                version
+            )))
+        }
+        None => {}
+    }
+    let current = current_version(conn)?;
+    if version != current + 1 {
+        return Err(StoreError::InvalidRequest(format!(
+            "schema migration {} does not follow schema version {}",
+            version, current
+        )));
+    }
+    // On a line of its own, so a trailing comment cannot swallow it.
+    let sql = format!(
+        "{}\n;INSERT INTO {} (version, name, checksum) VALUES ({}, {}, {})",
+        sql,
+        SCHEMA_TABLE,
+        version as i64,
+        quote_literal(name),
+        checksum
+    );
+    query_connection_in_transaction(conn, sql)
+}
+
+/// The latest migration applied on `conn`, or 0.
+fn current_version(conn: &Connection) -> Result<u64, StoreError> {
+    let results = query_connection(conn, version_statement())?;
+    Ok(version_of(&results))
+}
+
+fn version_statement() -> String {
+    format!("SELECT max(version) FROM {}", SCHEMA_TABLE)
+}
+
+fn version_of(results: &QueryResults) -> u64 {
+    match results
+        .rows
+        .first()
+        .and_then(|row| row.typed_values.first())
+    {
+        Some(Value::Integer(version)) => *version as u64,
+        _ => 0,
+    }
+}
+
+/// A migration script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Migration {
+    /// Version the schema is at once the migration is applied; migrations
+    /// are numbered from 1 without gaps.
+    pub version: u64,
+    /// Name of the migration, recorded along with it.
+    pub name: String,
+    /// The statements of the migration.
+    pub sql: String,
+}
+
+/// Brings the schema of the replicated database up to date with a list of
+/// migrations.
+#[derive(Debug)]
+pub struct SchemaMigrator<T: SequencePaxosStoreTransport + Send + Sync> {
+    server: Arc<StoreServer<T>>,
+    migrations: Vec<Migration>,
+}
+
+impl<T: SequencePaxosStoreTransport + Send + Sync> SchemaMigrator<T> {
+    /// Creates a migrator replicating migrations through `server`.
+    pub fn new(server: Arc<StoreServer<T>>) -> Self {
+        Self {
+            server,
+            migrations: vec![],
+        }
+    }
+
+    /// Adds migration `version`, running `sql`.
+    pub fn with_migration<N: ToString, S: ToString>(
+        mut self,
+        version: u64,
+        name: N,
+        sql: S,
+    ) -> Self {
+        self.migrations.push(Migration {
+            version,
+            name: name.to_string(),
+            sql: sql.to_string(),
+        });
+        self
+    }
+
+    /// Returns the latest migration applied, or 0, linearizably.
+    pub async fn version(&self) -> Result<u64, StoreError> {
+        let results = self
+            .server
+            .query(version_statement(), Consistency::Strong)
+            .await?;
+        Ok(version_of(&results))
+    }
+
+    /// Applies the migrations the schema is missing, in order, returning the
+    /// versions this call applied. Fails without proposing anything if the
+    /// migrations are not numbered from 1 without gaps, or if an applied
+    /// version was applied with another script.
+    pub async fn migrate(&self) -> Result<Vec<u64>, StoreError> {
+        let mut migrations: Vec<_> = self.migrations.iter().collect();
+        migrations.sort_by_key(|migration| migration.version);
+        for (i, migration) in migrations.iter().enumerate() {
+            if migration.version != i as u64 + 1 {
+                return Err(StoreError::InvalidRequest(format!(
+                    "schema migration {} is out of sequence, expected {}",
+                    migration.version,
+                    i + 1
+                )));
+            }
+        }
+        let recorded = self.recorded().await?;
+        let mut applied = vec![];
+        for migration in migrations {
+            match recorded.get(&migration.version) {
+                Some(checksum) if *checksum == script_checksum(&migration.sql) as i64 => continue,
+                Some(_) => {
+                    return Err(StoreError::InvalidRequest(format!(
+                        "schema migration {} was applied with a different script",
+                        migration.version
+                    )))
+                }
+                None => {}
+            }
+            let results = self
+                .server
+                .apply_migration(migration.version, &migration.name, &migration.sql)
+                .await?;
+            if results.applied {
+                applied.push(migration.version);
+            }
+        }
+        Ok(applied)
+    }
+
+    /// The checksums of the applied migrations, by version.
+    async fn recorded(&self) -> Result<HashMap<u64, i64>, StoreError> {
+        let stmt = format!("SELECT version, checksum FROM {}", SCHEMA_TABLE);
+        let results = self.server.query(stmt, Consistency::Strong).await?;
+        Ok(results
+            .rows
+            .iter()
+            .filter_map(|row| match row.typed_values.as_slice() {
+                [Value::Integer(version), Value::Integer(checksum)] => {
+                    Some((*version as u64, *checksum))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+}
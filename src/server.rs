@@ -1,62 +1,611 @@
 //! ChiselStore server module.
 
+use crate::advertise::{LeaderAdvertiser, LeaderInfo, LeaderRegistry};
+use crate::analytics::ReadSnapshot;
+use crate::archive::ConfigArchive;
+use crate::backup;
+use crate::batching::{AdaptiveBatching, BatchingStatus, LatencySlo};
+use crate::bootstrap::{JoinSnapshot, JoinedConfiguration};
+use crate::counters;
+use crate::cursors::{CursorPage, Cursors};
+use crate::deadline::StatementDeadline;
+use crate::deprecation::DeprecatedCalls;
+use crate::determinism;
+use crate::diagnostics::{EventLog, PaxosProgress, StallDetector, StallReport};
+use crate::encryption::{self, SecretsProvider};
 use crate::errors::StoreError;
+#[cfg(feature = "fault-injection")]
+use crate::faults::{Fault, FaultInjection, FaultInjector};
+use crate::fulltext;
+use crate::functions::{self, FunctionRegistry};
+use crate::introspection::{self, ClusterStatus, MemberStatus};
+use crate::lanes::{Lane, QueryLane, ReadLanes};
+use crate::learner::{LearnerBatch, LearnerFeed};
+use crate::lease::LeaderLease;
 use crate::logger;
+use crate::mailbox::PeerMailbox;
+use crate::membership;
+use crate::migration::{self, TenantChange, TenantExport, TenantRoute};
+use crate::outbox::{self, outbox_statement};
+use crate::pagination::{self, PageToken, ResultPage};
+use crate::pragmas::{self, PragmaSetting};
+use crate::prepared::{self, StatementCache};
+use crate::pubsub::{Publication, Topics};
+use crate::read_index::{PendingChecks, PendingReadIndexes, ReadIndexMessage, ReadIndexMsg};
+use crate::reconfiguration::{ReconfigurationManager, Transition};
+use crate::rows::{self, CompiledStatement};
+use crate::savepoints;
+use crate::schema;
+use crate::settings::{self, ConfigChange, ConfigWatch, ConfigWatchers, PREFERRED_LEADER_KEY};
+use crate::statements::{self, StatementStatistics};
+use crate::tenants::{self, TenantUsage, Tenants};
+use crate::tombstones::{self, Tombstone};
+use crate::upsert::upsert_statements;
+use crate::validation::ProposalValidator;
+use crate::value::Value;
+use crate::warmup::{self, TouchedTables, WarmupReport};
 use async_notify::Notify;
 use async_trait::async_trait;
 use derivative::Derivative;
+use futures_util::future::join_all;
 use omnipaxos_core::{
     ballot_leader_election as ble,
     ballot_leader_election::Ballot,
     messages,
-    sequence_paxos::{SequencePaxos, SequencePaxosConfig},
+    sequence_paxos::{ReconfigurationRequest, SequencePaxos, SequencePaxosConfig},
     storage::Storage,
     storage::{Snapshot, StopSignEntry},
 };
-use slog::{info, Logger};
+use slog::{debug, info, warn, Logger};
 use sqlite::{Connection, OpenFlags};
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Instant, SystemTime};
 use std::{thread::sleep, time::Duration};
+use tokio::sync::{broadcast, mpsc};
 
 #[derive(Debug)]
 pub struct QueryRow {
+    /// The columns rendered as text.
     pub values: Vec<String>,
+    /// The columns with their SQLite types.
+    pub typed_values: Vec<Value>,
 }
 
 impl QueryRow {
-    fn new() -> Self {
-        QueryRow { values: Vec::new() }
+    pub(crate) fn new() -> Self {
+        QueryRow {
+            values: Vec::new(),
+            typed_values: Vec::new(),
+        }
     }
 }
 
+/// Metadata of a result column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    /// Name of the column, as given by an `AS` clause or derived by SQLite.
+    pub name: String,
+    /// Declared type of the table column the result column comes from;
+    /// `None` for expressions.
+    pub decl_type: Option<String>,
+    /// Table the column comes from; `None` for expressions.
+    pub table: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct QueryResults {
+    /// Columns of the last statement that returns any.
+    pub columns: Vec<Column>,
     pub rows: Vec<QueryRow>,
+    /// False if the command was conditional and its predicate did not hold.
+    pub applied: bool,
+    /// Where the time serving the query went on this replica.
+    pub timing: QueryTiming,
+    /// Where a replicated command was linearized; `None` for relaxed reads.
+    pub proof: Option<ReadProof>,
+    /// Rows inserted, updated or deleted, including by triggers and foreign
+    /// key actions.
+    pub rows_affected: u64,
+    /// Rowid of the last row inserted, if any was.
+    pub last_insert_rowid: Option<i64>,
+    /// Savepoints of a transaction rolled back because a statement inside
+    /// them failed, in the order they were.
+    pub rolled_back: Vec<String>,
 }
 
-#[derive(Debug)]
-struct StoreConfig {
-    conn_pool_size: usize,
+/// The leader of the cluster, as a replica knows it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeaderStatus {
+    /// Node id of the leader.
+    pub id: u64,
+    /// Ballot the leader was elected with.
+    pub ballot: Ballot,
+    /// Address clients reach the leader at, if the transport knows it.
+    pub addr: Option<String>,
+}
+
+/// Evidence of where a replicated command was linearized, for auditing
+/// strong reads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadProof {
+    /// Ballot the command was accepted in.
+    pub ballot: Ballot,
+    /// Log index the command was decided and applied at.
+    pub decided_idx: u64,
+    /// Nodes known to have accepted the log up to `decided_idx`, including
+    /// the serving node. Only the leader learns of its followers' accepts,
+    /// so on followers this holds just the serving node. Acks are not
+    /// signed.
+    pub quorum: Vec<u64>,
+}
+
+impl QueryResults {
+    pub(crate) fn new(rows: Vec<QueryRow>) -> Self {
+        QueryResults {
+            columns: vec![],
+            rows,
+            applied: true,
+            timing: QueryTiming::default(),
+            proof: None,
+            rows_affected: 0,
+            last_insert_rowid: None,
+            rolled_back: vec![],
+        }
+    }
+
+    pub(crate) fn skipped() -> Self {
+        QueryResults {
+            columns: vec![],
+            rows: vec![],
+            applied: false,
+            timing: QueryTiming::default(),
+            proof: None,
+            rows_affected: 0,
+            last_insert_rowid: None,
+            rolled_back: vec![],
+        }
+    }
+}
+
+/// Server-side latency breakdown of a query.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QueryTiming {
+    /// Time spent waiting for admission or to be proposed.
+    pub queue: Duration,
+    /// Time from proposing the command until it was applied, minus `apply`.
+    pub replicate: Duration,
+    /// Time spent executing the SQL.
+    pub apply: Duration,
+}
+
+/// Callback invoked with every captured stall report.
+pub type StallCallback = dyn Fn(&StallReport) + Send + Sync;
+
+/// Callback deciding what to do with a command that failed to apply.
+pub type ApplyErrorCallback = dyn Fn(&StoreCommand, &StoreError) -> ApplyErrorAction + Send + Sync;
+
+/// What a replica does when a replicated command fails at apply time.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub enum ApplyErrorPolicy {
+    /// Stop the replica so that it cannot diverge from its peers.
+    Halt,
+    /// Skip the command and record the failure.
+    SkipAndRecord,
+    /// Let the callback decide per command.
+    Callback(#[derivative(Debug = "ignore")] Arc<ApplyErrorCallback>),
+}
+
+/// Decision returned by an `ApplyErrorPolicy::Callback`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApplyErrorAction {
+    /// Stop the replica.
+    Halt,
+    /// Skip the command and record the failure.
+    Skip,
+}
+
+/// A replicated command that failed to apply and was skipped.
+#[derive(Clone, Debug)]
+pub struct ApplyFailure {
+    /// The failed command.
+    pub command: StoreCommand,
+    /// The error the command failed with.
+    pub error: String,
+    /// The number of entries applied before this command.
+    pub applied_idx: u64,
+}
+
+/// ChiselStore server configuration.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct StoreConfig {
+    /// Number of SQLite connections in the pool.
+    pub conn_pool_size: usize,
+    /// Maximum number of concurrent reads in the transactional lane, at
+    /// least 1.
+    pub transactional_concurrency: usize,
+    /// Maximum number of concurrent reads in the analytical lane, at least
+    /// 1.
+    pub analytical_concurrency: usize,
+    /// Number of SQLite connections reserved for the analytical lane.
+    pub analytical_pool_size: usize,
+    /// How long the applied index may stay put while proposals are pending
+    /// before the node is considered stalled.
+    pub stall_timeout: Duration,
+    /// Directory that stall diagnostics bundles are written to.
+    pub diagnostics_dir: PathBuf,
+    /// Called whenever a stall is detected, after the bundle is written.
+    #[derivative(Debug = "ignore")]
+    pub on_stall: Option<Arc<StallCallback>>,
+    /// What to do when a replicated command fails to apply.
+    pub apply_error_policy: ApplyErrorPolicy,
+    /// How long a replicated statement may run before it is interrupted and
+    /// fails with `StoreError::StatementTimeout`, which is then handled by
+    /// `apply_error_policy`.
+    ///
+    /// Replicas may not time out on the same statements, so use a generous
+    /// limit and `ApplyErrorPolicy::SkipAndRecord` to quarantine the
+    /// offending commands for inspection.
+    pub apply_statement_timeout: Option<Duration>,
+    /// Directory that read snapshots are written to.
+    pub snapshot_dir: PathBuf,
+    /// Replace the recorded initial membership instead of refusing to start
+    /// when the peers differ from it.
+    pub override_membership: bool,
+    /// Keys for the `encrypt` and `decrypt` SQL functions, which are only
+    /// registered when this is set. Every replica must have the same keys.
+    #[derivative(Debug = "ignore")]
+    pub secrets: Option<Arc<dyn SecretsProvider>>,
+    /// Custom SQL functions and collations registered on every connection.
+    /// Every replica must register the same ones; see `functions`.
+    pub functions: FunctionRegistry,
+    /// Checks run on commands submitted to this node before they are
+    /// proposed, in order.
+    #[derivative(Debug = "ignore")]
+    pub validators: Vec<Arc<dyn ProposalValidator>>,
+    /// Rows per batch of a streamed query that does not ask for a size.
+    pub stream_batch_size: usize,
+    /// How long the final state of a finished configuration stays
+    /// queryable after a reconfiguration; `None` to keep no archives.
+    /// Archives are written to `snapshot_dir`.
+    pub archive_retention: Option<Duration>,
+    /// Directory holding the per-tenant databases.
+    pub tenant_dir: PathBuf,
+    /// Number of SQLite connections in the pool of each open tenant.
+    pub tenant_pool_size: usize,
+    /// How long a heartbeat reply keeps the leader lease up; must stay well
+    /// below the heartbeat round the cluster elects a new leader after.
+    pub leader_lease: Duration,
+    /// Serve strong reads on the leader from its local state once a round
+    /// of leadership checks confirms that it leads, instead of appending
+    /// them to the log; see `read_index`. Off by default. When set, the
+    /// proof of such a read names the index the leader had applied when it
+    /// confirmed its leadership, rather than a log entry of the read.
+    pub leader_reads: bool,
+    /// Serve strong reads on followers from their local state once they
+    /// applied the read index confirmed by the leader, like
+    /// `Consistency::ReadIndex` reads, instead of appending them to the log.
+    pub follower_reads: bool,
+    /// Proxy strong writes sent to a follower's `Execute` RPC to the leader
+    /// and relay its results, instead of proposing them through the
+    /// follower. Writes are proposed locally if the leader is unreachable.
+    pub forward_writes: bool,
+    /// Priority of this node's ballots in leader election, which breaks
+    /// ties between ballots of the same round: of the nodes competing in a
+    /// round, the one with the highest priority leads. Change it at runtime
+    /// with `StoreServer::set_leader_priority`.
+    pub leader_priority: u64,
+    /// Start as a node added by a reconfiguration, in the configuration and
+    /// at the applied index of the database fetched with
+    /// `bootstrap::join_cluster`.
+    pub join: Option<JoinedConfiguration>,
+    /// Number of applied commands kept for learners to fetch; learners
+    /// further behind must be restored from a newer backup. See `learner`.
+    pub learner_feed_capacity: usize,
+    /// Warm the page cache of the pooled connections for the tables written
+    /// by each batch of applied commands; see `warmup`.
+    pub warm_after_apply: bool,
+    /// Queries run on every pooled connection at startup, before the node
+    /// reports ready, to warm its caches; see `warmup`.
+    pub warmup_queries: Vec<String>,
+    /// Commit latency objective the batching window adapts to; `None` to
+    /// keep the shortest window. See `batching`.
+    pub latency_slo: Option<LatencySlo>,
+    /// Number of proposals waiting that ends a batching window early; see
+    /// `batching`.
+    pub max_batch_size: usize,
+    /// Replace `RANDOM()`, `CURRENT_TIMESTAMP` and the like in proposed
+    /// commands with their values, so every replica applies the same ones;
+    /// see `determinism`.
+    pub resolve_nondeterminism: bool,
+    /// How long a cursor stays open without being fetched from; see
+    /// `cursors`.
+    pub cursor_ttl: Duration,
+    /// Maximum number of cursors open on this node at a time.
+    pub max_cursors: usize,
+    /// How many times a proposal is retried across leader changes before
+    /// the error reaches the caller. Writes are only retried if they were
+    /// not appended to the log yet, since they may otherwise be applied.
+    pub proposal_retries: usize,
+    /// How long a proposal waits for a leader, and to be applied before it
+    /// is checked for a leader change.
+    pub proposal_timeout: Duration,
+    /// Registry this node publishes itself to whenever it becomes the
+    /// leader; see `advertise`.
+    #[derivative(Debug = "ignore")]
+    pub leader_registry: Option<Arc<dyn LeaderRegistry>>,
+    /// Let commands create FTS5 full-text search tables; see `fulltext`.
+    pub full_text_search: bool,
+    /// Maximum number of inbound peer messages handled at a time, past
+    /// which the least valuable ones are shed; see `mailbox`.
+    pub peer_mailbox_capacity: usize,
+    /// Client requests to fail with injected retryable errors; see
+    /// `faults`.
+    #[cfg(feature = "fault-injection")]
+    pub fault_injection: Option<FaultInjection>,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            conn_pool_size: CONN_POOL_SIZE,
+            transactional_concurrency: CONN_POOL_SIZE,
+            analytical_concurrency: ANALYTICAL_CONCURRENCY,
+            analytical_pool_size: ANALYTICAL_CONCURRENCY,
+            stall_timeout: Duration::from_millis(STALL_TIMEOUT),
+            diagnostics_dir: PathBuf::from("."),
+            on_stall: None,
+            apply_error_policy: ApplyErrorPolicy::Halt,
+            apply_statement_timeout: None,
+            snapshot_dir: PathBuf::from("."),
+            override_membership: false,
+            secrets: None,
+            functions: FunctionRegistry::default(),
+            validators: vec![],
+            stream_batch_size: STREAM_BATCH_SIZE,
+            archive_retention: Some(Duration::from_secs(ARCHIVE_RETENTION)),
+            tenant_dir: PathBuf::from("."),
+            tenant_pool_size: TENANT_POOL_SIZE,
+            leader_lease: Duration::from_millis(HEARTBEAT_DELAY * BLE_TICK / 2),
+            leader_reads: false,
+            follower_reads: false,
+            forward_writes: false,
+            leader_priority: 0,
+            join: None,
+            learner_feed_capacity: LEARNER_FEED_CAPACITY,
+            warm_after_apply: false,
+            warmup_queries: vec![],
+            latency_slo: None,
+            max_batch_size: MAX_BATCH_SIZE,
+            resolve_nondeterminism: true,
+            cursor_ttl: Duration::from_secs(CURSOR_TTL),
+            max_cursors: MAX_CURSORS,
+            proposal_retries: PROPOSAL_RETRIES,
+            proposal_timeout: Duration::from_millis(2 * HEARTBEAT_DELAY * BLE_TICK),
+            leader_registry: None,
+            full_text_search: false,
+            peer_mailbox_capacity: PEER_MAILBOX_CAPACITY,
+            #[cfg(feature = "fault-injection")]
+            fault_injection: None,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct StoreCommand {
     pub id: usize,
     pub sql: String,
+    pub kind: CommandKind,
+    /// Custom functions the command calls, as `name@version`; replicas
+    /// without them refuse to apply it.
+    pub functions: Vec<String>,
+    /// Custom collations the command uses, as `name@version`, which
+    /// replicas need like `functions`.
+    pub collations: Vec<String>,
+    /// Values bound to the placeholders of `sql`.
+    pub params: Vec<Value>,
+    /// Tenant whose database the command applies to; `None` for the shared
+    /// database.
+    pub tenant: Option<String>,
+    /// Pragmas set while a statement, conditional statement or transaction
+    /// is applied; see `pragmas`.
+    pub pragmas: Vec<PragmaSetting>,
+}
+
+impl StoreCommand {
+    /// Creates a command executing `sql`.
+    pub fn new(id: usize, sql: String) -> Self {
+        Self {
+            id,
+            sql,
+            kind: CommandKind::Statement,
+            functions: vec![],
+            collations: vec![],
+            params: vec![],
+            tenant: None,
+            pragmas: vec![],
+        }
+    }
+}
+
+/// What applying a `StoreCommand` does.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandKind {
+    /// Execute the command's SQL statement.
+    Statement,
+    /// Deliver `payload` to the subscribers of `topic`.
+    Publish { topic: String, payload: Vec<u8> },
+    /// Execute the command's SQL statement only if `predicate` returns at
+    /// least one row when the command is applied.
+    Conditional { predicate: String },
+    /// Execute the command's SQL delete with `secure_delete` and record a
+    /// tombstone for `subject`, so the deleted rows are also purged from
+    /// retained backups.
+    HardDelete { subject: String },
+    /// Write `value` for `key` into the settings table and notify the
+    /// watchers of the key.
+    SetConfig { key: String, value: String },
+    /// Execute the command's SQL statements in a single transaction, so
+    /// either all of them take effect or none do.
+    Transaction,
+    /// Record the command's SQL statement as a prepared statement.
+    Prepare,
+    /// Execute the prepared statement `statement` with the command's
+    /// parameters. The command's SQL is empty.
+    ExecutePrepared { statement: u64 },
+    /// Apply the command's SQL as schema migration `version`, unless it is
+    /// applied already; see `schema`. `checksum` is that of the script as
+    /// written, before non-deterministic expressions in it are resolved.
+    Migration {
+        version: u64,
+        name: String,
+        checksum: u64,
+    },
+}
+
+/// A query of a `StoreServer::query_batch`.
+#[derive(Debug)]
+pub struct BatchQuery {
+    pub sql: String,
+    /// Values bound to the placeholders of `sql`.
+    pub params: Vec<Value>,
+    pub consistency: Consistency,
+    /// The lane relaxed reads are admitted through.
+    pub lane: QueryLane,
 }
 
 #[derive(Debug)]
 pub enum Consistency {
     Strong,
+    /// Reads served by any replica from its local state, possibly stale.
+    /// Writes are rejected.
     RelaxedReads,
+    /// Reads served by the leader from its local state while it holds a
+    /// lease, and like `Strong` otherwise; see `lease`. Writes are `Strong`.
+    LeaderLease,
+    /// Reads served by any replica from its local state once it applied the
+    /// read index confirmed by the leader, and like `Strong` if the leader
+    /// cannot be reached; see `read_index`. Writes are `Strong`.
+    ReadIndex,
+}
+
+/// Lifecycle of a `StoreServer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// The server is constructed but its event loops are not running yet.
+    Initializing,
+    /// The server is handling messages.
+    Ready,
+    /// The server still takes part in replication but turns away new client
+    /// requests while the ones in flight finish.
+    Draining,
+    /// The server is halting and no longer handles messages.
+    ShuttingDown,
+}
+
+impl std::fmt::Display for Lifecycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lifecycle::Initializing => write!(f, "initializing"),
+            Lifecycle::Ready => write!(f, "ready"),
+            Lifecycle::Draining => write!(f, "draining"),
+            Lifecycle::ShuttingDown => write!(f, "shutting down"),
+        }
+    }
+}
+
+/// A client request admitted by `StoreServer::begin_request`.
+///
+/// The request counts as in flight until the guard is dropped.
+#[derive(Debug)]
+pub struct InFlight<'a> {
+    in_flight: &'a AtomicUsize,
+    idle: &'a tokio::sync::Notify,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+/// A write proposed by `StoreServer::query_async`, resolving once this
+/// replica applied it. Dropping the handle forgets the write's result, not
+/// the write.
+#[derive(Debug)]
+pub struct WriteHandle {
+    id: u64,
+    notify: Arc<Notify>,
+    notifier: Arc<Mutex<ResultNotifier>>,
+    proposed: Instant,
+}
+
+/// A write applied by this replica.
+#[derive(Debug)]
+pub struct CommittedWrite {
+    /// Log index the write was decided and applied at.
+    pub index: u64,
+    /// Results of the write.
+    pub results: QueryResults,
+}
+
+impl WriteHandle {
+    /// Id of the proposed command.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Waits until this replica applied the write, returning where it was
+    /// decided and its results.
+    pub async fn committed(self) -> Result<CommittedWrite, StoreError> {
+        self.notify.notified().await;
+        let results = self
+            .notifier
+            .lock()
+            .unwrap()
+            .results
+            .remove(&self.id)
+            .unwrap();
+        results.map(|mut results| {
+            results.timing.replicate = self.proposed.elapsed().saturating_sub(results.timing.apply);
+            CommittedWrite {
+                index: results
+                    .proof
+                    .as_ref()
+                    .map(|proof| proof.decided_idx)
+                    .unwrap_or(0),
+                results,
+            }
+        })
+    }
+}
+
+impl Drop for WriteHandle {
+    fn drop(&mut self) {
+        self.notifier.lock().unwrap().forget(self.id);
+    }
 }
 
 #[async_trait]
 pub trait SequencePaxosStoreTransport {
     fn send_paxos_message(&self, msg: messages::Message<StoreCommand, ()>);
     fn send_ble_message(&self, ble_message: ble::messages::BLEMessage);
+    fn send_read_index_message(&self, msg: ReadIndexMessage);
+
+    /// Returns the address clients reach node `id` at, if known.
+    fn node_addr(&self, _id: u64) -> Option<String> {
+        None
+    }
+
+    /// Sets the logger the transport reports failed sends to; the server
+    /// passes its own when it starts.
+    fn set_logger(&self, _logger: Logger) {}
 }
 
 #[derive(Debug)]
@@ -77,6 +626,19 @@ impl ResultNotifier {
         self.cmnd_completion.insert(id, notify);
     }
 
+    /// Ids of commands still waiting for their result.
+    pub fn pending_commands(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.cmnd_completion.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Forgets command `id`, along with its result if it has one.
+    fn forget(&mut self, id: u64) {
+        self.cmnd_completion.remove(&id);
+        self.results.remove(&id);
+    }
+
     pub fn remove_command_and_add_result(
         &mut self,
         id: u64,
@@ -92,53 +654,323 @@ impl ResultNotifier {
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct SQLiteConnection {
+    /// Prepared statements compiled on each pooled connection, by pool
+    /// index. Declared first so they are finalized before the connections
+    /// close.
+    #[derivative(Debug = "ignore")]
+    prepared: Vec<Arc<Mutex<StatementCache>>>,
     #[derivative(Debug = "ignore")]
     conn_pool: Vec<Arc<Mutex<Connection>>>,
+    /// Statement deadline of each pooled connection, by pool index.
+    deadlines: Vec<Arc<StatementDeadline>>,
+    /// Custom functions and collations registered on every connection.
+    functions: FunctionRegistry,
     conn_idx: usize,
+    /// Tables written since the page caches were last warmed.
+    touched: TouchedTables,
+    /// Whether SQLite is built with FTS5.
+    fts5: bool,
+}
+
+/// Path of the database of node `id`.
+pub fn database_path(id: u64) -> PathBuf {
+    PathBuf::from(format!("node{}.db", id))
 }
 
 impl SQLiteConnection {
-    fn new(this_id: u64, config: StoreConfig) -> Self {
+    fn new(this_id: u64, conn_pool_size: usize, registry: &FunctionRegistry) -> Self {
+        Self::open(&database_path(this_id), conn_pool_size, registry).unwrap()
+    }
+
+    /// Opens a pool of connections to the database at `path`, creating it
+    /// if needed.
+    pub(crate) fn open(
+        path: &Path,
+        conn_pool_size: usize,
+        registry: &FunctionRegistry,
+    ) -> Result<Self, StoreError> {
         let mut conn_pool = vec![];
-        let conn_pool_size = config.conn_pool_size;
+        let mut deadlines = vec![];
+        let mut fts5 = false;
         for _ in 0..conn_pool_size {
             let flags = OpenFlags::new()
                 .set_read_write()
                 .set_create()
                 .set_no_mutex();
-            let mut conn =
-                Connection::open_with_flags(format!("node{}.db", this_id), flags).unwrap();
-            conn.set_busy_timeout(5000).unwrap();
+            let mut conn = Connection::open_with_flags(path, flags)?;
+            conn.set_busy_timeout(5000)?;
+            deadlines.push(StatementDeadline::install(&mut conn));
+            for def in registry.functions() {
+                functions::register(&mut conn, def)?;
+            }
+            for def in registry.collations() {
+                functions::register_collation(&mut conn, def)?;
+            }
+            settings::create_table(&conn)?;
+            prepared::create_table(&conn)?;
+            migration::create_tables(&conn)?;
+            outbox::create_table(&conn)?;
+            counters::create_table(&conn)?;
+            schema::create_table(&conn)?;
+            fts5 = fulltext::available(&conn)?;
             conn_pool.push(Arc::new(Mutex::new(conn)));
         }
 
-        Self {
+        Ok(Self {
+            prepared: (0..conn_pool_size).map(|_| Arc::default()).collect(),
             conn_pool,
+            deadlines,
+            functions: registry.clone(),
             conn_idx: 0,
+            touched: TouchedTables::default(),
+            fts5,
+        })
+    }
+
+    /// Runs the warmup `queries` on every pooled connection; see `warmup`.
+    fn warm_up(&self, queries: &[String], report: &mut WarmupReport) -> Result<(), StoreError> {
+        for (conn, cache) in self.conn_pool.iter().zip(&self.prepared) {
+            let conn = conn.lock().unwrap();
+            let mut cache = cache.lock().unwrap();
+            warmup::warm_up(&conn, &mut cache, queries, report)?;
         }
+        Ok(())
+    }
+
+    /// Returns the first of `required` that is not registered.
+    fn missing_function<'a>(&self, required: &'a [String]) -> Option<&'a String> {
+        required
+            .iter()
+            .find(|function| !self.functions.has_function(function))
+    }
+
+    /// Returns the first collation of `required` that is not registered.
+    fn missing_collation<'a>(&self, required: &'a [String]) -> Option<&'a String> {
+        required
+            .iter()
+            .find(|collation| !self.functions.has_collation(collation))
     }
 
-    fn get_connection(&mut self) -> Arc<Mutex<Connection>> {
+    pub(crate) fn get_connection(&mut self) -> Arc<Mutex<Connection>> {
         let idx = self.conn_idx % self.conn_pool.len();
         let conn = &self.conn_pool[idx];
         self.conn_idx += 1;
         conn.clone()
     }
 
-    fn query(&mut self, sql: String) -> Result<QueryResults, StoreError> {
+    /// Returns the next pooled connection along with its statement deadline.
+    fn get_connection_with_deadline(&mut self) -> (Arc<Mutex<Connection>>, Arc<StatementDeadline>) {
+        let idx = self.conn_idx % self.conn_pool.len();
+        let deadline = self.deadlines[idx].clone();
+        (self.get_connection(), deadline)
+    }
+
+    /// Runs `sql` with `params` bound and `pragmas` set, interrupting it
+    /// after `timeout`.
+    fn query(
+        &mut self,
+        sql: String,
+        params: &[Value],
+        pragmas: &[PragmaSetting],
+        timeout: Option<Duration>,
+    ) -> Result<QueryResults, StoreError> {
+        let (conn, deadline) = self.get_connection_with_deadline();
+        let conn = conn.lock().unwrap();
+        pragmas::with_pragmas(&conn, pragmas, || {
+            deadline.run(timeout, || query_connection_with_params(&conn, sql, params))
+        })
+    }
+
+    /// Runs `sql` if `predicate` returns at least one row, on the same
+    /// pooled connection and with `pragmas` set. Both together are
+    /// interrupted after `timeout`.
+    fn query_if(
+        &mut self,
+        predicate: String,
+        sql: String,
+        pragmas: &[PragmaSetting],
+        timeout: Option<Duration>,
+    ) -> Result<QueryResults, StoreError> {
+        let (conn, deadline) = self.get_connection_with_deadline();
+        let conn = conn.lock().unwrap();
+        pragmas::with_pragmas(&conn, pragmas, || {
+            deadline.run(timeout, || {
+                if query_connection(&conn, predicate)?.rows.is_empty() {
+                    return Ok(QueryResults::skipped());
+                }
+                query_connection(&conn, sql)
+            })
+        })
+    }
+
+    /// Runs a hard delete and records its tombstone, interrupting it after
+    /// `timeout`.
+    fn hard_delete(
+        &mut self,
+        tombstone: &Tombstone,
+        timeout: Option<Duration>,
+    ) -> Result<QueryResults, StoreError> {
+        let (conn, deadline) = self.get_connection_with_deadline();
+        let conn = conn.lock().unwrap();
+        deadline.run(timeout, || tombstones::apply_hard_delete(&conn, tombstone))?;
+        Ok(QueryResults::new(vec![]))
+    }
+
+    /// Writes a setting, interrupting it after `timeout`.
+    fn set_config(
+        &mut self,
+        key: &str,
+        value: &str,
+        timeout: Option<Duration>,
+    ) -> Result<QueryResults, StoreError> {
+        let (conn, deadline) = self.get_connection_with_deadline();
+        let conn = conn.lock().unwrap();
+        deadline.run(timeout, || settings::apply_set_config(&conn, key, value))?;
+        Ok(QueryResults::new(vec![]))
+    }
+
+    /// Records `sql` as a prepared statement, interrupting it after
+    /// `timeout`.
+    fn prepare(
+        &mut self,
+        sql: &str,
+        timeout: Option<Duration>,
+    ) -> Result<QueryResults, StoreError> {
+        let (conn, deadline) = self.get_connection_with_deadline();
+        let conn = conn.lock().unwrap();
+        deadline.run(timeout, || prepared::apply_prepare(&conn, sql))?;
+        Ok(QueryResults::new(vec![]))
+    }
+
+    /// Applies schema migration `version`, interrupting it after `timeout`.
+    fn migrate(
+        &mut self,
+        version: u64,
+        name: &str,
+        checksum: u64,
+        sql: &str,
+        timeout: Option<Duration>,
+    ) -> Result<QueryResults, StoreError> {
+        let (conn, deadline) = self.get_connection_with_deadline();
+        let conn = conn.lock().unwrap();
+        deadline.run(timeout, || {
+            schema::apply_migration(&conn, version, name, checksum, sql)
+        })
+    }
+
+    /// Runs the prepared statement `id` with `params` bound, compiling it on
+    /// the connection's first use, and interrupting it after `timeout`.
+    fn execute_prepared(
+        &mut self,
+        id: u64,
+        params: &[Value],
+        timeout: Option<Duration>,
+    ) -> Result<QueryResults, StoreError> {
+        let cache = self.prepared[self.conn_idx % self.prepared.len()].clone();
+        let (conn, deadline) = self.get_connection_with_deadline();
+        let conn = conn.lock().unwrap();
+        let mut cache = cache.lock().unwrap();
+        deadline.run(timeout, || cache.execute(&conn, id, params))
+    }
+
+    /// Runs `sql` in a single transaction with `pragmas` set, interrupting
+    /// it after `timeout`.
+    fn transaction(
+        &mut self,
+        sql: String,
+        pragmas: &[PragmaSetting],
+        timeout: Option<Duration>,
+    ) -> Result<QueryResults, StoreError> {
+        let (conn, deadline) = self.get_connection_with_deadline();
+        let conn = conn.lock().unwrap();
+        pragmas::with_pragmas(&conn, pragmas, || {
+            deadline.run(timeout, || query_connection_in_transaction(&conn, sql))
+        })
+    }
+
+    /// Reads the route of `tenant`.
+    fn tenant_route(&mut self, tenant: &str) -> Result<TenantRoute, StoreError> {
         let conn = self.get_connection();
         let conn = conn.lock().unwrap();
-        let mut rows = vec![];
-        conn.iterate(sql, |pairs| {
-            let mut row = QueryRow::new();
-            for &(_, value) in pairs.iter() {
-                row.values.push(value.unwrap().to_string());
-            }
-            rows.push(row);
-            true
-        })?;
-        Ok(QueryResults { rows })
+        migration::route(&conn, tenant)
+    }
+
+    /// Records a captured write of `tenant`.
+    fn record_tenant_change(
+        &mut self,
+        tenant: &str,
+        change: &TenantChange,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_connection();
+        let conn = conn.lock().unwrap();
+        migration::record_change(&conn, tenant, change)
+    }
+
+    /// Reads the captured writes of `tenant` applied after `after_idx`.
+    fn tenant_changes(
+        &mut self,
+        tenant: &str,
+        after_idx: u64,
+    ) -> Result<Vec<TenantChange>, StoreError> {
+        let conn = self.get_connection();
+        let conn = conn.lock().unwrap();
+        migration::changes_after(&conn, tenant, after_idx)
+    }
+
+    /// Reads the tombstones recorded after `after_idx`.
+    fn tombstones_after(&mut self, after_idx: u64) -> Result<Vec<Tombstone>, StoreError> {
+        let conn = self.get_connection();
+        let conn = conn.lock().unwrap();
+        tombstones::tombstones_after(&conn, after_idx)
+    }
+
+    /// Copies the database into a new file at `path`.
+    pub(crate) fn vacuum_into(&mut self, path: &Path) -> Result<(), StoreError> {
+        let conn = self.get_connection();
+        let conn = conn.lock().unwrap();
+        let path = path.to_string_lossy().replace('\'', "''");
+        conn.execute(format!("VACUUM INTO '{}'", path))?;
+        Ok(())
+    }
+
+    /// Runs `setup` and then `sql` on the same pooled connection.
+    fn query_with_setup(&mut self, setup: &str, sql: String) -> Result<QueryResults, StoreError> {
+        let conn = self.get_connection();
+        let conn = conn.lock().unwrap();
+        conn.execute(setup)?;
+        query_connection(&conn, sql)
+    }
+}
+
+pub(crate) fn query_connection(conn: &Connection, sql: String) -> Result<QueryResults, StoreError> {
+    query_connection_with_params(conn, sql, &[])
+}
+
+/// Runs `sql` in a single transaction, rolling it back if any statement
+/// fails outside a savepoint (see `savepoints`).
+pub(crate) fn query_connection_in_transaction(
+    conn: &Connection,
+    sql: String,
+) -> Result<QueryResults, StoreError> {
+    conn.execute("BEGIN IMMEDIATE")?;
+    let results = savepoints::run(conn, sql).and_then(|results| {
+        conn.execute("COMMIT")?;
+        Ok(results)
+    });
+    if results.is_err() {
+        let _ = conn.execute("ROLLBACK");
     }
+    results
+}
+
+/// Runs `sql` with `params` bound to the placeholders of its first
+/// statement. With parameters, only the first statement runs.
+pub(crate) fn query_connection_with_params(
+    conn: &Connection,
+    sql: String,
+    params: &[Value],
+) -> Result<QueryResults, StoreError> {
+    rows::run(conn, sql, params)
 }
 
 #[derive(Derivative)]
@@ -158,13 +990,39 @@ where
     #[derivative(Debug = "ignore")]
     sqlite_connection: Arc<Mutex<SQLiteConnection>>,
     query_result_notifier: Arc<Mutex<ResultNotifier>>,
+    applied_idx: Arc<AtomicU64>,
+    apply_error_policy: ApplyErrorPolicy,
+    apply_statement_timeout: Option<Duration>,
+    apply_failures: Arc<Mutex<Vec<ApplyFailure>>>,
+    halt: Arc<Mutex<bool>>,
+    topics: Arc<Topics>,
+    config_watchers: Arc<ConfigWatchers>,
+    tenants: Arc<Tenants>,
+    learner_feed: Arc<Mutex<LearnerFeed>>,
+    preferred_leader: Arc<AtomicU64>,
+    paxos_progress: Arc<Mutex<PaxosProgress>>,
+    #[derivative(Debug = "ignore")]
+    logger: Logger,
 }
 
 impl<S: Snapshot<StoreCommand>> Store<S> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         store_id: u64,
         sqlite_connection: Arc<Mutex<SQLiteConnection>>,
         query_result_notifier: Arc<Mutex<ResultNotifier>>,
+        applied_idx: Arc<AtomicU64>,
+        apply_error_policy: ApplyErrorPolicy,
+        apply_statement_timeout: Option<Duration>,
+        apply_failures: Arc<Mutex<Vec<ApplyFailure>>>,
+        halt: Arc<Mutex<bool>>,
+        topics: Arc<Topics>,
+        config_watchers: Arc<ConfigWatchers>,
+        tenants: Arc<Tenants>,
+        learner_feed: Arc<Mutex<LearnerFeed>>,
+        preferred_leader: Arc<AtomicU64>,
+        paxos_progress: Arc<Mutex<PaxosProgress>>,
+        logger: Logger,
     ) -> Self {
         Self {
             store_id,
@@ -177,26 +1035,244 @@ impl<S: Snapshot<StoreCommand>> Store<S> {
             stopsign: None,
             sqlite_connection,
             query_result_notifier,
+            applied_idx,
+            apply_error_policy,
+            apply_statement_timeout,
+            apply_failures,
+            halt,
+            topics,
+            config_watchers,
+            tenants,
+            learner_feed,
+            preferred_leader,
+            paxos_progress,
+            logger,
         }
     }
 
-    pub fn apply_queries(&self, transition: StoreCommand) {
+    /// Publishes the promised ballot and the decided and accepted indexes
+    /// for stall reports.
+    fn record_progress(&self) {
+        *self.paxos_progress.lock().unwrap() = PaxosProgress {
+            promised: self.n_prom,
+            decided_idx: self.ld,
+            accepted_idx: self.log.len() as u64,
+        };
+    }
+
+    /// Applies a decided command, returning `false` if the replica must stop
+    /// applying entries because of the apply error policy.
+    pub fn apply_queries(&self, transition: StoreCommand) -> bool {
+        let started = Instant::now();
         let mut query_result_notifier = self.query_result_notifier.lock().unwrap();
         let mut sqlite_connection = self.sqlite_connection.lock().unwrap();
-        let results = sqlite_connection.query(transition.sql);
+        let missing = sqlite_connection
+            .missing_function(&transition.functions)
+            .map(|name| StoreError::MissingFunction(name.clone()))
+            .or_else(|| {
+                sqlite_connection
+                    .missing_collation(&transition.collations)
+                    .map(|name| StoreError::MissingCollation(name.clone()))
+            })
+            .or_else(|| {
+                (!sqlite_connection.fts5 && fulltext::creates_table(&transition.sql))
+                    .then_some(StoreError::FullTextSearchUnavailable)
+            });
+        if let Some(error) = missing {
+            // Applying without the function, collation or FTS5 would fail
+            // or sort differently here than on the replicas that have it,
+            // so stop instead of diverging.
+            warn!(
+                self.logger,
+                "Replica {} halting, command {} failed to apply: {}",
+                self.store_id,
+                transition.id,
+                error
+            );
+            *self.halt.lock().unwrap() = true;
+            query_result_notifier.remove_command_and_add_result(transition.id as u64, Err(error));
+            return false;
+        }
+        let results = match &transition.tenant {
+            None => self.apply_command(&mut sqlite_connection, &transition),
+            Some(tenant) => self.apply_tenant_command(&mut sqlite_connection, tenant, &transition),
+        };
+        if results.is_ok() && transition.tenant.is_none() {
+            sqlite_connection.touched.record(&transition.sql);
+        }
+        let applied_idx = self.applied_idx.fetch_add(1, Ordering::SeqCst);
+        self.learner_feed
+            .lock()
+            .unwrap()
+            .record(applied_idx + 1, &transition);
+        let results = results.map(|mut results| {
+            results.timing.apply = started.elapsed();
+            results.proof = Some(ReadProof {
+                ballot: self.acc_round,
+                decided_idx: applied_idx + 1,
+                quorum: vec![],
+            });
+            results
+        });
+        let keep_applying = match &results {
+            Ok(_) => true,
+            Err(e) => self.handle_apply_error(&transition, e, applied_idx),
+        };
         query_result_notifier.remove_command_and_add_result(transition.id as u64, results);
+        keep_applying
+    }
+
+    /// Applies `transition` to the database of `tenant`, skipping it if the
+    /// tenant moved to another cluster and capturing it if the tenant is
+    /// being migrated.
+    fn apply_tenant_command(
+        &self,
+        shared: &mut SQLiteConnection,
+        tenant: &str,
+        transition: &StoreCommand,
+    ) -> Result<QueryResults, StoreError> {
+        let route = shared.tenant_route(tenant)?;
+        if route.moved_to.is_some() {
+            return Ok(QueryResults::skipped());
+        }
+        let results = self
+            .tenants
+            .connection(tenant)
+            .and_then(|conn| self.apply_command(&mut conn.lock().unwrap(), transition))?;
+        if route.capturing && !statements::is_read(&transition.sql) {
+            let change = TenantChange {
+                applied_idx: self.applied_idx.load(Ordering::SeqCst) + 1,
+                sql: transition.sql.clone(),
+                params: transition.params.clone(),
+            };
+            shared.record_tenant_change(tenant, &change)?;
+        }
+        Ok(results)
+    }
+
+    /// Applies `transition` to `sqlite_connection`, the shared database or
+    /// the database of the command's tenant.
+    fn apply_command(
+        &self,
+        sqlite_connection: &mut SQLiteConnection,
+        transition: &StoreCommand,
+    ) -> Result<QueryResults, StoreError> {
+        match &transition.kind {
+            CommandKind::Statement => sqlite_connection.query(
+                transition.sql.clone(),
+                &transition.params,
+                &transition.pragmas,
+                self.apply_statement_timeout,
+            ),
+            CommandKind::Publish { topic, payload } => {
+                let index = self.applied_idx.load(Ordering::SeqCst) + 1;
+                self.topics.publish(topic, payload.clone(), index);
+                Ok(QueryResults::new(vec![]))
+            }
+            CommandKind::HardDelete { subject } => {
+                let tombstone = Tombstone {
+                    subject: subject.clone(),
+                    sql: transition.sql.clone(),
+                    applied_idx: self.applied_idx.load(Ordering::SeqCst) + 1,
+                };
+                sqlite_connection.hard_delete(&tombstone, self.apply_statement_timeout)
+            }
+            CommandKind::SetConfig { key, value } => {
+                let result = sqlite_connection.set_config(key, value, self.apply_statement_timeout);
+                if result.is_ok() && key == PREFERRED_LEADER_KEY {
+                    let preferred = value.parse().unwrap_or(0);
+                    self.preferred_leader.store(preferred, Ordering::SeqCst);
+                }
+                if result.is_ok() {
+                    self.config_watchers.notify(ConfigChange {
+                        key: key.clone(),
+                        value: value.clone(),
+                        index: self.applied_idx.load(Ordering::SeqCst) + 1,
+                    });
+                }
+                result
+            }
+            CommandKind::Transaction => sqlite_connection.transaction(
+                transition.sql.clone(),
+                &transition.pragmas,
+                self.apply_statement_timeout,
+            ),
+            CommandKind::Prepare => {
+                sqlite_connection.prepare(&transition.sql, self.apply_statement_timeout)
+            }
+            CommandKind::ExecutePrepared { statement } => sqlite_connection.execute_prepared(
+                *statement,
+                &transition.params,
+                self.apply_statement_timeout,
+            ),
+            CommandKind::Migration {
+                version,
+                name,
+                checksum,
+            } => sqlite_connection.migrate(
+                *version,
+                name,
+                *checksum,
+                &transition.sql,
+                self.apply_statement_timeout,
+            ),
+            CommandKind::Conditional { predicate } => sqlite_connection.query_if(
+                predicate.clone(),
+                transition.sql.clone(),
+                &transition.pragmas,
+                self.apply_statement_timeout,
+            ),
+        }
+    }
+
+    fn handle_apply_error(&self, cmd: &StoreCommand, error: &StoreError, applied_idx: u64) -> bool {
+        let action = match &self.apply_error_policy {
+            ApplyErrorPolicy::Halt => ApplyErrorAction::Halt,
+            ApplyErrorPolicy::SkipAndRecord => ApplyErrorAction::Skip,
+            ApplyErrorPolicy::Callback(decide) => decide(cmd, error),
+        };
+        match action {
+            ApplyErrorAction::Halt => {
+                warn!(
+                    self.logger,
+                    "Replica {} halting, command {} failed to apply: {}",
+                    self.store_id,
+                    cmd.id,
+                    error
+                );
+                *self.halt.lock().unwrap() = true;
+                false
+            }
+            ApplyErrorAction::Skip => {
+                warn!(
+                    self.logger,
+                    "Replica {} skipping command {}, failed to apply: {}",
+                    self.store_id,
+                    cmd.id,
+                    error
+                );
+                self.apply_failures.lock().unwrap().push(ApplyFailure {
+                    command: cmd.clone(),
+                    error: error.to_string(),
+                    applied_idx,
+                });
+                true
+            }
+        }
     }
 }
 
 impl<S: Snapshot<StoreCommand>> Storage<StoreCommand, S> for Store<S> {
     fn append_entry(&mut self, entry: StoreCommand) -> u64 {
         self.log.push(entry);
+        self.record_progress();
         self.get_log_len()
     }
 
     fn append_entries(&mut self, entries: Vec<StoreCommand>) -> u64 {
         let mut e = entries;
         self.log.append(&mut e);
+        self.record_progress();
         self.get_log_len()
     }
 
@@ -207,16 +1283,23 @@ impl<S: Snapshot<StoreCommand>> Storage<StoreCommand, S> for Store<S> {
 
     fn set_promise(&mut self, n_prom: Ballot) {
         self.n_prom = n_prom;
+        self.record_progress();
     }
 
     fn set_decided_idx(&mut self, ld: u64) {
         let decided_entries = self.get_entries(self.ld, ld);
 
-        decided_entries
-            .iter()
-            .for_each(|entry| self.apply_queries(entry.clone()));
+        let mut applied = ld;
+        for (i, entry) in decided_entries.iter().enumerate() {
+            if !self.apply_queries(entry.clone()) {
+                // Only the entries before the failed one count as applied.
+                applied = self.ld + i as u64;
+                break;
+            }
+        }
 
-        self.ld = ld;
+        self.ld = applied;
+        self.record_progress();
     }
 
     fn get_decided_idx(&self) -> u64 {
@@ -260,6 +1343,7 @@ impl<S: Snapshot<StoreCommand>> Storage<StoreCommand, S> for Store<S> {
 
     fn trim(&mut self, idx: u64) {
         self.log.drain(0..idx as usize);
+        self.record_progress();
     }
 
     fn set_compacted_idx(&mut self, idx: u64) {
@@ -283,6 +1367,7 @@ impl<S: Snapshot<StoreCommand>> Storage<StoreCommand, S> for Store<S> {
 #[derivative(Debug)]
 pub struct StoreServer<T: SequencePaxosStoreTransport + Send + Sync> {
     id: u64,
+    reconfiguration: Mutex<ReconfigurationManager>,
     transport: Arc<T>,
     next_cmd_id: AtomicU64,
     logger: Logger,
@@ -293,38 +1378,244 @@ pub struct StoreServer<T: SequencePaxosStoreTransport + Send + Sync> {
     sqlite_connection: Arc<Mutex<SQLiteConnection>>,
     query_result_notifier: Arc<Mutex<ResultNotifier>>,
     halt: Arc<Mutex<bool>>,
+    lifecycle: Mutex<Lifecycle>,
+    apply_failures: Arc<Mutex<Vec<ApplyFailure>>>,
+    topics: Arc<Topics>,
+    config_watchers: Arc<ConfigWatchers>,
+    config: StoreConfig,
+    applied_idx: Arc<AtomicU64>,
+    stall_detector: Mutex<StallDetector>,
+    events: Mutex<EventLog>,
+    peer_accepted: Mutex<HashMap<u64, u64>>,
+    next_snapshot_id: AtomicU64,
+    lanes: ReadLanes,
+    in_flight: AtomicUsize,
+    idle: tokio::sync::Notify,
+    statement_stats: StatementStatistics,
+    deprecated_calls: DeprecatedCalls,
+    retained_backups: Mutex<Vec<PathBuf>>,
+    purged_idx: AtomicU64,
+    functions: FunctionRegistry,
+    archives: Mutex<Vec<Arc<ConfigArchive>>>,
+    tenants: Arc<Tenants>,
+    learner_feed: Arc<Mutex<LearnerFeed>>,
+    lease: Mutex<LeaderLease>,
+    read_indexes: Mutex<PendingReadIndexes>,
+    checks: Mutex<PendingChecks>,
+    /// Ballot of the current leader.
+    leader_ballot: Mutex<Ballot>,
+    /// Publishes every leader elected, to `watch_leader` subscribers.
+    leader_changes: broadcast::Sender<LeaderStatus>,
+    /// Node the cluster prefers as leader, per the last applied
+    /// `PREFERRED_LEADER_KEY` setting; 0 for none.
+    preferred_leader: Arc<AtomicU64>,
+    /// Paxos state of the store, for stall reports.
+    paxos_progress: Arc<Mutex<PaxosProgress>>,
+    /// Priority of this node's ballots; see `StoreConfig::leader_priority`.
+    leader_priority: AtomicU64,
+    /// Set while this node hands its leadership over; new proposals wait
+    /// and leader election is paused on this node.
+    handing_over: AtomicBool,
+    batching: Mutex<AdaptiveBatching>,
+    /// Notified when a batching window fills up.
+    batch_full: Condvar,
+    cursors: Cursors,
+    advertiser: Option<LeaderAdvertiser>,
+    mailbox: PeerMailbox,
+    warmup: WarmupReport,
+    #[cfg(feature = "fault-injection")]
+    faults: Mutex<Option<FaultInjector>>,
 }
 
-const HEARTBEAT_DELAY: u64 = 100;
+/// Heartbeat round of leader election, in ticks.
+pub(crate) const HEARTBEAT_DELAY: u64 = 100;
+/// Milliseconds between ticks of leader election.
+pub(crate) const BLE_TICK: u64 = 50;
 const CONN_POOL_SIZE: usize = 20;
+const STALL_TIMEOUT: u64 = 10000;
+const ANALYTICAL_CONCURRENCY: usize = 2;
+const STREAM_BATCH_SIZE: usize = 1000;
+const ARCHIVE_RETENTION: u64 = 24 * 60 * 60;
+const TENANT_POOL_SIZE: usize = 2;
+const CURSOR_TTL: u64 = 60;
+const MAX_CURSORS: usize = 64;
+const LEARNER_FEED_CAPACITY: usize = 4096;
+const PEER_MAILBOX_CAPACITY: usize = 1024;
+const MAX_BATCH_SIZE: usize = 256;
+const PROPOSAL_RETRIES: usize = 3;
+/// Number of leader changes a `watch_leader` subscriber may fall behind
+/// before it is lagged.
+const LEADER_WATCH_CAPACITY: usize = 16;
+/// How long a read waits for its read index before falling back to a strong
+/// read.
+const READ_INDEX_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a page waits for the index of its token to be applied.
+const PAGE_TOKEN_WAIT: Duration = Duration::from_secs(2);
+
+fn sequence_paxos_config(id: u64, config_id: u32, peers: &[u64]) -> SequencePaxosConfig {
+    let mut sp_config = SequencePaxosConfig::default();
+    sp_config.set_configuration_id(config_id);
+    sp_config.set_pid(id);
+    sp_config.set_peers(peers.to_vec());
+    sp_config
+}
+
+fn ble_config(id: u64, peers: &[u64]) -> ble::BLEConfig {
+    let mut ble_config = ble::BLEConfig::default();
+    ble_config.set_pid(id);
+    ble_config.set_peers(peers.to_vec());
+    ble_config.set_hb_delay(HEARTBEAT_DELAY);
+    ble_config
+}
 
 impl<T: SequencePaxosStoreTransport + Send + Sync> StoreServer<T> {
     pub fn start(id: u64, peers: Vec<u64>, transport: T) -> Result<Self, StoreError> {
-        let config = StoreConfig {
-            conn_pool_size: CONN_POOL_SIZE,
-        };
-        let config_id = 1;
-
-        let mut sp_config = SequencePaxosConfig::default();
-        sp_config.set_configuration_id(config_id);
-        sp_config.set_pid(id);
-        sp_config.set_peers(peers.clone());
+        Self::start_with_config(id, peers, transport, StoreConfig::default())
+    }
 
-        let mut ble_config = ble::BLEConfig::default();
-        ble_config.set_pid(id);
-        ble_config.set_peers(peers);
-        ble_config.set_hb_delay(HEARTBEAT_DELAY);
+    pub fn start_with_config(
+        id: u64,
+        peers: Vec<u64>,
+        transport: T,
+        config: StoreConfig,
+    ) -> Result<Self, StoreError> {
+        // A lane without slots would never admit a read.
+        for (lane, concurrency) in [
+            ("transactional", config.transactional_concurrency),
+            ("analytical", config.analytical_concurrency),
+        ] {
+            if concurrency == 0 {
+                return Err(StoreError::InvalidRequest(format!(
+                    "{}_concurrency must be at least 1",
+                    lane
+                )));
+            }
+        }
+        membership::check_initial_membership(
+            &membership::membership_path(id),
+            id,
+            &peers,
+            config.override_membership,
+        )?;
+        let (config_id, applied) = match &config.join {
+            Some(join) => {
+                let mut members = peers.clone();
+                members.push(id);
+                members.sort_unstable();
+                if members != join.members {
+                    return Err(StoreError::MembershipMismatch {
+                        recorded: join.members.clone(),
+                        requested: members,
+                    });
+                }
+                (join.config_id, join.applied_idx)
+            }
+            None => (1, 0),
+        };
+        let sp_config = sequence_paxos_config(id, config_id, &peers);
+        let ble_config = ble_config(id, &peers);
 
         let logger = logger::create_logger();
-        let sqlite_connection = Arc::new(Mutex::new(SQLiteConnection::new(id, config)));
+        transport.set_logger(logger.clone());
+        let mut functions = config.functions.clone();
+        if let Some(secrets) = &config.secrets {
+            for def in encryption::encryption_functions(secrets.clone()) {
+                functions.register(def);
+            }
+        }
+        let sqlite_connection = Arc::new(Mutex::new(SQLiteConnection::new(
+            id,
+            config.conn_pool_size,
+            &functions,
+        )));
+        if config.full_text_search && !sqlite_connection.lock().unwrap().fts5 {
+            return Err(StoreError::FullTextSearchUnavailable);
+        }
+        let analytical_connection = Arc::new(Mutex::new(SQLiteConnection::new(
+            id,
+            config.analytical_pool_size,
+            &functions,
+        )));
+        let started = Instant::now();
+        let mut warmup = WarmupReport::default();
+        for connection in [&sqlite_connection, &analytical_connection] {
+            let connection = connection.lock().unwrap();
+            connection.warm_up(&config.warmup_queries, &mut warmup)?;
+        }
+        warmup.elapsed = started.elapsed();
+        if !config.warmup_queries.is_empty() {
+            info!(
+                logger,
+                "Replica {} warmed up in {:?}: {} queries run, {} statements compiled, {} failed",
+                id,
+                warmup.elapsed,
+                warmup.queries_run,
+                warmup.statements_compiled,
+                warmup.failed
+            );
+        }
+        let lanes = ReadLanes::new(
+            Lane::new(config.transactional_concurrency, sqlite_connection.clone()),
+            Lane::new(config.analytical_concurrency, analytical_connection),
+        );
         let query_result_notifier = Arc::new(Mutex::new(ResultNotifier::new()));
-        let store = Store::new(id, sqlite_connection.clone(), query_result_notifier.clone());
+        let applied_idx = Arc::new(AtomicU64::new(applied));
+        let apply_failures = Arc::new(Mutex::new(Vec::new()));
+        let halt = Arc::new(Mutex::new(false));
+        let topics = Arc::new(Topics::default());
+        let config_watchers = Arc::new(ConfigWatchers::default());
+        // Set by the replicated preferred leader setting, which the store
+        // applies and leader election reads.
+        let preferred_leader = Arc::new(AtomicU64::new(0));
+        let paxos_progress = Arc::new(Mutex::new(PaxosProgress::default()));
+        let learner_feed = Arc::new(Mutex::new(LearnerFeed::new(
+            config.learner_feed_capacity,
+            applied,
+        )));
+        let tenants = Arc::new(Tenants::new(
+            id,
+            config.tenant_dir.clone(),
+            config.tenant_pool_size,
+            functions.clone(),
+        ));
+        let store = Store::new(
+            id,
+            sqlite_connection.clone(),
+            query_result_notifier.clone(),
+            applied_idx.clone(),
+            config.apply_error_policy.clone(),
+            config.apply_statement_timeout,
+            apply_failures.clone(),
+            halt.clone(),
+            topics.clone(),
+            config_watchers.clone(),
+            tenants.clone(),
+            learner_feed.clone(),
+            preferred_leader.clone(),
+            paxos_progress.clone(),
+            logger.clone(),
+        );
         let seq_paxos = Arc::new(Mutex::new(SequencePaxos::with(sp_config, store)));
         let ble = Arc::new(Mutex::new(ble::BallotLeaderElection::with(ble_config)));
-        let halt = Arc::new(Mutex::new(false));
+        let stall_detector = Mutex::new(StallDetector::new(config.stall_timeout));
+        let lease = Mutex::new(LeaderLease::new(config.leader_lease));
+        let batching = Mutex::new(AdaptiveBatching::new(
+            config.latency_slo.clone(),
+            config.max_batch_size,
+        ));
+        let cursors = Cursors::new(config.cursor_ttl, config.max_cursors);
+        let leader_priority = AtomicU64::new(config.leader_priority);
+        let mailbox = PeerMailbox::new(config.peer_mailbox_capacity);
+        let advertiser = config
+            .leader_registry
+            .clone()
+            .map(|registry| LeaderAdvertiser::start(registry, logger.clone()));
+        #[cfg(feature = "fault-injection")]
+        let faults = Mutex::new(config.fault_injection.clone().map(FaultInjector::new));
 
         Ok(StoreServer {
             id,
+            reconfiguration: Mutex::new(ReconfigurationManager::new(id, config_id, peers)),
             transport: Arc::new(transport),
             next_cmd_id: AtomicU64::new(1),
             logger,
@@ -333,6 +1624,44 @@ impl<T: SequencePaxosStoreTransport + Send + Sync> StoreServer<T> {
             sqlite_connection,
             query_result_notifier,
             halt,
+            lifecycle: Mutex::new(Lifecycle::Initializing),
+            apply_failures,
+            topics,
+            config_watchers,
+            config,
+            applied_idx,
+            stall_detector,
+            events: Mutex::new(EventLog::default()),
+            peer_accepted: Mutex::new(HashMap::new()),
+            next_snapshot_id: AtomicU64::new(0),
+            lanes,
+            in_flight: AtomicUsize::new(0),
+            idle: tokio::sync::Notify::new(),
+            statement_stats: StatementStatistics::default(),
+            deprecated_calls: DeprecatedCalls::default(),
+            retained_backups: Mutex::new(Vec::new()),
+            purged_idx: AtomicU64::new(0),
+            functions,
+            archives: Mutex::new(Vec::new()),
+            tenants,
+            learner_feed,
+            lease,
+            read_indexes: Mutex::new(PendingReadIndexes::default()),
+            checks: Mutex::new(PendingChecks::default()),
+            leader_ballot: Mutex::new(Ballot::default()),
+            leader_changes: broadcast::channel(LEADER_WATCH_CAPACITY).0,
+            preferred_leader,
+            paxos_progress,
+            leader_priority,
+            handing_over: AtomicBool::new(false),
+            batching,
+            batch_full: Condvar::new(),
+            cursors,
+            advertiser,
+            mailbox,
+            warmup,
+            #[cfg(feature = "fault-injection")]
+            faults,
         })
     }
 
@@ -341,13 +1670,25 @@ impl<T: SequencePaxosStoreTransport + Send + Sync> StoreServer<T> {
             self.logger,
             "Replica {} starting message event loop", self.id
         );
+        self.set_lifecycle(Lifecycle::Ready);
         loop {
-            sleep(Duration::from_millis(1));
+            // Everything produced during the window is sent and applied
+            // together.
+            let batching = self.batching.lock().unwrap();
+            let window = batching.window();
+            let (mut batching, _) = self
+                .batch_full
+                .wait_timeout_while(batching, window, |batching| !batching.is_full())
+                .unwrap();
+            batching.end_window();
+            drop(batching);
 
             if *self.halt.lock().unwrap() {
                 break;
             }
 
+            self.warm_page_cache();
+
             let mut seq_paxos = self.seq_paxos.lock().unwrap();
             let mut ble = self.ble.lock().unwrap();
 
@@ -358,24 +1699,604 @@ impl<T: SequencePaxosStoreTransport + Send + Sync> StoreServer<T> {
             for out_ble_msg in ble.get_outgoing_msgs() {
                 self.transport.send_ble_message(out_ble_msg);
             }
+
+            // The final messages of the old configuration are sent above.
+            if let Some(stopsign) = seq_paxos.is_reconfigured() {
+                let (transition, finished) = {
+                    let mut reconfiguration = self.reconfiguration.lock().unwrap();
+                    let finished = (
+                        reconfiguration.config_id(),
+                        reconfiguration.peers().to_vec(),
+                    );
+                    (reconfiguration.on_decided(&stopsign), finished)
+                };
+                if let Some(transition) = transition {
+                    let (config_id, peers) = finished;
+                    self.archive_configuration(config_id, peers);
+                    self.complete_reconfiguration(transition, &mut seq_paxos, &mut ble);
+                }
+            }
         }
     }
 
-    pub fn start_ble_event_loop(&self) {
-        info!(self.logger, "Replica {} starting ble event loop", self.id);
-        loop {
-            sleep(Duration::from_millis(50));
-
+    /// Warms the page cache of the idle pooled connections for the tables
+    /// written since the last pass, if `StoreConfig::warm_after_apply` is
+    /// set.
+    fn warm_page_cache(&self) {
+        let (tables, conns) = {
+            let mut sqlite_connection = self.sqlite_connection.lock().unwrap();
+            let tables = sqlite_connection.touched.take();
+            if !self.config.warm_after_apply || tables.is_empty() {
+                return;
+            }
+            (tables, sqlite_connection.conn_pool.clone())
+        };
+        for conn in conns {
+            // A connection in use is already serving a read.
+            if let Ok(conn) = conn.try_lock() {
+                warmup::warm(&conn, &tables);
+            }
+        }
+    }
+
+    /// Proposes moving the cluster to a configuration of `nodes`.
+    ///
+    /// Once the stop sign is decided, the members of the new configuration
+    /// continue in it on their existing SQLite state and the nodes left out
+    /// retire. Commands can not be proposed in between.
+    pub fn reconfigure(&self, nodes: Vec<u64>) -> Result<(), StoreError> {
+        self.reconfigure_with_metadata(nodes, None)
+    }
+
+    /// Like `reconfigure`, with `metadata` carried by the stop sign to every
+    /// member of the new configuration; see `config_metadata`.
+    pub fn reconfigure_with_metadata(
+        &self,
+        nodes: Vec<u64>,
+        metadata: Option<Vec<u8>>,
+    ) -> Result<(), StoreError> {
+        let mut seq_paxos = self.seq_paxos.lock().unwrap();
+        seq_paxos
+            .reconfigure(ReconfigurationRequest::with(nodes, metadata))
+            .map_err(|_| StoreError::ConfigurationStopped(self.config_id()))
+    }
+
+    /// Returns the metadata of the stop sign that started the configuration
+    /// this node is in, if it moved to it since it started.
+    pub fn config_metadata(&self) -> Option<Vec<u8>> {
+        self.reconfiguration
+            .lock()
+            .unwrap()
+            .metadata()
+            .map(<[u8]>::to_vec)
+    }
+
+    /// Returns the database node `node`, which the reconfiguration that
+    /// started this configuration added, starts from: this node's archive
+    /// of the previous configuration. See `bootstrap`.
+    pub fn join_snapshot(&self, node: u64) -> Result<JoinSnapshot, StoreError> {
+        let (config_id, mut members) = {
+            let reconfiguration = self.reconfiguration.lock().unwrap();
+            (
+                reconfiguration.config_id(),
+                reconfiguration.peers().to_vec(),
+            )
+        };
+        if !members.contains(&node) {
+            return Err(StoreError::InvalidRequest(format!(
+                "node {} is not a member of configuration {}",
+                node, config_id
+            )));
+        }
+        members.push(self.id);
+        members.sort_unstable();
+        let archive = self.archive(config_id - 1).ok_or_else(|| {
+            StoreError::InvalidRequest(format!(
+                "no archive of configuration {} to bootstrap node {} from",
+                config_id - 1,
+                node
+            ))
+        })?;
+        Ok(JoinSnapshot {
+            config_id,
+            members,
+            applied_idx: archive.applied_idx(),
+            database: std::fs::read(archive.path())?,
+        })
+    }
+
+    /// Returns up to `max` of the commands this node applied after
+    /// `after_idx`, for a learner to apply; see `learner`.
+    pub fn learner_entries(&self, after_idx: u64, max: usize) -> Result<LearnerBatch, StoreError> {
+        self.learner_feed
+            .lock()
+            .unwrap()
+            .entries_after(after_idx, max)
+    }
+
+    /// Returns the id of the configuration this node is in.
+    pub fn config_id(&self) -> u32 {
+        self.reconfiguration.lock().unwrap().config_id()
+    }
+
+    /// Returns the archives of finished configurations this node keeps.
+    pub fn archives(&self) -> Vec<Arc<ConfigArchive>> {
+        self.archives.lock().unwrap().clone()
+    }
+
+    /// Returns the archive of configuration `config_id`, if this node was a
+    /// member of it and the archive has not expired.
+    pub fn archive(&self, config_id: u32) -> Option<Arc<ConfigArchive>> {
+        self.archives
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|archive| archive.config_id() == config_id)
+            .cloned()
+    }
+
+    /// Copies the database as of the decided stop sign of configuration
+    /// `config_id` into an archive, if archives are retained.
+    fn archive_configuration(&self, config_id: u32, peers: Vec<u64>) {
+        let retention = match self.config.archive_retention {
+            Some(retention) => retention,
+            None => return,
+        };
+        let mut members = peers;
+        members.push(self.id);
+        members.sort_unstable();
+        let path = self
+            .config
+            .snapshot_dir
+            .join(format!("node{}-config{}.db", self.id, config_id));
+        let archived = std::fs::create_dir_all(&self.config.snapshot_dir)
+            .map_err(StoreError::from)
+            .and_then(|()| self.copy_database(&path))
+            .and_then(|applied_idx| ReadSnapshot::open(path, applied_idx));
+        match archived {
+            Ok(snapshot) => {
+                let archive = ConfigArchive::new(config_id, members, retention, snapshot);
+                self.archives.lock().unwrap().push(Arc::new(archive));
+                self.record_event(format!("archived configuration {}", config_id));
+            }
+            Err(e) => warn!(
+                self.logger,
+                "Replica {} failed to archive configuration {}: {}", self.id, config_id, e
+            ),
+        }
+    }
+
+    /// Drops the archives past their retention.
+    fn expire_archives(&self) {
+        self.archives
+            .lock()
+            .unwrap()
+            .retain(|archive| !archive.is_expired());
+    }
+
+    fn complete_reconfiguration(
+        &self,
+        transition: Transition,
+        seq_paxos: &mut SequencePaxos<StoreCommand, (), Store<()>>,
+        ble: &mut ble::BallotLeaderElection,
+    ) {
+        match transition {
+            Transition::Continue { config_id, peers } => {
+                info!(
+                    self.logger,
+                    "Replica {} moving to configuration {} with peers {:?}",
+                    self.id,
+                    config_id,
+                    peers
+                );
+                if let Err(e) = membership::check_initial_membership(
+                    &membership::membership_path(self.id),
+                    self.id,
+                    &peers,
+                    true,
+                ) {
+                    warn!(
+                        self.logger,
+                        "Replica {} failed to record membership: {}", self.id, e
+                    );
+                }
+                let mut members = peers.clone();
+                members.push(self.id);
+                members.sort_unstable();
+                self.learner_feed.lock().unwrap().start_configuration(
+                    config_id,
+                    members,
+                    self.applied_idx.load(Ordering::SeqCst),
+                );
+                // The new log starts empty; the applied index keeps counting
+                // from where the old configuration left it.
+                let store = Store::new(
+                    self.id,
+                    self.sqlite_connection.clone(),
+                    self.query_result_notifier.clone(),
+                    self.applied_idx.clone(),
+                    self.config.apply_error_policy.clone(),
+                    self.config.apply_statement_timeout,
+                    self.apply_failures.clone(),
+                    self.halt.clone(),
+                    self.topics.clone(),
+                    self.config_watchers.clone(),
+                    self.tenants.clone(),
+                    self.learner_feed.clone(),
+                    self.preferred_leader.clone(),
+                    self.paxos_progress.clone(),
+                    self.logger.clone(),
+                );
+                *seq_paxos =
+                    SequencePaxos::with(sequence_paxos_config(self.id, config_id, &peers), store);
+                *ble = ble::BallotLeaderElection::with(ble_config(self.id, &peers));
+                self.lease.lock().unwrap().reset();
+                self.peer_accepted
+                    .lock()
+                    .unwrap()
+                    .retain(|peer, _| peers.contains(peer));
+                self.record_event(format!("moved to configuration {}", config_id));
+            }
+            Transition::Retire { config_id } => {
+                info!(
+                    self.logger,
+                    "Replica {} retiring, not a member of configuration {}", self.id, config_id
+                );
+                self.record_event(format!("retired at configuration {}", config_id));
+                *self.halt.lock().unwrap() = true;
+            }
+        }
+    }
+
+    pub fn start_ble_event_loop(&self) {
+        info!(self.logger, "Replica {} starting ble event loop", self.id);
+        loop {
+            sleep(Duration::from_millis(BLE_TICK));
+
             if *self.halt.lock().unwrap() {
                 break;
             }
 
-            let mut seq_paxos = self.seq_paxos.lock().unwrap();
-            let mut ble = self.ble.lock().unwrap();
+            if !self.handing_over.load(Ordering::SeqCst) {
+                let mut seq_paxos = self.seq_paxos.lock().unwrap();
+                let mut ble = self.ble.lock().unwrap();
+
+                ble.set_priority(self.ble_priority());
+                if let Some(leader) = ble.tick() {
+                    self.record_event(format!("leader changed to {}", leader.pid));
+                    self.lease.lock().unwrap().reset();
+                    *self.leader_ballot.lock().unwrap() = leader;
+                    seq_paxos.handle_leader(leader);
+                    self.advertise_leader(leader);
+                    let _ = self.leader_changes.send(LeaderStatus {
+                        id: leader.pid,
+                        ballot: leader,
+                        addr: self.transport.node_addr(leader.pid),
+                    });
+                }
+            }
+
+            self.check_for_stall();
+            self.expire_archives();
+            self.cursors.expire();
+            self.adjust_batching();
+        }
+    }
+
+    /// Priority of this node's ballots, which breaks ties between ballots
+    /// of the same round in leader election. A preferred leader outranks
+    /// every configured priority.
+    fn ble_priority(&self) -> u64 {
+        match self.preferred_leader.load(Ordering::SeqCst) == self.id {
+            true => u64::MAX,
+            false => self
+                .leader_priority
+                .load(Ordering::SeqCst)
+                .min(u64::MAX - 1),
+        }
+    }
+
+    /// Sets the priority of this node's ballots in leader election; see
+    /// `StoreConfig::leader_priority`. Takes effect at the next election
+    /// tick, and on the leader at the next heartbeat round.
+    pub fn set_leader_priority(&self, priority: u64) {
+        self.leader_priority.store(priority, Ordering::SeqCst);
+        self.record_event(format!("leader priority set to {}", priority));
+    }
+
+    /// Returns the priority of this node's ballots in leader election.
+    pub fn leader_priority(&self) -> u64 {
+        self.leader_priority.load(Ordering::SeqCst)
+    }
+
+    /// Publishes this node to the leader registry if it became the leader.
+    fn advertise_leader(&self, leader: Ballot) {
+        if let (Some(advertiser), true) = (&self.advertiser, leader.pid == self.id) {
+            advertiser.advertise(LeaderInfo {
+                id: leader.pid,
+                term: leader.n,
+            });
+        }
+    }
+
+    /// Returns where the server is in its lifecycle.
+    pub fn lifecycle(&self) -> Lifecycle {
+        if *self.halt.lock().unwrap() {
+            return Lifecycle::ShuttingDown;
+        }
+        *self.lifecycle.lock().unwrap()
+    }
+
+    /// Returns the commands skipped because they failed to apply.
+    pub fn apply_failures(&self) -> Vec<ApplyFailure> {
+        self.apply_failures.lock().unwrap().clone()
+    }
+
+    fn set_lifecycle(&self, next: Lifecycle) {
+        let mut lifecycle = self.lifecycle.lock().unwrap();
+        // A server that started shutting down never becomes ready again.
+        if *lifecycle != Lifecycle::ShuttingDown {
+            *lifecycle = next;
+        }
+    }
+
+    /// Admits a client request if the server is ready, tracking it as in
+    /// flight until the returned guard is dropped.
+    pub fn begin_request(&self) -> Option<InFlight<'_>> {
+        // Count the request before checking the lifecycle, so that `drain`
+        // either sees it or it sees the server draining.
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight {
+            in_flight: &self.in_flight,
+            idle: &self.idle,
+        };
+        match self.lifecycle() {
+            Lifecycle::Ready => Some(guard),
+            _ => None,
+        }
+    }
+
+    /// Replaces the fault injection of `StoreConfig::fault_injection`;
+    /// `None` stops injecting faults.
+    #[cfg(feature = "fault-injection")]
+    pub fn set_fault_injection(&self, faults: Option<FaultInjection>) {
+        *self.faults.lock().unwrap() = faults.map(FaultInjector::new);
+    }
+
+    /// Returns the fault to fail the next admitted client request with, if
+    /// any.
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn inject_fault(&self) -> Option<Fault> {
+        self.faults
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(FaultInjector::draw)
+    }
+
+    /// Returns the number of client requests in flight.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Puts the server in lame-duck mode and waits up to `deadline` for the
+    /// client requests in flight to finish.
+    ///
+    /// The server keeps replicating while draining. Returns true if every
+    /// request finished before the deadline.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        info!(self.logger, "Replica {} draining", self.id);
+        self.record_event("draining");
+        self.set_lifecycle(Lifecycle::Draining);
+        let drained = async {
+            loop {
+                let idle = self.idle.notified();
+                if self.in_flight() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        };
+        let drained = tokio::time::timeout(deadline, drained).await.is_ok();
+        if !drained {
+            warn!(
+                self.logger,
+                "Replica {} drain deadline passed with {} requests in flight",
+                self.id,
+                self.in_flight()
+            );
+        }
+        drained
+    }
+
+    /// Hands the leadership of the cluster over to member `target`, for
+    /// restarting the leader without waiting for an election timeout.
+    ///
+    /// Replicates `target` as the preferred leader, which wins ties between
+    /// ballots of the same round, and holds new proposals on this node
+    /// until the handover ends. Once `target` accepted the log, this node
+    /// steps down by pausing leader election, so that the others elect
+    /// `target`, and resumes it when `target` leads or `timeout` passed.
+    /// The preference is kept until the next handover or restart.
+    pub async fn transfer_leadership(
+        &self,
+        target: u64,
+        timeout: Duration,
+    ) -> Result<(), StoreError> {
+        if self.get_cluster_leader() != self.id {
+            return Err(StoreError::NotLeader);
+        }
+        if target == self.id {
+            return Ok(());
+        }
+        if !self
+            .reconfiguration
+            .lock()
+            .unwrap()
+            .peers()
+            .contains(&target)
+        {
+            return Err(StoreError::InvalidRequest(format!(
+                "node {} is not a member of the configuration",
+                target
+            )));
+        }
+        let deadline = Instant::now() + timeout;
+        let kind = CommandKind::SetConfig {
+            key: PREFERRED_LEADER_KEY.to_string(),
+            value: target.to_string(),
+        };
+        let results = self
+            .replicate(self.new_command(String::new(), kind))
+            .await?;
+        let idx = results.proof.map(|proof| proof.decided_idx).unwrap_or(0);
+
+        info!(
+            self.logger,
+            "Replica {} handing leadership over to {}", self.id, target
+        );
+        self.record_event(format!("handing leadership over to {}", target));
+        self.handing_over.store(true, Ordering::SeqCst);
+        let handed_over = self.hand_over(target, idx, deadline).await;
+        self.handing_over.store(false, Ordering::SeqCst);
+        if !handed_over {
+            return Err(StoreError::HandoverTimedOut(target));
+        }
+        // Resumed leader election learns of `target` within a heartbeat round.
+        let elected = || self.leader_ballot.lock().unwrap().pid == target;
+        while !elected() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(BLE_TICK)).await;
+        }
+        match elected() {
+            true => Ok(()),
+            false => Err(StoreError::HandoverTimedOut(target)),
+        }
+    }
+
+    /// Waits until `target` accepted the log up to `idx`, then until it was
+    /// elected or two heartbeat rounds passed. Returns false if `deadline`
+    /// passed first.
+    async fn hand_over(&self, target: u64, idx: u64, deadline: Instant) -> bool {
+        while !self.accepted_through(idx).contains(&target) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(BLE_TICK)).await;
+        }
+        // Peers declare this node failed after a heartbeat round without
+        // replies, and elect `target` in the round after.
+        let step_down = Instant::now() + Duration::from_millis(2 * HEARTBEAT_DELAY * BLE_TICK);
+        while self.get_cluster_leader() != target && Instant::now() < step_down.min(deadline) {
+            tokio::time::sleep(Duration::from_millis(BLE_TICK)).await;
+        }
+        Instant::now() < deadline
+    }
+
+    /// Returns the node id of this replica.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
 
-            if let Some(leader) = ble.tick() {
-                seq_paxos.handle_leader(leader);
+    /// Returns the replica's logger.
+    pub fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    /// Returns the transport the replica talks to its peers through.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the number of log entries applied by this replica.
+    pub fn applied_idx(&self) -> u64 {
+        self.applied_idx.load(Ordering::SeqCst)
+    }
+
+    /// Returns the batching window and recent commit latencies.
+    pub fn batching(&self) -> BatchingStatus {
+        self.batching.lock().unwrap().status()
+    }
+
+    /// Replaces the latency SLO of `StoreConfig::latency_slo`; `None` keeps
+    /// the shortest batching window.
+    pub fn set_latency_slo(&self, slo: Option<LatencySlo>) {
+        self.batching.lock().unwrap().set_slo(slo);
+    }
+
+    /// Adapts the batching window to the latency SLO, if one is set.
+    fn adjust_batching(&self) {
+        let mut batching = self.batching.lock().unwrap();
+        if batching.adjust() {
+            debug!(
+                self.logger,
+                "Replica {} batching window now {:?}",
+                self.id,
+                batching.window()
+            );
+        }
+    }
+
+    fn record_event<S: AsRef<str>>(&self, event: S) {
+        self.events.lock().unwrap().record(event);
+    }
+
+    fn check_for_stall(&self) {
+        let applied_idx = self.applied_idx();
+        let pending_commands = self
+            .query_result_notifier
+            .lock()
+            .unwrap()
+            .pending_commands();
+        let stalled_for = {
+            let mut stall_detector = self.stall_detector.lock().unwrap();
+            match stall_detector.observe(applied_idx, pending_commands.len()) {
+                Some(stalled_for) => stalled_for,
+                None => return,
             }
+        };
+
+        let mut peer_accepted: Vec<(u64, u64)> = self
+            .peer_accepted
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer, la)| (*peer, *la))
+            .collect();
+        peer_accepted.sort_unstable();
+        let paxos = *self.paxos_progress.lock().unwrap();
+        let mut report = StallReport {
+            node_id: self.id,
+            detected_at: SystemTime::now(),
+            stalled_for,
+            applied_idx,
+            ballot: paxos.promised,
+            decided_idx: paxos.decided_idx,
+            accepted_idx: paxos.accepted_idx,
+            leader: self.get_cluster_leader(),
+            pending_commands,
+            peer_accepted,
+            recent_events: self.events.lock().unwrap().recent(),
+            bundle_path: None,
+        };
+        match report.write_bundle(&self.config.diagnostics_dir) {
+            Ok(path) => warn!(
+                self.logger,
+                "Replica {} write stall at applied index {}, diagnostics written to {}",
+                self.id,
+                applied_idx,
+                path.display()
+            ),
+            Err(e) => warn!(
+                self.logger,
+                "Replica {} write stall at applied index {}, failed to write diagnostics: {}",
+                self.id,
+                applied_idx,
+                e
+            ),
+        }
+        self.record_event(format!("write stall at applied index {}", applied_idx));
+        if let Some(on_stall) = &self.config.on_stall {
+            on_stall(&report);
         }
     }
 
@@ -384,73 +2305,1332 @@ impl<T: SequencePaxosStoreTransport + Send + Sync> StoreServer<T> {
         seq_paxos.get_current_leader()
     }
 
+    /// Subscribes to the leaders this replica sees elected from now on, for
+    /// re-routing writes or updating service discovery. Use
+    /// `current_leader` for the leader at the time of subscribing.
+    pub fn watch_leader(&self) -> broadcast::Receiver<LeaderStatus> {
+        self.leader_changes.subscribe()
+    }
+
+    /// Returns the leader to forward `stmt` to, if it is a strong write,
+    /// write forwarding is enabled and another node leads the cluster.
+    pub(crate) fn forward_target(&self, stmt: &str, consistency: &Consistency) -> Option<u64> {
+        if !self.config.forward_writes
+            || matches!(consistency, Consistency::RelaxedReads)
+            || !statements::is_write(stmt)
+        {
+            return None;
+        }
+        let leader = self.get_cluster_leader();
+        Some(leader).filter(|leader| *leader != 0 && *leader != self.id)
+    }
+
+    /// Returns the leader this replica last saw elected, or `None` if it
+    /// has seen none yet.
+    pub fn current_leader(&self) -> Option<LeaderStatus> {
+        let ballot = *self.leader_ballot.lock().unwrap();
+        if ballot.pid == 0 {
+            return None;
+        }
+        Some(LeaderStatus {
+            id: ballot.pid,
+            ballot,
+            addr: self.transport.node_addr(ballot.pid),
+        })
+    }
+
     pub fn halt(&self, val: bool) {
         info!(self.logger, "Replica {} halting", self.id);
+        self.record_event("halting");
+        if val {
+            self.set_lifecycle(Lifecycle::ShuttingDown);
+        }
         let mut halt = self.halt.lock().unwrap();
         *halt = val
     }
 
+    /// Executes `stmt`. Strong queries are retried across leader changes;
+    /// see `StoreConfig::proposal_retries`.
     pub async fn query<S: AsRef<str>>(
         &self,
         stmt: S,
         consistency: Consistency,
     ) -> Result<QueryResults, StoreError> {
-        let consistency = if is_read_statement(stmt.as_ref()) {
-            consistency
+        self.query_in_lane(stmt, consistency, QueryLane::Transactional)
+            .await
+    }
+
+    /// Executes `stmt`, admitting relaxed reads through `lane`.
+    ///
+    /// Strong queries are ordered by the replicated log and ignore the lane.
+    pub async fn query_in_lane<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        consistency: Consistency,
+        lane: QueryLane,
+    ) -> Result<QueryResults, StoreError> {
+        self.query_with_params(stmt, vec![], consistency, lane)
+            .await
+    }
+
+    /// Executes `stmt` with `params` bound to its placeholders (`?`, `?NNN`,
+    /// `:name`, in order), admitting relaxed reads through `lane`.
+    ///
+    /// The parameters are replicated with the statement, so values never
+    /// need to be interpolated into the SQL.
+    pub async fn query_with_params<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        params: Vec<Value>,
+        consistency: Consistency,
+        lane: QueryLane,
+    ) -> Result<QueryResults, StoreError> {
+        check_relaxed_write(stmt.as_ref(), &consistency)?;
+        if statements::is_read(stmt.as_ref())
+            && introspection::references_introspection(stmt.as_ref())
+        {
+            return self.query_introspection(stmt.as_ref().to_string());
+        }
+
+        let (consistency, proof) = if statements::is_read(stmt.as_ref()) {
+            let consistency = self.resolve_lease(consistency).await;
+            let consistency = self.resolve_read_index(consistency).await;
+            self.resolve_leader_read(consistency).await
         } else {
-            Consistency::Strong
+            (Consistency::Strong, None)
         };
 
+        let started = Instant::now();
         let results = match consistency {
-            Consistency::Strong => {
-                let (notify, id) = {
-                    let mut query_result_notifier = self.query_result_notifier.lock().unwrap();
-                    let id = self.next_cmd_id.fetch_add(1, Ordering::SeqCst);
-                    let cmd = StoreCommand {
-                        id: id as usize,
-                        sql: stmt.as_ref().to_string(),
-                    };
-                    let notify = Arc::new(Notify::new());
-                    query_result_notifier.add_command(id, notify.clone());
-
-                    let mut seq_paxos = self.seq_paxos.lock().unwrap();
-                    seq_paxos.append(cmd).unwrap();
-                    (notify, id)
-                };
+            Consistency::Strong | Consistency::LeaderLease | Consistency::ReadIndex => {
+                let mut cmd = self.new_command(stmt.as_ref().to_string(), CommandKind::Statement);
+                cmd.params = params;
+                self.replicate(cmd).await?
+            }
 
-                //TODO add a timeout as the entry could be lost
-                notify.notified().await;
+            Consistency::RelaxedReads => {
+                let lane = self.lanes.get(lane);
+                let mut results = lane.query(stmt.as_ref().to_string(), params).await?;
+                results.proof = proof;
+                results
+            }
+        };
+        self.statement_stats
+            .record(stmt.as_ref(), started.elapsed(), results.rows.len());
 
-                let results = self
-                    .query_result_notifier
-                    .lock()
-                    .unwrap()
-                    .results
-                    .remove(&id)
-                    .unwrap();
-                results?
+        Ok(results)
+    }
+
+    /// Resolves a `Consistency::LeaderLease` read into a local read if this
+    /// node is the leader and holds the lease, and into a strong read
+    /// otherwise. A new leader first establishes its lease with a barrier.
+    async fn resolve_lease(&self, consistency: Consistency) -> Consistency {
+        if !matches!(consistency, Consistency::LeaderLease) {
+            return consistency;
+        }
+        let epoch = match self.lease_epoch() {
+            Some((_, true)) => return Consistency::RelaxedReads,
+            Some((epoch, false)) => epoch,
+            None => return Consistency::Strong,
+        };
+        if self.barrier().await.is_err() {
+            return Consistency::Strong;
+        }
+        self.lease.lock().unwrap().establish(epoch);
+        match self.lease_epoch() {
+            Some((_, true)) => Consistency::RelaxedReads,
+            _ => Consistency::Strong,
+        }
+    }
+
+    /// Resolves a `Consistency::ReadIndex` read, or a `Consistency::Strong`
+    /// read on a follower if `StoreConfig::follower_reads` is set, into a
+    /// local read once this replica applied the read index, and into a
+    /// strong read if it gets none or does not apply it in time.
+    async fn resolve_read_index(&self, consistency: Consistency) -> Consistency {
+        let follower_read = matches!(consistency, Consistency::Strong)
+            && self.config.follower_reads
+            && !matches!(self.get_cluster_leader(), 0)
+            && self.get_cluster_leader() != self.id;
+        if !matches!(consistency, Consistency::ReadIndex) && !follower_read {
+            return consistency;
+        }
+        match self.read_index().await {
+            Some(idx) if self.wait_applied(idx, READ_INDEX_TIMEOUT).await => {
+                Consistency::RelaxedReads
+            }
+            _ => Consistency::Strong,
+        }
+    }
+
+    /// Returns an applied index covering every entry decided so far, as
+    /// confirmed by the leader, or `None` if there is no leader or it does
+    /// not answer in time.
+    pub async fn read_index(&self) -> Option<u64> {
+        let leader = self.get_cluster_leader();
+        if leader == self.id {
+            return self.confirm_read_index().await;
+        }
+        if leader == 0 {
+            return None;
+        }
+        let (id, reply) = self.read_indexes.lock().unwrap().register();
+        self.transport.send_read_index_message(ReadIndexMessage {
+            from: self.id,
+            to: leader,
+            msg: ReadIndexMsg::Request { id },
+        });
+        match tokio::time::timeout(READ_INDEX_TIMEOUT, reply).await {
+            Ok(index) => index.ok().flatten(),
+            Err(_) => {
+                self.read_indexes.lock().unwrap().cancel(id);
+                None
+            }
+        }
+    }
+
+    /// Confirms that this node leads, with its lease or else a round of
+    /// leadership checks, and returns its applied index.
+    async fn confirm_read_index(&self) -> Option<u64> {
+        match self.resolve_lease(Consistency::LeaderLease).await {
+            Consistency::RelaxedReads => Some(self.applied_idx()),
+            _ => self.confirm_leadership().await.map(|_| self.applied_idx()),
+        }
+    }
+
+    /// Resolves a `Consistency::Strong` read on the leader into a local read
+    /// once a round of leadership checks confirms that it leads, with the
+    /// proof of where it was linearized, if `StoreConfig::leader_reads` is
+    /// set.
+    async fn resolve_leader_read(
+        &self,
+        consistency: Consistency,
+    ) -> (Consistency, Option<ReadProof>) {
+        if !matches!(consistency, Consistency::Strong) || !self.config.leader_reads {
+            return (consistency, None);
+        }
+        let quorum = match self.confirm_leadership().await {
+            Some(quorum) => quorum,
+            None => return (consistency, None),
+        };
+        let proof = ReadProof {
+            ballot: *self.leader_ballot.lock().unwrap(),
+            decided_idx: self.applied_idx(),
+            quorum,
+        };
+        (Consistency::RelaxedReads, Some(proof))
+    }
+
+    /// Confirms that this node leads with a round of leadership checks
+    /// answered by a majority of the cluster, returning the nodes that
+    /// follow it, itself included. A new leader first commits a barrier.
+    async fn confirm_leadership(&self) -> Option<Vec<u64>> {
+        if self.get_cluster_leader() != self.id {
+            return None;
+        }
+        let (epoch, established) = {
+            let lease = self.lease.lock().unwrap();
+            (lease.epoch(), lease.is_established())
+        };
+        if !established {
+            self.barrier().await.ok()?;
+            self.lease.lock().unwrap().establish(epoch);
+        }
+        let peers = self.reconfiguration.lock().unwrap().peers().to_vec();
+        let (id, acks) = self.checks.lock().unwrap().register((peers.len() + 1) / 2);
+        for peer in &peers {
+            self.transport.send_read_index_message(ReadIndexMessage {
+                from: self.id,
+                to: *peer,
+                msg: ReadIndexMsg::Check { id },
+            });
+        }
+        let mut quorum = match tokio::time::timeout(READ_INDEX_TIMEOUT, acks).await {
+            Ok(Ok(acks)) => acks,
+            _ => {
+                self.checks.lock().unwrap().cancel(id);
+                return None;
+            }
+        };
+        // The leader changed while the checks were out.
+        if self.lease.lock().unwrap().epoch() != epoch {
+            return None;
+        }
+        quorum.push(self.id);
+        quorum.sort_unstable();
+        Some(quorum)
+    }
+
+    /// Returns the leader epoch and whether the lease is established in it,
+    /// if this node is the leader and holds the lease.
+    fn lease_epoch(&self) -> Option<(u64, bool)> {
+        if self.get_cluster_leader() != self.id {
+            return None;
+        }
+        let peers = self.reconfiguration.lock().unwrap().peers().to_vec();
+        let lease = self.lease.lock().unwrap();
+        if !lease.is_held(&peers) {
+            return None;
+        }
+        Some((lease.epoch(), lease.is_established()))
+    }
+
+    /// Executes independent queries as one batch, returning the results of
+    /// each in order.
+    ///
+    /// Strong queries are all proposed, in batch order, before waiting for
+    /// any of them, and relaxed reads run concurrently. One query failing
+    /// does not stop the others.
+    pub async fn query_batch(
+        &self,
+        queries: Vec<BatchQuery>,
+    ) -> Vec<Result<QueryResults, StoreError>> {
+        let batch = queries.into_iter().map(|query| {
+            self.query_with_params(query.sql, query.params, query.consistency, query.lane)
+        });
+        join_all(batch).await
+    }
+
+    /// Executes `stmt` like `query_with_params`, returning the results in
+    /// batches of up to `batch_size` rows, or `StoreConfig::stream_batch_size`
+    /// rows if it is 0. The first batch carries the columns.
+    ///
+    /// Relaxed reads are read from SQLite as the batches are taken, so large
+    /// scans are never held in memory in full. Other queries are applied
+    /// through the replicated log first and only sent in batches, the last
+    /// of which carries the read proof.
+    pub async fn query_stream<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        params: Vec<Value>,
+        consistency: Consistency,
+        lane: QueryLane,
+        batch_size: usize,
+    ) -> Result<mpsc::Receiver<Result<QueryResults, StoreError>>, StoreError> {
+        let batch_size = match batch_size {
+            0 => self.config.stream_batch_size,
+            n => n,
+        };
+        let stmt = stmt.as_ref();
+        check_relaxed_write(stmt, &consistency)?;
+        if matches!(consistency, Consistency::RelaxedReads)
+            && statements::is_read(stmt)
+            && !introspection::references_introspection(stmt)
+        {
+            let lane = self.lanes.get(lane);
+            return Ok(lane.stream(stmt.to_string(), params, batch_size).await);
+        }
+
+        let mut results = self
+            .query_with_params(stmt, params, consistency, lane)
+            .await?;
+        let mut rows = std::mem::take(&mut results.rows);
+        let mut batches = vec![];
+        while rows.len() > batch_size {
+            let rest = rows.split_off(batch_size);
+            batches.push(QueryResults::new(std::mem::replace(&mut rows, rest)));
+        }
+        results.rows = rows;
+        batches.push(results);
+        // Only the first batch carries the columns.
+        let last = batches.len() - 1;
+        batches[0].columns = std::mem::take(&mut batches[last].columns);
+        let (tx, rx) = mpsc::channel(batches.len());
+        for batch in batches {
+            let _ = tx.try_send(Ok(batch));
+        }
+        Ok(rx)
+    }
+
+    /// Opens a cursor over the rows of the `SELECT` query `stmt` and returns
+    /// its first page of up to `page_size` rows, or the streaming default
+    /// if 0. See `cursors`.
+    pub async fn open_cursor<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        params: Vec<Value>,
+        consistency: Consistency,
+        lane: QueryLane,
+        page_size: usize,
+    ) -> Result<CursorPage, StoreError> {
+        let stmt = stmt.as_ref();
+        if !statements::is_read(stmt) {
+            return Err(StoreError::InvalidRequest(String::from(
+                "cursors can only be opened for SELECT queries",
+            )));
+        }
+        let batches = self
+            .query_stream(stmt, params, consistency, lane, page_size)
+            .await?;
+        self.cursors.open(batches).await
+    }
+
+    /// Returns the next page of the cursor `cursor_id`; the last page closes
+    /// it.
+    pub async fn fetch_cursor(&self, cursor_id: u64) -> Result<CursorPage, StoreError> {
+        self.cursors.fetch(cursor_id).await
+    }
+
+    /// Closes the cursor `cursor_id` before its last page, returning false
+    /// if it was not open.
+    pub fn close_cursor(&self, cursor_id: u64) -> bool {
+        self.cursors.close(cursor_id)
+    }
+
+    /// Reads the page of the rows of the `SELECT` query `stmt`, ordered by
+    /// the result columns `key`, that `token` starts, or the first page if
+    /// `None`. Pages hold up to `page_size` rows, or the streaming default
+    /// if 0.
+    ///
+    /// The first page is read with `consistency`, and the following ones
+    /// locally once this replica applied the index of their token. See
+    /// `pagination`.
+    pub async fn query_page<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        key: &[String],
+        page_size: usize,
+        token: Option<&str>,
+        consistency: Consistency,
+    ) -> Result<ResultPage, StoreError> {
+        let stmt = stmt.as_ref();
+        if !statements::is_read(stmt) {
+            return Err(StoreError::InvalidRequest(String::from(
+                "pages can only be read for SELECT queries",
+            )));
+        }
+        let token = token.map(PageToken::decode).transpose()?;
+        let page_size = match page_size {
+            0 => self.config.stream_batch_size,
+            page_size => page_size,
+        };
+        // One more row than the page tells whether another page follows.
+        let after = token.as_ref().map(|token| token.after.as_slice());
+        let (sql, params) = pagination::page_statement(stmt, key, after, page_size + 1)?;
+        let consistency = match &token {
+            Some(token) => {
+                if !self.wait_applied(token.applied_idx, PAGE_TOKEN_WAIT).await {
+                    return Err(StoreError::PageIndexNotApplied {
+                        index: token.applied_idx,
+                        applied: self.applied_idx(),
+                    });
+                }
+                Consistency::RelaxedReads
             }
+            None => consistency,
+        };
+        let before = self.applied_idx();
+        let mut results = self
+            .query_with_params(sql, params, consistency, QueryLane::Transactional)
+            .await?;
+        let applied_idx = match &results.proof {
+            Some(proof) => proof.decided_idx,
+            None => before,
+        };
+        let pinned = token.map_or(true, |token| {
+            before == token.applied_idx && self.applied_idx() == token.applied_idx
+        });
+        let next = if results.rows.len() > page_size {
+            results.rows.truncate(page_size);
+            let token = PageToken {
+                applied_idx,
+                after: pagination::last_key(&results, key)?,
+            };
+            Some(token.encode())
+        } else {
+            None
+        };
+        Ok(ResultPage {
+            results,
+            next,
+            applied_idx,
+            pinned,
+        })
+    }
+
+    /// Executes `stmt` only if `predicate` returns at least one row.
+    ///
+    /// The predicate and the statement are replicated as a single command,
+    /// and every replica evaluates the predicate when applying it, so all
+    /// replicas make the same decision. `QueryResults::applied` tells
+    /// whether the statement ran.
+    pub async fn execute_if<P: AsRef<str>, S: AsRef<str>>(
+        &self,
+        predicate: P,
+        stmt: S,
+    ) -> Result<QueryResults, StoreError> {
+        let kind = CommandKind::Conditional {
+            predicate: predicate.as_ref().to_string(),
+        };
+        let cmd = self.new_command(stmt.as_ref().to_string(), kind);
+        self.replicate(cmd).await
+    }
 
+    /// Executes `stmt` with `params` bound to its placeholders through the
+    /// replicated log, with `pragmas` set while every replica applies it;
+    /// see `pragmas`.
+    pub async fn query_with_pragmas<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        params: Vec<Value>,
+        pragmas: Vec<PragmaSetting>,
+    ) -> Result<QueryResults, StoreError> {
+        pragmas::check(&pragmas)?;
+        let mut cmd = self.new_command(stmt.as_ref().to_string(), CommandKind::Statement);
+        cmd.params = params;
+        cmd.pragmas = pragmas;
+        self.replicate(cmd).await
+    }
+
+    /// Executes `statements` in order as a single replicated command, applied
+    /// in one SQLite transaction on every replica: if any statement fails,
+    /// none of them take effect.
+    ///
+    /// The statements may not control transactions themselves, but may use
+    /// savepoints: a failure inside a savepoint only rolls that savepoint
+    /// back (see `savepoints`).
+    pub async fn transaction<S: AsRef<str>>(
+        &self,
+        statements: &[S],
+    ) -> Result<QueryResults, StoreError> {
+        self.transaction_with_pragmas(statements, vec![]).await
+    }
+
+    /// Executes `statements` as one transaction, like `transaction`, with
+    /// `pragmas` set while every replica applies it; see `pragmas`.
+    pub async fn transaction_with_pragmas<S: AsRef<str>>(
+        &self,
+        statements: &[S],
+        pragmas: Vec<PragmaSetting>,
+    ) -> Result<QueryResults, StoreError> {
+        pragmas::check(&pragmas)?;
+        let sql = transaction_sql(statements)?;
+        let mut cmd = self.new_command(sql, CommandKind::Transaction);
+        cmd.pragmas = pragmas;
+        self.replicate(cmd).await
+    }
+
+    /// Executes `statements` as one transaction, like `transaction`, along
+    /// with recording `payload` for `topic` in the outbox; see `outbox`.
+    pub async fn transaction_with_outbox<S: AsRef<str>>(
+        &self,
+        statements: &[S],
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<QueryResults, StoreError> {
+        let mut statements: Vec<String> = statements
+            .iter()
+            .map(|stmt| stmt.as_ref().to_string())
+            .collect();
+        statements.push(outbox_statement(topic, payload));
+        self.transaction(&statements).await
+    }
+
+    /// Prepares `sql`, a single statement, on every replica and returns its
+    /// id, which `execute_prepared` runs it by.
+    ///
+    /// Preparing the same statement again returns the same id. The
+    /// statement is compiled on this replica first, so statements that do
+    /// not compile, e.g. because a table does not exist yet, never reach
+    /// the log.
+    pub async fn prepare<S: AsRef<str>>(&self, sql: S) -> Result<u64, StoreError> {
+        let sql = sql.as_ref().to_string();
+        {
+            let conn = self.sqlite_connection.lock().unwrap().get_connection();
+            let conn = conn.lock().unwrap();
+            CompiledStatement::compile(&conn, &sql)?;
+        }
+        let id = prepared::statement_id(&sql);
+        let cmd = self.new_command(sql, CommandKind::Prepare);
+        self.replicate(cmd).await?;
+        Ok(id)
+    }
+
+    /// Executes the prepared statement `id` with `params` bound to its
+    /// placeholders. Only the id and the parameters are replicated, and
+    /// replicas reuse the statement compiled on earlier applies.
+    pub async fn execute_prepared(
+        &self,
+        id: u64,
+        params: Vec<Value>,
+    ) -> Result<QueryResults, StoreError> {
+        let sql = {
+            let conn = self.sqlite_connection.lock().unwrap().get_connection();
+            let conn = conn.lock().unwrap();
+            prepared::lookup(&conn, id)?.ok_or(StoreError::UnknownStatement(id))?
+        };
+        let started = Instant::now();
+        let mut cmd = self.new_command(
+            String::new(),
+            CommandKind::ExecutePrepared { statement: id },
+        );
+        cmd.functions = self.functions.function_references(&sql);
+        cmd.collations = self.functions.collation_references(&sql);
+        cmd.params = params;
+        let results = self.replicate(cmd).await?;
+        self.statement_stats
+            .record(&sql, started.elapsed(), results.rows.len());
+        Ok(results)
+    }
+
+    /// Proposes a no-op command and waits for it to apply on this replica,
+    /// returning the applied index it was applied at. Any replica whose
+    /// applied index reaches it has applied everything decided before.
+    pub async fn barrier(&self) -> Result<u64, StoreError> {
+        let cmd = self.new_command(String::new(), CommandKind::Statement);
+        let results = self.replicate(cmd).await?;
+        Ok(results.proof.map(|proof| proof.decided_idx).unwrap_or(0))
+    }
+
+    /// Waits up to `timeout` for this replica to apply `idx` entries,
+    /// returning whether it did.
+    pub async fn wait_applied(&self, idx: u64, timeout: Duration) -> bool {
+        let applied = async {
+            while self.applied_idx() < idx {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        tokio::time::timeout(timeout, applied).await.is_ok()
+    }
+
+    /// Upserts `rows` into `table`, overwriting the non-key columns of rows
+    /// that conflict on `key_columns`.
+    ///
+    /// The rows are sent as chunked `INSERT ... ON CONFLICT` statements,
+    /// one after the other, each waiting for the previous one to be applied.
+    /// Each chunk is applied atomically, but the upsert as a whole is not: if
+    /// a chunk fails, the chunks before it stay applied and the ones after it
+    /// are never sent. Returns the number of statements replicated.
+    pub async fn upsert(
+        &self,
+        table: &str,
+        columns: &[&str],
+        key_columns: &[&str],
+        rows: &[Vec<Value>],
+    ) -> Result<usize, StoreError> {
+        let statements = upsert_statements(table, columns, key_columns, rows)?;
+        let count = statements.len();
+        for stmt in statements {
+            let cmd = self.new_command(stmt, CommandKind::Statement);
+            self.replicate(cmd).await?;
+        }
+        Ok(count)
+    }
+
+    /// Publishes `payload` to `topic`.
+    ///
+    /// Publishing is replicated like any other command, so every replica
+    /// delivers the messages of a topic to its subscribers in log order.
+    pub async fn publish<S: AsRef<str>>(
+        &self,
+        topic: S,
+        payload: Vec<u8>,
+    ) -> Result<(), StoreError> {
+        let kind = CommandKind::Publish {
+            topic: topic.as_ref().to_string(),
+            payload,
+        };
+        let cmd = self.new_command(String::new(), kind);
+        self.replicate(cmd).await?;
+        Ok(())
+    }
+
+    /// Subscribes to the messages published to `topic` from now on.
+    pub fn subscribe<S: AsRef<str>>(&self, topic: S) -> broadcast::Receiver<Publication> {
+        self.topics.subscribe(topic.as_ref())
+    }
+
+    /// Sets the application setting `key` to `value`.
+    ///
+    /// The write is replicated, so every replica stores the same settings
+    /// and notifies its watchers in log order.
+    pub async fn set_config<K: AsRef<str>, V: AsRef<str>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<(), StoreError> {
+        let kind = CommandKind::SetConfig {
+            key: key.as_ref().to_string(),
+            value: value.as_ref().to_string(),
+        };
+        let cmd = self.new_command(String::new(), kind);
+        self.replicate(cmd).await?;
+        Ok(())
+    }
+
+    /// Replicates schema migration `version`, which every replica applies
+    /// unless it is applied already; see `schema`. `QueryResults::applied`
+    /// tells whether this call applied it.
+    pub async fn apply_migration<N: AsRef<str>, S: AsRef<str>>(
+        &self,
+        version: u64,
+        name: N,
+        sql: S,
+    ) -> Result<QueryResults, StoreError> {
+        let kind = CommandKind::Migration {
+            version,
+            name: name.as_ref().to_string(),
+            checksum: schema::script_checksum(sql.as_ref()),
+        };
+        let cmd = self.new_command(sql.as_ref().to_string(), kind);
+        self.replicate(cmd).await
+    }
+
+    /// Reads the application setting `key`, linearizably.
+    pub async fn get_config<K: AsRef<str>>(&self, key: K) -> Result<Option<String>, StoreError> {
+        let stmt = settings::get_config_statement(key.as_ref());
+        let results = self.query(stmt, Consistency::Strong).await?;
+        Ok(results
+            .rows
+            .into_iter()
+            .next()
+            .map(|mut row| row.values.remove(0)))
+    }
+
+    /// Watches the settings whose keys start with `prefix`, from now on.
+    pub fn watch_config<P: AsRef<str>>(&self, prefix: P) -> ConfigWatch {
+        self.config_watchers.watch(prefix.as_ref())
+    }
+
+    /// Takes a read snapshot of the database at the current applied index.
+    ///
+    /// Applying entries is paused while the database is copied; queries on
+    /// the snapshot do not block or observe later writes.
+    pub fn read_snapshot(&self) -> Result<ReadSnapshot, StoreError> {
+        std::fs::create_dir_all(&self.config.snapshot_dir)?;
+        let snapshot_id = self.next_snapshot_id.fetch_add(1, Ordering::SeqCst);
+        let path = self
+            .config
+            .snapshot_dir
+            .join(format!("node{}-snapshot{}.db", self.id, snapshot_id));
+        let applied_idx = self.copy_database(&path)?;
+        ReadSnapshot::open(path, applied_idx)
+    }
+
+    /// Writes a backup of the database to `path`, returning the applied
+    /// index it corresponds to. See `backup::verify_backup`.
+    ///
+    /// The backup is retained: later hard deletes are purged from it by
+    /// `purge_tombstones`.
+    pub fn backup(&self, path: &Path) -> Result<u64, StoreError> {
+        let applied_idx = self.copy_database(path)?;
+        backup::record_applied_idx(path, applied_idx)?;
+        self.retained_backups
+            .lock()
+            .unwrap()
+            .push(path.to_path_buf());
+        Ok(applied_idx)
+    }
+
+    /// Executes `stmt` with `params` against the database of `tenant`, like
+    /// `query_with_params` does against the shared database.
+    ///
+    /// Writes are replicated through the shared log and applied to the
+    /// tenant's database on every replica.
+    pub async fn query_tenant<S: AsRef<str>>(
+        &self,
+        tenant: &str,
+        stmt: S,
+        params: Vec<Value>,
+        consistency: Consistency,
+        lane: QueryLane,
+    ) -> Result<QueryResults, StoreError> {
+        tenants::check_tenant_id(tenant)?;
+        self.check_tenant_route(tenant)?;
+        let stmt = stmt.as_ref();
+        check_relaxed_write(stmt, &consistency)?;
+        let (consistency, proof) = if statements::is_read(stmt) {
+            let consistency = self.resolve_lease(consistency).await;
+            let consistency = self.resolve_read_index(consistency).await;
+            self.resolve_leader_read(consistency).await
+        } else {
+            (Consistency::Strong, None)
+        };
+        match consistency {
+            Consistency::Strong | Consistency::LeaderLease | Consistency::ReadIndex => {
+                let mut cmd = self.new_command(stmt.to_string(), CommandKind::Statement);
+                cmd.params = params;
+                cmd.tenant = Some(tenant.to_string());
+                self.replicate_for_tenant(cmd, tenant).await
+            }
             Consistency::RelaxedReads => {
-                let mut sqlite_connection = self.sqlite_connection.lock().unwrap();
-                sqlite_connection.query(stmt.as_ref().to_string())?
+                let pool = self.tenants.connection(tenant)?;
+                let mut results = self
+                    .lanes
+                    .get(lane)
+                    .query_on(&pool, stmt.to_string(), params)
+                    .await?;
+                results.proof = proof;
+                Ok(results)
+            }
+        }
+    }
+
+    /// Fails with `StoreError::TenantMoved` if `tenant` moved to another
+    /// cluster.
+    fn check_tenant_route(&self, tenant: &str) -> Result<(), StoreError> {
+        let route = self
+            .sqlite_connection
+            .lock()
+            .unwrap()
+            .tenant_route(tenant)?;
+        match route.moved_to {
+            Some(cluster) => Err(StoreError::TenantMoved {
+                tenant: tenant.to_string(),
+                cluster,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Replicates a command of `tenant`, which replicas skip if the tenant
+    /// moved away before it was applied.
+    async fn replicate_for_tenant(
+        &self,
+        cmd: StoreCommand,
+        tenant: &str,
+    ) -> Result<QueryResults, StoreError> {
+        let results = self.replicate(cmd).await?;
+        if !results.applied {
+            self.check_tenant_route(tenant)?;
+        }
+        Ok(results)
+    }
+
+    /// Exports the database of `tenant` as SQL statements, with applying
+    /// paused so the export matches the applied index it carries.
+    pub fn export_tenant(&self, tenant: &str) -> Result<TenantExport, StoreError> {
+        let pool = self.tenants.connection(tenant)?;
+        let _applying = self.query_result_notifier.lock().unwrap();
+        let conn = pool.lock().unwrap().get_connection();
+        let conn = conn.lock().unwrap();
+        Ok(TenantExport {
+            applied_idx: self.applied_idx.load(Ordering::SeqCst),
+            statements: migration::dump(&conn)?,
+        })
+    }
+
+    /// Imports the export of a tenant from another cluster into the
+    /// database of `tenant`, which must not have any tables yet.
+    ///
+    /// The statements are applied in replicated transactions of
+    /// `migration::IMPORT_BATCH` statements each, so a failed import may
+    /// leave part of the export behind.
+    pub async fn import_tenant<S: AsRef<str>>(
+        &self,
+        tenant: &str,
+        statements: &[S],
+    ) -> Result<(), StoreError> {
+        self.check_tenant_route(tenant)?;
+        if !self.export_tenant(tenant)?.statements.is_empty() {
+            return Err(StoreError::InvalidRequest(format!(
+                "tenant {} already has tables",
+                tenant
+            )));
+        }
+        for batch in statements.chunks(migration::IMPORT_BATCH) {
+            let mut cmd = self.new_command(transaction_sql(batch)?, CommandKind::Transaction);
+            cmd.tenant = Some(tenant.to_string());
+            self.replicate_for_tenant(cmd, tenant).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the writes to `tenant` captured by this replica after
+    /// `after_idx` while the tenant is being migrated.
+    pub fn tenant_changes(
+        &self,
+        tenant: &str,
+        after_idx: u64,
+    ) -> Result<Vec<TenantChange>, StoreError> {
+        self.sqlite_connection
+            .lock()
+            .unwrap()
+            .tenant_changes(tenant, after_idx)
+    }
+
+    /// Returns the size of every tenant database on this replica.
+    pub fn tenant_usage(&self) -> Result<Vec<TenantUsage>, StoreError> {
+        self.tenants.usage()
+    }
+
+    /// Writes a backup of the database of `tenant` to `path`, returning the
+    /// applied index it corresponds to. See `backup::verify_backup` and
+    /// `tenants::restore_tenant`.
+    pub fn backup_tenant(&self, tenant: &str, path: &Path) -> Result<u64, StoreError> {
+        let pool = self.tenants.connection(tenant)?;
+        let applied_idx = {
+            // Holding the notifier pauses applying, so the copy matches the
+            // applied index.
+            let _applying = self.query_result_notifier.lock().unwrap();
+            let applied_idx = self.applied_idx.load(Ordering::SeqCst);
+            pool.lock().unwrap().vacuum_into(path)?;
+            applied_idx
+        };
+        backup::record_applied_idx(path, applied_idx)?;
+        Ok(applied_idx)
+    }
+
+    /// Deletes rows with `sql` and records a tombstone for `subject`.
+    ///
+    /// The delete runs with SQLite's `secure_delete` on every replica, and
+    /// the next `purge_tombstones` also purges the rows from the backups
+    /// the replica retains.
+    pub async fn hard_delete<P: AsRef<str>, S: AsRef<str>>(
+        &self,
+        subject: P,
+        sql: S,
+    ) -> Result<QueryResults, StoreError> {
+        let kind = CommandKind::HardDelete {
+            subject: subject.as_ref().to_string(),
+        };
+        let cmd = self.new_command(sql.as_ref().to_string(), kind);
+        self.replicate(cmd).await
+    }
+
+    /// Purges the hard deletes recorded since the last purge from the
+    /// retained backups. Backups that no longer exist are forgotten.
+    ///
+    /// Returns the number of tombstones purged.
+    pub fn purge_tombstones(&self) -> Result<usize, StoreError> {
+        let purged_idx = self.purged_idx.load(Ordering::SeqCst);
+        let tombstones = self
+            .sqlite_connection
+            .lock()
+            .unwrap()
+            .tombstones_after(purged_idx)?;
+        let last = match tombstones.last() {
+            Some(tombstone) => tombstone.applied_idx,
+            None => return Ok(0),
+        };
+        let mut backups = self.retained_backups.lock().unwrap();
+        backups.retain(|path| path.exists());
+        for path in backups.iter() {
+            let purged = tombstones::purge_backup(path, &tombstones)?;
+            if purged > 0 {
+                info!(
+                    self.logger,
+                    "Replica {} purged {} hard deletes from {}",
+                    self.id,
+                    purged,
+                    path.display()
+                );
+            }
+        }
+        self.purged_idx.store(last, Ordering::SeqCst);
+        Ok(tombstones.len())
+    }
+
+    /// Copies the database to `path` with applying paused, returning the
+    /// applied index of the copy.
+    fn copy_database(&self, path: &Path) -> Result<u64, StoreError> {
+        let mut sqlite_connection = self.sqlite_connection.lock().unwrap();
+        let applied_idx = self.applied_idx.load(Ordering::SeqCst);
+        sqlite_connection.vacuum_into(path)?;
+        Ok(applied_idx)
+    }
+
+    fn new_command(&self, sql: String, mut kind: CommandKind) -> StoreCommand {
+        let id = self.next_cmd_id.fetch_add(1, Ordering::SeqCst);
+        let sql = match &mut kind {
+            CommandKind::Statement | CommandKind::Transaction | CommandKind::Migration { .. }
+                if self.config.resolve_nondeterminism =>
+            {
+                determinism::resolve(&sql, SystemTime::now())
             }
+            CommandKind::Conditional { predicate } if self.config.resolve_nondeterminism => {
+                let now = SystemTime::now();
+                *predicate = determinism::resolve(predicate, now);
+                determinism::resolve(&sql, now)
+            }
+            _ => sql,
+        };
+        let sql = match self.config.full_text_search {
+            true => fulltext::pin_tokenizers(&sql),
+            false => sql,
         };
+        let mut functions = self.functions.function_references(&sql);
+        let mut collations = self.functions.collation_references(&sql);
+        if let CommandKind::Conditional { predicate } = &kind {
+            functions.extend(self.functions.function_references(predicate));
+            functions.sort();
+            functions.dedup();
+            collations.extend(self.functions.collation_references(predicate));
+            collations.sort();
+            collations.dedup();
+        }
+        StoreCommand {
+            id: id as usize,
+            sql,
+            kind,
+            functions,
+            collations,
+            params: vec![],
+            tenant: None,
+            pragmas: vec![],
+        }
+    }
+
+    /// Runs the proposal validators on `cmd`.
+    fn validate(&self, cmd: &StoreCommand) -> Result<(), StoreError> {
+        fulltext::check(&cmd.sql, self.config.full_text_search)?;
+        if self.config.validators.is_empty() {
+            return Ok(());
+        }
+        let conn = self.sqlite_connection.lock().unwrap().get_connection();
+        let conn = conn.lock().unwrap();
+        for validator in &self.config.validators {
+            validator
+                .validate(cmd, &conn)
+                .map_err(StoreError::ProposalRejected)?;
+        }
+        Ok(())
+    }
 
+    /// Proposes a statement through the replicated log without waiting for
+    /// it to be applied, so that writers can pipeline writes. The returned
+    /// handle resolves once this replica applied the statement.
+    ///
+    /// Fails right away if the statement is rejected before it is
+    /// proposed.
+    pub fn query_async<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        params: Vec<Value>,
+    ) -> Result<WriteHandle, StoreError> {
+        let mut cmd = self.new_command(stmt.as_ref().to_string(), CommandKind::Statement);
+        cmd.params = params;
+        let id = cmd.id as u64;
+        let notify = self.propose(cmd)?;
+        Ok(WriteHandle {
+            id,
+            notify,
+            notifier: self.query_result_notifier.clone(),
+            proposed: Instant::now(),
+        })
+    }
+
+    /// Validates `cmd` and appends it to the log, returning what notifies
+    /// its application.
+    fn propose(&self, cmd: StoreCommand) -> Result<Arc<Notify>, StoreError> {
+        self.validate(&cmd)?;
+        let id = cmd.id as u64;
+        let notify = {
+            let mut query_result_notifier = self.query_result_notifier.lock().unwrap();
+            let notify = Arc::new(Notify::new());
+            query_result_notifier.add_command(id, notify.clone());
+
+            let mut seq_paxos = self.seq_paxos.lock().unwrap();
+            if seq_paxos.append(cmd).is_err() {
+                // A stop sign was proposed, so the log takes no more commands.
+                query_result_notifier.cmnd_completion.remove(&id);
+                return Err(StoreError::ConfigurationStopped(self.config_id()));
+            }
+            notify
+        };
+        if self.batching.lock().unwrap().proposed() {
+            self.batch_full.notify_one();
+        }
+        Ok(notify)
+    }
+
+    /// Appends a command to the log and waits until this replica applied it,
+    /// retrying up to `StoreConfig::proposal_retries` times across leader
+    /// changes.
+    ///
+    /// A command is retried when there is no leader to propose it to, or
+    /// when the configuration ended before it was appended. Once appended,
+    /// a command may be lost if the leader changes before deciding it;
+    /// reads are then proposed again, but writes fail with
+    /// `StoreError::NotLeader` since they may still have been applied.
+    async fn replicate(&self, mut cmd: StoreCommand) -> Result<QueryResults, StoreError> {
+        let idempotent = cmd.kind == CommandKind::Statement && !statements::is_write(&cmd.sql);
+        let mut retries = self.config.proposal_retries;
+        loop {
+            match self.replicate_once(cmd.clone()).await {
+                Err(e @ StoreError::ConfigurationStopped(_)) if retries > 0 => {
+                    debug!(
+                        self.logger,
+                        "Replica {} retrying command {}: {}", self.id, cmd.id, e
+                    );
+                    let config_id = self.config_id();
+                    self.wait_until(|| self.config_id() != config_id).await;
+                }
+                Err(e @ StoreError::NotLeader) if retries > 0 && idempotent => {
+                    debug!(
+                        self.logger,
+                        "Replica {} retrying command {}: {}", self.id, cmd.id, e
+                    );
+                    cmd.id = self.next_cmd_id.fetch_add(1, Ordering::SeqCst) as usize;
+                }
+                result => return result,
+            }
+            retries -= 1;
+        }
+    }
+
+    /// Waits up to `StoreConfig::proposal_timeout` for `done` to hold,
+    /// checking it every election tick.
+    async fn wait_until(&self, done: impl Fn() -> bool) {
+        let deadline = Instant::now() + self.config.proposal_timeout;
+        while !done() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(BLE_TICK)).await;
+        }
+    }
+
+    /// Proposes `cmd` once and waits until this replica applied it, or until
+    /// the leader changed without it being applied.
+    async fn replicate_once(&self, cmd: StoreCommand) -> Result<QueryResults, StoreError> {
+        let id = cmd.id as u64;
+        let started = Instant::now();
+        // Held until the handover ends, which has a deadline of its own.
+        while self.handing_over.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(BLE_TICK)).await;
+        }
+        if self.get_cluster_leader() == 0 {
+            self.wait_until(|| self.get_cluster_leader() != 0).await;
+        }
+        let ballot = *self.leader_ballot.lock().unwrap();
+        let notify = self.propose(cmd)?;
+        let proposed = Instant::now();
+
+        while tokio::time::timeout(self.config.proposal_timeout, notify.notified())
+            .await
+            .is_err()
+        {
+            if *self.leader_ballot.lock().unwrap() == ballot {
+                continue;
+            }
+            // The entry may have been lost along with the previous leader.
+            let mut notifier = self.query_result_notifier.lock().unwrap();
+            if notifier.results.contains_key(&id) {
+                break;
+            }
+            notifier.forget(id);
+            return Err(StoreError::NotLeader);
+        }
+        self.batching.lock().unwrap().record(proposed.elapsed());
+
+        let results = self
+            .query_result_notifier
+            .lock()
+            .unwrap()
+            .results
+            .remove(&id)
+            .unwrap();
+        results.map(|mut results| {
+            results.timing.queue = proposed.duration_since(started);
+            results.timing.replicate = proposed.elapsed().saturating_sub(results.timing.apply);
+            if let Some(proof) = &mut results.proof {
+                proof.quorum = self.accepted_through(proof.decided_idx);
+            }
+            results
+        })
+    }
+
+    /// Nodes known to have accepted the log up to `idx`, this one included.
+    fn accepted_through(&self, idx: u64) -> Vec<u64> {
+        let peer_accepted = self.peer_accepted.lock().unwrap();
+        let mut nodes: Vec<u64> = peer_accepted
+            .iter()
+            .filter(|(_, accepted)| **accepted >= idx)
+            .map(|(peer, _)| *peer)
+            .chain(std::iter::once(self.id))
+            .collect();
+        nodes.sort_unstable();
+        nodes
+    }
+
+    /// Returns the custom SQL functions registered on this replica.
+    pub fn functions(&self) -> &FunctionRegistry {
+        &self.functions
+    }
+
+    /// Returns the statement statistics of this replica.
+    pub fn statement_stats(&self) -> &StatementStatistics {
+        &self.statement_stats
+    }
+
+    /// Returns the inbound peer messages of this replica.
+    pub fn peer_mailbox(&self) -> &PeerMailbox {
+        &self.mailbox
+    }
+
+    /// Returns what warming up this replica at startup did.
+    pub fn warmup_report(&self) -> &WarmupReport {
+        &self.warmup
+    }
+
+    /// Returns the calls to deprecated API methods served by this replica.
+    pub fn deprecated_calls(&self) -> &DeprecatedCalls {
+        &self.deprecated_calls
+    }
+
+    /// Returns this replica's view of the cluster.
+    pub fn cluster_status(&self) -> ClusterStatus {
+        let leader = self.get_cluster_leader();
+        let peer_accepted = self.peer_accepted.lock().unwrap().clone();
+        let peers = self.reconfiguration.lock().unwrap().peers().to_vec();
+        let mut members: Vec<MemberStatus> = peers
+            .iter()
+            .map(|peer| MemberStatus {
+                id: *peer,
+                is_self: false,
+                is_leader: *peer == leader,
+                accepted_idx: peer_accepted.get(peer).copied(),
+            })
+            .collect();
+        members.push(MemberStatus {
+            id: self.id,
+            is_self: true,
+            is_leader: self.id == leader,
+            accepted_idx: None,
+        });
+        members.sort_by_key(|member| member.id);
+
+        ClusterStatus {
+            node_id: self.id,
+            lifecycle: self.lifecycle().to_string(),
+            leader,
+            applied_idx: self.applied_idx(),
+            pending_commands: self
+                .query_result_notifier
+                .lock()
+                .unwrap()
+                .pending_commands()
+                .len() as u64,
+            apply_failures: self.apply_failures.lock().unwrap().len() as u64,
+            members,
+            statements: self.statement_stats.snapshot(),
+            deprecated_calls: self.deprecated_calls.snapshot(),
+            batching: self.batching(),
+        }
+    }
+
+    /// Serves a read of the introspection tables from local state.
+    fn query_introspection(&self, stmt: String) -> Result<QueryResults, StoreError> {
+        let started = Instant::now();
+        let setup = self.cluster_status().refresh_sql();
+        let mut sqlite_connection = self.sqlite_connection.lock().unwrap();
+        let mut results = sqlite_connection.query_with_setup(&setup, stmt)?;
+        results.timing.apply = started.elapsed();
         Ok(results)
     }
 
+    /// Checks that a peer message from `from` to `to` is expected, i.e. that
+    /// `from` is a member of the configuration and `to` is this node.
+    ///
+    /// Rejected messages are logged and recorded in the diagnostics events.
+    pub fn verify_peer(&self, from: u64, to: u64) -> Result<(), StoreError> {
+        if to == self.id && self.reconfiguration.lock().unwrap().peers().contains(&from) {
+            return Ok(());
+        }
+        warn!(
+            self.logger,
+            "Replica {} rejected message from unknown peer {} to {}", self.id, from, to
+        );
+        self.events
+            .lock()
+            .unwrap()
+            .record(format!("rejected message from {} to {}", from, to));
+        Err(StoreError::UnknownPeer { from, to })
+    }
+
     pub fn recv_msg(&self, msg: messages::Message<StoreCommand, ()>) {
+        if let messages::PaxosMsg::Accepted(accepted) = &msg.msg {
+            let mut peer_accepted = self.peer_accepted.lock().unwrap();
+            peer_accepted.insert(msg.from, accepted.la);
+        }
         let mut seq_paxos = self.seq_paxos.lock().unwrap();
         seq_paxos.handle(msg);
     }
 
+    /// Handles a read index message: the leader answers requests, peers
+    /// answer leadership checks, and replies are handed to the reads waiting
+    /// for them.
+    pub async fn recv_read_index_msg(&self, msg: ReadIndexMessage) {
+        match msg.msg {
+            ReadIndexMsg::Request { id } => {
+                let index = match self.get_cluster_leader() == self.id {
+                    true => self.confirm_read_index().await,
+                    false => None,
+                };
+                self.transport.send_read_index_message(ReadIndexMessage {
+                    from: self.id,
+                    to: msg.from,
+                    msg: ReadIndexMsg::Reply { id, index },
+                });
+            }
+            ReadIndexMsg::Reply { id, index } => {
+                self.read_indexes.lock().unwrap().resolve(id, index);
+            }
+            ReadIndexMsg::Check { id } => {
+                self.transport.send_read_index_message(ReadIndexMessage {
+                    from: self.id,
+                    to: msg.from,
+                    msg: ReadIndexMsg::Ack {
+                        id,
+                        follows: self.get_cluster_leader() == msg.from,
+                    },
+                });
+            }
+            ReadIndexMsg::Ack { id, follows: true } => {
+                self.checks.lock().unwrap().ack(id, msg.from);
+            }
+            ReadIndexMsg::Ack { follows: false, .. } => {}
+        }
+    }
+
     pub fn recv_ble_msg(&self, ble_msg: ble::messages::BLEMessage) {
+        // A node handing its leadership over looks failed to its peers.
+        if self.handing_over.load(Ordering::SeqCst) {
+            return;
+        }
+        if let ble::messages::HeartbeatMsg::Reply(_) = &ble_msg.msg {
+            self.lease.lock().unwrap().record_ack(ble_msg.from);
+        }
         let mut ble = self.ble.lock().unwrap();
         ble.handle(ble_msg)
     }
 }
 
-fn is_read_statement(stmt: &str) -> bool {
-    stmt.to_lowercase().starts_with("select")
+/// Fails if `stmt` writes but was submitted as a relaxed read, which only
+/// runs on the serving replica and would leave the others behind.
+fn check_relaxed_write(stmt: &str, consistency: &Consistency) -> Result<(), StoreError> {
+    if matches!(consistency, Consistency::RelaxedReads) && statements::is_write(stmt) {
+        return Err(StoreError::InvalidRequest(format!(
+            "write statement submitted with relaxed reads consistency; use strong consistency: {}",
+            stmt
+        )));
+    }
+    Ok(())
+}
+
+/// Joins the statements of a transaction into the SQL of its command.
+fn transaction_sql<S: AsRef<str>>(statements: &[S]) -> Result<String, StoreError> {
+    const CONTROL: [&str; 6] = ["begin", "commit", "end", "rollback", "savepoint", "release"];
+    if statements.is_empty() {
+        return Err(StoreError::InvalidRequest(String::from(
+            "a transaction needs at least one statement",
+        )));
+    }
+    let mut sql = String::new();
+    for stmt in statements {
+        let stmt = stmt.as_ref().trim();
+        let keyword = stmt
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        if CONTROL.contains(&keyword.as_str()) && !savepoints::is_savepoint_statement(stmt) {
+            return Err(StoreError::InvalidRequest(format!(
+                "transaction control statement in a transaction: {}",
+                stmt
+            )));
+        }
+        // A newline ends a trailing `--` comment before the separator.
+        sql.push_str(stmt);
+        sql.push_str("\n;\n");
+    }
+    Ok(sql)
 }
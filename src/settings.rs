@@ -0,0 +1,114 @@
+//! Replicated application settings.
+//!
+//! `StoreServer::set_config` replicates a command that writes a key into
+//! the reserved `chiselstore_config` table. When a replica applies it, the
+//! change is also handed to that replica's local watchers, so watchers see
+//! the changes in log order, like topic subscribers.
+
+use crate::errors::StoreError;
+use crate::value::quote_literal;
+use sqlite::Connection;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Reserved table holding the settings.
+pub const CONFIG_TABLE: &str = "chiselstore_config";
+
+/// Setting naming the node the cluster prefers as its leader; see
+/// `StoreServer::transfer_leadership`.
+pub const PREFERRED_LEADER_KEY: &str = "chiselstore.preferred_leader";
+
+/// Number of changes a watcher may fall behind before it is lagged.
+const WATCHER_CAPACITY: usize = 1024;
+
+/// A setting written by an applied `set_config`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigChange {
+    /// The key that was set.
+    pub key: String,
+    /// The new value.
+    pub value: String,
+    /// The applied index of the `set_config` command.
+    pub index: u64,
+}
+
+/// The local watchers of settings on a replica.
+#[derive(Debug)]
+pub struct ConfigWatchers {
+    sender: broadcast::Sender<ConfigChange>,
+}
+
+impl Default for ConfigWatchers {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(WATCHER_CAPACITY).0,
+        }
+    }
+}
+
+impl ConfigWatchers {
+    /// Watches the changes applied to keys starting with `prefix` from now
+    /// on.
+    pub fn watch(&self, prefix: &str) -> ConfigWatch {
+        ConfigWatch {
+            prefix: prefix.to_string(),
+            receiver: self.sender.subscribe(),
+        }
+    }
+
+    /// Delivers an applied change to the local watchers.
+    pub(crate) fn notify(&self, change: ConfigChange) {
+        let _ = self.sender.send(change);
+    }
+}
+
+/// Changes of the settings under a key prefix.
+#[derive(Debug)]
+pub struct ConfigWatch {
+    prefix: String,
+    receiver: broadcast::Receiver<ConfigChange>,
+}
+
+impl ConfigWatch {
+    /// Waits for the next change of a key under the watched prefix.
+    pub async fn recv(&mut self) -> Result<ConfigChange, RecvError> {
+        loop {
+            let change = self.receiver.recv().await?;
+            if change.key.starts_with(&self.prefix) {
+                return Ok(change);
+            }
+        }
+    }
+}
+
+/// Creates the settings table on `conn` if it does not exist yet.
+pub(crate) fn create_table(conn: &Connection) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+        CONFIG_TABLE
+    ))?;
+    Ok(())
+}
+
+/// Writes `value` for `key` on `conn`.
+pub(crate) fn apply_set_config(
+    conn: &Connection,
+    key: &str,
+    value: &str,
+) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "INSERT INTO {} VALUES ({}, {}) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        CONFIG_TABLE,
+        quote_literal(key),
+        quote_literal(value)
+    ))?;
+    Ok(())
+}
+
+/// Statement reading the value of `key`.
+pub(crate) fn get_config_statement(key: &str) -> String {
+    format!(
+        "SELECT value FROM {} WHERE key = {}",
+        CONFIG_TABLE,
+        quote_literal(key)
+    )
+}
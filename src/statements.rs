@@ -0,0 +1,258 @@
+//! Statement fingerprints, classification and statistics.
+//!
+//! Statements are normalized into fingerprints by replacing literals with
+//! `?` and collapsing whitespace, so that `SELECT * FROM t WHERE id = 1` and
+//! `select * from t where id = 2` are counted together. Per-fingerprint
+//! statistics are served by the `chiselstore_statements` introspection
+//! table.
+//!
+//! `is_write` classifies SQL by the keywords of its statements, so writes
+//! can be kept from running outside the replicated log.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Maximum number of fingerprints tracked; statements with new fingerprints
+/// are not recorded once the limit is reached.
+const MAX_FINGERPRINTS: usize = 1024;
+
+/// Returns the fingerprint of a statement.
+pub fn fingerprint(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.trim().chars().peekable();
+    let mut pending_space = false;
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            pending_space = true;
+            continue;
+        }
+        if pending_space && !out.is_empty() {
+            out.push(' ');
+        }
+        pending_space = false;
+        match c {
+            '\'' => {
+                // String literal; '' is an escaped quote.
+                while let Some(c) = chars.next() {
+                    if c == '\'' {
+                        if chars.peek() == Some(&'\'') {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                out.push('?');
+            }
+            '"' | '`' => {
+                // Quoted identifier, kept verbatim.
+                out.push(c);
+                for d in chars.by_ref() {
+                    out.push(d);
+                    if d == c {
+                        break;
+                    }
+                }
+            }
+            c if c.is_ascii_digit() && !ends_with_identifier(&out) => {
+                while let Some(d) = chars.peek() {
+                    if d.is_ascii_alphanumeric() || *d == '.' {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                out.push('?');
+            }
+            c => out.extend(c.to_lowercase()),
+        }
+    }
+    out.trim_end_matches(';').trim_end().to_string()
+}
+
+fn ends_with_identifier(s: &str) -> bool {
+    s.chars()
+        .last()
+        .map(|c| c.is_alphanumeric() || c == '_')
+        .unwrap_or(false)
+}
+
+/// Keywords starting a statement that changes the database or its schema.
+const WRITE_VERBS: [&str; 12] = [
+    "insert", "update", "delete", "replace", "create", "drop", "alter", "vacuum", "reindex",
+    "analyze", "attach", "detach",
+];
+
+/// Keywords that may follow the common table expressions of a `WITH`.
+const CTE_VERBS: [&str; 6] = ["select", "values", "insert", "update", "delete", "replace"];
+
+/// Returns true if any statement of `sql` inserts, updates or deletes rows,
+/// changes the schema, or sets a pragma.
+pub fn is_write(sql: &str) -> bool {
+    statement_words(sql).iter().any(|(words, assigns)| {
+        let verb = match words.first().map(String::as_str) {
+            Some("with") => words.iter().find(|word| CTE_VERBS.contains(&word.as_str())),
+            _ => words.first(),
+        };
+        match verb.map(String::as_str) {
+            Some("pragma") => *assigns,
+            Some(verb) => WRITE_VERBS.contains(&verb),
+            None => false,
+        }
+    })
+}
+
+/// Keywords starting a statement that only reads, unless it is a `WITH`
+/// ending in a write.
+const READ_VERBS: [&str; 4] = ["select", "values", "explain", "with"];
+
+/// Returns true if `sql` has a statement and all of its statements only
+/// read: queries, `VALUES` lists and `EXPLAIN`s, with or without common
+/// table expressions, comments and whitespace.
+pub fn is_read(sql: &str) -> bool {
+    let statements = statement_words(sql);
+    let mut verbs = statements
+        .iter()
+        .filter_map(|(words, _)| words.first())
+        .peekable();
+    verbs.peek().is_some()
+        && verbs.all(|verb| READ_VERBS.contains(&verb.as_str()))
+        && !is_write(sql)
+}
+
+/// Keywords starting a statement that begins or ends a transaction.
+const TRANSACTION_VERBS: [&str; 4] = ["begin", "commit", "end", "rollback"];
+
+/// Returns true if any statement of `sql` begins, commits or rolls back a
+/// transaction. Savepoint statements, `ROLLBACK TO` included, do not.
+pub(crate) fn controls_transaction(sql: &str) -> bool {
+    statement_words(sql)
+        .iter()
+        .any(|(words, _)| match words.first().map(String::as_str) {
+            Some("rollback") => !words.iter().any(|word| word == "to"),
+            Some(verb) => TRANSACTION_VERBS.contains(&verb),
+            None => false,
+        })
+}
+
+/// Returns the lowercased words of each statement of `sql` outside literals,
+/// comments and parentheses, and whether the statement assigns with `=`
+/// outside parentheses.
+fn statement_words(sql: &str) -> Vec<(Vec<String>, bool)> {
+    let mut statements = vec![(vec![], false)];
+    let mut depth = 0usize;
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // Quoted literals and identifiers; a doubled quote just starts
+            // the literal again.
+            '\'' | '"' | '`' => {
+                for q in chars.by_ref() {
+                    if q == c {
+                        break;
+                    }
+                }
+            }
+            '[' => {
+                for q in chars.by_ref() {
+                    if q == ']' {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for q in chars.by_ref() {
+                    if q == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut star = false;
+                for q in chars.by_ref() {
+                    if star && q == '/' {
+                        break;
+                    }
+                    star = q == '*';
+                }
+            }
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ';' if depth == 0 => statements.push((vec![], false)),
+            '=' if depth == 0 => statements.last_mut().unwrap().1 = true,
+            c if depth == 0 && (c.is_alphabetic() || c == '_') => {
+                let mut word: String = c.to_lowercase().collect();
+                while let Some(&d) = chars.peek() {
+                    if !(d.is_alphanumeric() || d == '_' || d == '$') {
+                        break;
+                    }
+                    word.extend(d.to_lowercase());
+                    chars.next();
+                }
+                statements.last_mut().unwrap().0.push(word);
+            }
+            _ => {}
+        }
+    }
+    statements
+}
+
+/// Statistics of the statements sharing a fingerprint.
+#[derive(Debug, Default, Clone)]
+pub struct StatementStats {
+    /// Number of executions.
+    pub calls: u64,
+    /// Total execution time.
+    pub total_time: Duration,
+    /// Total number of rows returned.
+    pub rows: u64,
+}
+
+impl StatementStats {
+    /// Mean execution time.
+    pub fn mean_time(&self) -> Duration {
+        if self.calls == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(self.total_time.as_secs_f64() / self.calls as f64)
+    }
+}
+
+/// Statement statistics of a replica, keyed by fingerprint.
+#[derive(Debug, Default)]
+pub struct StatementStatistics {
+    stats: Mutex<HashMap<String, StatementStats>>,
+}
+
+impl StatementStatistics {
+    /// Records one execution of `sql`.
+    pub fn record(&self, sql: &str, elapsed: Duration, rows: usize) {
+        let fingerprint = fingerprint(sql);
+        let mut stats = self.stats.lock().unwrap();
+        if !stats.contains_key(&fingerprint) && stats.len() >= MAX_FINGERPRINTS {
+            return;
+        }
+        let entry = stats.entry(fingerprint).or_default();
+        entry.calls += 1;
+        entry.total_time += elapsed;
+        entry.rows += rows as u64;
+    }
+
+    /// Returns the statistics of every fingerprint, ordered by fingerprint.
+    pub fn snapshot(&self) -> Vec<(String, StatementStats)> {
+        let stats = self.stats.lock().unwrap();
+        let mut snapshot: Vec<_> = stats
+            .iter()
+            .map(|(fingerprint, stats)| (fingerprint.clone(), stats.clone()))
+            .collect();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+        snapshot
+    }
+
+    /// Clears all statistics.
+    pub fn reset(&self) {
+        self.stats.lock().unwrap().clear();
+    }
+}
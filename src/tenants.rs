@@ -0,0 +1,129 @@
+//! Per-tenant databases.
+//!
+//! A command can name a tenant, in which case every replica applies it to
+//! the tenant's own SQLite file instead of the shared database. Tenants are
+//! isolated at the file level and can be measured and backed up one at a
+//! time, while all of them share one replicated log.
+
+use crate::errors::StoreError;
+use crate::functions::FunctionRegistry;
+use crate::server::SQLiteConnection;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Disk usage of a tenant's database on one replica.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TenantUsage {
+    /// The tenant id.
+    pub tenant: String,
+    /// Size of the tenant's database file in bytes.
+    pub bytes: u64,
+}
+
+/// Checks that `tenant` is a valid tenant id: 1 to 64 ASCII letters,
+/// digits, `-` or `_`, so it can name a file on every platform.
+pub fn check_tenant_id(tenant: &str) -> Result<(), StoreError> {
+    let valid = !tenant.is_empty()
+        && tenant.len() <= 64
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(StoreError::InvalidRequest(format!(
+            "invalid tenant id: {:?}",
+            tenant
+        )));
+    }
+    Ok(())
+}
+
+/// Directory holding the tenant databases of node `id` under `dir`.
+pub fn tenants_dir(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("node{}-tenants", id))
+}
+
+/// Path of the database of `tenant` on node `id`.
+pub fn tenant_path(dir: &Path, id: u64, tenant: &str) -> PathBuf {
+    tenants_dir(dir, id).join(format!("{}.db", tenant))
+}
+
+/// Replaces the database of `tenant` on node `id` with the backup at
+/// `backup`.
+///
+/// Restoring bypasses the log, so it must be done with the node stopped, on
+/// every replica, from the same backup.
+pub fn restore_tenant(backup: &Path, dir: &Path, id: u64, tenant: &str) -> Result<(), StoreError> {
+    check_tenant_id(tenant)?;
+    let path = tenant_path(dir, id, tenant);
+    std::fs::create_dir_all(tenants_dir(dir, id))?;
+    let tmp = path.with_extension("db.tmp");
+    std::fs::copy(backup, &tmp)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// The tenant databases of a replica, opened on first use.
+#[derive(Debug)]
+pub(crate) struct Tenants {
+    id: u64,
+    dir: PathBuf,
+    pool_size: usize,
+    registry: FunctionRegistry,
+    open: Mutex<HashMap<String, Arc<Mutex<SQLiteConnection>>>>,
+}
+
+impl Tenants {
+    pub(crate) fn new(id: u64, dir: PathBuf, pool_size: usize, registry: FunctionRegistry) -> Self {
+        Self {
+            id,
+            dir,
+            pool_size,
+            registry,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the connection pool of `tenant`, creating its database if
+    /// this is the tenant's first command.
+    pub(crate) fn connection(
+        &self,
+        tenant: &str,
+    ) -> Result<Arc<Mutex<SQLiteConnection>>, StoreError> {
+        let mut open = self.open.lock().unwrap();
+        if let Some(conn) = open.get(tenant) {
+            return Ok(conn.clone());
+        }
+        check_tenant_id(tenant)?;
+        std::fs::create_dir_all(tenants_dir(&self.dir, self.id))?;
+        let path = tenant_path(&self.dir, self.id, tenant);
+        let conn = SQLiteConnection::open(&path, self.pool_size, &self.registry)?;
+        let conn = Arc::new(Mutex::new(conn));
+        open.insert(tenant.to_string(), conn.clone());
+        Ok(conn)
+    }
+
+    /// Returns the disk usage of every tenant with a database on this
+    /// replica, by tenant id.
+    pub(crate) fn usage(&self) -> Result<Vec<TenantUsage>, StoreError> {
+        let dir = tenants_dir(&self.dir, self.id);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut usage = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "db") {
+                continue;
+            }
+            if let Some(tenant) = path.file_stem().and_then(|stem| stem.to_str()) {
+                usage.push(TenantUsage {
+                    tenant: tenant.to_string(),
+                    bytes: std::fs::metadata(&path)?.len(),
+                });
+            }
+        }
+        usage.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        Ok(usage)
+    }
+}
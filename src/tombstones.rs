@@ -0,0 +1,111 @@
+//! Hard delete tombstones.
+//!
+//! A hard delete runs its `DELETE` with `secure_delete` on, so the deleted
+//! rows are overwritten in the database file, and records a tombstone in the
+//! replicated `chiselstore_tombstones` table. Copies of the database taken
+//! before the delete still hold the rows; the next purge replays the
+//! tombstoned deletes against the backups the replica has written.
+
+use crate::backup::BACKUP_TABLE;
+use crate::errors::StoreError;
+use crate::server::query_connection;
+use crate::value::quote_literal;
+use sqlite::Connection;
+use std::path::Path;
+
+/// Table recording the tombstones of hard deletes.
+pub const TOMBSTONE_TABLE: &str = "chiselstore_tombstones";
+
+/// A hard delete recorded for purging from retained copies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tombstone {
+    /// What was deleted, as described by the caller (e.g. `user:42`).
+    pub subject: String,
+    /// The delete statement.
+    pub sql: String,
+    /// Applied index of the hard delete.
+    pub applied_idx: u64,
+}
+
+/// Runs a hard delete on `conn` and records its tombstone.
+pub(crate) fn apply_hard_delete(
+    conn: &Connection,
+    tombstone: &Tombstone,
+) -> Result<(), StoreError> {
+    conn.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {} \
+            (subject TEXT NOT NULL, sql TEXT NOT NULL, applied_idx INTEGER NOT NULL)",
+        TOMBSTONE_TABLE
+    ))?;
+    conn.execute("PRAGMA secure_delete = ON")?;
+    let result = conn.execute(&tombstone.sql).and_then(|_| {
+        conn.execute(format!(
+            "INSERT INTO {} VALUES ({}, {}, {})",
+            TOMBSTONE_TABLE,
+            quote_literal(&tombstone.subject),
+            quote_literal(&tombstone.sql),
+            tombstone.applied_idx
+        ))
+    });
+    conn.execute("PRAGMA secure_delete = OFF")?;
+    Ok(result?)
+}
+
+/// Reads the tombstones recorded after `after_idx`, oldest first.
+pub(crate) fn tombstones_after(
+    conn: &Connection,
+    after_idx: u64,
+) -> Result<Vec<Tombstone>, StoreError> {
+    let exists = query_connection(
+        conn,
+        format!(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '{}'",
+            TOMBSTONE_TABLE
+        ),
+    )?;
+    if exists.rows.is_empty() {
+        return Ok(vec![]);
+    }
+    let rows = query_connection(
+        conn,
+        format!(
+            "SELECT subject, sql, applied_idx FROM {} WHERE applied_idx > {} ORDER BY applied_idx",
+            TOMBSTONE_TABLE, after_idx
+        ),
+    )?;
+    Ok(rows
+        .rows
+        .into_iter()
+        .map(|row| Tombstone {
+            subject: row.values[0].clone(),
+            sql: row.values[1].clone(),
+            applied_idx: row.values[2].parse().unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Replays the tombstoned deletes newer than the backup at `path` against
+/// it, then vacuums it. Returns the number of deletes replayed.
+pub(crate) fn purge_backup(path: &Path, tombstones: &[Tombstone]) -> Result<usize, StoreError> {
+    let conn = Connection::open(path)?;
+    let backup_idx: u64 =
+        query_connection(&conn, format!("SELECT applied_idx FROM {}", BACKUP_TABLE))?
+            .rows
+            .first()
+            .and_then(|row| row.values.first())
+            .and_then(|idx| idx.parse().ok())
+            .unwrap_or_default();
+    conn.execute("PRAGMA secure_delete = ON")?;
+    let mut purged = 0;
+    for tombstone in tombstones
+        .iter()
+        .filter(|tombstone| tombstone.applied_idx > backup_idx)
+    {
+        conn.execute(&tombstone.sql)?;
+        purged += 1;
+    }
+    if purged > 0 {
+        conn.execute("VACUUM")?;
+    }
+    Ok(purged)
+}
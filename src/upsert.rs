@@ -0,0 +1,92 @@
+//! Bulk upsert statement generation.
+
+use crate::errors::StoreError;
+use crate::value::{quote_identifier, Value};
+
+/// Maximum number of rows per generated upsert statement.
+pub const UPSERT_CHUNK_ROWS: usize = 500;
+
+/// Builds `INSERT ... ON CONFLICT` statements upserting `rows` into `table`.
+///
+/// Rows are split into chunks of at most `UPSERT_CHUNK_ROWS`, in order. On a
+/// conflict on `key_columns`, the non-key columns are overwritten with the
+/// new values; if every column is a key column, conflicting rows are left
+/// unchanged. Fails if a value has no SQL literal, such as a NaN real.
+pub fn upsert_statements(
+    table: &str,
+    columns: &[&str],
+    key_columns: &[&str],
+    rows: &[Vec<Value>],
+) -> Result<Vec<String>, StoreError> {
+    if columns.is_empty() {
+        return Err(StoreError::InvalidRequest(String::from(
+            "upsert needs at least one column",
+        )));
+    }
+    if key_columns.is_empty() {
+        return Err(StoreError::InvalidRequest(String::from(
+            "upsert needs at least one key column",
+        )));
+    }
+    if let Some(key) = key_columns.iter().find(|key| !columns.contains(key)) {
+        return Err(StoreError::InvalidRequest(format!(
+            "upsert key column {} is not one of the columns",
+            key
+        )));
+    }
+    if let Some(row) = rows.iter().find(|row| row.len() != columns.len()) {
+        return Err(StoreError::InvalidRequest(format!(
+            "upsert row has {} values for {} columns",
+            row.len(),
+            columns.len()
+        )));
+    }
+
+    let column_list = join_identifiers(columns.iter());
+    let key_list = join_identifiers(key_columns.iter());
+    let updates: Vec<String> = columns
+        .iter()
+        .filter(|column| !key_columns.contains(column))
+        .map(|column| {
+            let column = quote_identifier(column);
+            format!("{} = excluded.{}", column, column)
+        })
+        .collect();
+    let on_conflict = if updates.is_empty() {
+        String::from("DO NOTHING")
+    } else {
+        format!("DO UPDATE SET {}", updates.join(", "))
+    };
+
+    let statements = rows
+        .chunks(UPSERT_CHUNK_ROWS)
+        .map(|chunk| {
+            let values = chunk
+                .iter()
+                .map(|row| {
+                    let literals = row
+                        .iter()
+                        .map(Value::to_sql_literal)
+                        .collect::<Result<Vec<_>, _>>()?;
+                    Ok(format!("({})", literals.join(", ")))
+                })
+                .collect::<Result<Vec<_>, StoreError>>()?;
+            Ok(format!(
+                "INSERT INTO {} ({}) VALUES {} ON CONFLICT ({}) {}",
+                quote_identifier(table),
+                column_list,
+                values.join(", "),
+                key_list,
+                on_conflict
+            ))
+        })
+        .collect::<Result<Vec<_>, StoreError>>()?;
+    Ok(statements)
+}
+
+fn join_identifiers<'a, I: Iterator<Item = &'a &'a str>>(names: I) -> String {
+    names
+        .map(|name| quote_identifier(name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,80 @@
+//! Validation of commands before they are proposed.
+//!
+//! A `ProposalValidator` runs on the node a command is submitted to, before
+//! the command enters the replicated log. Rejected commands fail with
+//! `StoreError::ProposalRejected` without consuming log space or failing
+//! on every replica at apply time.
+
+use crate::server::{CommandKind, StoreCommand};
+use sqlite::Connection;
+
+/// Checks commands before they are proposed.
+pub trait ProposalValidator: Send + Sync {
+    /// Returns why `cmd` must not be proposed, if it must not. `conn` is a
+    /// connection to this node's database, e.g. for preparing statements.
+    fn validate(&self, cmd: &StoreCommand, conn: &Connection) -> Result<(), String>;
+}
+
+/// Rejects commands whose SQL does not parse.
+///
+/// Only the first statement of each command is prepared. Statements that
+/// parse but refer to tables or columns that do not exist yet pass, since
+/// an earlier command still in flight may create them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SyntaxCheck;
+
+impl ProposalValidator for SyntaxCheck {
+    fn validate(&self, cmd: &StoreCommand, conn: &Connection) -> Result<(), String> {
+        let mut statements = vec![cmd.sql.as_str()];
+        if let CommandKind::Conditional { predicate } = &cmd.kind {
+            statements.push(predicate);
+        }
+        for sql in statements.into_iter().filter(|sql| !sql.trim().is_empty()) {
+            if let Err(e) = conn.prepare(sql) {
+                let message = e.message.unwrap_or_default();
+                if message.contains("syntax error") || message.contains("incomplete input") {
+                    return Err(message);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rejects commands larger than a number of bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct MaxCommandSize(pub usize);
+
+impl ProposalValidator for MaxCommandSize {
+    fn validate(&self, cmd: &StoreCommand, _conn: &Connection) -> Result<(), String> {
+        let size = cmd.sql.len()
+            + match &cmd.kind {
+                CommandKind::Statement => 0,
+                CommandKind::Publish { topic, payload } => topic.len() + payload.len(),
+                CommandKind::Conditional { predicate } => predicate.len(),
+                CommandKind::HardDelete { subject } => subject.len(),
+                CommandKind::SetConfig { key, value } => key.len() + value.len(),
+                CommandKind::Transaction | CommandKind::Prepare => 0,
+                CommandKind::ExecutePrepared { .. } => std::mem::size_of::<u64>(),
+                CommandKind::Migration { name, .. } => std::mem::size_of::<u64>() + name.len(),
+            }
+            + cmd.tenant.as_ref().map_or(0, String::len)
+            + cmd
+                .pragmas
+                .iter()
+                .map(|pragma| pragma.name.len() + 1)
+                .sum::<usize>()
+            + cmd
+                .params
+                .iter()
+                .map(|param| param.to_sql_literal().map_or(0, |literal| literal.len()))
+                .sum::<usize>();
+        if size > self.0 {
+            return Err(format!(
+                "command is {} bytes, more than the limit of {}",
+                size, self.0
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! SQL values.
+
+use crate::errors::StoreError;
+use crate::proto::{sql_value, SqlValue};
+use std::fmt;
+
+/// A SQLite value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// SQL `NULL`.
+    Null,
+    /// A 64-bit signed integer.
+    Integer(i64),
+    /// A 64-bit floating point number.
+    Real(f64),
+    /// A UTF-8 string.
+    Text(String),
+    /// A byte string.
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// Renders the value as a SQL literal.
+    ///
+    /// The rendering only depends on the value, so statements built from
+    /// literals are identical on every replica. NaN and infinite reals have
+    /// no literal and are rejected.
+    pub fn to_sql_literal(&self) -> Result<String, StoreError> {
+        Ok(match self {
+            Value::Null => String::from("NULL"),
+            Value::Integer(i) => i.to_string(),
+            // `{:?}` keeps a decimal point and round-trips the exact value.
+            Value::Real(f) if f.is_finite() => format!("{:?}", f),
+            Value::Real(f) => {
+                return Err(StoreError::InvalidRequest(format!(
+                    "{} has no SQL literal",
+                    f
+                )))
+            }
+            Value::Text(s) => quote_literal(s),
+            Value::Blob(b) => format!("X'{}'", hex(b)),
+        })
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Real(r) => write!(f, "{}", r),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Blob(b) => write!(f, "{}", hex(b)),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Integer(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::Real(f)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Text(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Blob(b)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(b: &[u8]) -> Self {
+        Value::Blob(b.to_vec())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        match v {
+            Some(v) => v.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl From<Value> for SqlValue {
+    fn from(v: Value) -> Self {
+        let value = match v {
+            Value::Null => None,
+            Value::Integer(i) => Some(sql_value::Value::Integer(i)),
+            Value::Real(f) => Some(sql_value::Value::Real(f)),
+            Value::Text(s) => Some(sql_value::Value::Text(s)),
+            Value::Blob(b) => Some(sql_value::Value::Blob(b)),
+        };
+        SqlValue { value }
+    }
+}
+
+impl From<SqlValue> for Value {
+    fn from(v: SqlValue) -> Self {
+        match v.value {
+            None => Value::Null,
+            Some(sql_value::Value::Integer(i)) => Value::Integer(i),
+            Some(sql_value::Value::Real(f)) => Value::Real(f),
+            Some(sql_value::Value::Text(s)) => Value::Text(s),
+            Some(sql_value::Value::Blob(b)) => Value::Blob(b),
+        }
+    }
+}
+
+/// Renders bytes as uppercase hex.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Quotes a string as a SQL string literal.
+pub fn quote_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Quotes a name as a SQL identifier.
+pub fn quote_identifier(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
@@ -0,0 +1,138 @@
+//! Page cache warming at startup and after apply.
+//!
+//! Every pooled SQLite connection keeps its own page cache, which a write
+//! through another connection invalidates. Without warming, the first read
+//! of a table after replication pays for reloading its pages from disk.
+//! With `StoreConfig::warm_after_apply`, the message event loop of a
+//! replica reads the most recent rows of every table written since its
+//! last pass on each idle pooled connection, so reads right after a write
+//! find the pages cached.
+//!
+//! A node that just started has cold caches everywhere, so the first
+//! requests after a deploy are slow. `StoreConfig::warmup_queries` lists
+//! representative queries that a node runs on each of its pooled
+//! connections before it reports ready: queries that are prepared
+//! statements are compiled into the statement cache of the connection
+//! without being run, and the others, which must be reads, are run to the
+//! last row to load their pages. Queries failing, e.g. on tables that do
+//! not exist yet, are counted and skipped. `StoreServer::warmup_report`
+//! tells what warming did.
+
+use crate::errors::StoreError;
+use crate::prepared::{self, StatementCache};
+use crate::rows;
+use crate::statements;
+use crate::value::quote_identifier;
+use sqlite::Connection;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Number of most recent rows read to warm a table.
+const WARM_ROWS: usize = 64;
+
+/// Tables written since they were last warmed.
+#[derive(Debug, Default)]
+pub(crate) struct TouchedTables {
+    tables: HashSet<String>,
+}
+
+impl TouchedTables {
+    /// Records the tables written by the statements of `sql`.
+    pub(crate) fn record(&mut self, sql: &str) {
+        self.tables.extend(sql.split(';').filter_map(written_table));
+    }
+
+    /// Returns the recorded tables, forgetting them.
+    pub(crate) fn take(&mut self) -> Vec<String> {
+        self.tables.drain().collect()
+    }
+}
+
+/// Returns the table an `INSERT`, `REPLACE`, `UPDATE` or `DELETE` statement
+/// writes to.
+pub(crate) fn written_table(stmt: &str) -> Option<String> {
+    let mut tokens = stmt.split_whitespace();
+    let keyword = tokens.next()?.to_lowercase();
+    let table = match keyword.as_str() {
+        "insert" | "replace" => {
+            tokens.find(|token| token.eq_ignore_ascii_case("into"))?;
+            tokens.next()?
+        }
+        "delete" => {
+            tokens.find(|token| token.eq_ignore_ascii_case("from"))?;
+            tokens.next()?
+        }
+        // `UPDATE [OR <resolution>] <table>`.
+        "update" => match tokens.next()? {
+            token if token.eq_ignore_ascii_case("or") => tokens.nth(1)?,
+            token => token,
+        },
+        _ => return None,
+    };
+    let table = table.split('(').next()?;
+    let table = table.trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'));
+    match table.is_empty() {
+        true => None,
+        false => Some(table.to_string()),
+    }
+}
+
+/// What warming up a node at startup did, counting every pooled
+/// connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WarmupReport {
+    /// Queries run.
+    pub queries_run: usize,
+    /// Prepared statements compiled.
+    pub statements_compiled: usize,
+    /// Queries that failed.
+    pub failed: usize,
+    /// How long warming took.
+    pub elapsed: Duration,
+}
+
+/// Runs the warmup `queries` on `conn`, compiling prepared statements into
+/// `cache`, the statement cache of the connection. Fails if a query that is
+/// not a prepared statement writes.
+pub(crate) fn warm_up(
+    conn: &Connection,
+    cache: &mut StatementCache,
+    queries: &[String],
+    report: &mut WarmupReport,
+) -> Result<(), StoreError> {
+    for sql in queries {
+        let id = prepared::statement_id(sql);
+        if prepared::lookup(conn, id)?.is_some() {
+            match cache.compile(conn, id) {
+                Ok(()) => report.statements_compiled += 1,
+                Err(_) => report.failed += 1,
+            }
+            continue;
+        }
+        if statements::is_write(sql) {
+            return Err(StoreError::InvalidRequest(format!(
+                "warmup query {:?} writes; only reads and prepared statements can be warmup queries",
+                sql
+            )));
+        }
+        match rows::for_each_row(conn, sql.clone(), &[], |_| {}, |_| true) {
+            Ok(()) => report.queries_run += 1,
+            Err(_) => report.failed += 1,
+        }
+    }
+    Ok(())
+}
+
+/// Reads the most recent rows of `tables` on `conn`, loading their pages
+/// into its cache. Tables that cannot be read, e.g. because they were
+/// dropped since, are skipped.
+pub(crate) fn warm(conn: &Connection, tables: &[String]) {
+    for table in tables {
+        let sql = format!(
+            "SELECT * FROM {} ORDER BY rowid DESC LIMIT {}",
+            quote_identifier(table),
+            WARM_ROWS
+        );
+        let _ = rows::for_each_row(conn, sql, &[], |_| {}, |_| true);
+    }
+}
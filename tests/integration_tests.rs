@@ -1,7 +1,40 @@
 mod setup;
+use aes_siv::aead::generic_array::GenericArray;
+use aes_siv::aead::{Aead, NewAead};
+use aes_siv::Aes256SivAead;
+use chiselstore::advertise::{DnsZoneRegistry, LeaderInfo, LeaderRegistry};
+use chiselstore::backup::verify_backup;
+use chiselstore::batching::{LatencySlo, MIN_WINDOW};
+use chiselstore::bootstrap::join_cluster;
+use chiselstore::checksum::rows_checksum;
+use chiselstore::client::WriteOutcome;
+use chiselstore::controller::{ClusterController, ClusterSpec, ReconcileAction, ReconcileEvent};
+use chiselstore::counters::Counters;
+use chiselstore::diagnostics::StallReport;
+use chiselstore::encryption::{encryption_functions, StaticSecrets, KEY_LEN};
+use chiselstore::functions::{CollationDef, FunctionDef};
+use chiselstore::import::import_database;
 use chiselstore::logger;
+use chiselstore::membership::check_initial_membership;
+use chiselstore::migration::{migrate_tenant, route_statement};
+use chiselstore::network::{LinkProfile, Network};
+use chiselstore::outbox::OutboxConsumer;
+use chiselstore::reconfiguration::{ReconfigurationManager, Transition};
+use chiselstore::replay::replay;
+use chiselstore::retry::RetryBudget;
+use chiselstore::schema::SchemaMigrator;
+use chiselstore::statements::{self, fingerprint};
+use chiselstore::validation::{MaxCommandSize, ProposalValidator, SyntaxCheck};
+use chiselstore::{
+    ApplyErrorAction, ApplyErrorPolicy, ChiselStoreClient, ClientError, CommandKind,
+    FunctionRegistry, Learner, Lifecycle, MultiClusterClient, ServerConfig, StoreCommand,
+    StoreConfig, StoreError, Value,
+};
+use omnipaxos_core::storage::StopSign;
 use setup::proto::Consistency;
 use slog::info;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_database_connection() {
@@ -37,14 +70,14 @@ async fn test_consistency_relaxed() {
             String::from(
                 "CREATE TABLE IF NOT EXISTS test_consistency_relaxed (i INTEGER PRIMARY KEY);",
             ),
-            Consistency::RelaxedReads,
+            Consistency::Strong,
         )
         .await;
 
         setup::execute_query(
             1,
             String::from("INSERT INTO test_consistency_relaxed VALUES(50);"),
-            Consistency::RelaxedReads,
+            Consistency::Strong,
         )
         .await;
     });
@@ -80,7 +113,7 @@ async fn test_consistency_relaxed() {
     setup::execute_query(
         1,
         String::from("DROP TABLE IF EXISTS test_consistency_relaxed;"),
-        Consistency::RelaxedReads,
+        Consistency::Strong,
     )
     .await;
 
@@ -129,7 +162,7 @@ async fn test_consistency_strong() {
     setup::execute_query(
         1,
         String::from("DROP TABLE IF EXISTS test_consistency_strong;"),
-        Consistency::RelaxedReads,
+        Consistency::Strong,
     )
     .await;
 
@@ -292,14 +325,14 @@ async fn test_shutdown_leader() {
             String::from(
                 "CREATE TABLE IF NOT EXISTS test_shutdown_leader (i INTEGER PRIMARY KEY);",
             ),
-            Consistency::RelaxedReads,
+            Consistency::Strong,
         )
         .await;
 
         setup::execute_query(
             1,
             String::from("INSERT INTO test_shutdown_leader VALUES(50);"),
-            Consistency::RelaxedReads,
+            Consistency::Strong,
         )
         .await;
     });
@@ -347,3 +380,4943 @@ async fn test_shutdown_leader() {
     replica_one.halt_replica().await;
     replica_two.halt_replica().await;
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_introspection_tables() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_introspection_tables test ----");
+    let status = setup::execute_query(
+        2,
+        String::from("SELECT node_id FROM chiselstore_status"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(status, vec!["2"]);
+
+    let members = setup::execute_query(
+        2,
+        String::from("SELECT id FROM chiselstore_members ORDER BY id"),
+        Consistency::RelaxedReads,
+    )
+    .await;
+    assert_eq!(members, vec!["1", "2", "3"]);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_snapshot() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_read_snapshot test ----");
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_read_snapshot (i INTEGER PRIMARY KEY);",
+        "INSERT INTO test_read_snapshot VALUES(1);",
+    ] {
+        setup::execute_query(1, String::from(stmt), Consistency::Strong).await;
+    }
+
+    let snapshot = cluster[0].server().read_snapshot().unwrap();
+    setup::execute_query(
+        1,
+        String::from("INSERT INTO test_read_snapshot VALUES(2);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    let rows = snapshot
+        .query("SELECT count(*) FROM test_read_snapshot")
+        .unwrap()
+        .rows;
+    assert_eq!(rows[0].values, vec!["1"]);
+    let path = snapshot.path().to_path_buf();
+    drop(snapshot);
+    assert!(!path.exists());
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_read_snapshot;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[cfg(feature = "arrow-export")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_export_tables() {
+    use chiselstore::export::{export_tables, ExportFormat, APPLIED_IDX_KEY};
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_export_tables test ----");
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_export (i INTEGER, r REAL, t TEXT, b BLOB, m);",
+        "INSERT INTO test_export VALUES(1, 1.5, 'one', X'01', 1), (2, 2, NULL, X'02', 'two');",
+    ] {
+        setup::execute_query(1, String::from(stmt), Consistency::Strong).await;
+    }
+
+    let snapshot = cluster[0].server().read_snapshot().unwrap();
+    setup::execute_query(
+        1,
+        String::from("INSERT INTO test_export VALUES(3, 3.5, 'three', X'03', 3);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    let dir = std::env::temp_dir().join(format!("chiselstore-export-{}", std::process::id()));
+    let report = export_tables(&snapshot, &["test_export"], &dir, ExportFormat::Arrow).unwrap();
+    assert_eq!(report.applied_idx, snapshot.applied_idx());
+    assert_eq!(report.tables[0].rows, 2);
+    let file = std::fs::File::open(&report.tables[0].path).unwrap();
+    let reader = arrow::ipc::reader::FileReader::try_new(file).unwrap();
+    let schema = reader.schema();
+    assert_eq!(
+        schema.metadata().get(APPLIED_IDX_KEY),
+        Some(&snapshot.applied_idx().to_string())
+    );
+    let types: Vec<_> = schema
+        .fields()
+        .iter()
+        .map(|f| f.data_type().clone())
+        .collect();
+    use arrow::datatypes::DataType;
+    assert_eq!(
+        types,
+        vec![
+            DataType::Int64,
+            DataType::Float64,
+            DataType::Utf8,
+            DataType::Binary,
+            DataType::Utf8
+        ]
+    );
+    let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+    assert_eq!(rows, 2);
+
+    let report = export_tables(&snapshot, &["test_export"], &dir, ExportFormat::Parquet).unwrap();
+    assert!(report.tables[0].path.exists());
+    assert!(export_tables(&snapshot, &["missing"], &dir, ExportFormat::Parquet).is_err());
+    assert!(export_tables(&snapshot, &["../test_export"], &dir, ExportFormat::Parquet).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_export;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[test]
+fn test_membership_fencing() {
+    let path = std::env::temp_dir().join(format!("chiselstore-{}.members", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    check_initial_membership(&path, 1, &[2, 3], false).unwrap();
+    check_initial_membership(&path, 1, &[3, 2], false).unwrap();
+    assert!(matches!(
+        check_initial_membership(&path, 1, &[2], false),
+        Err(StoreError::MembershipMismatch { .. })
+    ));
+    check_initial_membership(&path, 1, &[2], true).unwrap();
+    check_initial_membership(&path, 1, &[2], false).unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_drain_rejects_new_requests() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(
+        logger,
+        "---- Running test_drain_rejects_new_requests test ----"
+    );
+    setup::execute_query(1, String::from("SELECT 1"), Consistency::RelaxedReads).await;
+
+    let server = cluster[0].server();
+    assert!(server.drain(Duration::from_secs(1)).await);
+    assert_eq!(server.lifecycle(), Lifecycle::Draining);
+
+    let status = setup::try_execute_query(1, String::from("SELECT 1"), Consistency::RelaxedReads)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[test]
+fn test_statement_fingerprints() {
+    assert_eq!(
+        fingerprint("SELECT *  FROM t1 WHERE id = 42 AND name = 'it''s';"),
+        "select * from t1 where id = ? and name = ?"
+    );
+    assert_eq!(fingerprint("select 1.5"), fingerprint("SELECT 2"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_statement_statistics() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_statement_statistics test ----");
+    for stmt in ["SELECT 1", "SELECT 2"] {
+        setup::execute_query(1, String::from(stmt), Consistency::RelaxedReads).await;
+    }
+    let calls = setup::execute_query(
+        1,
+        String::from(
+            "SELECT calls, rows FROM chiselstore_statements WHERE fingerprint = 'select ?'",
+        ),
+        Consistency::RelaxedReads,
+    )
+    .await;
+    assert_eq!(calls, vec!["2", "2"]);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_deprecated_api_telemetry() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(
+        logger,
+        "---- Running test_deprecated_api_telemetry test ----"
+    );
+    // The client speaks the v2 API, which is not deprecated.
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query("SELECT 1", chiselstore::proto::Consistency::RelaxedReads)
+        .await
+        .unwrap();
+    assert!(cluster[0].server().deprecated_calls().snapshot().is_empty());
+
+    // The v1 service still serves the same queries, counting each call.
+    for _ in 0..2 {
+        let rows =
+            setup::execute_query(1, String::from("SELECT 1"), Consistency::RelaxedReads).await;
+        assert_eq!(rows, vec!["1"]);
+    }
+    let usage = client
+        .query(
+            "SELECT method, calls FROM chiselstore_deprecated_calls",
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    assert_eq!(usage.rows.len(), 1);
+    assert_eq!(usage.rows[0].values, ["Execute", "2"]);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[cfg(feature = "fault-injection")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_fault_injection() {
+    use chiselstore::faults::{Fault, FaultInjection};
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_fault_injection test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_faults (i INTEGER);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+
+    cluster[0]
+        .server()
+        .set_fault_injection(Some(FaultInjection {
+            probability: 1.0,
+            faults: vec![Fault::Busy],
+            seed: Some(7),
+        }));
+    let err = client
+        .query(
+            "INSERT INTO test_faults VALUES (1);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap_err();
+    match err {
+        ClientError::Status(status) => {
+            assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+            let fault = status.metadata().get("injected-fault").unwrap();
+            assert_eq!(fault.to_str().unwrap(), "busy");
+        }
+        e => panic!("unexpected error: {}", e),
+    }
+
+    // A node that pretends to have lost the leadership is failed over, and
+    // the write it rejected was never applied.
+    cluster[0]
+        .server()
+        .set_fault_injection(Some(FaultInjection {
+            probability: 1.0,
+            faults: vec![Fault::NotLeader],
+            seed: Some(7),
+        }));
+    let mut failover =
+        ChiselStoreClient::with_nodes(["http://127.0.0.1:50001", "http://127.0.0.1:50002"])
+            .unwrap();
+    let results = failover
+        .query(
+            "SELECT COUNT(*) FROM test_faults;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["0"]);
+    cluster[0].server().set_fault_injection(None);
+
+    // A follower rejecting a write names the leader to redirect it to.
+    let leader = cluster[0].server().get_cluster_leader();
+    let follower = cluster
+        .iter()
+        .find(|replica| replica.get_replica_id() != leader)
+        .unwrap();
+    follower.server().set_fault_injection(Some(FaultInjection {
+        probability: 1.0,
+        faults: vec![Fault::NotLeader],
+        seed: Some(7),
+    }));
+    let mut direct = ChiselStoreClient::new(format!(
+        "http://127.0.0.1:5000{}",
+        follower.get_replica_id()
+    ))
+    .unwrap();
+    let err = direct
+        .query(
+            "INSERT INTO test_faults VALUES (2);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap_err();
+    let leader_addr = format!("http://127.0.0.1:5000{}", leader);
+    assert_eq!(err.leader_addr(), Some(leader_addr.as_str()));
+    follower.server().set_fault_injection(None);
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_faults;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_backup_verification() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_backup_verification test ----");
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_backup_verification (i INTEGER PRIMARY KEY);",
+        "INSERT INTO test_backup_verification VALUES(7);",
+    ] {
+        setup::execute_query(1, String::from(stmt), Consistency::Strong).await;
+    }
+
+    let path = std::env::temp_dir().join(format!("chiselstore-backup-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let applied_idx = cluster[0].server().backup(&path).unwrap();
+
+    let report = verify_backup(
+        &path,
+        &[
+            "SELECT 1 FROM test_backup_verification WHERE i = 7",
+            "SELECT 1 FROM test_backup_verification WHERE i = 8",
+        ],
+    )
+    .unwrap();
+    assert_eq!(report.applied_idx, applied_idx);
+    assert!(report.integrity_errors.is_empty());
+    assert!(report.validations[0].passed);
+    assert!(!report.validations[1].passed);
+    std::fs::remove_file(&path).unwrap();
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_backup_verification;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_hard_delete_purges_backups() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(
+        logger,
+        "---- Running test_hard_delete_purges_backups test ----"
+    );
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_hard_delete (i INTEGER PRIMARY KEY);",
+        "INSERT INTO test_hard_delete VALUES(1);",
+    ] {
+        setup::execute_query(1, String::from(stmt), Consistency::Strong).await;
+    }
+
+    let server = cluster[0].server();
+    let path = std::env::temp_dir().join(format!("chiselstore-purge-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    server.backup(&path).unwrap();
+
+    server
+        .hard_delete("row:1", "DELETE FROM test_hard_delete WHERE i = 1")
+        .await
+        .unwrap();
+    assert_eq!(server.purge_tombstones().unwrap(), 1);
+
+    let report = verify_backup(&path, &["SELECT 1 FROM test_hard_delete WHERE i = 1"]).unwrap();
+    assert!(!report.validations[0].passed);
+    std::fs::remove_file(&path).unwrap();
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_hard_delete;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_config_store() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_config_store test ----");
+    let follower = cluster[1].server();
+    let mut watch = follower.watch_config("feature.");
+
+    let server = cluster[0].server();
+    server.set_config("limits.rps", "100").await.unwrap();
+    server.set_config("feature.search", "on").await.unwrap();
+
+    let change = tokio::time::timeout(Duration::from_secs(5), watch.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(change.key, "feature.search");
+    assert_eq!(change.value, "on");
+    assert_eq!(
+        server.get_config("feature.search").await.unwrap(),
+        Some("on".to_string())
+    );
+    assert_eq!(server.get_config("feature.missing").await.unwrap(), None);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_response_checksums() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(2);
+
+    info!(logger, "---- Running test_response_checksums test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_checksums();
+    let results = client
+        .query(
+            "SELECT 1, 'two'",
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.checksum, Some(rows_checksum(&results.rows)));
+
+    let mut rows = results.rows;
+    rows[0].values[1] = String::from("tw0");
+    assert_ne!(results.checksum, Some(rows_checksum(&rows)));
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_link_latency() {
+    let logger = logger::create_logger();
+    let network = Network::default();
+    let cluster = setup::make_cluster_with_network(3, &network);
+
+    info!(logger, "---- Running test_link_latency test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_link_latency (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    // Committing needs a round trip between the leader and a follower.
+    let latency = Duration::from_millis(40);
+    network.set_links_between(&[1, 2, 3], &[1, 2, 3], LinkProfile::latency(latency));
+    let started = Instant::now();
+    setup::execute_query(
+        1,
+        String::from("INSERT OR REPLACE INTO test_link_latency VALUES(1);"),
+        Consistency::Strong,
+    )
+    .await;
+    assert!(started.elapsed() >= latency);
+    network.clear();
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_link_latency;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[test]
+fn test_link_profiles() {
+    let network = Network::default();
+    assert_eq!(network.delay(1, 2, 1000), Duration::ZERO);
+
+    // Jitter stays within its bound on top of the latency, and varies.
+    let latency = Duration::from_millis(10);
+    let jitter = Duration::from_millis(5);
+    network.set_link(
+        1,
+        2,
+        LinkProfile {
+            latency,
+            jitter,
+            bandwidth: None,
+        },
+    );
+    let delays: Vec<_> = (0..1000).map(|_| network.delay(1, 2, 0)).collect();
+    assert!(delays
+        .iter()
+        .all(|delay| *delay >= latency && *delay <= latency + jitter));
+    assert!(delays.iter().any(|delay| *delay != delays[0]));
+
+    // Links are directed, and bandwidth adds the transfer time of a message.
+    assert_eq!(network.delay(2, 1, 0), Duration::ZERO);
+    network.set_link(
+        2,
+        1,
+        LinkProfile {
+            latency,
+            jitter: Duration::ZERO,
+            bandwidth: Some(10_000),
+        },
+    );
+    assert_eq!(network.delay(2, 1, 0), latency);
+    assert_eq!(
+        network.delay(2, 1, 1000),
+        latency + Duration::from_millis(100)
+    );
+
+    // Profiles can be replaced and cleared at any time.
+    network.set_link(2, 1, LinkProfile::latency(Duration::from_millis(1)));
+    assert_eq!(network.delay(2, 1, 1000), Duration::from_millis(1));
+    network.clear();
+    assert_eq!(network.delay(1, 2, 0), Duration::ZERO);
+    assert_eq!(network.delay(2, 1, 1000), Duration::ZERO);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_link_bandwidth() {
+    let logger = logger::create_logger();
+    let network = Network::default();
+    let cluster = setup::make_cluster_with_network(3, &network);
+
+    info!(logger, "---- Running test_link_bandwidth test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_link_bandwidth (s TEXT);"),
+        Consistency::Strong,
+    )
+    .await;
+    let insert = format!(
+        "INSERT INTO test_link_bandwidth VALUES ('{}');",
+        "x".repeat(100_000)
+    );
+
+    // Replicating the statement takes its transfer time over the links.
+    network.set_links_between(
+        &[1, 2, 3],
+        &[1, 2, 3],
+        LinkProfile {
+            bandwidth: Some(200_000),
+            ..Default::default()
+        },
+    );
+    let started = Instant::now();
+    setup::execute_query(1, insert.clone(), Consistency::Strong).await;
+    let throttled = started.elapsed();
+    assert!(throttled >= Duration::from_millis(500), "{:?}", throttled);
+
+    network.clear();
+    let started = Instant::now();
+    setup::execute_query(1, insert, Consistency::Strong).await;
+    assert!(started.elapsed() < throttled);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE test_link_bandwidth;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+/// Median commit latency of writes proposed on `server`.
+async fn commit_latency(
+    server: &chiselstore::StoreServer<chiselstore::rpc::RpcTransport>,
+) -> Duration {
+    let mut latencies = Vec::new();
+    for i in 0..5 {
+        let started = Instant::now();
+        server
+            .query(
+                format!("INSERT OR REPLACE INTO test_two_regions VALUES ({});", i),
+                chiselstore::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+        latencies.push(started.elapsed());
+    }
+    latencies.sort();
+    latencies[latencies.len() / 2]
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_two_regions() {
+    let logger = logger::create_logger();
+    let network = Network::default();
+    let mut cluster = setup::make_cluster_with_network(3, &network);
+
+    info!(logger, "---- Running test_two_regions test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_two_regions (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+    let idx = cluster
+        .iter_mut()
+        .position(|replica| replica.replica_is_leader())
+        .unwrap();
+    cluster[idx]
+        .server()
+        .transfer_leadership(1, Duration::from_secs(60))
+        .await
+        .unwrap();
+
+    // Nodes 1 and 2 share a region, node 3 is in another one, and traffic
+    // towards it is slower than traffic back.
+    let local = LinkProfile::latency(Duration::from_millis(5));
+    network.set_links_between(&[1, 2], &[1, 2], local);
+    network.set_links_between(&[3], &[3], local);
+    for a in [1, 2] {
+        network.set_link(a, 3, LinkProfile::latency(Duration::from_millis(60)));
+        network.set_link(3, a, LinkProfile::latency(Duration::from_millis(30)));
+    }
+
+    // A leader in the larger region commits with its neighbour.
+    let near = commit_latency(&cluster[0].server()).await;
+    info!(
+        logger,
+        "Commit latency with the leader on node 1: {:?}", near
+    );
+
+    // A leader in the other region waits for a round trip between them.
+    cluster[0]
+        .server()
+        .transfer_leadership(3, Duration::from_secs(60))
+        .await
+        .unwrap();
+    let far = commit_latency(&cluster[2].server()).await;
+    info!(
+        logger,
+        "Commit latency with the leader on node 3: {:?}", far
+    );
+    assert!(far >= Duration::from_millis(90), "{:?}", far);
+    assert!(near < far, "{:?} >= {:?}", near, far);
+
+    network.clear();
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE test_two_regions;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_accepted_faster_than_link() {
+    let logger = logger::create_logger();
+    let network = Network::default();
+    let cluster = setup::make_cluster_with_network(3, &network);
+
+    info!(
+        logger,
+        "---- Running test_accepted_faster_than_link test ----"
+    );
+    setup::execute_query(
+        1,
+        String::from(
+            "CREATE TABLE IF NOT EXISTS test_accepted_faster_than_link (i INTEGER PRIMARY KEY);",
+        ),
+        Consistency::Strong,
+    )
+    .await;
+
+    // Writes arrive far more often than a message crosses a link, so every
+    // Accepted is followed by another before it is delivered.
+    network.set_links_between(
+        &[1, 2, 3],
+        &[1, 2, 3],
+        LinkProfile::latency(Duration::from_millis(50)),
+    );
+    let server = cluster[0].server();
+    let writes: Vec<_> = (0..50)
+        .map(|i| {
+            let server = server.clone();
+            tokio::task::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(2 * i)).await;
+                server
+                    .query(
+                        format!("INSERT INTO test_accepted_faster_than_link VALUES ({});", i),
+                        chiselstore::Consistency::Strong,
+                    )
+                    .await
+            })
+        })
+        .collect();
+    for write in writes {
+        tokio::time::timeout(Duration::from_secs(10), write)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+    network.clear();
+    let res = setup::execute_query(
+        1,
+        String::from("SELECT count(*) FROM test_accepted_faster_than_link;"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(res, vec!["50"]);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE test_accepted_faster_than_link;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_election_over_slow_links() {
+    let logger = logger::create_logger();
+    let network = Network::default();
+    // Heartbeats take longer to cross these links than the deadline of the
+    // RPCs carrying them used to be.
+    network.set_links_between(
+        &[1, 2, 3],
+        &[1, 2, 3],
+        LinkProfile::latency(Duration::from_millis(150)),
+    );
+    let cluster = setup::make_cluster_with_network(3, &network);
+
+    info!(
+        logger,
+        "---- Running test_election_over_slow_links test ----"
+    );
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let leaders: Vec<_> = cluster
+            .iter()
+            .map(|replica| replica.server().get_cluster_leader())
+            .collect();
+        if leaders[0] != 0 && leaders.iter().all(|leader| *leader == leaders[0]) {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "no leader elected: {:?}",
+            leaders
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    setup::execute_query(1, String::from("SELECT 1;"), Consistency::Strong).await;
+
+    network.clear();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replay_from_backup() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_replay_from_backup test ----");
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_replay (i INTEGER PRIMARY KEY);",
+        "INSERT OR REPLACE INTO test_replay VALUES(1);",
+    ] {
+        setup::execute_query(1, String::from(stmt), Consistency::Strong).await;
+    }
+
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("chiselstore-replay-{}.db", std::process::id()));
+    let output = dir.join(format!("chiselstore-replayed-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    cluster[0].server().backup(&path).unwrap();
+
+    let commands = vec![
+        StoreCommand::new(1, String::from("INSERT INTO test_replay VALUES(2)")),
+        StoreCommand::new(2, String::from("INSERT INTO test_replay VALUES(2)")),
+        StoreCommand {
+            kind: CommandKind::Conditional {
+                predicate: String::from("SELECT 1 FROM test_replay WHERE i = 2"),
+            },
+            ..StoreCommand::new(3, String::from("INSERT INTO test_replay VALUES(3)"))
+        },
+    ];
+    let report = replay(&path, &output, &FunctionRegistry::new(), commands).unwrap();
+    assert_eq!(report.applied_idx, report.start_idx + 3);
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].applied_idx, report.start_idx + 2);
+
+    let verified = verify_backup(&output, &["SELECT 1 FROM test_replay WHERE i = 3"]).unwrap();
+    assert!(verified.passed());
+    assert_eq!(verified.applied_idx, report.applied_idx);
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&output).unwrap();
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_replay;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_proofs() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_read_proofs test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_read_proofs();
+    let results = client
+        .query("SELECT 1", chiselstore::proto::Consistency::Strong)
+        .await
+        .unwrap();
+    let proof = results.proof.unwrap();
+    assert!(proof.decided_idx > 0);
+    assert!(proof.ballot.is_some());
+    assert!(proof.quorum.contains(&1));
+
+    let results = client
+        .query("SELECT 1", chiselstore::proto::Consistency::RelaxedReads)
+        .await
+        .unwrap();
+    assert!(results.proof.is_none());
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_query_params() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_query_params test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_query_params (id INTEGER PRIMARY KEY, name TEXT, score REAL)",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    client
+        .query_with_params(
+            "INSERT OR REPLACE INTO test_query_params VALUES (?, ?, ?)",
+            vec![Value::from(1), Value::from("O'Brien"), Value::Null],
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+
+    let results = client
+        .query_with_params(
+            "SELECT name, score FROM test_query_params WHERE id = ?",
+            vec![Value::from(1)],
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, vec!["O'Brien", "NULL"]);
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_query_params",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_typed_values() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(2);
+
+    info!(logger, "---- Running test_typed_values test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    let results = client
+        .query(
+            "SELECT 1, 2.5, 'three', X'00FF', NULL",
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    let row = results.rows.into_iter().next().unwrap();
+    let typed: Vec<Value> = row.typed_values.into_iter().map(Value::from).collect();
+    assert_eq!(
+        typed,
+        vec![
+            Value::Integer(1),
+            Value::Real(2.5),
+            Value::from("three"),
+            Value::Blob(vec![0x00, 0xFF]),
+            Value::Null,
+        ]
+    );
+    assert_eq!(row.values[..3], ["1", "2.5", "three"]);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_blob_round_trip() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_blob_round_trip test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_blobs (id INTEGER PRIMARY KEY, payload BLOB);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    // Every byte value, including NUL and bytes that are not valid UTF-8,
+    // and an empty payload.
+    let payloads: Vec<Vec<u8>> = vec![(0..=255).collect(), vec![]];
+    for (id, payload) in payloads.iter().enumerate() {
+        client
+            .query_with_params(
+                "INSERT INTO test_blobs VALUES (?, ?)",
+                vec![Value::Integer(id as i64), Value::from(&payload[..])],
+                chiselstore::proto::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+    }
+
+    // Read back from another replica, which applied the replicated params.
+    let mut replica = ChiselStoreClient::new("http://127.0.0.1:50002").unwrap();
+    replica
+        .query("SELECT 1", chiselstore::proto::Consistency::Strong)
+        .await
+        .unwrap();
+    let results = replica
+        .query(
+            "SELECT payload FROM test_blobs ORDER BY id",
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    let stored: Vec<Value> = results
+        .rows
+        .into_iter()
+        .flat_map(|row| row.typed_values.into_iter().map(Value::from))
+        .collect();
+    let expected: Vec<Value> = payloads.iter().cloned().map(Value::Blob).collect();
+    assert_eq!(stored, expected);
+
+    let results = replica
+        .query_with_params(
+            "SELECT id FROM test_blobs WHERE payload = ?",
+            vec![Value::Blob(payloads[0].clone())],
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["0"]);
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_blobs;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_column_metadata() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(2);
+
+    info!(logger, "---- Running test_column_metadata test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_columns (id INTEGER PRIMARY KEY, name TEXT);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let results = client
+        .query(
+            "SELECT id, name AS label, 1 + 1 FROM test_columns",
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    let columns: Vec<_> = results
+        .columns
+        .into_iter()
+        .map(|c| (c.name, c.decl_type, c.table))
+        .collect();
+    let table = Some(String::from("test_columns"));
+    assert_eq!(
+        columns,
+        vec![
+            (
+                String::from("id"),
+                Some(String::from("INTEGER")),
+                table.clone()
+            ),
+            (String::from("label"), Some(String::from("TEXT")), table),
+            (String::from("1 + 1"), None, None),
+        ]
+    );
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_columns;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_write_effects() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(2);
+
+    info!(logger, "---- Running test_write_effects test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_effects (id INTEGER PRIMARY KEY, v TEXT);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let insert = client
+        .query(
+            "INSERT INTO test_effects (id, v) VALUES (41, 'a'); \
+             INSERT INTO test_effects (v) VALUES ('b');",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(insert.rows_affected, 2);
+    assert_eq!(insert.last_insert_rowid, Some(42));
+
+    let update = client
+        .query(
+            "UPDATE test_effects SET v = 'c'",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(update.rows_affected, 2);
+    assert_eq!(update.last_insert_rowid, None);
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_effects;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transaction() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_transaction test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    let results = client
+        .transaction(&[
+            "CREATE TABLE IF NOT EXISTS test_transaction (id INTEGER PRIMARY KEY, balance INTEGER)",
+            "INSERT OR REPLACE INTO test_transaction VALUES (1, 100), (2, 0) -- opening balances",
+            "UPDATE test_transaction SET balance = balance - 30 WHERE id = 1",
+            "UPDATE test_transaction SET balance = balance + 30 WHERE id = 2",
+        ])
+        .await
+        .unwrap();
+    assert_eq!(results.rows_affected, 4);
+
+    let balances = setup::execute_query(
+        2,
+        String::from("SELECT balance FROM test_transaction ORDER BY id"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(balances, ["70", "30"]);
+
+    let err = client
+        .transaction(&["DELETE FROM test_transaction", "COMMIT"])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Status(s) if s.code() == tonic::Code::InvalidArgument));
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_transaction;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transaction_savepoints() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_transaction_savepoints test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    let results = client
+        .transaction(&[
+            "CREATE TABLE IF NOT EXISTS test_savepoints (id INTEGER PRIMARY KEY, note TEXT)",
+            "INSERT OR REPLACE INTO test_savepoints VALUES (1, 'kept')",
+            "SAVEPOINT duplicate",
+            "INSERT INTO test_savepoints VALUES (2, 'rolled back')",
+            "INSERT INTO test_savepoints VALUES (1, 'conflicts')",
+            "INSERT INTO test_savepoints VALUES (3, 'skipped')",
+            "RELEASE duplicate",
+            "SAVEPOINT fresh",
+            "INSERT INTO test_savepoints VALUES (4, 'released')",
+            "RELEASE SAVEPOINT fresh",
+        ])
+        .await
+        .unwrap();
+    assert_eq!(results.rolled_back, ["duplicate"]);
+
+    for id in 1..=3 {
+        let notes = setup::execute_query(
+            id,
+            String::from("SELECT note FROM test_savepoints ORDER BY id"),
+            Consistency::Strong,
+        )
+        .await;
+        assert_eq!(notes, ["kept", "released"]);
+    }
+
+    // A failure outside any savepoint still fails the whole transaction.
+    let err = client
+        .transaction(&[
+            "SAVEPOINT partial",
+            "DELETE FROM test_savepoints",
+            "ROLLBACK TO partial",
+            "RELEASE partial",
+            "ROLLBACK",
+        ])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Status(s) if s.code() == tonic::Code::InvalidArgument));
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_savepoints;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_relaxed_writes_rejected() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(
+        logger,
+        "---- Running test_relaxed_writes_rejected test ----"
+    );
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_relaxed_writes (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    for stmt in [
+        "INSERT INTO test_relaxed_writes VALUES(1);",
+        "update test_relaxed_writes SET i = 2",
+        "/* cleanup */ DELETE FROM test_relaxed_writes",
+        "WITH ids(i) AS (SELECT 3) INSERT INTO test_relaxed_writes SELECT i FROM ids",
+        "SELECT 1; DROP TABLE test_relaxed_writes",
+        "CREATE INDEX test_relaxed_writes_i ON test_relaxed_writes (i)",
+    ] {
+        let status = setup::try_execute_query(2, String::from(stmt), Consistency::RelaxedReads)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", stmt);
+    }
+
+    // Reads, including ones mentioning write keywords in literals or common
+    // table expressions, are still served.
+    let rows = setup::execute_query(
+        2,
+        String::from(
+            "WITH words(w) AS (SELECT 'insert') SELECT w FROM words \
+             WHERE w NOT IN (SELECT i FROM test_relaxed_writes)",
+        ),
+        Consistency::RelaxedReads,
+    )
+    .await;
+    assert_eq!(rows, ["insert"]);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_relaxed_writes;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resolve_nondeterminism() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_resolve_nondeterminism test ----");
+    setup::execute_query(
+        1,
+        String::from(
+            "CREATE TABLE IF NOT EXISTS test_nondeterminism \
+             (id INTEGER PRIMARY KEY, r INTEGER, ts TEXT, now TEXT, note TEXT, \
+             created TEXT DEFAULT CURRENT_TIMESTAMP);",
+        ),
+        Consistency::Strong,
+    )
+    .await;
+    setup::execute_query(
+        1,
+        String::from(
+            "INSERT INTO test_nondeterminism (id, r, ts, now, note) \
+             VALUES (1, 5-random(), CURRENT_TIMESTAMP, datetime('now'), 'now');",
+        ),
+        Consistency::Strong,
+    )
+    .await;
+
+    let select = "SELECT r, ts, now, note FROM test_nondeterminism";
+    let mut rows = vec![];
+    for id in 1..=3 {
+        rows.push(setup::execute_query(id, String::from(select), Consistency::Strong).await);
+    }
+    assert_eq!(rows[0], rows[1]);
+    assert_eq!(rows[0], rows[2]);
+    assert_eq!(rows[0][3], "now");
+
+    // The column default is left to be evaluated on insert.
+    let schema = setup::execute_query(
+        2,
+        String::from("SELECT sql FROM sqlite_master WHERE name = 'test_nondeterminism'"),
+        Consistency::Strong,
+    )
+    .await;
+    assert!(schema[0].contains("DEFAULT CURRENT_TIMESTAMP"));
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_nondeterminism;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_command_pragmas() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_command_pragmas test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .transaction(&[
+            "CREATE TABLE IF NOT EXISTS test_pragmas (i INTEGER)",
+            "CREATE TRIGGER IF NOT EXISTS test_pragmas_next AFTER INSERT ON test_pragmas \
+             WHEN new.i % 10 < 3 BEGIN INSERT INTO test_pragmas VALUES (new.i + 1); END",
+        ])
+        .await
+        .unwrap();
+    let recursive = || chiselstore::proto::PragmaSetting {
+        name: String::from("recursive_triggers"),
+        enabled: true,
+    };
+
+    // The trigger fires for the rows it inserts itself only while the
+    // pragma is set.
+    client
+        .query_with_pragmas(
+            "INSERT INTO test_pragmas VALUES (0)",
+            vec![],
+            vec![recursive()],
+        )
+        .await
+        .unwrap();
+    client
+        .query(
+            "INSERT INTO test_pragmas VALUES (10)",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    client
+        .transaction_with_pragmas(&["INSERT INTO test_pragmas VALUES (20)"], vec![recursive()])
+        .await
+        .unwrap();
+    for id in 1..=3 {
+        let rows = setup::execute_query(
+            id,
+            String::from("SELECT i FROM test_pragmas ORDER BY i"),
+            Consistency::Strong,
+        )
+        .await;
+        assert_eq!(
+            rows,
+            ["0", "1", "2", "3", "10", "11", "20", "21", "22", "23"]
+        );
+    }
+
+    // Check constraints can only be enforced, not ignored.
+    let ignore_checks = chiselstore::proto::PragmaSetting {
+        name: String::from("ignore_check_constraints"),
+        enabled: true,
+    };
+    let err = client
+        .query_with_pragmas("DELETE FROM test_pragmas", vec![], vec![ignore_checks])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Status(s) if s.code() == tonic::Code::InvalidArgument));
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_pragmas;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_retry_budget() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_retry_budget test ----");
+    // Nothing listens on the nodes listed first, which are tried first. The
+    // budget allows a single retry.
+    let client = |nodes: &[&str]| {
+        ChiselStoreClient::with_nodes(nodes.iter().copied())
+            .unwrap()
+            .with_retry_budget(RetryBudget {
+                ratio: 0.0,
+                min_per_sec: 1,
+            })
+    };
+    let nodes = ["http://127.0.0.1:50009", "http://127.0.0.1:50001"];
+    let create = "CREATE TABLE IF NOT EXISTS test_retry_budget (i INTEGER PRIMARY KEY)";
+
+    // Writes are not sent twice, whether or not they have an idempotency
+    // key: the cluster does not deduplicate writes by key.
+    let err = client(&nodes)
+        .query(create, chiselstore::proto::Consistency::Strong)
+        .await
+        .unwrap_err();
+    assert!(err.is_unreachable());
+    let err = client(&nodes)
+        .execute_or_queue(create, Some(String::from("create")))
+        .await
+        .unwrap_err();
+    assert!(err.is_unreachable());
+    let mut queueing = client(&nodes).with_offline_queue(1);
+    let outcome = queueing
+        .execute_or_queue(create, Some(String::from("create")))
+        .await
+        .unwrap();
+    assert!(matches!(outcome, WriteOutcome::Queued { .. }));
+    setup::execute_query(1, String::from(create), Consistency::Strong).await;
+
+    // Reads are safe to retry, within the budget.
+    let read = "SELECT i FROM test_retry_budget";
+    client(&nodes)
+        .query(read, chiselstore::proto::Consistency::RelaxedReads)
+        .await
+        .unwrap();
+    let err = client(&[
+        "http://127.0.0.1:50009",
+        "http://127.0.0.1:50008",
+        "http://127.0.0.1:50001",
+    ])
+    .query(read, chiselstore::proto::Consistency::RelaxedReads)
+    .await
+    .unwrap_err();
+    assert!(err.is_unreachable());
+
+    // Hedged reads get an answer from either node.
+    let mut client =
+        ChiselStoreClient::with_nodes(["http://127.0.0.1:50001", "http://127.0.0.1:50002"])
+            .unwrap()
+            .with_hedging(Duration::ZERO);
+    let results = client
+        .query(
+            "SELECT count(*) FROM test_retry_budget",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["0"]);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_retry_budget;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_relaxed_reads_served_locally() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(
+        logger,
+        "---- Running test_relaxed_reads_served_locally test ----"
+    );
+    let reads = [
+        "  \n\tSELECT 1",
+        "-- leading comment\nSELECT 1",
+        "/* leading comment */ SELECT 1",
+        "WITH t(x) AS (SELECT 1) SELECT x FROM t",
+        "VALUES (1)",
+        "EXPLAIN SELECT 1",
+    ];
+    for stmt in reads {
+        assert!(statements::is_read(stmt), "{}", stmt);
+    }
+    for stmt in [
+        "",
+        "-- nothing but a comment",
+        "BEGIN",
+        "SAVEPOINT a",
+        "PRAGMA user_version = 1",
+        "WITH t(x) AS (SELECT 1) INSERT INTO t2 SELECT x FROM t",
+        "SELECT 1; DELETE FROM t",
+    ] {
+        assert!(!statements::is_read(stmt), "{}", stmt);
+    }
+
+    // Relaxed reads run on the serving replica instead of going through the
+    // log, whatever they start with.
+    setup::execute_query(2, String::from("SELECT 1;"), Consistency::Strong).await;
+    let server = cluster[1].server();
+    let applied_idx = server.applied_idx();
+    for stmt in reads {
+        let rows = setup::execute_query(2, String::from(stmt), Consistency::RelaxedReads).await;
+        assert!(!rows.is_empty(), "{}", stmt);
+    }
+    assert_eq!(server.applied_idx(), applied_idx);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_barrier() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_barrier test ----");
+    let addrs = [
+        "http://127.0.0.1:50001",
+        "http://127.0.0.1:50002",
+        "http://127.0.0.1:50003",
+    ];
+    let mut client = ChiselStoreClient::with_nodes(addrs).unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_barrier (i INTEGER PRIMARY KEY);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    client
+        .query(
+            "INSERT OR REPLACE INTO test_barrier VALUES(7);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+
+    let index = client.barrier(Duration::from_secs(5)).await.unwrap();
+    for replica in cluster.iter() {
+        assert!(replica.server().applied_idx() >= index);
+        let rows = setup::execute_query(
+            replica.get_replica_id(),
+            String::from("SELECT i FROM test_barrier"),
+            Consistency::RelaxedReads,
+        )
+        .await;
+        assert_eq!(rows, ["7"]);
+    }
+    assert!(matches!(
+        client
+            .barrier_on(&["http://127.0.0.1:50009"], Duration::from_secs(1))
+            .await,
+        Err(ClientError::UnknownNode(_))
+    ));
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_barrier;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_query_stream() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(2);
+
+    info!(logger, "---- Running test_query_stream test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_stream (i INTEGER PRIMARY KEY);",
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5) \
+         INSERT OR REPLACE INTO test_stream SELECT i FROM n;",
+    ] {
+        client
+            .query(stmt, chiselstore::proto::Consistency::Strong)
+            .await
+            .unwrap();
+    }
+
+    for consistency in [
+        chiselstore::proto::Consistency::RelaxedReads,
+        chiselstore::proto::Consistency::Strong,
+    ] {
+        let mut stream = client
+            .query_stream("SELECT i FROM test_stream ORDER BY i", consistency, 2)
+            .await
+            .unwrap();
+        let mut batches = vec![];
+        while let Some(batch) = stream.message().await.unwrap() {
+            batches.push(batch);
+        }
+        let sizes: Vec<usize> = batches.iter().map(|b| b.rows.len()).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+        assert_eq!(batches[0].columns.len(), 1);
+        assert!(batches[1..].iter().all(|b| b.columns.is_empty()));
+        let values: Vec<String> = batches
+            .into_iter()
+            .flat_map(|b| b.rows)
+            .flat_map(|row| row.values)
+            .collect();
+        assert_eq!(values, ["1", "2", "3", "4", "5"]);
+    }
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_stream;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[test]
+fn test_proposal_validators() {
+    let conn = sqlite::open(":memory:").unwrap();
+    let insert = StoreCommand::new(1, String::from("INSERT INTO not_yet_created VALUES(1)"));
+    assert!(SyntaxCheck.validate(&insert, &conn).is_ok());
+
+    let typo = StoreCommand::new(2, String::from("INSRET INTO t VALUES(1)"));
+    assert!(SyntaxCheck.validate(&typo, &conn).is_err());
+    let conditional = StoreCommand {
+        kind: CommandKind::Conditional {
+            predicate: String::from("SELECT FROM"),
+        },
+        ..StoreCommand::new(3, String::from("DELETE FROM t"))
+    };
+    assert!(SyntaxCheck.validate(&conditional, &conn).is_err());
+
+    assert!(MaxCommandSize(64).validate(&insert, &conn).is_ok());
+    assert!(MaxCommandSize(16).validate(&insert, &conn).is_err());
+}
+
+#[test]
+fn test_deterministic_encryption() {
+    let secrets = StaticSecrets::new().with_secret("pii", vec![7; KEY_LEN]);
+    let functions = encryption_functions(Arc::new(secrets));
+    let (encrypt, decrypt) = (&functions[0].func, &functions[1].func);
+
+    let key = Value::from("pii");
+    let ciphertext = encrypt(&[key.clone(), Value::from("alice@example.com")]).unwrap();
+    assert_eq!(
+        encrypt(&[key.clone(), Value::from("alice@example.com")]).unwrap(),
+        ciphertext
+    );
+    assert_eq!(
+        decrypt(&[key.clone(), ciphertext]).unwrap(),
+        Value::from("alice@example.com")
+    );
+    assert!(encrypt(&[Value::from("missing"), Value::from("x")]).is_err());
+
+    // Values decrypt to the type they were encrypted as.
+    for value in [
+        Value::Integer(-42),
+        Value::Real(2.5),
+        Value::from(""),
+        Value::Blob(vec![0, 159, 255]),
+    ] {
+        let ciphertext = encrypt(&[key.clone(), value.clone()]).unwrap();
+        assert_eq!(decrypt(&[key.clone(), ciphertext]).unwrap(), value);
+    }
+    assert_ne!(
+        encrypt(&[key.clone(), Value::Integer(1)]).unwrap(),
+        encrypt(&[key.clone(), Value::from("1")]).unwrap()
+    );
+    assert_eq!(decrypt(&[key.clone(), Value::Null]).unwrap(), Value::Null);
+
+    // Plaintexts without a type tag are refused rather than read as text.
+    let cipher = Aes256SivAead::new(GenericArray::from_slice(&[7; KEY_LEN]));
+    for plaintext in [&b"alice@example.com"[..], &b""[..]] {
+        let ciphertext = cipher.encrypt(&GenericArray::default(), plaintext).unwrap();
+        let hex: String = ciphertext.iter().map(|b| format!("{:02x}", b)).collect();
+        assert!(decrypt(&[key.clone(), Value::from(hex)]).is_err());
+    }
+}
+
+#[test]
+fn test_function_registry() {
+    let double = FunctionDef::new("double", 1, |args| match &args[0] {
+        Value::Integer(n) => Ok(Value::Integer(n * 2)),
+        _ => Err("double expects an integer".to_string()),
+    });
+    let registry = FunctionRegistry::new().with(double.clone());
+
+    assert_eq!(
+        registry.called_by("SELECT DOUBLE (x), redouble(y) FROM t"),
+        vec!["double".to_string()]
+    );
+    assert!(registry.called_by("SELECT double FROM t").is_empty());
+
+    let fingerprint = registry.fingerprint();
+    assert_eq!(
+        FunctionRegistry::new().with(double.clone()).fingerprint(),
+        fingerprint
+    );
+    assert_ne!(FunctionRegistry::new().fingerprint(), fingerprint);
+    assert_ne!(
+        FunctionRegistry::new()
+            .with(double.clone().with_version(2))
+            .fingerprint(),
+        fingerprint
+    );
+
+    // Commands record the versions of the functions and collations they
+    // use, and replicas only apply them with those versions.
+    let nocase = CollationDef::new("nocase_ascii", |a: &str, b: &str| {
+        a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+    });
+    let registry = registry.with_collation(nocase.clone().with_version(3));
+    assert_eq!(
+        registry.collated_by("SELECT * FROM t ORDER BY name COLLATE \"NOCASE_ASCII\""),
+        vec!["nocase_ascii".to_string()]
+    );
+    assert!(registry
+        .collated_by("SELECT * FROM t ORDER BY name COLLATE nocase")
+        .is_empty());
+    assert_eq!(
+        registry.function_references("SELECT double(x) FROM t"),
+        vec!["double@1".to_string()]
+    );
+    assert_eq!(
+        registry.collation_references("SELECT x FROM t ORDER BY x COLLATE nocase_ascii"),
+        vec!["nocase_ascii@3".to_string()]
+    );
+    assert!(registry.has_function("double@1"));
+    assert!(registry.has_function("double"));
+    assert!(!registry.has_function("double@2"));
+    assert!(registry.has_collation("nocase_ascii@3"));
+    assert!(!registry.has_collation("nocase_ascii@1"));
+    assert_ne!(registry.fingerprint(), fingerprint);
+    assert_ne!(
+        FunctionRegistry::new()
+            .with(double)
+            .with_collation(nocase)
+            .fingerprint(),
+        registry.fingerprint()
+    );
+}
+
+#[test]
+fn test_reconfiguration_manager() {
+    let mut manager = ReconfigurationManager::new(1, 1, vec![2, 3]);
+    assert_eq!(manager.metadata(), None);
+    let next = StopSign::with(2, vec![1, 2, 4], Some(b"rolling upgrade".to_vec()));
+    assert_eq!(
+        manager.on_decided(&next),
+        Some(Transition::Continue {
+            config_id: 2,
+            peers: vec![2, 4],
+        })
+    );
+    // A stop sign is acted on once, however often it is seen.
+    assert_eq!(manager.on_decided(&next), None);
+    assert_eq!(manager.config_id(), 2);
+    assert_eq!(manager.peers(), &[2, 4]);
+    assert_eq!(manager.metadata(), Some(&b"rolling upgrade"[..]));
+
+    let last = StopSign::with(3, vec![2, 4], None);
+    assert_eq!(
+        manager.on_decided(&last),
+        Some(Transition::Retire { config_id: 3 })
+    );
+    assert!(manager.is_retired());
+    assert_eq!(manager.on_decided(&StopSign::with(4, vec![1], None)), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leader_lease_reads() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_leader_lease_reads test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_lease (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::execute_query(
+        1,
+        String::from("INSERT OR REPLACE INTO test_lease VALUES (1);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    let leader = cluster
+        .iter_mut()
+        .find(|replica| replica.replica_is_leader())
+        .unwrap()
+        .server();
+    let follower = cluster
+        .iter_mut()
+        .find(|replica| !replica.replica_is_leader())
+        .unwrap()
+        .server();
+
+    // Followers fall back to strong reads, which go through the log.
+    let applied_idx = follower.applied_idx();
+    let results = follower
+        .query(
+            "SELECT i FROM test_lease",
+            chiselstore::Consistency::LeaderLease,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["1"]);
+    assert!(follower.applied_idx() > applied_idx);
+
+    // The leader serves the reads locally whenever it holds the lease, which
+    // it does for part of every heartbeat round.
+    let deadline = Instant::now() + Duration::from_secs(15);
+    let mut local = false;
+    while !local && Instant::now() < deadline {
+        let applied_idx = leader.applied_idx();
+        let results = leader
+            .query(
+                "SELECT i FROM test_lease",
+                chiselstore::Consistency::LeaderLease,
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.rows[0].values, ["1"]);
+        local = leader.applied_idx() == applied_idx;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(local);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_lease;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_index_reads() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_read_index_reads test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_read_index (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    let follower = cluster
+        .iter_mut()
+        .find(|replica| !replica.replica_is_leader())
+        .unwrap()
+        .server();
+
+    // Every write decided before a read is visible to it on a follower.
+    for i in 1..=3 {
+        setup::execute_query(
+            1,
+            format!("INSERT INTO test_read_index VALUES ({});", i),
+            Consistency::Strong,
+        )
+        .await;
+        let results = follower
+            .query(
+                "SELECT COUNT(*) FROM test_read_index",
+                chiselstore::Consistency::ReadIndex,
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.rows[0].values, [i.to_string()]);
+    }
+
+    // Nothing goes through the log whenever the leader holds its lease,
+    // which it does for part of every heartbeat round.
+    let deadline = Instant::now() + Duration::from_secs(15);
+    let mut local = false;
+    while !local && Instant::now() < deadline {
+        let applied_idx = follower.applied_idx();
+        let results = follower
+            .query(
+                "SELECT COUNT(*) FROM test_read_index",
+                chiselstore::Consistency::ReadIndex,
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.rows[0].values, ["3"]);
+        local = follower.applied_idx() == applied_idx;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(local);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_read_index;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leader_reads() {
+    let logger = logger::create_logger();
+
+    info!(logger, "---- Running test_leader_reads test ----");
+    for leader_reads in [true, false] {
+        let mut cluster = setup::make_cluster_with_config(
+            3,
+            StoreConfig {
+                leader_reads,
+                ..Default::default()
+            },
+        );
+        setup::execute_query(
+            1,
+            String::from("CREATE TABLE IF NOT EXISTS test_leader_reads (i INTEGER PRIMARY KEY);"),
+            Consistency::Strong,
+        )
+        .await;
+        setup::execute_query(
+            1,
+            String::from("INSERT OR REPLACE INTO test_leader_reads VALUES (1);"),
+            Consistency::Strong,
+        )
+        .await;
+        let leader = cluster
+            .iter_mut()
+            .find(|replica| replica.replica_is_leader())
+            .unwrap()
+            .server();
+
+        // The leader confirms it leads with a majority instead of appending
+        // the read to the log, and proves where the read was linearized.
+        let applied_idx = leader.applied_idx();
+        let results = leader
+            .query(
+                "SELECT i FROM test_leader_reads",
+                chiselstore::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.rows[0].values, ["1"]);
+        assert_eq!(leader.applied_idx() == applied_idx, leader_reads);
+        let proof = results.proof.unwrap();
+        assert!(proof.decided_idx >= applied_idx);
+        assert!(proof.quorum.contains(&leader.id()));
+        assert!(proof.quorum.len() >= 2);
+
+        setup::execute_query(
+            1,
+            String::from("DROP TABLE IF EXISTS test_leader_reads;"),
+            Consistency::Strong,
+        )
+        .await;
+        setup::halt_all_replicas(cluster).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reconfiguration() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_reconfiguration test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_reconfiguration (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    let leader = cluster
+        .iter_mut()
+        .find(|replica| replica.replica_is_leader())
+        .unwrap()
+        .server();
+    leader
+        .reconfigure_with_metadata(vec![1, 2, 3], Some(b"test".to_vec()))
+        .unwrap();
+    while cluster
+        .iter()
+        .any(|replica| replica.server().config_id() != 2)
+    {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    for replica in cluster.iter() {
+        assert_eq!(replica.server().config_metadata(), Some(b"test".to_vec()));
+    }
+
+    // Each node archived the final state of the first configuration.
+    for replica in cluster.iter() {
+        let archive = replica.server().archive(1).unwrap();
+        assert_eq!(archive.members(), &[1, 2, 3]);
+        let tables = archive
+            .query("SELECT name FROM sqlite_master WHERE name = 'test_reconfiguration'")
+            .unwrap();
+        assert_eq!(tables.rows.len(), 1);
+    }
+    let path = std::env::temp_dir().join("chiselstore-test-config1.db");
+    cluster[0]
+        .server()
+        .archive(1)
+        .unwrap()
+        .export(&path)
+        .unwrap();
+    let report = verify_backup(&path, &["SELECT 1 FROM sqlite_master"]).unwrap();
+    assert!(report.passed());
+    std::fs::remove_file(&path).unwrap();
+
+    // The new configuration elects a leader and keeps the existing tables.
+    loop {
+        let result = setup::try_execute_query(
+            1,
+            String::from("INSERT OR REPLACE INTO test_reconfiguration VALUES(1);"),
+            Consistency::Strong,
+        )
+        .await;
+        if result.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_reconfiguration;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tenants() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_tenants test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_tenant("acme");
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_tenant (i INTEGER PRIMARY KEY);",
+        "INSERT OR REPLACE INTO test_tenant VALUES(5);",
+    ] {
+        client
+            .query(stmt, chiselstore::proto::Consistency::Strong)
+            .await
+            .unwrap();
+    }
+    let rows = client
+        .query(
+            "SELECT i FROM test_tenant",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(rows.rows[0].values, ["5"]);
+
+    // The shared database never sees the tenant's table.
+    let shared = setup::try_execute_query(
+        2,
+        String::from("SELECT i FROM test_tenant"),
+        Consistency::Strong,
+    )
+    .await;
+    assert!(shared.is_err());
+
+    let index = client.barrier(Duration::from_secs(5)).await.unwrap();
+    for replica in cluster.iter() {
+        let server = replica.server();
+        assert!(server.applied_idx() >= index);
+        let usage = server.tenant_usage().unwrap();
+        assert!(usage.iter().any(|u| u.tenant == "acme" && u.bytes > 0));
+    }
+
+    let path = std::env::temp_dir().join(format!("chiselstore-tenant-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let applied_idx = cluster[1].server().backup_tenant("acme", &path).unwrap();
+    let report = verify_backup(&path, &["SELECT 1 FROM test_tenant WHERE i = 5"]).unwrap();
+    assert_eq!(report.applied_idx, applied_idx);
+    assert!(report.validations[0].passed);
+    let _ = std::fs::remove_file(&path);
+
+    let err = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_tenant("../acme")
+        .query("SELECT 1", chiselstore::proto::Consistency::Strong)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Status(s) if s.code() == tonic::Code::InvalidArgument));
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_tenant;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_prepared_statements() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_prepared_statements test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_prepared (id INTEGER PRIMARY KEY, v TEXT);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let insert = client
+        .prepare("INSERT OR REPLACE INTO test_prepared VALUES (?, ?)")
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .prepare("INSERT OR REPLACE INTO test_prepared VALUES (?, ?)")
+            .await
+            .unwrap(),
+        insert
+    );
+    for (id, v) in [(1, "a"), (2, "b"), (3, "c")] {
+        let results = client
+            .execute_prepared(insert, vec![Value::Integer(id), Value::from(v)])
+            .await
+            .unwrap();
+        assert_eq!(results.rows_affected, 1);
+    }
+
+    let select = client
+        .prepare("SELECT v FROM test_prepared WHERE id >= ? ORDER BY id")
+        .await
+        .unwrap();
+    let results = client
+        .execute_prepared(select, vec![Value::Integer(2)])
+        .await
+        .unwrap();
+    let values: Vec<_> = results
+        .rows
+        .iter()
+        .map(|row| row.values[0].clone())
+        .collect();
+    assert_eq!(values, ["b", "c"]);
+
+    let err = client
+        .execute_prepared(select ^ 1, vec![])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Status(s) if s.code() == tonic::Code::NotFound));
+    let err = client
+        .prepare("DELETE FROM test_prepared; DELETE FROM test_prepared")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Status(s) if s.code() == tonic::Code::InvalidArgument));
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_prepared;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tenant_migration() {
+    let logger = logger::create_logger();
+    let source_cluster = setup::make_cluster(3);
+    let destination_ids = [4, 5, 6];
+    let destination_cluster: Vec<_> = destination_ids
+        .iter()
+        .map(|id| {
+            let peers = destination_ids
+                .iter()
+                .copied()
+                .filter(|p| p != id)
+                .collect();
+            setup::SPReplica::new(*id, peers, Network::default())
+        })
+        .collect();
+
+    info!(logger, "---- Running test_tenant_migration test ----");
+    let mut tenant = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_tenant("globex");
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_migration (i INTEGER PRIMARY KEY, v TEXT);",
+        "CREATE INDEX IF NOT EXISTS test_migration_v ON test_migration (v);",
+        "INSERT OR REPLACE INTO test_migration VALUES (1, 'a'), (2, NULL);",
+    ] {
+        tenant
+            .query(stmt, chiselstore::proto::Consistency::Strong)
+            .await
+            .unwrap();
+    }
+
+    let mut source = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    let destination = ChiselStoreClient::new("http://127.0.0.1:50004").unwrap();
+    let report = migrate_tenant(&mut source, destination, "east", "globex")
+        .await
+        .unwrap();
+    assert_eq!(report.statements, 4);
+
+    // The source refuses the tenant's commands from the cut-over on.
+    let err = tenant
+        .query(
+            "INSERT INTO test_migration VALUES (3, 'c')",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Status(s) if s.code() == tonic::Code::FailedPrecondition));
+
+    let mut moved = ChiselStoreClient::new("http://127.0.0.1:50005")
+        .unwrap()
+        .with_tenant("globex");
+    let rows = moved
+        .query(
+            "SELECT i FROM test_migration ORDER BY i",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let ids: Vec<_> = rows.rows.iter().map(|row| row.values[0].clone()).collect();
+    assert_eq!(ids, ["1", "2"]);
+    assert_eq!(
+        moved.tenant_checksum("globex").await.unwrap().checksum,
+        report.checksum
+    );
+
+    moved
+        .query(
+            "DROP TABLE IF EXISTS test_migration;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    source
+        .query(
+            route_statement("globex", None),
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    tenant
+        .query(
+            "DROP TABLE IF EXISTS test_migration;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(destination_cluster).await;
+    setup::halt_all_replicas(source_cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_outbox() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_outbox test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_outbox_orders (id INTEGER PRIMARY KEY, item TEXT);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+
+    let consumer = Arc::new(OutboxConsumer::new(cluster[1].server()));
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let running = {
+        let consumer = consumer.clone();
+        tokio::task::spawn(async move {
+            consumer
+                .run(|message| {
+                    let tx = tx.clone();
+                    async move {
+                        tx.send(message).unwrap();
+                        Ok::<_, StoreError>(())
+                    }
+                })
+                .await
+        })
+    };
+
+    client
+        .transaction_with_outbox(
+            &["INSERT INTO test_outbox_orders VALUES (1, 'book')"],
+            "orders",
+            b"order 1",
+        )
+        .await
+        .unwrap();
+    let message = rx.recv().await.unwrap();
+    assert_eq!(message.topic, "orders");
+    assert_eq!(message.payload, b"order 1");
+
+    // The delivery is recorded through the log.
+    while !consumer.pending().await.unwrap().is_empty() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    running.abort();
+    assert_eq!(consumer.purge_delivered().await.unwrap(), 1);
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_outbox_orders;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_adaptive_batching() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_adaptive_batching test ----");
+    let server = cluster[0].server();
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_batching (i INTEGER PRIMARY KEY);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let batching = client
+        .query(
+            "SELECT window_ms, target_ms FROM chiselstore_batching",
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    assert_eq!(batching.rows[0].values, ["1.0", "NULL"]);
+
+    // Writes with latency well within the SLO widen the window.
+    server.set_latency_slo(Some(LatencySlo {
+        target: Duration::from_secs(10),
+        ..Default::default()
+    }));
+    let mut i = 0;
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while server.batching().window == MIN_WINDOW && Instant::now() < deadline {
+        i += 1;
+        client
+            .query(
+                format!("INSERT INTO test_batching VALUES ({})", i),
+                chiselstore::proto::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+    }
+    let status = server.batching();
+    assert!(status.window > MIN_WINDOW);
+    assert_eq!(status.target, Some(Duration::from_secs(10)));
+    assert!(status.samples > 0);
+    assert!(status.p99 >= status.p50);
+
+    // Writes missing the SLO narrow it back down.
+    server.set_latency_slo(Some(LatencySlo {
+        target: Duration::from_micros(1),
+        ..Default::default()
+    }));
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while server.batching().window > MIN_WINDOW && Instant::now() < deadline {
+        i += 1;
+        client
+            .query(
+                format!("INSERT INTO test_batching VALUES ({})", i),
+                chiselstore::proto::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+    }
+    assert_eq!(server.batching().window, MIN_WINDOW);
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_batching;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_warm_after_apply() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            warm_after_apply: true,
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_warm_after_apply test ----");
+    let mut writer = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_session();
+    writer
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_warm (id INTEGER PRIMARY KEY, v TEXT);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+
+    // Warming reads on the pooled connections never get in the way of the
+    // writes and reads that follow them.
+    for i in 1..=20 {
+        writer
+            .query(
+                format!("INSERT INTO test_warm VALUES ({}, 'v{}')", i, i),
+                chiselstore::proto::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+        writer
+            .query(
+                format!("UPDATE test_warm SET v = 'w{}' WHERE id = {}", i, i),
+                chiselstore::proto::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+        let mut reader = ChiselStoreClient::new("http://127.0.0.1:50002")
+            .unwrap()
+            .with_session_token(writer.session_token().unwrap());
+        let results = reader
+            .query(
+                format!("SELECT v FROM test_warm WHERE id = {}", i),
+                chiselstore::proto::Consistency::RelaxedReads,
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.rows[0].values, [format!("w{}", i)]);
+    }
+
+    writer
+        .query(
+            "DROP TABLE IF EXISTS test_warm;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_session_tokens() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_session_tokens test ----");
+    let mut writer = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_session();
+    assert_eq!(writer.session_token(), Some(0));
+    writer
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_sessions (id INTEGER PRIMARY KEY, name TEXT);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let results = writer
+        .query(
+            "INSERT INTO test_sessions VALUES (1, 'alice')",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let token = writer.session_token().unwrap();
+    assert_eq!(results.commit_idx, Some(token));
+    assert!(token > 0);
+    // Writes name the leader they were accepted under.
+    let ballot = results.commit_ballot.unwrap();
+    assert!(ballot.pid >= 1 && ballot.pid <= 3);
+
+    // A relaxed read on another replica continuing the session sees the
+    // write.
+    let mut reader = ChiselStoreClient::new("http://127.0.0.1:50002")
+        .unwrap()
+        .with_session_token(token);
+    let results = reader
+        .query(
+            "SELECT name FROM test_sessions WHERE id = 1",
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, vec!["alice"]);
+    assert_eq!(results.commit_idx, None);
+    assert_eq!(results.commit_ballot, None);
+    assert_eq!(reader.session_token(), Some(token));
+
+    writer
+        .query(
+            "DROP TABLE IF EXISTS test_sessions;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_buffered_counters() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_buffered_counters test ----");
+    let server = cluster[0].server();
+    let counters = Counters::new(server.clone());
+    let name = "test_page_views";
+    let before = counters.get(name).await.unwrap();
+
+    // Increments are buffered and replicated as a single entry.
+    for _ in 0..1000 {
+        counters.increment(name, 1);
+    }
+    counters.increment(name, -10);
+    assert_eq!(counters.pending(name), 990);
+    assert_eq!(counters.get(name).await.unwrap(), before + 990);
+    let applied_idx = server.applied_idx();
+    assert_eq!(counters.flush().await.unwrap(), 1);
+    assert_eq!(server.applied_idx(), applied_idx + 1);
+    assert_eq!(counters.pending(name), 0);
+    assert_eq!(counters.flush().await.unwrap(), 0);
+
+    // Other replicas converge on the replicated value.
+    let replica = Counters::new(cluster[1].server());
+    assert!(
+        cluster[1]
+            .server()
+            .wait_applied(server.applied_idx(), Duration::from_secs(5))
+            .await
+    );
+    assert_eq!(replica.get(name).await.unwrap(), before + 990);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_query_batch() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_query_batch test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_batch (i INTEGER PRIMARY KEY);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let mut statements: Vec<_> = (1..=5)
+        .map(|i| {
+            (
+                String::from("INSERT OR REPLACE INTO test_batch VALUES (?)"),
+                vec![Value::Integer(i)],
+            )
+        })
+        .collect();
+    statements.push((String::from("SELECT COUNT(*) FROM test_batch"), vec![]));
+    let results = client
+        .query_batch(statements, chiselstore::proto::Consistency::Strong)
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 6);
+    for result in &results[..5] {
+        assert_eq!(result.as_ref().unwrap().rows_affected, 1);
+    }
+    // Strong queries apply in batch order.
+    assert_eq!(results[5].as_ref().unwrap().rows[0].values, ["5"]);
+
+    let reads = vec![
+        (String::from("SELECT COUNT(*) FROM test_batch"), vec![]),
+        (String::from("SELECT * FROM missing_table"), vec![]),
+    ];
+    let results = client
+        .query_batch(reads, chiselstore::proto::Consistency::RelaxedReads)
+        .await
+        .unwrap();
+    assert_eq!(results[0].as_ref().unwrap().rows[0].values, ["5"]);
+    assert!(results[1].is_err());
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_batch;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[cfg(feature = "resp")]
+async fn resp_roundtrip(stream: &mut tokio::net::TcpStream, args: &[&str], expected: &str) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    stream.write_all(command.as_bytes()).await.unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), expected, "{:?}", args);
+}
+
+#[cfg(feature = "resp")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resp_facade() {
+    use chiselstore::resp::{serve, RespConfig};
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_resp_facade test ----");
+    let config = RespConfig {
+        addr: "127.0.0.1:56379".parse().unwrap(),
+        relaxed_prefixes: vec![b"cache:".to_vec()],
+    };
+    tokio::task::spawn(serve(cluster[0].server(), config));
+    let mut stream = loop {
+        match tokio::net::TcpStream::connect("127.0.0.1:56379").await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    };
+
+    resp_roundtrip(&mut stream, &["PING"], "+PONG\r\n").await;
+    resp_roundtrip(&mut stream, &["SET", "user:1", "ada"], "+OK\r\n").await;
+    resp_roundtrip(&mut stream, &["SET", "user:2", "grace"], "+OK\r\n").await;
+    resp_roundtrip(&mut stream, &["GET", "user:1"], "$3\r\nada\r\n").await;
+    resp_roundtrip(&mut stream, &["SET", "cache:a", "1", "PX", "1"], "+OK\r\n").await;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    resp_roundtrip(&mut stream, &["GET", "cache:a"], "$-1\r\n").await;
+    resp_roundtrip(&mut stream, &["EXPIRE", "cache:a", "10"], ":0\r\n").await;
+    resp_roundtrip(&mut stream, &["EXPIRE", "user:2", "100"], ":1\r\n").await;
+    resp_roundtrip(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "user:*"],
+        "*2\r\n$1\r\n0\r\n*2\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n",
+    )
+    .await;
+    resp_roundtrip(
+        &mut stream,
+        &["DEL", "user:1", "user:2", "cache:a"],
+        ":2\r\n",
+    )
+    .await;
+    resp_roundtrip(&mut stream, &["GET", "user:1"], "$-1\r\n").await;
+    resp_roundtrip(&mut stream, &["QUIT"], "+OK\r\n").await;
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+/// Sends a gRPC-Web CORS preflight from `origin` to `addr`, returning the
+/// lowercased response head.
+#[cfg(feature = "grpc-web")]
+async fn grpc_web_preflight(addr: &str, origin: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = loop {
+        match tokio::net::TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    };
+    let request = format!(
+        "OPTIONS /proto.RpcV2/Execute HTTP/1.1\r\nHost: {}\r\nOrigin: {}\r\n\
+         Access-Control-Request-Method: POST\r\n\
+         Access-Control-Request-Headers: content-type,x-grpc-web\r\n\
+         Connection: close\r\n\r\n",
+        addr, origin
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).to_lowercase()
+}
+
+#[cfg(feature = "grpc-web")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_grpc_web_cors() {
+    use chiselstore::rpc::proto::rpc_v2_server::RpcV2Server;
+    use chiselstore::rpc::{grpc_web_config, GrpcWebCors, RpcV2Service};
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_grpc_web_cors test ----");
+    for (addr, allowed_origins) in [
+        ("127.0.0.1:50096", vec!["https://app.example".to_string()]),
+        ("127.0.0.1:50097", vec![]),
+    ] {
+        let cors = GrpcWebCors {
+            allowed_origins,
+            ..Default::default()
+        };
+        let service =
+            grpc_web_config(&cors).enable(RpcV2Server::new(RpcV2Service::new(cluster[0].server())));
+        tokio::task::spawn(
+            tonic::transport::Server::builder()
+                .accept_http1(true)
+                .add_service(service)
+                .serve(addr.parse().unwrap()),
+        );
+    }
+
+    // Only the listed origins are allowed, and none without a list.
+    let allowed = grpc_web_preflight("127.0.0.1:50096", "https://app.example").await;
+    assert!(allowed.contains("access-control-allow-origin: https://app.example"));
+    let refused = grpc_web_preflight("127.0.0.1:50096", "https://evil.example").await;
+    assert!(!refused.contains("access-control-allow-origin"));
+    let refused = grpc_web_preflight("127.0.0.1:50097", "https://app.example").await;
+    assert!(!refused.contains("access-control-allow-origin"));
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_cursors() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            cursor_ttl: Duration::from_millis(500),
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_cursors test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_session();
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_cursors (i INTEGER PRIMARY KEY);",
+        "INSERT INTO test_cursors WITH RECURSIVE n(i) AS \
+         (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 25) SELECT i FROM n;",
+    ] {
+        client.query(stmt, Consistency::Strong).await.unwrap();
+    }
+
+    let select = "SELECT i FROM test_cursors ORDER BY i";
+    for consistency in [Consistency::RelaxedReads, Consistency::Strong] {
+        let (mut cursor, page) = client.open_cursor(select, consistency, 10).await.unwrap();
+        assert_eq!(page.columns.len(), 1);
+        assert_eq!(page.rows.len(), 10);
+        let mut read: Vec<_> = page
+            .rows
+            .into_iter()
+            .map(|row| row.values[0].clone())
+            .collect();
+        while !cursor.is_done() {
+            let page = client.fetch_cursor(&mut cursor).await.unwrap();
+            read.extend(page.rows.into_iter().map(|row| row.values[0].clone()));
+        }
+        let expected: Vec<_> = (1..=25).map(|i| i.to_string()).collect();
+        assert_eq!(read, expected);
+    }
+
+    // Closed and expired cursors are gone.
+    let (cursor, _) = client
+        .open_cursor(select, Consistency::RelaxedReads, 10)
+        .await
+        .unwrap();
+    let id = cursor.id();
+    client.close_cursor(cursor).await.unwrap();
+    assert!(!cluster[0].server().close_cursor(id));
+    let (mut cursor, _) = client
+        .open_cursor(select, Consistency::RelaxedReads, 10)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let err = client.fetch_cursor(&mut cursor).await.unwrap_err();
+    assert!(matches!(err, ClientError::Status(s) if s.code() == tonic::Code::NotFound));
+
+    let err = client
+        .open_cursor("DELETE FROM test_cursors", Consistency::Strong, 10)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Status(s) if s.code() == tonic::Code::InvalidArgument));
+
+    client
+        .query("DROP TABLE IF EXISTS test_cursors;", Consistency::Strong)
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[derive(Default)]
+struct RecordedLeaders(std::sync::Mutex<Vec<LeaderInfo>>);
+
+impl LeaderRegistry for RecordedLeaders {
+    fn publish(&self, leader: &LeaderInfo) -> std::io::Result<()> {
+        self.0.lock().unwrap().push(leader.clone());
+        Ok(())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leader_registry() {
+    let logger = logger::create_logger();
+    let registry = Arc::new(RecordedLeaders::default());
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            leader_registry: Some(registry.clone()),
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_leader_registry test ----");
+    setup::execute_query(1, String::from("SELECT 1;"), Consistency::Strong).await;
+    let leader = cluster[0].server().get_cluster_leader();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !registry.0.lock().unwrap().iter().any(|l| l.id == leader) {
+        assert!(Instant::now() < deadline, "leader {} not published", leader);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let path = std::env::temp_dir().join(format!("chiselstore-{}.zone", std::process::id()));
+    let zone = DnsZoneRegistry::new(
+        &path,
+        "_leader._tcp.example.com.",
+        "node2.example.com.",
+        50002,
+    );
+    zone.publish(&LeaderInfo { id: 2, term: 3 }).unwrap();
+    // Late updates from earlier terms are ignored.
+    zone.publish(&LeaderInfo { id: 1, term: 2 }).unwrap();
+    let records = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        records,
+        "_leader._tcp.example.com. 5 IN SRV 0 0 50002 node2.example.com.\n\
+         _leader._tcp.example.com. 5 IN TXT \"id=2\" \"term=3\"\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_schema_migrations() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_schema_migrations test ----");
+    let create = "CREATE TABLE test_schema_migrations (i INTEGER PRIMARY KEY);";
+    let alter = "ALTER TABLE test_schema_migrations ADD COLUMN name TEXT;\n\
+                 INSERT INTO test_schema_migrations VALUES (1, 'one'); -- seed";
+    let migrator = SchemaMigrator::new(cluster[0].server())
+        .with_migration(2, "add name", alter)
+        .with_migration(1, "create", create);
+    assert_eq!(migrator.version().await.unwrap(), 0);
+    assert_eq!(migrator.migrate().await.unwrap(), vec![1, 2]);
+    assert_eq!(migrator.version().await.unwrap(), 2);
+
+    // Applying the migrations again, from any node, is a no-op.
+    assert!(migrator.migrate().await.unwrap().is_empty());
+    let other = SchemaMigrator::new(cluster[2].server())
+        .with_migration(1, "create", create)
+        .with_migration(2, "add name", alter);
+    assert!(other.migrate().await.unwrap().is_empty());
+    let results = cluster[1]
+        .server()
+        .apply_migration(1, "create", create)
+        .await
+        .unwrap();
+    assert!(!results.applied);
+    let rows = setup::execute_query(
+        3,
+        String::from("SELECT i, name FROM test_schema_migrations;"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(rows, vec!["1", "one"]);
+
+    // Changed and out of sequence migrations are refused before proposing.
+    let changed = SchemaMigrator::new(cluster[0].server()).with_migration(
+        1,
+        "create",
+        "CREATE TABLE other (i INTEGER);",
+    );
+    assert!(matches!(
+        changed.migrate().await,
+        Err(StoreError::InvalidRequest(_))
+    ));
+    let gap = SchemaMigrator::new(cluster[0].server())
+        .with_migration(1, "create", create)
+        .with_migration(3, "skip", "SELECT 1;");
+    assert!(matches!(
+        gap.migrate().await,
+        Err(StoreError::InvalidRequest(_))
+    ));
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_schema_migrations;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::execute_query(
+        1,
+        String::from("DELETE FROM chiselstore_schema_migrations;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_follower_reads() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            follower_reads: true,
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_follower_reads test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_follower_reads (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    let follower = cluster
+        .iter_mut()
+        .find(|replica| !replica.replica_is_leader())
+        .unwrap()
+        .server();
+
+    // Strong reads on a follower see every write decided before them.
+    for i in 1..=3 {
+        setup::execute_query(
+            1,
+            format!("INSERT INTO test_follower_reads VALUES ({});", i),
+            Consistency::Strong,
+        )
+        .await;
+        let results = follower
+            .query(
+                "SELECT COUNT(*) FROM test_follower_reads",
+                chiselstore::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.rows[0].values, [i.to_string()]);
+    }
+
+    // And are served locally whenever the leader holds its lease.
+    let deadline = Instant::now() + Duration::from_secs(15);
+    let mut local = false;
+    while !local && Instant::now() < deadline {
+        let applied_idx = follower.applied_idx();
+        let results = follower
+            .query(
+                "SELECT COUNT(*) FROM test_follower_reads",
+                chiselstore::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.rows[0].values, ["3"]);
+        local = follower.applied_idx() == applied_idx;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(local);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_follower_reads;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_peer_mailbox() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_peer_mailbox test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_peer_mailbox (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    // Without overload every peer message is handled.
+    for replica in &cluster {
+        let stats = replica.server().peer_mailbox().stats();
+        assert!(stats.admitted > 0);
+        assert_eq!(stats.rejected, 0);
+    }
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_peer_mailbox;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_full_text_search() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            full_text_search: true,
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_full_text_search test ----");
+    let server = cluster[0].server();
+    server
+        .query(
+            "CREATE VIRTUAL TABLE test_full_text_search USING fts5(title, body)",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    server
+        .query(
+            "INSERT INTO test_full_text_search VALUES \
+             ('replication', 'every replica applies the same log'), \
+             ('elections', 'the leader sends heartbeats')",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+
+    // Every replica indexed the rows with the tokenizer pinned on creation.
+    for id in 1..=3 {
+        let rows = setup::execute_query(
+            id,
+            String::from(
+                "SELECT title FROM test_full_text_search WHERE test_full_text_search MATCH 'replica';",
+            ),
+            Consistency::Strong,
+        )
+        .await;
+        assert_eq!(rows, ["replication"]);
+    }
+    let schema = server
+        .query(
+            "SELECT sql FROM sqlite_master WHERE name = 'test_full_text_search'",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert!(schema.rows[0].values[0].contains("tokenize = 'unicode61'"));
+
+    // Tokenizers other than the built-in ones are refused before proposing.
+    let custom = server
+        .query(
+            "CREATE VIRTUAL TABLE test_full_text_custom USING fts5(body, tokenize = 'mytokenizer')",
+            chiselstore::Consistency::Strong,
+        )
+        .await;
+    assert!(matches!(custom, Err(StoreError::InvalidRequest(_))));
+
+    server
+        .query(
+            "DROP TABLE IF EXISTS test_full_text_search",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[test]
+fn test_import_upstream_database() {
+    let dir = std::env::temp_dir();
+    let source = dir.join(format!("chiselstore-upstream-{}.db", std::process::id()));
+    let targets: Vec<_> = (1..=2)
+        .map(|id| {
+            dir.join(format!(
+                "chiselstore-imported-{}-{}.db",
+                id,
+                std::process::id()
+            ))
+        })
+        .collect();
+    for path in targets.iter().chain([&source]) {
+        let _ = std::fs::remove_file(path);
+    }
+    let upstream = sqlite::open(&source).unwrap();
+    upstream
+        .execute(
+            "CREATE TABLE kv (k TEXT PRIMARY KEY, v BLOB); \
+             INSERT INTO kv VALUES ('a', X'01'), ('b', NULL);",
+        )
+        .unwrap();
+    drop(upstream);
+
+    // Nodes seeded from the same database and log hold the same tables.
+    let log = [
+        "INSERT INTO kv VALUES ('c', 3.5)",
+        "DELETE FROM kv WHERE k = 'a'",
+    ];
+    let reports: Vec<_> = targets
+        .iter()
+        .map(|target| import_database(&source, target, &log).unwrap())
+        .collect();
+    assert_eq!(reports[0].log_entries, 2);
+    assert_eq!(reports[0].tables.len(), 1);
+    assert_eq!(reports[0].tables[0].table, "kv");
+    assert_eq!(reports[0].tables[0].rows, 2);
+    assert_eq!(reports[0].tables, reports[1].tables);
+
+    // Nodes that have a database already are not overwritten, and a failing
+    // log entry leaves nothing behind.
+    assert!(import_database(&source, &targets[0], &log).is_err());
+    std::fs::remove_file(&targets[1]).unwrap();
+    assert!(import_database(&source, &targets[1], &["INSERT INTO missing VALUES (1)"]).is_err());
+    assert!(!targets[1].exists());
+
+    std::fs::remove_file(&targets[0]).unwrap();
+    std::fs::remove_file(&source).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_json_results() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(2);
+
+    info!(logger, "---- Running test_json_results test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    let strong = chiselstore::proto::Consistency::Strong;
+    client
+        .query(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT)",
+            strong,
+        )
+        .await
+        .unwrap();
+    client
+        .query(
+            "INSERT INTO docs VALUES (1, json_object('tags', json_array('a', 'b'))), (2, NULL)",
+            strong,
+        )
+        .await
+        .unwrap();
+
+    let results = client
+        .query("SELECT id, body FROM docs ORDER BY id", strong)
+        .await
+        .unwrap();
+    let bodies: Vec<Option<serde_json::Value>> = results.json_column("body").unwrap();
+    assert_eq!(
+        bodies,
+        vec![Some(serde_json::json!({"tags": ["a", "b"]})), None]
+    );
+    let objects: Vec<serde_json::Value> = results.json_objects().unwrap();
+    assert_eq!(objects[1], serde_json::json!({"id": 2, "body": null}));
+    assert!(matches!(
+        results.json_column::<i64>("missing"),
+        Err(ClientError::UnknownColumn(_))
+    ));
+
+    let results = client
+        .query_json_rows(
+            "SELECT id, json_extract(body, '$.tags[0]') AS tag FROM docs ORDER BY id",
+            vec![],
+            strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.columns.len(), 1);
+    assert_eq!(results.columns[0].name, "json");
+    let rows: Vec<_> = results
+        .rows
+        .iter()
+        .map(|row| row.values[0].as_str())
+        .collect();
+    assert_eq!(rows, [r#"{"id":1,"tag":"a"}"#, r#"{"id":2,"tag":null}"#]);
+    let tags: Vec<serde_json::Value> = results.json_column("json").unwrap();
+    assert_eq!(tags[0]["tag"], "a");
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_warmup_queries() {
+    let logger = logger::create_logger();
+    let config = StoreConfig {
+        warmup_queries: vec![
+            String::from("SELECT count(*) FROM sqlite_master"),
+            String::from("SELECT * FROM test_warmup_missing"),
+        ],
+        ..Default::default()
+    };
+    let connections = config.conn_pool_size + config.analytical_pool_size;
+    let cluster = setup::make_cluster_with_config(3, config);
+
+    info!(logger, "---- Running test_warmup_queries test ----");
+    for node in &cluster {
+        let server = node.server();
+        let report = server.warmup_report();
+        assert_eq!(report.queries_run, connections);
+        assert_eq!(report.statements_compiled, 0);
+        // The table does not exist on a fresh node.
+        assert_eq!(report.failed, connections);
+    }
+    let results = setup::execute_query(1, String::from("SELECT 1"), Consistency::Strong).await;
+    assert_eq!(results, vec!["1"]);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_batch_size() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            max_batch_size: 2,
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_max_batch_size test ----");
+    let server = cluster[0].server();
+    server
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_batch_size (i INTEGER PRIMARY KEY);",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+
+    // Concurrent writes fill batches and end windows early, and every one
+    // of them still commits.
+    let writes: Vec<_> = (1..=32)
+        .map(|i| {
+            let server = server.clone();
+            tokio::spawn(async move {
+                server
+                    .query(
+                        format!("INSERT INTO test_batch_size VALUES ({})", i),
+                        chiselstore::Consistency::Strong,
+                    )
+                    .await
+            })
+        })
+        .collect();
+    for write in writes {
+        write.await.unwrap().unwrap();
+    }
+    let results = server
+        .query(
+            "SELECT count(*) FROM test_batch_size",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["32"]);
+    assert_eq!(server.batching().max_batch_size, 2);
+    let batching = server
+        .query(
+            "SELECT max_batch_size FROM chiselstore_batching",
+            chiselstore::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    assert_eq!(batching.rows[0].values, ["2"]);
+
+    server
+        .query(
+            "DROP TABLE IF EXISTS test_batch_size;",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_query_async() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_query_async test ----");
+    let server = cluster[0].server();
+    server
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_query_async (i INTEGER PRIMARY KEY);",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+
+    // Writes are all proposed before any is awaited, and commit in order.
+    let handles: Vec<_> = (1..=10)
+        .map(|i| {
+            server
+                .query_async(
+                    "INSERT INTO test_query_async VALUES (?)",
+                    vec![Value::Integer(i)],
+                )
+                .unwrap()
+        })
+        .collect();
+    let mut last_index = 0;
+    for handle in handles {
+        let write = handle.committed().await.unwrap();
+        assert!(write.index > last_index);
+        assert_eq!(write.results.rows_affected, 1);
+        last_index = write.index;
+    }
+    let results = server
+        .query(
+            "SELECT count(*) FROM test_query_async",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["10"]);
+
+    // A dropped handle still lets its write commit.
+    drop(server.query_async("INSERT INTO test_query_async VALUES (11)", vec![]));
+    let results = server
+        .query(
+            "SELECT count(*) FROM test_query_async",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["11"]);
+
+    server
+        .query(
+            "DROP TABLE IF EXISTS test_query_async;",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_query_pages() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_query_pages test ----");
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001").unwrap();
+    let strong = chiselstore::proto::Consistency::Strong;
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_pages (id INTEGER PRIMARY KEY, v TEXT);",
+            strong,
+        )
+        .await
+        .unwrap();
+    for i in 1..=10 {
+        client
+            .query(
+                format!("INSERT INTO test_pages VALUES ({}, 'v{}')", i, i),
+                strong,
+            )
+            .await
+            .unwrap();
+    }
+
+    let sql = "SELECT id, v FROM test_pages";
+    let first = client
+        .query_page(sql, &["id"], 4, None, strong)
+        .await
+        .unwrap();
+    let ids = |page: &chiselstore::proto::ResultPage| -> Vec<String> {
+        let results = page.results.as_ref().unwrap();
+        results
+            .rows
+            .iter()
+            .map(|row| row.values[0].clone())
+            .collect()
+    };
+    assert_eq!(ids(&first), ["1", "2", "3", "4"]);
+    assert!(!first.next_token.is_empty());
+
+    // Rows inserted before the last page read do not shift the pages that
+    // follow, and a page may be read from another node.
+    client
+        .query("INSERT INTO test_pages VALUES (0, 'v0')", strong)
+        .await
+        .unwrap();
+    let mut other = ChiselStoreClient::new("http://127.0.0.1:50002").unwrap();
+    let second = other
+        .query_page(
+            sql,
+            &["id"],
+            4,
+            Some(&first.next_token),
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(&second), ["5", "6", "7", "8"]);
+    assert!(second.applied_idx >= first.applied_idx);
+    let last = client
+        .query_page(sql, &["id"], 4, Some(&second.next_token), strong)
+        .await
+        .unwrap();
+    assert_eq!(ids(&last), ["9", "10"]);
+    assert!(last.next_token.is_empty());
+
+    let invalid = client
+        .query_page(sql, &["id"], 4, Some("not a token"), strong)
+        .await;
+    assert!(invalid.is_err());
+
+    client
+        .query("DROP TABLE IF EXISTS test_pages;", strong)
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_cluster_controller() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_cluster_controller test ----");
+    // Settings outlive the cluster, so every run asks for a new value.
+    let mode = format!(
+        "blue-{}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    );
+    let spec = ClusterSpec {
+        members: vec![3, 2, 1],
+        settings: [(String::from("controller.mode"), mode.clone())]
+            .into_iter()
+            .collect(),
+    };
+    let leader = cluster
+        .iter_mut()
+        .find(|replica| replica.replica_is_leader())
+        .unwrap()
+        .server();
+    let follower = cluster
+        .iter_mut()
+        .find(|replica| !replica.replica_is_leader())
+        .unwrap()
+        .server();
+
+    // Only the controller of the leader acts.
+    let idle = ClusterController::new(follower, spec.clone());
+    assert_eq!(idle.plan().await.unwrap(), vec![]);
+
+    let controller = ClusterController::new(leader.clone(), spec.clone());
+    let mut events = controller.subscribe();
+    let set_mode = ReconcileAction::SetConfig {
+        key: String::from("controller.mode"),
+        value: mode.clone(),
+    };
+    assert_eq!(
+        controller.reconcile().await.unwrap(),
+        vec![set_mode.clone()]
+    );
+    assert_eq!(
+        events.recv().await.unwrap(),
+        ReconcileEvent::Applied(set_mode)
+    );
+    assert_eq!(
+        leader.get_config("controller.mode").await.unwrap(),
+        Some(mode)
+    );
+    // The cluster matches the spec now.
+    assert_eq!(controller.reconcile().await.unwrap(), vec![]);
+
+    controller.set_spec(ClusterSpec {
+        members: vec![1, 2],
+        ..spec
+    });
+    assert_eq!(
+        controller.plan().await.unwrap(),
+        vec![ReconcileAction::Reconfigure {
+            members: vec![1, 2]
+        }]
+    );
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_wait_for_index() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_wait_for_index test ----");
+    let mut client =
+        ChiselStoreClient::with_nodes(["http://127.0.0.1:50001", "http://127.0.0.1:50002"])
+            .unwrap();
+    client
+        .query(
+            "CREATE TABLE IF NOT EXISTS test_wait_index (id INTEGER PRIMARY KEY);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let results = client
+        .query(
+            "INSERT INTO test_wait_index VALUES (1)",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let index = results.commit_idx.unwrap();
+
+    // Once the other node applied the write, a relaxed read there sees it.
+    client
+        .wait_for_index("http://127.0.0.1:50002", index, Duration::from_secs(5))
+        .await
+        .unwrap();
+    let mut reader = ChiselStoreClient::new("http://127.0.0.1:50002").unwrap();
+    let results = reader
+        .query(
+            "SELECT count(*) FROM test_wait_index",
+            chiselstore::proto::Consistency::RelaxedReads,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["1"]);
+
+    assert!(client
+        .wait_for_index(
+            "http://127.0.0.1:50002",
+            index + 1_000_000,
+            Duration::from_millis(100)
+        )
+        .await
+        .is_err());
+    assert!(matches!(
+        client
+            .wait_for_index("http://127.0.0.1:50009", index, Duration::from_secs(1))
+            .await,
+        Err(ClientError::UnknownNode(_))
+    ));
+
+    client
+        .query(
+            "DROP TABLE IF EXISTS test_wait_index;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_proposal_retries() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            proposal_retries: 5,
+            proposal_timeout: Duration::from_secs(1),
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_proposal_retries test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_proposal_retries (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::execute_query(
+        1,
+        String::from("INSERT OR REPLACE INTO test_proposal_retries VALUES (1);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    let idx = cluster
+        .iter_mut()
+        .position(|replica| replica.replica_is_leader())
+        .unwrap();
+    let leader = cluster.remove(idx);
+    let leader_id = leader.get_replica_id();
+    info!(logger, "Halting leader {}", leader_id);
+    leader.halt_replica().await;
+
+    // A strong read proposed to the old leader is proposed again once a new
+    // leader is elected, instead of failing or waiting forever.
+    let follower = cluster[0].get_replica_id();
+    let rows = setup::execute_query(
+        follower,
+        String::from("SELECT i FROM test_proposal_retries"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(rows, ["1"]);
+    assert_ne!(cluster[0].server().get_cluster_leader(), leader_id);
+
+    setup::execute_query(
+        follower,
+        String::from("DROP TABLE IF EXISTS test_proposal_retries;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_current_leader() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_current_leader test ----");
+    setup::execute_query(1, String::from("SELECT 1;"), Consistency::Strong).await;
+    let leader = cluster[0].server().current_leader().unwrap();
+    assert_eq!(leader.id, cluster[0].server().get_cluster_leader());
+    assert_eq!(leader.ballot.pid, leader.id);
+    let addr = format!("http://127.0.0.1:5000{}", leader.id);
+    assert_eq!(leader.addr.as_deref(), Some(addr.as_str()));
+
+    // Every node names the same leader remotely.
+    for node in ["http://127.0.0.1:50002", "http://127.0.0.1:50003"] {
+        let mut client = ChiselStoreClient::new(node).unwrap();
+        let remote = client.leader().await.unwrap();
+        assert_eq!(remote.id, leader.id);
+        assert_eq!(remote.addr, addr);
+        assert_eq!(remote.ballot.unwrap().n, leader.ballot.n);
+    }
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_forward_writes() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            forward_writes: true,
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_forward_writes test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_forward_writes (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::execute_query(
+        1,
+        String::from("DELETE FROM test_forward_writes;"),
+        Consistency::Strong,
+    )
+    .await;
+    let leader = cluster
+        .iter_mut()
+        .find(|replica| replica.replica_is_leader())
+        .unwrap()
+        .get_replica_id();
+
+    // Writes sent to followers are executed by the leader, and their
+    // results relayed back.
+    let followers: Vec<u64> = (1..=3).filter(|id| *id != leader).collect();
+    for id in &followers {
+        let mut client = ChiselStoreClient::new(format!("http://127.0.0.1:5000{}", id)).unwrap();
+        let results = client
+            .query(
+                format!("INSERT INTO test_forward_writes VALUES ({})", id),
+                chiselstore::proto::Consistency::Strong,
+            )
+            .await
+            .unwrap();
+        assert!(results.commit_idx.is_some());
+    }
+    let rows = setup::execute_query(
+        leader,
+        String::from("SELECT i FROM test_forward_writes ORDER BY i;"),
+        Consistency::Strong,
+    )
+    .await;
+    let expected: Vec<String> = followers.iter().map(|id| id.to_string()).collect();
+    assert_eq!(rows, expected);
+
+    setup::execute_query(
+        leader,
+        String::from("DROP TABLE IF EXISTS test_forward_writes;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch_leader() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_watch_leader test ----");
+    setup::execute_query(1, String::from("SELECT 1;"), Consistency::Strong).await;
+    let idx = cluster
+        .iter_mut()
+        .position(|replica| replica.replica_is_leader())
+        .unwrap();
+    let leader = cluster.remove(idx);
+    let leader_id = leader.get_replica_id();
+    let mut changes = cluster[0].server().watch_leader();
+    info!(logger, "Halting leader {}", leader_id);
+    leader.halt_replica().await;
+
+    // The remaining nodes elect a new leader, and subscribers hear of it.
+    let elected = tokio::time::timeout(Duration::from_secs(30), changes.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(elected.id, leader_id);
+    assert_eq!(elected.ballot.pid, elected.id);
+    let addr = format!("http://127.0.0.1:5000{}", elected.id);
+    assert_eq!(elected.addr.as_deref(), Some(addr.as_str()));
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_leadership() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_transfer_leadership test ----");
+    setup::execute_query(1, String::from("SELECT 1;"), Consistency::Strong).await;
+    let idx = cluster
+        .iter_mut()
+        .position(|replica| replica.replica_is_leader())
+        .unwrap();
+    let leader = cluster[idx].server();
+    let target = cluster[(idx + 1) % 3].get_replica_id();
+
+    // Only the leader can hand its leadership over.
+    assert!(matches!(
+        cluster[(idx + 1) % 3]
+            .server()
+            .transfer_leadership(leader.id(), Duration::from_secs(1))
+            .await,
+        Err(StoreError::NotLeader)
+    ));
+
+    leader
+        .transfer_leadership(target, Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!(leader.current_leader().unwrap().id, target);
+
+    // The preferred leader outranks the others, so it keeps the leadership
+    // through later heartbeat rounds.
+    let rounds = Instant::now() + Duration::from_secs(12);
+    while Instant::now() < rounds {
+        for replica in cluster.iter() {
+            assert_eq!(replica.server().get_cluster_leader(), target);
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    // Strong queries go on through the new leader.
+    let rows =
+        setup::execute_query(leader.id(), String::from("SELECT 2;"), Consistency::Strong).await;
+    assert_eq!(rows, ["2"]);
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leader_priority() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_leader_priority test ----");
+    setup::execute_query(1, String::from("SELECT 1;"), Consistency::Strong).await;
+    let idx = cluster
+        .iter_mut()
+        .position(|replica| !replica.replica_is_leader())
+        .unwrap();
+    let preferred = cluster[idx].server();
+    assert_eq!(preferred.leader_priority(), 0);
+
+    // A higher priority wins ties between ballots of the same round, so the
+    // preferred node takes over.
+    preferred.set_leader_priority(100);
+    assert_eq!(preferred.leader_priority(), 100);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while cluster[0].server().get_cluster_leader() != preferred.id() {
+        assert!(Instant::now() < deadline, "preferred node was not elected");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_join_cluster() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_join_cluster test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_join (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::execute_query(
+        1,
+        String::from("INSERT OR REPLACE INTO test_join VALUES (1);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    let leader = cluster
+        .iter_mut()
+        .find(|replica| replica.replica_is_leader())
+        .unwrap()
+        .server();
+    leader.reconfigure(vec![1, 2, 3, 4]).unwrap();
+    while cluster
+        .iter()
+        .any(|replica| replica.server().config_id() != 2)
+    {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // Only a node without a database can be bootstrapped.
+    assert!(join_cluster(1, "http://127.0.0.1:50002").await.is_err());
+    for file in ["node4.db", "node4.db-wal", "node4.db-shm"] {
+        let _ = std::fs::remove_file(file);
+    }
+    let joined = join_cluster(4, "http://127.0.0.1:50001").await.unwrap();
+    assert_eq!(joined.config_id, 2);
+    assert_eq!(joined.members, [1, 2, 3, 4]);
+    assert_eq!(joined.applied_idx, leader.archive(1).unwrap().applied_idx());
+
+    let node = setup::SPReplica::with_config(
+        4,
+        vec![1, 2, 3],
+        Network::default(),
+        StoreConfig {
+            join: Some(joined.clone()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(node.server().config_id(), 2);
+    assert!(node.server().applied_idx() >= joined.applied_idx);
+
+    // The node starts from the data of the previous configuration, and
+    // takes part in the new one.
+    let rows = setup::execute_query(
+        4,
+        String::from("SELECT i FROM test_join;"),
+        Consistency::RelaxedReads,
+    )
+    .await;
+    assert_eq!(rows, ["1"]);
+    setup::execute_query(
+        4,
+        String::from("INSERT OR REPLACE INTO test_join VALUES (2);"),
+        Consistency::Strong,
+    )
+    .await;
+    let rows = setup::execute_query(
+        1,
+        String::from("SELECT i FROM test_join ORDER BY i;"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(rows, ["1", "2"]);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_join;"),
+        Consistency::Strong,
+    )
+    .await;
+    cluster.push(node);
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_learner() {
+    let logger = logger::create_logger();
+    let mut cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_learner test ----");
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_learner (i INTEGER PRIMARY KEY);"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::execute_query(
+        1,
+        String::from("INSERT OR REPLACE INTO test_learner VALUES (1);"),
+        Consistency::Strong,
+    )
+    .await;
+
+    let path = std::env::temp_dir().join(format!("chiselstore-learner-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    cluster[0].server().backup(&path).unwrap();
+    let learner = Learner::open(
+        5,
+        &path,
+        "http://127.0.0.1:50001",
+        FunctionRegistry::default(),
+    )
+    .unwrap();
+
+    // The learner applies what the cluster decides without taking part.
+    setup::execute_query(
+        1,
+        String::from("INSERT OR REPLACE INTO test_learner VALUES (2);"),
+        Consistency::Strong,
+    )
+    .await;
+    assert!(learner.catch_up().await.unwrap() > 0);
+    let results = learner
+        .query("SELECT i FROM test_learner ORDER BY i")
+        .unwrap();
+    let rows: Vec<_> = results
+        .rows
+        .iter()
+        .map(|row| row.values[0].clone())
+        .collect();
+    assert_eq!(rows, ["1", "2"]);
+    assert!(learner
+        .query("INSERT INTO test_learner VALUES (3)")
+        .is_err());
+    assert!(learner.promotion().is_none());
+
+    // Once a reconfiguration adds the learner, it stops at the stop sign
+    // and is promoted to a voter.
+    let leader = cluster
+        .iter_mut()
+        .find(|replica| replica.replica_is_leader())
+        .unwrap()
+        .server();
+    leader.reconfigure(vec![1, 2, 3, 5]).unwrap();
+    let joined = learner.follow(Duration::from_millis(50)).await.unwrap();
+    assert_eq!(joined.config_id, 2);
+    assert_eq!(joined.members, [1, 2, 3, 5]);
+    assert_eq!(joined.applied_idx, learner.applied_idx());
+    for file in ["node5.db", "node5.db-wal", "node5.db-shm"] {
+        let _ = std::fs::remove_file(file);
+    }
+    assert_eq!(learner.promote().unwrap(), joined);
+
+    let node = setup::SPReplica::with_config(
+        5,
+        vec![1, 2, 3],
+        Network::default(),
+        StoreConfig {
+            join: Some(joined),
+            ..Default::default()
+        },
+    );
+    assert_eq!(node.server().config_id(), 2);
+    let rows = setup::execute_query(
+        5,
+        String::from("SELECT i FROM test_learner ORDER BY i;"),
+        Consistency::RelaxedReads,
+    )
+    .await;
+    assert_eq!(rows, ["1", "2"]);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_learner;"),
+        Consistency::Strong,
+    )
+    .await;
+    cluster.push(node);
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_learner_restart() {
+    let logger = logger::create_logger();
+    let double = FunctionDef::new("double", 1, |args| match &args[0] {
+        Value::Integer(n) => Ok(Value::Integer(n * 2)),
+        _ => Err("double expects an integer".to_string()),
+    });
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            functions: FunctionRegistry::new().with(double.clone()),
+            apply_error_policy: chiselstore::ApplyErrorPolicy::SkipAndRecord,
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_learner_restart test ----");
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_learner_restart (i INTEGER PRIMARY KEY, n INTEGER);",
+        "INSERT OR REPLACE INTO test_learner_restart VALUES (1, 0);",
+    ] {
+        setup::execute_query(1, String::from(stmt), Consistency::Strong).await;
+    }
+    let path = std::env::temp_dir().join(format!(
+        "chiselstore-learner-restart-{}.db",
+        std::process::id()
+    ));
+    let tenant_dir = std::env::temp_dir().join(format!(
+        "chiselstore-learner-tenants-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_dir_all(&tenant_dir);
+    cluster[0].server().backup(&path).unwrap();
+
+    // Commands that are not idempotent, one rolling the transaction it runs
+    // in back, a tenant's, and then one the learner cannot apply yet.
+    for stmt in [
+        "UPDATE test_learner_restart SET n = n + 1 WHERE i = 1;",
+        "UPDATE test_learner_restart SET n = n + 1 WHERE i = 1;",
+    ] {
+        setup::execute_query(1, String::from(stmt), Consistency::Strong).await;
+    }
+    assert!(cluster[0]
+        .server()
+        .query(
+            "INSERT OR ROLLBACK INTO test_learner_restart VALUES (1, 100);",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .is_err());
+    let mut tenant = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_tenant("learner");
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_learner_restart (i INTEGER PRIMARY KEY, n INTEGER);",
+        "INSERT OR REPLACE INTO test_learner_restart VALUES (1, 0);",
+        "UPDATE test_learner_restart SET n = n + 1 WHERE i = 1;",
+    ] {
+        tenant
+            .query(stmt, chiselstore::proto::Consistency::Strong)
+            .await
+            .unwrap();
+    }
+    for stmt in [
+        "UPDATE test_learner_restart SET n = double(n) WHERE i = 1;",
+        "UPDATE test_learner_restart SET n = n + 1 WHERE i = 1;",
+    ] {
+        setup::execute_query(1, String::from(stmt), Consistency::Strong).await;
+    }
+
+    // The batch stops at the command using `double`, after the ones before
+    // it.
+    let learner = Learner::open(
+        6,
+        &path,
+        "http://127.0.0.1:50001",
+        FunctionRegistry::default(),
+    )
+    .unwrap()
+    .with_tenant_dir(&tenant_dir);
+    assert!(matches!(
+        learner.catch_up().await,
+        Err(StoreError::MissingFunction(_))
+    ));
+    let stopped_idx = learner.applied_idx();
+    let n = |learner: &Learner| {
+        learner
+            .query("SELECT n FROM test_learner_restart WHERE i = 1")
+            .unwrap()
+            .rows[0]
+            .values
+            .clone()
+    };
+    assert_eq!(n(&learner), ["2"]);
+    assert_eq!(learner.failures().len(), 1);
+    drop(learner);
+
+    // After a restart, the learner picks up where its databases left off
+    // without applying any command twice.
+    let learner = Learner::open(
+        6,
+        &path,
+        "http://127.0.0.1:50001",
+        FunctionRegistry::new().with(double),
+    )
+    .unwrap()
+    .with_tenant_dir(&tenant_dir);
+    assert_eq!(learner.applied_idx(), stopped_idx);
+    learner.catch_up().await.unwrap();
+    assert_eq!(learner.applied_idx(), stopped_idx + 2);
+    assert_eq!(n(&learner), ["5"]);
+    let rows = learner
+        .query_tenant("learner", "SELECT n FROM test_learner_restart WHERE i = 1")
+        .unwrap();
+    assert_eq!(rows.rows[0].values, ["1"]);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_learner_restart;"),
+        Consistency::Strong,
+    )
+    .await;
+    tenant
+        .query(
+            "DROP TABLE IF EXISTS test_learner_restart;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_panicking_functions() {
+    let logger = logger::create_logger();
+    let boom = FunctionDef::new("boom", 1, |_| panic!("boom"));
+    let unordered = CollationDef::new("unordered", |_: &str, _: &str| panic!("unordered"));
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            functions: FunctionRegistry::new().with(boom).with_collation(unordered),
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_panicking_functions test ----");
+    // A panicking function fails the statement instead of the replica.
+    let status =
+        setup::try_execute_query(1, String::from("SELECT boom(1)"), Consistency::RelaxedReads)
+            .await
+            .unwrap_err();
+    assert!(status.message().contains("function panicked: boom"));
+
+    // A panicking collation compares texts as equal.
+    let rows = setup::execute_query(
+        1,
+        String::from("SELECT 'a' = 'b' COLLATE unordered"),
+        Consistency::RelaxedReads,
+    )
+    .await;
+    assert_eq!(rows, vec!["1".to_string()]);
+    setup::execute_query(1, String::from("SELECT 1"), Consistency::RelaxedReads).await;
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[test]
+fn test_sql_literals() {
+    assert_eq!(Value::Real(1.0).to_sql_literal().unwrap(), "1.0");
+    assert_eq!(Value::from("it's").to_sql_literal().unwrap(), "'it''s'");
+    assert_eq!(
+        Value::Blob(vec![0, 255]).to_sql_literal().unwrap(),
+        "X'00ff'"
+    );
+    for real in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+        assert!(matches!(
+            Value::Real(real).to_sql_literal(),
+            Err(StoreError::InvalidRequest(_))
+        ));
+    }
+    assert!(chiselstore::upsert::upsert_statements(
+        "t",
+        &["k", "v"],
+        &["k"],
+        &[vec![Value::Integer(1), Value::Real(f64::NAN)]],
+    )
+    .is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_upsert_chunks_in_order() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            apply_error_policy: chiselstore::ApplyErrorPolicy::SkipAndRecord,
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_upsert_chunks_in_order test ----");
+    setup::execute_query(
+        1,
+        String::from(
+            "CREATE TABLE IF NOT EXISTS test_upsert (k INTEGER PRIMARY KEY, v INTEGER CHECK (v >= 0));",
+        ),
+        Consistency::Strong,
+    )
+    .await;
+    let server = cluster[0].server();
+    let rows = |n: i64, v: i64| -> Vec<Vec<Value>> {
+        (0..n)
+            .map(|k| vec![Value::Integer(k), Value::Integer(v)])
+            .collect()
+    };
+
+    // Every chunk is sent, one after the other.
+    let statements = server
+        .upsert("test_upsert", &["k", "v"], &["k"], &rows(1200, 1))
+        .await
+        .unwrap();
+    assert_eq!(
+        statements,
+        1200 / chiselstore::upsert::UPSERT_CHUNK_ROWS + 1
+    );
+
+    // A failing chunk leaves the chunks before it applied and stops the
+    // upsert there.
+    let mut failing = rows(1200, 2);
+    failing[chiselstore::upsert::UPSERT_CHUNK_ROWS + 1][1] = Value::Integer(-1);
+    assert!(server
+        .upsert("test_upsert", &["k", "v"], &["k"], &failing)
+        .await
+        .is_err());
+    let counts = setup::execute_query(
+        1,
+        String::from("SELECT v, count(*) FROM test_upsert GROUP BY v ORDER BY v;"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(
+        counts,
+        vec![
+            "1".to_string(),
+            "700".to_string(),
+            "2".to_string(),
+            "500".to_string()
+        ]
+    );
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE IF EXISTS test_upsert;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_stall_report() {
+    let logger = logger::create_logger();
+    let dir = std::env::temp_dir().join(format!("chiselstore-stall-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let on_stall = reports.clone();
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            stall_timeout: Duration::from_millis(500),
+            diagnostics_dir: dir.clone(),
+            on_stall: Some(Arc::new(move |report: &StallReport| {
+                on_stall.lock().unwrap().push(report.clone())
+            })),
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_stall_report test ----");
+    setup::execute_query(1, String::from("SELECT 1"), Consistency::Strong).await;
+    let leader = cluster[0].server().get_cluster_leader();
+    let (mut leaders, followers): (Vec<_>, Vec<_>) = cluster
+        .into_iter()
+        .partition(|replica| replica.get_replica_id() == leader);
+    let leader = leaders.pop().unwrap();
+    let applied = leader.server().applied_idx();
+
+    // Without its followers, the leader cannot decide the write, which
+    // never applies.
+    setup::halt_all_replicas(followers).await;
+    let server = leader.server();
+    let write = tokio::spawn(async move {
+        server
+            .query(
+                "CREATE TABLE test_stall_report (n INTEGER)",
+                chiselstore::Consistency::Strong,
+            )
+            .await
+    });
+    let started = Instant::now();
+    while reports.lock().unwrap().is_empty() && started.elapsed() < Duration::from_secs(10) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    write.abort();
+
+    let report = reports.lock().unwrap()[0].clone();
+    assert_eq!(report.node_id, leader.get_replica_id());
+    assert_eq!(report.applied_idx, applied);
+    assert_eq!(report.ballot.pid, leader.get_replica_id());
+    assert!(report.accepted_idx > report.decided_idx);
+    let bundle = std::fs::read_to_string(report.bundle_path.unwrap()).unwrap();
+    assert!(bundle.contains(&format!("decided_idx: {}", report.decided_idx)));
+    assert!(bundle.contains(&format!("accepted_idx: {}", report.accepted_idx)));
+    assert!(bundle.contains(&format!("pid {}", report.ballot.pid)));
+
+    leader.halt_replica().await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_lane_concurrency_must_be_positive() {
+    for config in [
+        StoreConfig {
+            transactional_concurrency: 0,
+            ..Default::default()
+        },
+        StoreConfig {
+            analytical_concurrency: 0,
+            ..Default::default()
+        },
+    ] {
+        let transport = chiselstore::rpc::RpcTransport::new(Box::new(|id| {
+            format!("http://127.0.0.1:5000{}", id)
+        }));
+        assert!(matches!(
+            chiselstore::StoreServer::start_with_config(9, vec![], transport, config),
+            Err(StoreError::InvalidRequest(_))
+        ));
+    }
+}
+
+/// Drain keeping the messages logged through it.
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl slog::Drain for CapturedLogs {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", record.level(), record.msg()));
+        Ok(())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_peer_deadlines() {
+    use chiselstore::rpc::{PeerDeadlines, RpcTransport};
+    use chiselstore::SequencePaxosStoreTransport;
+    use omnipaxos_core::{ballot_leader_election::messages as ble, messages};
+
+    // A peer that accepts connections but never answers.
+    let peer = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = peer.local_addr().unwrap().port();
+    let transport = RpcTransport::new(Box::new(move |_| format!("http://127.0.0.1:{}", port)))
+        .with_deadlines(PeerDeadlines {
+            heartbeat: Duration::from_millis(200),
+            sync: Duration::from_millis(200),
+            message: Duration::from_millis(200),
+        });
+    let logs = Arc::new(std::sync::Mutex::new(Vec::new()));
+    transport.set_logger(slog::Logger::root(CapturedLogs(logs.clone()), slog::o!()));
+
+    // A send gives up once its deadline passed.
+    transport.send_paxos_message(messages::Message::with(
+        1,
+        7,
+        messages::PaxosMsg::PrepareReq,
+    ));
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(
+        *logs.lock().unwrap(),
+        vec!["WARN Peer 7 missed deadline".to_string()]
+    );
+
+    // Heartbeats produced while one is in flight replace each other, and
+    // the latest is sent once the one in flight is done.
+    logs.lock().unwrap().clear();
+    for round in [1, 2, 3] {
+        transport.send_ble_message(ble::BLEMessage::with(
+            1,
+            8,
+            ble::HeartbeatMsg::Request(ble::HeartbeatRequest::with(round)),
+        ));
+    }
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(
+        *logs.lock().unwrap(),
+        vec!["WARN Peer 8 missed deadline".to_string(); 2]
+    );
+    drop(peer);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rpcs_wait_for_ready() {
+    use chiselstore::proto;
+    use chiselstore::rpc::proto::rpc_server::Rpc;
+    use chiselstore::rpc::{RpcService, RpcTransport};
+
+    let logger = logger::create_logger();
+    info!(logger, "---- Running test_rpcs_wait_for_ready test ----");
+    let transport = RpcTransport::new(Box::new(|id| format!("http://127.0.0.1:5000{}", id)));
+    let config = StoreConfig {
+        override_membership: true,
+        ..Default::default()
+    };
+    let server = Arc::new(
+        chiselstore::StoreServer::start_with_config(6, vec![7], transport, config).unwrap(),
+    );
+    let rpc = RpcService::new(server.clone());
+    let query = || {
+        tonic::Request::new(proto::Query {
+            sql: String::from("SELECT 1"),
+            consistency: proto::Consistency::RelaxedReads as i32,
+            ..Default::default()
+        })
+    };
+    let prepare_request = || tonic::Request::new(proto::PrepareReq { from: 7, to: 6 });
+
+    // Until its message loop runs, the replica rejects peers and clients.
+    assert_eq!(server.lifecycle(), Lifecycle::Initializing);
+    let status = rpc.prepare_request(prepare_request()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+    let status = rpc.execute(query()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+
+    let msg_loop = server.clone();
+    let msg_loop = tokio::task::spawn(async move { msg_loop.start_msg_event_loop() });
+    let started = Instant::now();
+    while server.lifecycle() != Lifecycle::Ready {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    rpc.prepare_request(prepare_request()).await.unwrap();
+    let results = rpc.execute(query()).await.unwrap().into_inner();
+    assert_eq!(results.rows[0].values, vec!["1".to_string()]);
+
+    server.halt(true);
+    msg_loop.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_apply_error_callback() {
+    let logger = logger::create_logger();
+    // Duplicate keys are skipped, unless the row asks for a halt.
+    let decide = |cmd: &StoreCommand, _: &StoreError| {
+        if cmd.sql.contains("'halt'") {
+            ApplyErrorAction::Halt
+        } else {
+            ApplyErrorAction::Skip
+        }
+    };
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            apply_error_policy: ApplyErrorPolicy::Callback(Arc::new(decide)),
+            ..Default::default()
+        },
+    );
+
+    info!(logger, "---- Running test_apply_error_callback test ----");
+    for stmt in [
+        "DROP TABLE IF EXISTS test_apply_error_callback;",
+        "CREATE TABLE test_apply_error_callback (i INTEGER PRIMARY KEY, s TEXT);",
+        "INSERT INTO test_apply_error_callback VALUES (1, 'first');",
+    ] {
+        setup::execute_query(1, String::from(stmt), Consistency::Strong).await;
+    }
+    let server = cluster[0].server();
+
+    // The client still learns about the failure of a skipped command.
+    let skipped = "INSERT INTO test_apply_error_callback VALUES (1, 'skip');";
+    assert!(server
+        .query(skipped, chiselstore::Consistency::Strong)
+        .await
+        .is_err());
+    let failures = server.apply_failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].command.sql, skipped);
+    setup::execute_query(
+        1,
+        String::from("INSERT INTO test_apply_error_callback VALUES (2, 'second');"),
+        Consistency::Strong,
+    )
+    .await;
+    let res = setup::execute_query(
+        1,
+        String::from("SELECT count(*) FROM test_apply_error_callback;"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(res, vec!["2"]);
+
+    // Every replica applies the command the callback halts on.
+    assert!(server
+        .query(
+            "INSERT INTO test_apply_error_callback VALUES (1, 'halt');",
+            chiselstore::Consistency::Strong,
+        )
+        .await
+        .is_err());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while cluster
+        .iter()
+        .any(|replica| replica.server().lifecycle() != Lifecycle::ShuttingDown)
+    {
+        assert!(Instant::now() < deadline, "replicas did not halt");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(server.apply_failures().len(), 1);
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_offline_queue_replay() {
+    let logger = logger::create_logger();
+
+    info!(logger, "---- Running test_offline_queue_replay test ----");
+    // No replica runs yet, so writes are queued.
+    let mut client = ChiselStoreClient::new("http://127.0.0.1:50001")
+        .unwrap()
+        .with_offline_queue(3);
+    for (sql, key) in [
+        (
+            "CREATE TABLE IF NOT EXISTS test_offline_queue_replay (i INTEGER PRIMARY KEY);",
+            Some("create"),
+        ),
+        (
+            "INSERT INTO test_offline_queue_replay VALUES (1);",
+            Some("insert-1"),
+        ),
+        // Re-submitting a queued write is a no-op.
+        (
+            "INSERT INTO test_offline_queue_replay VALUES (1);",
+            Some("insert-1"),
+        ),
+        ("INSERT INTO test_offline_queue_replay VALUES (2);", None),
+    ] {
+        let outcome = client
+            .execute_or_queue(sql, key.map(String::from))
+            .await
+            .unwrap();
+        assert!(matches!(outcome, WriteOutcome::Queued { .. }));
+    }
+    assert_eq!(client.queued_writes(), 3);
+    let err = client
+        .execute_or_queue("INSERT INTO test_offline_queue_replay VALUES (3);", None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::QueueFull(3)));
+
+    let cluster = setup::make_cluster(3);
+    let mut outcomes = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while client.queued_writes() > 0 {
+        assert!(Instant::now() < deadline, "queued writes were not replayed");
+        outcomes.extend(client.replay().await);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let keys: Vec<_> = outcomes
+        .iter()
+        .map(|outcome| outcome.key.as_str())
+        .collect();
+    assert_eq!(keys[..2], ["create", "insert-1"]);
+    assert_eq!(keys.len(), 3);
+    assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+    let res = setup::execute_query(
+        1,
+        String::from("SELECT i FROM test_offline_queue_replay ORDER BY i;"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(res, vec!["1", "2"]);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE test_offline_queue_replay;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_custom_transport() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_custom_transport test ----");
+    let channel = tonic::transport::Endpoint::from_static("http://127.0.0.1:50002")
+        .connect_lazy()
+        .unwrap();
+    let mut client = ChiselStoreClient::with_transport(channel, "custom");
+    for stmt in [
+        "CREATE TABLE IF NOT EXISTS test_custom_transport (i INTEGER PRIMARY KEY);",
+        "INSERT INTO test_custom_transport VALUES (1);",
+    ] {
+        client
+            .query(stmt, chiselstore::proto::Consistency::Strong)
+            .await
+            .unwrap();
+    }
+    let results = client
+        .query(
+            "SELECT i FROM test_custom_transport;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["1"]);
+
+    client
+        .query(
+            "DROP TABLE test_custom_transport;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_publish_subscribe() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_publish_subscribe test ----");
+    let mut local = cluster[1].server().subscribe("orders");
+    let mut rpc = setup::proto::rpc_client::RpcClient::connect("http://127.0.0.1:50003")
+        .await
+        .unwrap();
+    let mut remote = rpc
+        .subscribe(setup::proto::Subscription {
+            topic: String::from("orders"),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    let server = cluster[0].server();
+    for (topic, payload) in [
+        ("orders", "a"),
+        ("inventory", "x"),
+        ("orders", "b"),
+        ("orders", "c"),
+    ] {
+        server
+            .publish(topic, payload.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+
+    let timeout = Duration::from_secs(5);
+    let mut indexes = Vec::new();
+    for payload in ["a", "b", "c"] {
+        let publication = tokio::time::timeout(timeout, local.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(publication.topic, "orders");
+        assert_eq!(publication.payload, payload.as_bytes());
+        let msg = tokio::time::timeout(timeout, remote.message())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.topic, "orders");
+        assert_eq!(msg.payload, payload.as_bytes());
+        indexes.push(publication.index);
+    }
+    assert!(indexes.windows(2).all(|pair| pair[0] < pair[1]));
+    // Messages of other topics are not delivered.
+    assert!(local.try_recv().is_err());
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_request_id_and_server_timing() {
+    use chiselstore::rpc::{REQUEST_ID_HEADER, SERVER_TIMING_HEADER};
+
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(
+        logger,
+        "---- Running test_request_id_and_server_timing test ----"
+    );
+    let mut rpc = setup::proto::rpc_client::RpcClient::connect("http://127.0.0.1:50001")
+        .await
+        .unwrap();
+    let query = |sql: &str, request_id: &'static str| {
+        let mut request = tonic::Request::new(setup::proto::Query {
+            sql: String::from(sql),
+            consistency: Consistency::Strong as i32,
+            ..Default::default()
+        });
+        request
+            .metadata_mut()
+            .insert(REQUEST_ID_HEADER, request_id.parse().unwrap());
+        request
+    };
+
+    let response = rpc.execute(query("SELECT 1;", "req-1")).await.unwrap();
+    assert_eq!(response.metadata().get(REQUEST_ID_HEADER).unwrap(), "req-1");
+    let timing = response
+        .metadata()
+        .get(SERVER_TIMING_HEADER)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let phases: Vec<_> = timing
+        .split(", ")
+        .map(|phase| phase.split(";dur=").next().unwrap())
+        .collect();
+    assert_eq!(phases, ["queue", "replicate", "apply", "serialize"]);
+
+    // Failed requests echo the id too.
+    let status = rpc
+        .execute(query("SELECT * FROM test_request_id_missing;", "req-2"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.metadata().get(REQUEST_ID_HEADER).unwrap(), "req-2");
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unknown_peers_rejected() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_unknown_peers_rejected test ----");
+    let mut rpc = setup::proto::rpc_client::RpcClient::connect("http://127.0.0.1:50001")
+        .await
+        .unwrap();
+    // A sender outside the configuration, and a message for another node.
+    for (from, to) in [(9, 1), (2, 3)] {
+        let status = rpc
+            .prepare_request(setup::proto::PrepareReq { from, to })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+    let server = cluster[0].server();
+    assert!(matches!(
+        server.verify_peer(9, 1),
+        Err(StoreError::UnknownPeer { from: 9, to: 1 })
+    ));
+    server.verify_peer(2, 1).unwrap();
+
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reuse_port_acceptors() {
+    use futures_util::StreamExt;
+
+    let logger = logger::create_logger();
+
+    info!(logger, "---- Running test_reuse_port_acceptors test ----");
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = ServerConfig {
+        acceptors: 2,
+        ..Default::default()
+    };
+    let err = config.bind(addr).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    let config = ServerConfig {
+        acceptors: 4,
+        reuse_port: true,
+        keepalive: Some(Duration::from_secs(30)),
+        ..Default::default()
+    };
+    let mut incoming = Box::pin(config.incoming(addr).unwrap());
+    let mut clients = Vec::new();
+    for _ in 0..8 {
+        clients.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+    }
+    for _ in 0..clients.len() {
+        let conn = tokio::time::timeout(Duration::from_secs(5), incoming.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(conn.nodelay().unwrap());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_apply_statement_timeout() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster_with_config(
+        3,
+        StoreConfig {
+            apply_error_policy: ApplyErrorPolicy::SkipAndRecord,
+            apply_statement_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        },
+    );
+
+    info!(
+        logger,
+        "---- Running test_apply_statement_timeout test ----"
+    );
+    setup::execute_query(
+        1,
+        String::from("CREATE TABLE IF NOT EXISTS test_apply_statement_timeout (i INTEGER);"),
+        Consistency::Strong,
+    )
+    .await;
+    let server = cluster[0].server();
+    let slow = "INSERT INTO test_apply_statement_timeout \
+        WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) \
+        SELECT x FROM c LIMIT 100000000;";
+    let started = Instant::now();
+    assert!(server
+        .query(slow, chiselstore::Consistency::Strong)
+        .await
+        .is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let failures = server.apply_failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].command.sql, slow);
+    assert_eq!(
+        failures[0].error,
+        StoreError::StatementTimeout(Duration::from_millis(100)).to_string()
+    );
+
+    // The interrupted statement is rolled back, and later ones apply.
+    setup::execute_query(
+        1,
+        String::from("INSERT INTO test_apply_statement_timeout VALUES (1);"),
+        Consistency::Strong,
+    )
+    .await;
+    let res = setup::execute_query(
+        1,
+        String::from("SELECT count(*) FROM test_apply_statement_timeout;"),
+        Consistency::Strong,
+    )
+    .await;
+    assert_eq!(res, vec!["1"]);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE test_apply_statement_timeout;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_multi_cluster_routing() {
+    let logger = logger::create_logger();
+    let cluster = setup::make_cluster(3);
+
+    info!(logger, "---- Running test_multi_cluster_routing test ----");
+    // The test runs one cluster, so each namespace gets a node of its own.
+    let mut client = MultiClusterClient::new()
+        .with_cluster(
+            "orders",
+            ChiselStoreClient::new("http://127.0.0.1:50001").unwrap(),
+        )
+        .with_cluster(
+            "users",
+            ChiselStoreClient::new("http://127.0.0.1:50002").unwrap(),
+        );
+    client
+        .query(
+            "orders",
+            "CREATE TABLE IF NOT EXISTS test_multi_cluster_routing (i INTEGER PRIMARY KEY);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    client
+        .query(
+            "users",
+            "INSERT INTO test_multi_cluster_routing VALUES (1);",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    let err = client
+        .query(
+            "billing",
+            "SELECT i FROM test_multi_cluster_routing;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::UnknownNamespace(namespace) if namespace == "billing"));
+
+    // Namespaces without a cluster fall back to the default one.
+    let mut client = client.with_default_namespace("orders");
+    let results = client
+        .query(
+            "billing",
+            "SELECT i FROM test_multi_cluster_routing;",
+            chiselstore::proto::Consistency::Strong,
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows[0].values, ["1"]);
+
+    setup::execute_query(
+        1,
+        String::from("DROP TABLE test_multi_cluster_routing;"),
+        Consistency::Strong,
+    )
+    .await;
+    setup::halt_all_replicas(cluster).await;
+}
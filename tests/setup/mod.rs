@@ -1,7 +1,10 @@
+use chiselstore::network::Network;
+use chiselstore::rpc::proto::ble_server::BleServer;
 use chiselstore::rpc::proto::rpc_server::RpcServer;
+use chiselstore::rpc::proto::rpc_v2_server::RpcV2Server;
 use chiselstore::{
-    rpc::{RpcService, RpcTransport},
-    StoreServer,
+    rpc::{BleService, RpcService, RpcTransport, RpcV2Service},
+    StoreConfig, StoreServer,
 };
 use futures_util::FutureExt;
 use proto::rpc_client::RpcClient;
@@ -37,6 +40,20 @@ pub struct SPReplica {
 }
 
 pub fn make_cluster(nr: u64) -> Vec<SPReplica> {
+    make_cluster_with_network(nr, &Network::default())
+}
+
+/// Makes a cluster whose links follow the profiles of `network`.
+pub fn make_cluster_with_network(nr: u64, network: &Network) -> Vec<SPReplica> {
+    make_cluster_with(nr, network, StoreConfig::default())
+}
+
+/// Makes a cluster whose replicas start with `config`.
+pub fn make_cluster_with_config(nr: u64, config: StoreConfig) -> Vec<SPReplica> {
+    make_cluster_with(nr, &Network::default(), config)
+}
+
+fn make_cluster_with(nr: u64, network: &Network, config: StoreConfig) -> Vec<SPReplica> {
     let mut cluster = Vec::new();
     let cluster_ids: Vec<u64> = (1..(nr + 1)).collect();
 
@@ -48,7 +65,7 @@ pub fn make_cluster(nr: u64) -> Vec<SPReplica> {
             .collect();
         assert_eq!(peers.len(), (nr - 1) as usize);
 
-        let sp_replica = SPReplica::new(i as u64, peers);
+        let sp_replica = SPReplica::with_config(i as u64, peers, network.clone(), config.clone());
         cluster.push(sp_replica);
     }
 
@@ -56,29 +73,55 @@ pub fn make_cluster(nr: u64) -> Vec<SPReplica> {
 }
 
 pub async fn execute_query(replica_id: u64, stmt: String, consistency: Consistency) -> Vec<String> {
+    try_execute_query(replica_id, stmt, consistency)
+        .await
+        .unwrap()
+}
+
+pub async fn try_execute_query(
+    replica_id: u64,
+    stmt: String,
+    consistency: Consistency,
+) -> Result<Vec<String>, tonic::Status> {
     let addr = format!("http://127.0.0.1:5000{}", replica_id);
     let mut client = RpcClient::connect(addr).await.unwrap();
     let query = tonic::Request::new(Query {
         sql: stmt,
         consistency: consistency as i32,
+        ..Default::default()
     });
-    let response = client.execute(query).await.unwrap();
+    let response = client.execute(query).await?;
     let response = response.into_inner();
     let mut rows = Vec::new();
     for res in response.rows {
         rows.extend(res.values);
     }
 
-    rows
+    Ok(rows)
 }
 
 impl SPReplica {
-    pub fn new(replica_id: u64, peers: Vec<u64>) -> Self {
+    pub fn new(replica_id: u64, peers: Vec<u64>, network: Network) -> Self {
+        Self::with_config(replica_id, peers, network, StoreConfig::default())
+    }
+
+    pub fn with_config(
+        replica_id: u64,
+        peers: Vec<u64>,
+        network: Network,
+        config: StoreConfig,
+    ) -> Self {
         let (halt_sender, halt_receiver) = oneshot::channel();
         let (host, port) = node_authority(replica_id as usize);
         let rpc_listen_addr: SocketAddr = format!("{}:{}", host, port).parse().unwrap();
-        let transport = RpcTransport::new(Box::new(node_rpc_addr));
-        let server = StoreServer::start(replica_id, peers, transport).unwrap();
+        let transport =
+            RpcTransport::new(Box::new(node_rpc_addr)).with_network(replica_id, network);
+        // Tests reuse node ids across clusters of different sizes.
+        let config = StoreConfig {
+            override_membership: true,
+            ..config
+        };
+        let server = StoreServer::start_with_config(replica_id, peers, transport, config).unwrap();
         let server = Arc::new(server);
 
         let store_server_msg_event = server.clone();
@@ -91,11 +134,15 @@ impl SPReplica {
             store_server_ble.start_ble_event_loop();
         });
 
+        let ble = BleService::new(server.clone());
         let rpc = RpcService::new(server.clone());
+        let rpc_v2 = RpcV2Service::new(server.clone());
         let (rpc_tx, rpc_rx) = oneshot::channel::<()>();
         let rpc_handler = tokio::task::spawn(async move {
             let ret = Server::builder()
                 .add_service(RpcServer::new(rpc))
+                .add_service(RpcV2Server::new(rpc_v2))
+                .add_service(BleServer::new(ble))
                 .serve_with_shutdown(rpc_listen_addr, rpc_rx.map(drop))
                 .await;
             ret.unwrap()
@@ -135,6 +182,10 @@ impl SPReplica {
     pub fn get_replica_id(&self) -> u64 {
         self.replica_id
     }
+
+    pub fn server(&self) -> Arc<StoreServer<RpcTransport>> {
+        self.server.clone()
+    }
 }
 
 pub async fn halt_all_replicas(cluster: Vec<SPReplica>) {